@@ -0,0 +1,104 @@
+//! Compares lookups in an in-memory [`BlockTable`] with lookups in a
+//! [`SortedBlockIndex`].
+//!
+//! Run with `cargo bench -p rugix-bundle --bench block_lookup`. The number of blocks
+//! defaults to 2^20 (a 4 GiB slot with 4 KiB blocks) and can be changed by setting the
+//! `RUGIX_BENCH_BLOCKS` environment variable.
+//!
+//! Results on a single core of an Intel Xeon server with 5 GiB of memory:
+//!
+//! ```plain
+//! Building index with 1048576 blocks...
+//! block table: build 87.43ms, 40 ns/lookup (524357 hits), ~40960 KiB in memory
+//! sorted index: build 252.36ms, 655 ns/lookup (524357 hits), ~512 KiB in memory
+//!
+//! Building index with 4194304 blocks...
+//! block table: build 557.94ms, 66 ns/lookup (524353 hits), ~163840 KiB in memory
+//! sorted index: build 1.36s, 908 ns/lookup (524353 hits), ~2048 KiB in memory
+//! ```
+//!
+//! Lookups in the sorted index are about 15 times slower, which is negligible compared
+//! to hashing and writing a 4 KiB block, while memory usage is reduced by a factor of 80.
+
+use std::time::{Duration, Instant};
+
+use rugix_bundle::block_encoding::block_index::{BlockIndexBuilder, BlockIndexConfig};
+use rugix_bundle::block_encoding::block_table::BlockTable;
+use rugix_bundle::block_encoding::sorted_index::{SortedBlockIndex, GROUP_SIZE};
+use rugix_chunker::ChunkerAlgorithm;
+use rugix_hashes::{HashAlgorithm, HashDigest};
+
+/// Number of lookups to perform.
+const NUM_QUERIES: u64 = 1 << 20;
+
+fn main() {
+    let num_blocks = std::env::var("RUGIX_BENCH_BLOCKS")
+        .ok()
+        .and_then(|blocks| blocks.parse().ok())
+        .unwrap_or(1u64 << 20);
+    let hash_algorithm = HashAlgorithm::Sha256;
+    let hash_size = hash_algorithm.hash_size();
+
+    println!("Building index with {num_blocks} blocks...");
+    let mut builder = BlockIndexBuilder::new(BlockIndexConfig {
+        hash_algorithm,
+        chunker: ChunkerAlgorithm::Fixed { block_size_kib: 4 },
+    })
+    .unwrap();
+    let mut block = vec![0; 4096];
+    for idx in 0..num_blocks {
+        // Make every block unique.
+        block[..8].copy_from_slice(&idx.to_be_bytes());
+        builder.process(&block);
+    }
+    let index = builder.finalize();
+    // Every other query is a miss.
+    let queries = (0..NUM_QUERIES)
+        .map(|query| {
+            block[..8]
+                .copy_from_slice(&(query.wrapping_mul(7919) % (2 * num_blocks)).to_be_bytes());
+            hash_algorithm.hash(&block)
+        })
+        .collect::<Vec<_>>();
+
+    let start = Instant::now();
+    let table = BlockTable::from_index(&index);
+    let build_time = start.elapsed();
+    let (hits, lookup_time) = run_queries(&queries, |hash| table.get(&index, hash).is_some());
+    // The table itself stores a block id per block in addition to the hashes.
+    let table_memory = num_blocks as usize * (hash_size + std::mem::size_of::<usize>());
+    report("block table", build_time, lookup_time, hits, table_memory);
+
+    let path = std::env::temp_dir().join(format!(
+        "rugix-block-lookup-bench-{}.rugix-sorted-index",
+        std::process::id()
+    ));
+    let start = Instant::now();
+    SortedBlockIndex::write(&index, &path).unwrap();
+    let sorted = SortedBlockIndex::open(&path).unwrap();
+    let build_time = start.elapsed();
+    let (hits, lookup_time) = run_queries(&queries, |hash| sorted.query(hash).is_some());
+    let sorted_memory = sorted.len().div_ceil(GROUP_SIZE) * hash_size;
+    report("sorted index", build_time, lookup_time, hits, sorted_memory);
+    std::fs::remove_file(&path).unwrap();
+}
+
+fn run_queries(queries: &[HashDigest], mut lookup: impl FnMut(&[u8]) -> bool) -> (u64, Duration) {
+    let start = Instant::now();
+    let mut hits = 0;
+    for query in queries {
+        if lookup(query.raw()) {
+            hits += 1;
+        }
+    }
+    (hits, start.elapsed())
+}
+
+fn report(name: &str, build_time: Duration, lookup_time: Duration, hits: u64, memory: usize) {
+    println!(
+        "{name}: build {:.2?}, {:.0} ns/lookup ({hits} hits), ~{} KiB in memory",
+        build_time,
+        lookup_time.as_nanos() as f64 / NUM_QUERIES as f64,
+        memory / 1024,
+    );
+}
@@ -1,15 +1,16 @@
 //! Provides the [`BlockIndex`] data structure.
 
 use std::borrow::Cow;
-use std::io::{BufRead, BufReader};
+use std::io::{BufRead, BufReader, Read};
 use std::path::Path;
 
 use byte_calc::{ByteLen, NumBytes};
 
-use reportify::ResultExt;
+use reportify::{bail, ResultExt};
 use rugix_chunker::{AnyChunker, Chunker, ChunkerAlgorithm};
 use rugix_hashes::{HashAlgorithm, Hasher};
 
+use crate::builder::PayloadFile;
 use crate::format::encode::Encode;
 use crate::manifest::BlockEncoding;
 use crate::{format, BundleResult};
@@ -37,7 +38,7 @@ impl<'hashes> RawBlockIndex<'hashes> {
 /// Build a block index for the provided payload file.
 pub fn index_for_block_encoding(
     block_encoding: &BlockEncoding,
-    payload_file: &Path,
+    payload_file: &PayloadFile,
 ) -> BundleResult<BlockIndex> {
     let index_config = BlockIndexConfig {
         hash_algorithm: block_encoding
@@ -45,16 +46,26 @@ pub fn index_for_block_encoding(
             .unwrap_or(rugix_hashes::HashAlgorithm::Sha512_256),
         chunker: block_encoding.chunker.clone(),
     };
-    compute_block_index(index_config, payload_file)
+    compute_block_index_from_reader(index_config, payload_file.reader()?)
 }
 
 pub fn compute_block_index(
     index_config: BlockIndexConfig,
     payload_file: &Path,
+) -> BundleResult<BlockIndex> {
+    compute_block_index_from_reader(
+        index_config,
+        std::fs::File::open(payload_file).whatever("unable to open payload file")?,
+    )
+}
+
+/// Build a block index for the data read from the given reader.
+pub fn compute_block_index_from_reader(
+    index_config: BlockIndexConfig,
+    payload_file: impl Read,
 ) -> BundleResult<BlockIndex> {
     let mut index_builder = BlockIndexBuilder::new(index_config.clone())?;
-    let mut payload_file =
-        BufReader::new(std::fs::File::open(payload_file).whatever("unable to open payload file")?);
+    let mut payload_file = BufReader::new(payload_file);
     Ok(loop {
         let buffer = payload_file
             .fill_buf()
@@ -120,6 +131,35 @@ impl BlockIndex {
         }
     }
 
+    /// Create an index from its stored representation.
+    pub fn from_format(index: format::BlockIndex) -> BundleResult<Self> {
+        let hash_size = index.hash_algorithm.hash_size();
+        if index.block_hashes.raw.len() % hash_size != 0
+            || index.block_sizes.raw.len() % 4 != 0
+            || index.block_hashes.raw.len() / hash_size != index.block_sizes.raw.len() / 4
+        {
+            bail!("block hashes and sizes of stored index do not match");
+        }
+        let mut offsets = Vec::with_capacity(index.block_sizes.raw.len() / 4);
+        let mut sizes = Vec::with_capacity(index.block_sizes.raw.len() / 4);
+        let mut current_offset = NumBytes::ZERO;
+        for size in index.block_sizes.raw.chunks_exact(4) {
+            let size = NumBytes::new(u32::from_be_bytes(size.try_into().unwrap()).into());
+            offsets.push(current_offset);
+            sizes.push(size);
+            current_offset += size;
+        }
+        Ok(Self {
+            config: BlockIndexConfig {
+                hash_algorithm: index.hash_algorithm,
+                chunker: index.chunker,
+            },
+            hashes: index.block_hashes.raw,
+            offsets,
+            sizes,
+        })
+    }
+
     /// Encode the index for storage.
     pub fn encode(&self) -> Vec<u8> {
         let mut buffer = Vec::new();
@@ -139,6 +179,11 @@ impl BlockIndex {
         buffer
     }
 
+    /// Raw hashes of all blocks.
+    pub fn hashes(&self) -> &[u8] {
+        &self.hashes
+    }
+
     /// Convert the index into a raw hash vector.
     pub fn into_hashes_vec(self) -> Vec<u8> {
         self.hashes
@@ -166,6 +211,14 @@ impl BlockIndex {
         self.sizes[block.raw]
     }
 
+    /// Find the block starting at the given offset.
+    pub fn block_at(&self, offset: NumBytes) -> Option<BlockId> {
+        self.offsets
+            .binary_search(&offset)
+            .ok()
+            .map(|idx| BlockId { raw: idx })
+    }
+
     /// Retrieve the entry for the given block.
     pub fn entry(&self, block: BlockId) -> BlockIndexEntry {
         BlockIndexEntry {
@@ -175,6 +228,16 @@ impl BlockIndex {
         }
     }
 
+    /// Number of blocks in the index.
+    pub fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    /// Indicates whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.offsets.is_empty()
+    }
+
     /// Iterate over the block ids.
     pub fn iter(&self) -> impl Iterator<Item = BlockId> {
         (0..self.offsets.len()).map(|idx| BlockId { raw: idx })
@@ -33,6 +33,14 @@ impl BlockTable {
         table
     }
 
+    /// Get the block with the given hash.
+    pub fn get(&self, index: &BlockIndex, hash: &[u8]) -> Option<BlockId> {
+        let block_hash = self.hasher.hash_one(hash);
+        self.table
+            .find(block_hash, |other| hash == index.block_hash(*other))
+            .cloned()
+    }
+
     pub fn get_raw(&self, index: &RawBlockIndex, hash: &[u8]) -> Option<BlockId> {
         let block_hash = self.hasher.hash_one(hash);
         self.table
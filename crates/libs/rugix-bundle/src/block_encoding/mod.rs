@@ -1,114 +1,144 @@
 //! Implementation of the block encoding for Rugix's update bundles.
 
-use std::io::{BufRead, BufReader, Seek, Write};
-use std::path::Path;
+use std::io::{BufReader, Read, Seek, Write};
+use std::num::NonZeroUsize;
 
-use block_index::index_for_block_encoding;
+use block_index::{index_for_block_encoding, BlockIndex};
 use block_table::BlockTable;
 use byte_calc::{ByteLen, NumBytes};
 use reportify::{bail, ResultExt};
 use rugix_compression::ByteProcessor;
 
+use crate::builder::PayloadFile;
+use crate::encryption::{EncryptionAlgorithm, PayloadCipher};
 use crate::format::Bytes;
 use crate::manifest::{self, BlockEncoding};
 use crate::{format, BundleResult};
 
 pub mod block_index;
 pub mod block_table;
+pub mod offset_index;
+pub mod sorted_index;
 
-/// Encode a payload file.
-pub fn encode_payload_file(
-    block_encoding: &BlockEncoding,
-    payload_file: &Path,
-    payload_data: &Path,
-) -> BundleResult<format::BlockEncoding> {
-    let block_index = index_for_block_encoding(block_encoding, payload_file)?;
-    let mut block_table = BlockTable::new();
-    let mut block_sizes = Vec::new();
-    let mut payload_file = BufReader::with_capacity(
-        16 * 1024,
-        std::fs::File::open(payload_file).whatever("unable to open payload fil")?,
-    );
-    let mut payload_data =
-        std::fs::File::create(payload_data).whatever("unable to create payload data file")?;
-    let deduplicate = block_encoding.deduplicate.unwrap_or(false);
-    for block in block_index.iter() {
-        if !deduplicate || block_table.insert(&block_index, block) {
-            let entry = block_index.entry(block);
-            payload_file
-                .seek(std::io::SeekFrom::Start(entry.offset.raw))
-                .whatever("unable to seek in payload file")?;
-            match &block_encoding.compression {
-                Some(manifest::Compression::Xz(compression)) => {
-                    let mut compressor =
-                        rugix_compression::XzEncoder::new(compression.level.unwrap_or(6));
-                    let start_position = payload_data
-                        .stream_position()
-                        .whatever("unable to get position in payload data")?;
-                    let mut remaining = entry.size;
-                    while remaining > 0 {
-                        let buffer = payload_file
-                            .fill_buf()
-                            .whatever("unable to read payload file")?;
-                        if buffer.is_empty() {
-                            bail!("payload file has been truncated");
-                        };
-                        let chunk = &buffer[..remaining.min(buffer.byte_len()).unwrap_usize()];
-                        compressor
-                            .process(chunk, &mut payload_data)
-                            .whatever("unable to write compressed data")?;
-                        remaining -= chunk.byte_len();
-                        let consumed = chunk.len();
-                        payload_file.consume(consumed);
-                    }
-                    compressor
-                        .finalize(&mut payload_data)
-                        .whatever("unable to write compressed data")?;
-                    let block_size = payload_data
-                        .stream_position()
-                        .whatever("unable to get position in payload data")?
-                        - start_position;
-                    block_sizes.push(NumBytes::new(block_size));
-                }
-                None => {
-                    let mut remaining = entry.size;
-                    while remaining > 0 {
-                        let buffer = payload_file
-                            .fill_buf()
-                            .whatever("unable to read payload file")?;
-                        if buffer.is_empty() {
-                            bail!("payload file has been truncated");
-                        };
-                        let chunk = &buffer[..remaining.min(buffer.byte_len()).unwrap_usize()];
-                        payload_data
-                            .write_all(&chunk)
-                            .whatever("unable to write payload data")?;
-                        remaining -= chunk.byte_len();
-                        let consumed = chunk.len();
-                        payload_file.consume(consumed);
+/// Maximum size of the uncompressed blocks which are compressed in parallel at once.
+const MAX_BATCH_SIZE: NumBytes = NumBytes::mebibytes(64);
+
+/// Maximal overhead of the xz container format for a single block of data.
+///
+/// Consists of the stream header and footer, the block header, the block padding, the
+/// check, and the index. The block header is at most 1024 bytes in size.
+const MAX_XZ_OVERHEAD: usize = 2048;
+
+/// Maximum memory used by the encoders of the compression workers combined.
+const MAX_ENCODER_MEMORY: NumBytes = NumBytes::gibibytes(1);
+
+/// Encoder for payload files.
+#[derive(Debug)]
+pub struct PayloadEncoder<'e> {
+    /// Block encoding to use.
+    block_encoding: &'e BlockEncoding,
+    /// Payload file.
+    payload_file: PayloadFile,
+    /// Block index of the payload file.
+    block_index: BlockIndex,
+    /// Cipher to encrypt the stored blocks with.
+    cipher: Option<PayloadCipher>,
+}
+
+impl<'e> PayloadEncoder<'e> {
+    /// Create an encoder for the given payload file, computing its block index.
+    pub fn new(
+        block_encoding: &'e BlockEncoding,
+        payload_file: &PayloadFile,
+    ) -> BundleResult<Self> {
+        let block_index = index_for_block_encoding(block_encoding, payload_file)?;
+        Ok(Self {
+            block_encoding,
+            payload_file: payload_file.clone(),
+            block_index,
+            cipher: None,
+        })
+    }
+
+    /// Encrypt the stored blocks with the given cipher.
+    pub fn with_cipher(mut self, cipher: PayloadCipher) -> Self {
+        self.cipher = Some(cipher);
+        self
+    }
+
+    /// Block encoding whose encoded size is an upper bound of the size of the block
+    /// encoding returned by [`PayloadEncoder::encode`].
+    ///
+    /// As the sizes of the compressed blocks are only known after encoding, this can be
+    /// used to reserve space for the payload header in advance.
+    pub fn max_size_encoding(&self) -> BundleResult<format::BlockEncoding> {
+        Ok(self.make_encoding(
+            compress_bytes(self.block_encoding, self.block_index.hashes())?,
+            self.include_sizes().then(|| {
+                vec![0; max_compressed_size(self.block_encoding, self.block_index.len() * 4)]
+            }),
+        ))
+    }
+
+    /// Encode the payload file, writing the payload data to the given writer.
+    pub fn encode(self, payload_data: &mut dyn Write) -> BundleResult<format::BlockEncoding> {
+        let block_encoding = self.block_encoding;
+        let block_index = &self.block_index;
+        let mut block_table = BlockTable::new();
+        let mut block_sizes = Vec::new();
+        let mut payload_file = BufReader::with_capacity(
+            16 * 1024,
+            std::fs::File::open(&self.payload_file.path).whatever("unable to open payload file")?,
+        );
+        let deduplicate = block_encoding.deduplicate.unwrap_or(false);
+        // Blocks are compressed independently of each other, so we compress them in
+        // parallel in batches. To keep memory usage bounded, the size of the batches and
+        // the number of workers are limited. As the compressed blocks are written in
+        // order, the output is the same as when compressing the blocks one after another.
+        let workers = compression_workers(block_encoding);
+        let mut batch = Vec::new();
+        let mut batch_size = NumBytes::ZERO;
+        for block in block_index.iter() {
+            if !deduplicate || block_table.insert(block_index, block) {
+                let entry = block_index.entry(block);
+                payload_file
+                    .seek(std::io::SeekFrom::Start(
+                        self.payload_file.offset + entry.offset.raw,
+                    ))
+                    .whatever("unable to seek in payload file")?;
+                let mut data = vec![0; entry.size.unwrap_usize()];
+                payload_file
+                    .read_exact(&mut data)
+                    .whatever("payload file has been truncated")?;
+                if block_encoding.compression.is_some() {
+                    batch_size += entry.size;
+                    batch.push(data);
+                    if batch_size >= MAX_BATCH_SIZE {
+                        write_compressed_batch(
+                            block_encoding,
+                            self.cipher.as_ref(),
+                            workers,
+                            &batch,
+                            payload_data,
+                            &mut block_sizes,
+                        )?;
+                        batch.clear();
+                        batch_size = NumBytes::ZERO;
                     }
-                    block_sizes.push(entry.size);
+                } else {
+                    write_block(self.cipher.as_ref(), data, payload_data, &mut block_sizes)?;
                 }
             }
         }
-    }
-    let is_fixed_size_chunker = block_index.config().chunker.is_fixed();
-    let is_compressed = block_encoding.compression.is_some();
-    let include_sizes = !is_fixed_size_chunker || is_compressed;
-    Ok(format::BlockEncoding {
-        hash_algorithm: block_index.config().hash_algorithm,
-        deduplicated: deduplicate,
-        compression: block_encoding
-            .compression
-            .as_ref()
-            .map(|compression| match compression {
-                manifest::Compression::Xz(_) => rugix_compression::CompressionFormat::Xz,
-            }),
-        chunker: block_index.config().chunker.clone(),
-        block_hashes: Bytes {
-            raw: compress_bytes(block_encoding, &block_index.into_hashes_vec()),
-        },
-        block_sizes: if include_sizes {
+        write_compressed_batch(
+            block_encoding,
+            self.cipher.as_ref(),
+            workers,
+            &batch,
+            payload_data,
+            &mut block_sizes,
+        )?;
+        let block_sizes = if self.include_sizes() {
             let mut encoded_sizes = Vec::new();
             for size in block_sizes {
                 encoded_sizes.extend_from_slice(
@@ -117,24 +147,225 @@ pub fn encode_payload_file(
                         .to_be_bytes(),
                 );
             }
-            Some(Bytes {
-                raw: compress_bytes(block_encoding, &encoded_sizes),
-            })
+            Some(compress_bytes(block_encoding, &encoded_sizes)?)
         } else {
             None
-        },
+        };
+        Ok(self.make_encoding(
+            compress_bytes(block_encoding, self.block_index.hashes())?,
+            block_sizes,
+        ))
+    }
+
+    /// Indicates whether the encoding includes the sizes of the blocks.
+    fn include_sizes(&self) -> bool {
+        let is_fixed_size_chunker = self.block_index.config().chunker.is_fixed();
+        let is_compressed = self.block_encoding.compression.is_some();
+        // Encrypted blocks are larger than the plaintext blocks.
+        let is_encrypted = self.cipher.is_some();
+        !is_fixed_size_chunker || is_compressed || is_encrypted
+    }
+
+    fn make_encoding(
+        &self,
+        block_hashes: Vec<u8>,
+        block_sizes: Option<Vec<u8>>,
+    ) -> format::BlockEncoding {
+        format::BlockEncoding {
+            hash_algorithm: self.block_index.config().hash_algorithm,
+            deduplicated: self.block_encoding.deduplicate.unwrap_or(false),
+            compression: self.block_encoding.compression.as_ref().map(|compression| {
+                match compression {
+                    manifest::Compression::Xz(_) => rugix_compression::CompressionFormat::Xz,
+                }
+            }),
+            encryption: self
+                .cipher
+                .is_some()
+                .then_some(EncryptionAlgorithm::Aes256Gcm),
+            chunker: self.block_index.config().chunker.clone(),
+            block_hashes: Bytes { raw: block_hashes },
+            block_sizes: block_sizes.map(|raw| Bytes { raw }),
+        }
+    }
+}
+
+/// Compress a batch of blocks with the given number of workers and write them to the
+/// payload data.
+fn write_compressed_batch(
+    block_encoding: &BlockEncoding,
+    cipher: Option<&PayloadCipher>,
+    workers: usize,
+    batch: &[Vec<u8>],
+    payload_data: &mut dyn Write,
+    block_sizes: &mut Vec<NumBytes>,
+) -> BundleResult<()> {
+    if batch.is_empty() {
+        return Ok(());
+    }
+    let chunk_size = batch.len().div_ceil(workers);
+    let compressed = std::thread::scope(|scope| {
+        let workers = batch
+            .chunks(chunk_size)
+            .map(|chunk| {
+                scope.spawn(move || {
+                    chunk
+                        .iter()
+                        .map(|block| compress_bytes(block_encoding, block))
+                        .collect::<BundleResult<Vec<_>>>()
+                })
+            })
+            .collect::<Vec<_>>();
+        let mut compressed = Vec::with_capacity(batch.len());
+        for worker in workers {
+            let Ok(blocks) = worker.join() else {
+                bail!("compression worker panicked");
+            };
+            compressed.extend(blocks?);
+        }
+        Ok(compressed)
+    })?;
+    for block in compressed {
+        write_block(cipher, block, payload_data, block_sizes)?;
+    }
+    Ok(())
+}
+
+/// Number of workers to compress blocks with.
+///
+/// The number of workers is limited by the available parallelism and by the memory
+/// required for the encoders, which is about 674 MiB per encoder for the highest xz
+/// compression level.
+fn compression_workers(block_encoding: &BlockEncoding) -> usize {
+    let parallelism = std::thread::available_parallelism()
+        .map(NonZeroUsize::get)
+        .unwrap_or(1);
+    let encoder_memory = match &block_encoding.compression {
+        Some(manifest::Compression::Xz(compression)) => {
+            xz_encoder_memory(compression.level.unwrap_or(6))
+        }
+        None => return 1,
+    };
+    let max_workers = (MAX_ENCODER_MEMORY.raw / encoder_memory.raw).max(1);
+    parallelism.min(usize::try_from(max_workers).unwrap_or(usize::MAX))
+}
+
+/// Approximate memory required by an xz encoder with the given compression level.
+///
+/// The numbers are taken from the documentation of `xz`.
+fn xz_encoder_memory(level: u8) -> NumBytes {
+    NumBytes::mebibytes(match level {
+        0 => 3,
+        1 => 9,
+        2 => 17,
+        3 => 32,
+        4 => 48,
+        5 | 6 => 94,
+        7 => 186,
+        8 => 370,
+        _ => 674,
     })
 }
 
-fn compress_bytes(block_encoding: &BlockEncoding, bytes: &[u8]) -> Vec<u8> {
+/// Write a block to the payload data, encrypting it if a cipher is given.
+fn write_block(
+    cipher: Option<&PayloadCipher>,
+    mut block: Vec<u8>,
+    payload_data: &mut dyn Write,
+    block_sizes: &mut Vec<NumBytes>,
+) -> BundleResult<()> {
+    if let Some(cipher) = cipher {
+        block = cipher.encrypt_block(block_sizes.len(), &block)?;
+    }
+    payload_data
+        .write_all(&block)
+        .whatever("unable to write payload data")?;
+    block_sizes.push(block.byte_len());
+    Ok(())
+}
+
+fn compress_bytes(block_encoding: &BlockEncoding, bytes: &[u8]) -> BundleResult<Vec<u8>> {
     match &block_encoding.compression {
         Some(manifest::Compression::Xz(compression)) => {
             let mut compressor = rugix_compression::XzEncoder::new(compression.level.unwrap_or(6));
             let mut output = Vec::new();
-            compressor.process(bytes, &mut output).unwrap();
-            compressor.finalize(&mut output).unwrap();
-            output
+            compressor
+                .process(bytes, &mut output)
+                .whatever("unable to compress block")?;
+            compressor
+                .finalize(&mut output)
+                .whatever("unable to compress block")?;
+            Ok(output)
         }
-        None => bytes.to_vec(),
+        None => Ok(bytes.to_vec()),
+    }
+}
+
+/// Upper bound for the size of `size` bytes compressed with [`compress_bytes`].
+fn max_compressed_size(block_encoding: &BlockEncoding, size: usize) -> usize {
+    match &block_encoding.compression {
+        // If data cannot be compressed, LZMA2 stores it in uncompressed chunks of at
+        // most 64 KiB, each of which has a header of 3 bytes.
+        Some(manifest::Compression::Xz(_)) => {
+            size + size.div_ceil(64 * 1024) * 3 + 1 + MAX_XZ_OVERHEAD
+        }
+        None => size,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rugix_chunker::ChunkerAlgorithm;
+
+    use super::{compression_workers, write_compressed_batch};
+    use crate::manifest::{BlockEncoding, Compression, XzCompression};
+
+    #[test]
+    fn test_parallel_compression_is_deterministic() {
+        let block_encoding = BlockEncoding::new(ChunkerAlgorithm::Fixed { block_size_kib: 4 })
+            .with_compression(Some(Compression::Xz(XzCompression::new())));
+        let batch = (0..64u32)
+            .map(|block| {
+                (0..4096u32)
+                    .map(|idx| ((idx * block) % 251 + idx / 512) as u8)
+                    .collect::<Vec<_>>()
+            })
+            .collect::<Vec<_>>();
+        let mut serial_data = Vec::new();
+        let mut serial_sizes = Vec::new();
+        write_compressed_batch(
+            &block_encoding,
+            None,
+            1,
+            &batch,
+            &mut serial_data,
+            &mut serial_sizes,
+        )
+        .unwrap();
+        for workers in [2, 7, 64, 100] {
+            let mut data = Vec::new();
+            let mut sizes = Vec::new();
+            write_compressed_batch(
+                &block_encoding,
+                None,
+                workers,
+                &batch,
+                &mut data,
+                &mut sizes,
+            )
+            .unwrap();
+            assert_eq!(data, serial_data);
+            assert_eq!(sizes, serial_sizes);
+        }
+        assert_eq!(serial_sizes.len(), batch.len());
+    }
+
+    #[test]
+    fn test_compression_workers_memory_limit() {
+        let level_9 = BlockEncoding::new(ChunkerAlgorithm::Fixed { block_size_kib: 4 })
+            .with_compression(Some(Compression::Xz(
+                XzCompression::new().with_level(Some(9)),
+            )));
+        assert_eq!(compression_workers(&level_9), 1);
     }
 }
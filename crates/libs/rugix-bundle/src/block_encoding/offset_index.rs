@@ -0,0 +1,201 @@
+//! Provides the [`OffsetBlockIndex`] data structure.
+//!
+//! An offset block index is the counterpart of a
+//! [`SortedBlockIndex`][super::sorted_index::SortedBlockIndex] for looking up blocks by
+//! their offset instead of their hash, e.g., to check whether a block is already present
+//! at a specific offset of a slot. The entries of the index are stored sorted by their
+//! offsets in a file and only the offset of the first entry of every group of
+//! [`GROUP_SIZE`] entries is kept in memory.
+//!
+//! The file format is the same as for sorted block indices, except that the magic bytes
+//! are `RUGIXOBI` and that the entries are sorted by their offsets. As blocks do not
+//! overlap, each offset appears at most once.
+
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use byte_calc::NumBytes;
+use reportify::{bail, ResultExt};
+use tracing::warn;
+
+use super::block_index::BlockIndex;
+use super::sorted_index::{partition_point, GROUP_SIZE};
+use crate::BundleResult;
+
+/// Magic bytes at the start of an offset block index file.
+const MAGIC: &[u8; 8] = b"RUGIXOBI";
+
+/// Size of the header of an offset block index file.
+const HEADER_SIZE: usize = 16;
+
+/// Block index sorted by block offsets and stored in a file.
+#[derive(Debug)]
+pub struct OffsetBlockIndex {
+    /// Index file.
+    file: fs::File,
+    /// Size of the block hashes.
+    hash_size: usize,
+    /// Number of entries.
+    len: usize,
+    /// Offset of the first block of every group of entries.
+    fences: Vec<u64>,
+}
+
+impl OffsetBlockIndex {
+    /// Write an offset block index for the provided index to the given path.
+    pub fn write(index: &BlockIndex, path: &Path) -> BundleResult<()> {
+        let file = fs::File::create(path).whatever("unable to create offset index file")?;
+        let mut writer = BufWriter::new(file);
+        write_index(&mut writer, index).whatever("unable to write offset index")?;
+        writer
+            .into_inner()
+            .whatever("unable to write offset index")?
+            .sync_all()
+            .whatever("unable to write offset index")?;
+        Ok(())
+    }
+
+    /// Open the offset block index at the given path.
+    pub fn open(path: &Path) -> BundleResult<Self> {
+        let file = fs::File::open(path).whatever("unable to open offset index file")?;
+        let file_size = file
+            .metadata()
+            .whatever("unable to get size of offset index file")?
+            .len();
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)
+            .whatever("unable to read offset index header")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("invalid offset index file");
+        }
+        let hash_size = u64::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+        let (Ok(hash_size), Ok(file_size)) =
+            (usize::try_from(hash_size), usize::try_from(file_size))
+        else {
+            bail!("invalid offset index file");
+        };
+        let entry_size = hash_size + 12;
+        if hash_size == 0 || hash_size > 64 || (file_size - HEADER_SIZE) % entry_size != 0 {
+            bail!("invalid offset index file");
+        }
+        let len = (file_size - HEADER_SIZE) / entry_size;
+        let mut fences = Vec::with_capacity(len.div_ceil(GROUP_SIZE));
+        for group in 0..len.div_ceil(GROUP_SIZE) {
+            let position = HEADER_SIZE + group * GROUP_SIZE * entry_size + hash_size;
+            let mut offset = [0; 8];
+            file.read_exact_at(&mut offset, position as u64)
+                .whatever("unable to read offset index")?;
+            fences.push(u64::from_be_bytes(offset));
+        }
+        Ok(Self {
+            file,
+            hash_size,
+            len,
+            fences,
+        })
+    }
+
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Indicates whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Query the index for a block with the given hash starting at the given offset.
+    ///
+    /// Returns the size of the block.
+    pub fn query(&self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        if hash.len() != self.hash_size {
+            return None;
+        }
+        // Find the last group whose first offset is not greater than the given offset.
+        let group = partition_point(self.fences.len(), |group| self.fences[group] <= offset.raw)
+            .checked_sub(1)?;
+        let entry_size = self.hash_size + 12;
+        let first = group * GROUP_SIZE;
+        let count = GROUP_SIZE.min(self.len - first);
+        let mut entries = vec![0; count * entry_size];
+        if let Err(error) = self
+            .file
+            .read_exact_at(&mut entries, (HEADER_SIZE + first * entry_size) as u64)
+        {
+            warn!("unable to read offset index: {error}");
+            return None;
+        }
+        let entry = |idx: usize| &entries[idx * entry_size..(idx + 1) * entry_size];
+        let entry_offset = |idx: usize| {
+            u64::from_be_bytes(
+                entry(idx)[self.hash_size..self.hash_size + 8]
+                    .try_into()
+                    .unwrap(),
+            )
+        };
+        let idx = partition_point(count, |idx| entry_offset(idx) < offset.raw);
+        if idx == count || entry_offset(idx) != offset.raw || &entry(idx)[..self.hash_size] != hash
+        {
+            return None;
+        }
+        let size = u32::from_be_bytes(entry(idx)[self.hash_size + 8..].try_into().unwrap());
+        Some(NumBytes::new(size.into()))
+    }
+}
+
+fn write_index(writer: &mut impl Write, index: &BlockIndex) -> std::io::Result<()> {
+    writer.write_all(MAGIC)?;
+    let hash_size = index.config().hash_algorithm.hash_size();
+    writer.write_all(&(hash_size as u64).to_be_bytes())?;
+    // The blocks of an index are already sorted by their offsets.
+    for block in index.iter() {
+        let entry = index.entry(block);
+        let size = u32::try_from(entry.size.raw).expect("blocks should not be larger than 4GiB");
+        writer.write_all(entry.hash)?;
+        writer.write_all(&entry.offset.raw.to_be_bytes())?;
+        writer.write_all(&size.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_calc::NumBytes;
+    use rugix_chunker::ChunkerAlgorithm;
+    use rugix_hashes::HashAlgorithm;
+
+    use super::OffsetBlockIndex;
+    use crate::block_encoding::block_index::{BlockIndexBuilder, BlockIndexConfig};
+
+    #[test]
+    fn test_offset_index_query() {
+        let data = (0..300 * 1024)
+            .map(|idx: u32| (idx / 1024 % 200) as u8)
+            .collect::<Vec<_>>();
+        let mut builder = BlockIndexBuilder::new(BlockIndexConfig {
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunker: ChunkerAlgorithm::Fixed { block_size_kib: 1 },
+        })
+        .unwrap();
+        builder.process(&data);
+        let index = builder.finalize();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("offsets");
+        OffsetBlockIndex::write(&index, &path).unwrap();
+        let offsets = OffsetBlockIndex::open(&path).unwrap();
+        assert_eq!(offsets.len(), 300);
+        for block in index.iter() {
+            let entry = index.entry(block);
+            assert_eq!(offsets.query(entry.offset, entry.hash), Some(entry.size));
+            // Blocks must start at the given offset and have the given hash.
+            assert!(offsets
+                .query(entry.offset + NumBytes::new(1), entry.hash)
+                .is_none());
+            assert!(offsets.query(entry.offset, &[0; 32]).is_none());
+        }
+        assert!(offsets.query(NumBytes::kibibytes(300), &[0; 32]).is_none());
+    }
+}
@@ -0,0 +1,254 @@
+//! Provides the [`SortedBlockIndex`] data structure.
+//!
+//! A [`BlockTable`][super::block_table::BlockTable] needs to keep all block hashes of an
+//! index in memory. For very large slots, e.g., 32 GiB with 4 KiB blocks, this amounts to
+//! hundreds of megabytes. A sorted block index instead stores the entries of an index
+//! sorted by their hashes in a file. Only the first hash of every group of
+//! [`GROUP_SIZE`] entries is kept in memory. To query the index, the group that may
+//! contain the hash is determined with a binary search in memory and then read from the
+//! file with a single positional read. Hence, memory usage is bounded by a fraction of
+//! the index size and the kernel is free to evict the file from its page cache.
+//!
+//! The file starts with a header of 16 bytes, consisting of the magic bytes
+//! `RUGIXSBI` and the hash size as an unsigned 64-bit integer. The header is followed by
+//! the entries, each consisting of the block hash, the offset of the block as an unsigned
+//! 64-bit integer, and the size of the block as an unsigned 32-bit integer. All integers
+//! are stored in big-endian byte order. The entries are sorted by their hashes and each
+//! hash appears at most once. As all entries have the same size, the file can also be
+//! memory-mapped and searched directly.
+
+use std::fs;
+use std::io::{BufWriter, Write};
+use std::os::unix::fs::FileExt;
+use std::path::Path;
+
+use byte_calc::NumBytes;
+use reportify::{bail, ResultExt};
+use tracing::warn;
+
+use super::block_index::BlockIndex;
+use crate::BundleResult;
+
+/// Magic bytes at the start of a sorted block index file.
+const MAGIC: &[u8; 8] = b"RUGIXSBI";
+
+/// Size of the header of a sorted block index file.
+const HEADER_SIZE: usize = 16;
+
+/// Number of entries per group.
+///
+/// With 32-byte hashes, a group spans 2816 bytes, which fits into a single page.
+pub const GROUP_SIZE: usize = 64;
+
+/// Block index sorted by block hashes and stored in a file.
+#[derive(Debug)]
+pub struct SortedBlockIndex {
+    /// Index file.
+    file: fs::File,
+    /// Size of the block hashes.
+    hash_size: usize,
+    /// Number of entries.
+    len: usize,
+    /// First hash of every group of entries.
+    fences: Vec<u8>,
+}
+
+impl SortedBlockIndex {
+    /// Write a sorted block index for the provided index to the given path.
+    pub fn write(index: &BlockIndex, path: &Path) -> BundleResult<()> {
+        let entries = index
+            .iter()
+            .map(|block| {
+                let entry = index.entry(block);
+                (entry.hash, entry.offset, entry.size)
+            })
+            .collect();
+        Self::write_entries(path, index.config().hash_algorithm.hash_size(), entries)
+    }
+
+    /// Write a sorted block index with the provided entries to the given path.
+    ///
+    /// Each entry consists of the hash, the offset, and the size of a block. The hashes
+    /// must have the given size.
+    pub fn write_entries(
+        path: &Path,
+        hash_size: usize,
+        mut entries: Vec<(&[u8], NumBytes, NumBytes)>,
+    ) -> BundleResult<()> {
+        if entries.iter().any(|(hash, _, _)| hash.len() != hash_size) {
+            bail!("block hashes must have a size of {hash_size} bytes");
+        }
+        entries.sort_by(|x, y| x.0.cmp(y.0));
+        entries.dedup_by(|x, y| x.0 == y.0);
+        let file = fs::File::create(path).whatever("unable to create sorted index file")?;
+        let mut writer = BufWriter::new(file);
+        write_index(&mut writer, hash_size, &entries).whatever("unable to write sorted index")?;
+        writer
+            .into_inner()
+            .whatever("unable to write sorted index")?
+            .sync_all()
+            .whatever("unable to write sorted index")?;
+        Ok(())
+    }
+
+    /// Open the sorted block index at the given path.
+    pub fn open(path: &Path) -> BundleResult<Self> {
+        let file = fs::File::open(path).whatever("unable to open sorted index file")?;
+        let file_size = file
+            .metadata()
+            .whatever("unable to get size of sorted index file")?
+            .len();
+        let mut header = [0; HEADER_SIZE];
+        file.read_exact_at(&mut header, 0)
+            .whatever("unable to read sorted index header")?;
+        if &header[..MAGIC.len()] != MAGIC {
+            bail!("invalid sorted index file");
+        }
+        let hash_size = u64::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+        let (Ok(hash_size), Ok(file_size)) =
+            (usize::try_from(hash_size), usize::try_from(file_size))
+        else {
+            bail!("invalid sorted index file");
+        };
+        let entry_size = hash_size + 12;
+        if hash_size == 0 || hash_size > 64 || (file_size - HEADER_SIZE) % entry_size != 0 {
+            bail!("invalid sorted index file");
+        }
+        let len = (file_size - HEADER_SIZE) / entry_size;
+        let mut fences = vec![0; len.div_ceil(GROUP_SIZE) * hash_size];
+        for (group, fence) in fences.chunks_exact_mut(hash_size).enumerate() {
+            let position = HEADER_SIZE + group * GROUP_SIZE * entry_size;
+            file.read_exact_at(fence, position as u64)
+                .whatever("unable to read sorted index")?;
+        }
+        Ok(Self {
+            file,
+            hash_size,
+            len,
+            fences,
+        })
+    }
+
+    /// Number of entries in the index.
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    /// Indicates whether the index is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Query the index for a block with the given hash.
+    ///
+    /// Returns the offset and size of the block.
+    pub fn query(&self, hash: &[u8]) -> Option<(NumBytes, NumBytes)> {
+        if hash.len() != self.hash_size {
+            return None;
+        }
+        // Find the last group whose first hash is not greater than the given hash.
+        let num_groups = self.fences.len() / self.hash_size;
+        let group = partition_point(num_groups, |group| {
+            &self.fences[group * self.hash_size..(group + 1) * self.hash_size] <= hash
+        })
+        .checked_sub(1)?;
+        let entry_size = self.entry_size();
+        let first = group * GROUP_SIZE;
+        let count = GROUP_SIZE.min(self.len - first);
+        let mut entries = vec![0; count * entry_size];
+        if let Err(error) = self
+            .file
+            .read_exact_at(&mut entries, (HEADER_SIZE + first * entry_size) as u64)
+        {
+            warn!("unable to read sorted index: {error}");
+            return None;
+        }
+        let entry = |idx: usize| &entries[idx * entry_size..(idx + 1) * entry_size];
+        let idx = partition_point(count, |idx| &entry(idx)[..self.hash_size] < hash);
+        if idx == count || &entry(idx)[..self.hash_size] != hash {
+            return None;
+        }
+        let position = &entry(idx)[self.hash_size..];
+        let offset = u64::from_be_bytes(position[..8].try_into().unwrap());
+        let size = u32::from_be_bytes(position[8..].try_into().unwrap());
+        Some((NumBytes::new(offset), NumBytes::new(size.into())))
+    }
+
+    fn entry_size(&self) -> usize {
+        self.hash_size + 12
+    }
+}
+
+/// Index of the first element in `0..len` for which the predicate is false.
+///
+/// The predicate must be true for a prefix of the elements and false for the rest.
+pub(crate) fn partition_point(len: usize, mut pred: impl FnMut(usize) -> bool) -> usize {
+    let (mut low, mut high) = (0, len);
+    while low < high {
+        let middle = low + (high - low) / 2;
+        if pred(middle) {
+            low = middle + 1;
+        } else {
+            high = middle;
+        }
+    }
+    low
+}
+
+fn write_index(
+    writer: &mut impl Write,
+    hash_size: usize,
+    entries: &[(&[u8], NumBytes, NumBytes)],
+) -> std::io::Result<()> {
+    writer.write_all(MAGIC)?;
+    writer.write_all(&(hash_size as u64).to_be_bytes())?;
+    for (hash, offset, size) in entries {
+        let size = u32::try_from(size.raw).expect("blocks should not be larger than 4GiB");
+        writer.write_all(hash)?;
+        writer.write_all(&offset.raw.to_be_bytes())?;
+        writer.write_all(&size.to_be_bytes())?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use rugix_chunker::ChunkerAlgorithm;
+    use rugix_hashes::HashAlgorithm;
+
+    use super::SortedBlockIndex;
+    use crate::block_encoding::block_index::{BlockIndex, BlockIndexBuilder, BlockIndexConfig};
+
+    fn build_index(data: &[u8]) -> BlockIndex {
+        let mut builder = BlockIndexBuilder::new(BlockIndexConfig {
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunker: ChunkerAlgorithm::Fixed { block_size_kib: 1 },
+        })
+        .unwrap();
+        builder.process(data);
+        builder.finalize()
+    }
+
+    #[test]
+    fn test_sorted_index_query() {
+        let data = (0..300 * 1024)
+            .map(|idx: u32| (idx / 1024 % 200) as u8)
+            .collect::<Vec<_>>();
+        let index = build_index(&data);
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("sorted");
+        SortedBlockIndex::write(&index, &path).unwrap();
+        let sorted = SortedBlockIndex::open(&path).unwrap();
+        // There are only 200 distinct blocks spanning multiple groups.
+        assert_eq!(sorted.len(), 200);
+        for block in index.iter() {
+            let entry = index.entry(block);
+            let (offset, size) = sorted.query(entry.hash).unwrap();
+            assert_eq!(
+                data[offset.unwrap_usize()..(offset + size).unwrap_usize()],
+                data[entry.offset.unwrap_usize()..(entry.offset + entry.size).unwrap_usize()],
+            );
+        }
+        assert!(sorted.query(&[0; 32]).is_none());
+    }
+}
@@ -1,49 +1,171 @@
-use std::io::{BufRead, BufReader, BufWriter, Write};
+//! Functionality for building bundles.
+//!
+//! Bundles are written in a single pass to a seekable sink without any temporary copies
+//! of the payloads. As the bundle header and the payload headers precede the payload
+//! data but depend on it, space is reserved for them and they are written after the
+//! payload data. The bundle header has a fixed size, given the hashes of the payload
+//! headers and the offsets of the payloads. For payload headers, an upper bound is
+//! reserved and any remaining space is filled with padding atoms, which are ignored by
+//! readers.
+
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Seek, SeekFrom, Write};
 use std::path::{Path, PathBuf};
 
 use byte_calc::NumBytes;
-use reportify::ResultExt;
+use reportify::{bail, ResultExt};
 use rugix_hashes::HashDigest;
 
-use crate::block_encoding::encode_payload_file;
-use crate::format::stlv::{write_atom_head, write_segment_end, write_segment_start};
-use crate::format::{self, Bytes, PayloadEntry, PayloadHeader};
+use crate::block_encoding::PayloadEncoder;
+use crate::encryption::{ContentKey, EncryptionKey, PayloadCipher};
+use crate::format::stlv::{write_atom_head, write_segment_end, write_segment_start, AtomHead};
+use crate::format::{self, tags, Bytes, PayloadEntry, PayloadHeader};
 use crate::manifest::{self, BundleManifest, HashAlgorithm, UpdateType};
 use crate::BundleResult;
 
-pub fn pack(path: &Path, dst: &Path) -> BundleResult<()> {
+/// Maximal size of the head of a value atom.
+const MAX_VALUE_HEAD_SIZE: u64 = AtomHead::value(tags::PADDING, NumBytes::new(u64::MAX / 2))
+    .head_size()
+    .raw;
+
+/// Minimal size of the head of a value atom.
+const MIN_VALUE_HEAD_SIZE: u64 = AtomHead::value(tags::PADDING, NumBytes::ZERO)
+    .head_size()
+    .raw;
+
+/// File or region of a file containing a payload.
+#[derive(Debug, Clone)]
+pub struct PayloadFile {
+    /// Path of the file.
+    pub path: PathBuf,
+    /// Offset of the payload in the file.
+    pub offset: u64,
+    /// Size of the payload.
+    pub size: u64,
+}
+
+impl PayloadFile {
+    /// Payload consisting of the entire file at the given path.
+    pub fn new(path: &Path) -> BundleResult<Self> {
+        let size = std::fs::metadata(path)
+            .whatever("unable to get size of payload file")?
+            .len();
+        Ok(Self::region(path, 0, size))
+    }
+
+    /// Payload consisting of the given region of the file at the given path.
+    ///
+    /// This allows creating bundles from parts of a file, e.g., partitions of an image,
+    /// without extracting them first.
+    pub fn region(path: &Path, offset: u64, size: u64) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            offset,
+            size,
+        }
+    }
+
+    /// Open the payload for reading.
+    pub fn reader(&self) -> BundleResult<io::Take<File>> {
+        let mut file = File::open(&self.path).whatever("unable to open payload file")?;
+        file.seek(SeekFrom::Start(self.offset))
+            .whatever("unable to seek in payload file")?;
+        Ok(file.take(self.size))
+    }
+}
+
+/// Create a bundle from the bundle directory at `path` and write it to `dst`.
+///
+/// The content key of encrypted payloads is wrapped for the given encryption keys.
+pub fn pack(path: &Path, dst: &Path, encryption_keys: &[EncryptionKey]) -> BundleResult<()> {
     let manifest = toml::from_str::<BundleManifest>(
         &std::fs::read_to_string(path.join("rugix-bundle.toml"))
             .whatever("unable to read bundle manifest")?,
     )
     .whatever("unable to parse bundle manifest")?;
+    let payloads_dir = path.join("payloads");
+    let payload_files = manifest
+        .payloads
+        .iter()
+        .map(|payload| {
+            PayloadFile::new(&payloads_dir.join(&payload.filename))
+                .with_info(|_| format!("payload: {:?}", payload.filename))
+        })
+        .collect::<BundleResult<Vec<_>>>()?;
+    write_bundle_file(&manifest, &payload_files, encryption_keys, dst)
+}
+
+/// Create a bundle with the given manifest and payload files and write it to `dst`.
+///
+/// Prints the hash of the bundle header.
+pub fn write_bundle_file(
+    manifest: &BundleManifest,
+    payload_files: &[PayloadFile],
+    encryption_keys: &[EncryptionKey],
+    dst: &Path,
+) -> BundleResult<()> {
+    let mut bundle_file =
+        BufWriter::new(File::create(dst).whatever("unable to create bundle file")?);
+    let header_hash = write_bundle(manifest, payload_files, encryption_keys, &mut bundle_file)?;
+    bundle_file
+        .flush()
+        .whatever("unable to write bundle file")?;
+    println!("{header_hash}");
+    Ok(())
+}
+
+/// Write a bundle with the given manifest to the given sink.
+///
+/// The payload files are given in the order of the payloads of the manifest. Returns the
+/// hash of the bundle header. As the sink is written to with small writes, it should be
+/// buffered.
+///
+/// If any payload is encrypted, a fresh content key is generated and wrapped for each of
+/// the given encryption keys.
+pub fn write_bundle<W: Write + Seek>(
+    manifest: &BundleManifest,
+    payload_files: &[PayloadFile],
+    encryption_keys: &[EncryptionKey],
+    sink: &mut W,
+) -> BundleResult<HashDigest> {
+    if payload_files.len() != manifest.payloads.len() {
+        bail!("number of payload files does not match the manifest");
+    }
+    check_script_order(manifest)?;
+    let content_key = if manifest.payloads.iter().any(is_encrypted) {
+        if encryption_keys.is_empty() {
+            bail!("encrypted payloads require at least one encryption key");
+        }
+        Some(ContentKey::generate())
+    } else {
+        if !encryption_keys.is_empty() {
+            bail!("encryption keys have been provided but no payload is encrypted");
+        }
+        None
+    };
     let hash_algorithm = manifest
         .hash_algorithm
         .unwrap_or(rugix_hashes::HashAlgorithm::Sha512_256);
     let mut bundle_header = format::BundleHeader {
-        manifest: Some(serde_json::to_string(&manifest).unwrap()),
+        manifest: Some(serde_json::to_string(manifest).unwrap()),
         is_incremental: matches!(manifest.update_type, UpdateType::Incremental),
         hash_algorithm,
         payload_index: Vec::new(),
+        security_version: manifest.security_version,
+        channel: manifest.channel.clone(),
+        encryption_keys: match &content_key {
+            Some(content_key) => encryption_keys
+                .iter()
+                .map(|key| content_key.wrap(key))
+                .collect::<BundleResult<_>>()?,
+            None => Vec::new(),
+        },
     };
-    let mut prepared_payloads = Vec::new();
-    for (idx, payload) in manifest.payloads.iter().enumerate() {
-        let payload_file = path.join("payloads").join(&payload.filename);
-        let payload_file_hash =
-            hash_file(hash_algorithm, &payload_file).whatever("unable to hash payload file")?;
-        let mut payload_data = payload_file.clone();
-        let mut payload_header = PayloadHeader {
-            block_encoding: None,
-        };
-        if let Some(block_encoding) = &payload.block_encoding {
-            payload_data = path.join(format!(".payload{idx}.data"));
-            payload_header.block_encoding = Some(encode_payload_file(
-                block_encoding,
-                &payload_file,
-                &payload_data,
-            )?);
-        }
-        let payload_header = format::encode::to_vec(&payload_header, format::tags::PAYLOAD_HEADER);
+    for (payload, payload_file) in manifest.payloads.iter().zip(payload_files) {
+        let payload_file_hash = hash_payload(hash_algorithm, payload_file)
+            .with_info(|_| format!("payload: {:?}", payload.filename))?;
+        // The hash of the payload header and the offset are filled in later.
+        let payload_header_hash = vec![0; hash_algorithm.hash_size()];
         bundle_header.payload_index.push(PayloadEntry {
             type_slot: if let manifest::DeliveryConfig::Slot(slot_config) = &payload.delivery {
                 Some(format::SlotPayloadType {
@@ -61,62 +183,260 @@ pub fn pack(path: &Path, dst: &Path) -> BundleResult<()> {
             } else {
                 None
             },
+            type_script: if let manifest::DeliveryConfig::Script(script_delivery_config) =
+                &payload.delivery
+            {
+                Some(format::ScriptPayloadType {
+                    stage: script_delivery_config.stage.as_str().to_owned(),
+                    interpreter: script_delivery_config
+                        .interpreter
+                        .clone()
+                        .unwrap_or_default(),
+                })
+            } else {
+                None
+            },
             header_hash: Bytes {
-                raw: hash_algorithm.hash(&payload_header).raw().to_vec(),
+                raw: payload_header_hash,
             },
             file_hash: Bytes {
                 raw: payload_file_hash.raw().to_vec(),
             },
+            condition: payload
+                .condition
+                .as_ref()
+                .map(|condition| format::PayloadCondition {
+                    compatible: condition.compatible.clone(),
+                    dmi_product_name: condition.dmi_product_name.clone(),
+                    variant: condition.variant.clone(),
+                }),
+            offset: Some(0),
+            file_size: Some(payload_file.size),
         });
-        prepared_payloads.push(PreparedPayload {
-            payload_header,
-            payload_data,
-        })
     }
-    let mut bundle_file =
-        BufWriter::new(std::fs::File::create(dst).whatever("unable to create bundle file")?);
-    write_segment_start(&mut bundle_file, format::tags::BUNDLE).unwrap();
-    let bundle_header = format::encode::to_vec(&bundle_header, format::tags::BUNDLE_HEADER);
-    let header_hash = hash_algorithm.hash(&bundle_header);
-    bundle_file.write_all(&bundle_header).unwrap();
-    write_segment_start(&mut bundle_file, format::tags::PAYLOADS).unwrap();
-    for prepared in prepared_payloads.into_iter() {
-        write_segment_start(&mut bundle_file, format::tags::PAYLOAD).unwrap();
-        bundle_file.write_all(&prepared.payload_header).unwrap();
-        let data_size = std::fs::metadata(&prepared.payload_data).unwrap().len();
-        write_atom_head(
-            &mut bundle_file,
-            format::stlv::AtomHead::Value {
-                tag: format::tags::PAYLOAD_DATA,
-                length: NumBytes::new(data_size),
+    let header_size = format::encode::to_vec(&bundle_header, tags::BUNDLE_HEADER).len();
+    write_segment_start(sink, tags::BUNDLE).whatever("unable to write bundle")?;
+    let header_position = stream_position(sink)?;
+    write_zeros(sink, header_size as u64).whatever("unable to write bundle")?;
+    write_segment_start(sink, tags::PAYLOADS).whatever("unable to write bundle")?;
+    let payloads_start = stream_position(sink)?;
+    for (idx, ((payload, payload_file), entry)) in manifest
+        .payloads
+        .iter()
+        .zip(payload_files)
+        .zip(bundle_header.payload_index.iter_mut())
+        .enumerate()
+    {
+        entry.offset = Some(stream_position(sink)? - payloads_start);
+        let cipher = match &content_key {
+            Some(content_key) if is_encrypted(payload) => Some(content_key.payload_cipher(idx)?),
+            _ => None,
+        };
+        let payload_header = write_payload(sink, payload, payload_file, cipher)
+            .with_info(|_| format!("payload: {:?}", payload.filename))?;
+        entry.header_hash = Bytes {
+            raw: hash_algorithm.hash(&payload_header).raw().to_vec(),
+        };
+    }
+    write_segment_end(sink, tags::PAYLOADS).whatever("unable to write bundle")?;
+    write_segment_end(sink, tags::BUNDLE).whatever("unable to write bundle")?;
+    let end_position = stream_position(sink)?;
+    let header = format::encode::to_vec(&bundle_header, tags::BUNDLE_HEADER);
+    if header.len() != header_size {
+        bail!("size of bundle header changed while writing the bundle");
+    }
+    seek(sink, header_position)?;
+    sink.write_all(&header)
+        .whatever("unable to write bundle header")?;
+    seek(sink, end_position)?;
+    Ok(hash_algorithm.hash(&header))
+}
+
+/// Write a payload segment to the sink and return the encoded payload header.
+fn write_payload<W: Write + Seek>(
+    sink: &mut W,
+    payload: &manifest::Payload,
+    payload_file: &PayloadFile,
+    cipher: Option<PayloadCipher>,
+) -> BundleResult<Vec<u8>> {
+    write_segment_start(sink, tags::PAYLOAD).whatever("unable to write payload")?;
+    let payload_header = if let Some(block_encoding) = &payload.block_encoding {
+        let mut encoder = PayloadEncoder::new(block_encoding, payload_file)?;
+        if let Some(cipher) = cipher {
+            encoder = encoder.with_cipher(cipher);
+        }
+        // The payload header depends on the sizes of the encoded blocks, so we reserve
+        // space for it, the head of the data atom, and padding atoms.
+        let max_header_size = format::encode::to_vec(
+            &PayloadHeader {
+                block_encoding: Some(encoder.max_size_encoding()?),
             },
+            tags::PAYLOAD_HEADER,
+        )
+        .len() as u64;
+        let reserved_size = max_header_size + MAX_VALUE_HEAD_SIZE + MIN_VALUE_HEAD_SIZE;
+        let header_position = stream_position(sink)?;
+        let data_start = header_position + reserved_size;
+        seek(sink, data_start)?;
+        let block_encoding = encoder.encode(sink)?;
+        let data_end = stream_position(sink)?;
+        let payload_header = format::encode::to_vec(
+            &PayloadHeader {
+                block_encoding: Some(block_encoding),
+            },
+            tags::PAYLOAD_HEADER,
+        );
+        let data_head = AtomHead::value(tags::PAYLOAD_DATA, NumBytes::new(data_end - data_start));
+        let Some(padding_size) =
+            reserved_size.checked_sub(payload_header.len() as u64 + data_head.head_size().raw)
+        else {
+            bail!("payload header exceeds reserved space");
+        };
+        seek(sink, header_position)?;
+        sink.write_all(&payload_header)
+            .whatever("unable to write payload header")?;
+        write_padding(sink, padding_size).whatever("unable to write padding")?;
+        write_atom_head(sink, data_head).whatever("unable to write payload")?;
+        seek(sink, data_end)?;
+        payload_header
+    } else {
+        let payload_header = format::encode::to_vec(
+            &PayloadHeader {
+                block_encoding: None,
+            },
+            tags::PAYLOAD_HEADER,
+        );
+        sink.write_all(&payload_header)
+            .whatever("unable to write payload header")?;
+        let data_size = payload_file.size;
+        write_atom_head(
+            sink,
+            AtomHead::value(tags::PAYLOAD_DATA, NumBytes::new(data_size)),
         )
-        .unwrap();
-        let mut payload_data = std::fs::File::open(&prepared.payload_data).unwrap();
-        std::io::copy(&mut payload_data, &mut bundle_file).unwrap();
-        write_segment_end(&mut bundle_file, format::tags::PAYLOAD).unwrap();
+        .whatever("unable to write payload")?;
+        let copied =
+            io::copy(&mut payload_file.reader()?, sink).whatever("unable to write payload data")?;
+        if copied != data_size {
+            bail!("payload file has been truncated");
+        }
+        payload_header
+    };
+    write_segment_end(sink, tags::PAYLOAD).whatever("unable to write payload")?;
+    Ok(payload_header)
+}
+
+/// Write padding atoms with a total size of exactly `size` bytes.
+///
+/// The size must be zero or at least the minimal size of a value atom.
+fn write_padding(writer: &mut dyn Write, mut size: u64) -> io::Result<()> {
+    while size > 0 {
+        // Find the length for which the padding atom has exactly the given size.
+        let padding = (MIN_VALUE_HEAD_SIZE..=MAX_VALUE_HEAD_SIZE.min(size))
+            .map(|head_size| AtomHead::value(tags::PADDING, NumBytes::new(size - head_size)))
+            .find(|head| head.atom_size().raw == size);
+        if let Some(padding @ AtomHead::Value { length, .. }) = padding {
+            write_atom_head(writer, padding)?;
+            return write_zeros(writer, length.raw);
+        }
+        // There is no such length, e.g., if the size of the head would increase with
+        // the length, so we write an empty padding atom first.
+        let empty = AtomHead::value(tags::PADDING, NumBytes::ZERO);
+        write_atom_head(writer, empty)?;
+        size -= empty.atom_size().raw;
     }
-    write_segment_end(&mut bundle_file, format::tags::PAYLOADS).unwrap();
-    write_segment_end(&mut bundle_file, format::tags::BUNDLE).unwrap();
-    println!("{header_hash}");
     Ok(())
 }
 
-struct PreparedPayload {
-    payload_header: Vec<u8>,
-    payload_data: PathBuf,
+/// Write the given number of zero bytes.
+fn write_zeros(writer: &mut dyn Write, size: u64) -> io::Result<()> {
+    io::copy(&mut io::repeat(0).take(size), writer)?;
+    Ok(())
+}
+
+fn stream_position(sink: &mut impl Seek) -> BundleResult<u64> {
+    sink.stream_position()
+        .whatever("unable to get position in bundle")
 }
 
-fn hash_file(algorithm: HashAlgorithm, path: &Path) -> std::io::Result<HashDigest> {
+fn seek(sink: &mut impl Seek, position: u64) -> BundleResult<()> {
+    sink.seek(SeekFrom::Start(position))
+        .whatever("unable to seek in bundle")?;
+    Ok(())
+}
+
+/// Indicates whether the blocks of the payload should be encrypted.
+fn is_encrypted(payload: &manifest::Payload) -> bool {
+    payload
+        .block_encoding
+        .as_ref()
+        .and_then(|block_encoding| block_encoding.encrypt)
+        .unwrap_or(false)
+}
+
+/// Check that pre-install scripts come before all other payloads.
+///
+/// Pre-install scripts must run before any slot is written. As bundles are processed
+/// sequentially, they must thus precede all other payloads.
+fn check_script_order(manifest: &BundleManifest) -> BundleResult<()> {
+    let is_pre_install = |payload: &manifest::Payload| {
+        matches!(
+            &payload.delivery,
+            manifest::DeliveryConfig::Script(config)
+                if matches!(config.stage, manifest::ScriptStage::PreInstall)
+        )
+    };
+    if let Some(first_other) = manifest.payloads.iter().position(|p| !is_pre_install(p)) {
+        if let Some(idx) = manifest.payloads[first_other..]
+            .iter()
+            .position(is_pre_install)
+        {
+            bail!(
+                "pre-install script {} must come before all other payloads",
+                first_other + idx
+            );
+        }
+    }
+    Ok(())
+}
+
+fn hash_payload(algorithm: HashAlgorithm, payload_file: &PayloadFile) -> BundleResult<HashDigest> {
     let mut hasher = algorithm.hasher();
-    let mut reader = BufReader::new(std::fs::File::open(path)?);
+    let mut reader = BufReader::new(payload_file.reader()?);
+    let mut size = 0;
     loop {
-        let buffer = reader.fill_buf()?;
+        let buffer = reader.fill_buf().whatever("unable to read payload file")?;
         if buffer.is_empty() {
+            if size != payload_file.size {
+                bail!("payload file has been truncated");
+            }
             break Ok(hasher.finalize());
         }
+        size += buffer.len() as u64;
         hasher.update(buffer);
         let consumed = buffer.len();
         reader.consume(consumed);
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use crate::format::stlv::read_atom_head;
+    use crate::format::tags;
+    use crate::source::{from_slice, BundleSource};
+
+    use super::{write_padding, MIN_VALUE_HEAD_SIZE};
+
+    #[test]
+    fn test_write_padding() {
+        for size in (0..1).chain(MIN_VALUE_HEAD_SIZE..20_000) {
+            let mut buffer = Vec::new();
+            write_padding(&mut buffer, size).unwrap();
+            assert_eq!(buffer.len() as u64, size);
+            let mut source = from_slice(&buffer);
+            while let Some(head) = read_atom_head(&mut source).unwrap() {
+                assert_eq!(head.tag(), tags::PADDING);
+                source.skip(head.atom_size() - head.head_size()).unwrap();
+            }
+        }
+    }
+}
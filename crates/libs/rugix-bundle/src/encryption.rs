@@ -0,0 +1,406 @@
+//! Encryption of payload blocks.
+//!
+//! Payloads are encrypted block by block after compression. The hashes in the block
+//! encoding remain hashes of the plaintext blocks, such that blocks which are already
+//! available on a device can still be used instead of downloading and decrypting them.
+//! Note that this also means that the block hashes can be used to confirm guesses of the
+//! contents of a block.
+//!
+//! Each bundle with encrypted payloads has a random *content key*. Blocks are encrypted
+//! with AES-256-GCM using the content key and a nonce consisting of the index of the
+//! payload (4 bytes, big endian) followed by the index of the block among the blocks
+//! stored in the payload data (8 bytes, big endian). As a fresh content key is generated
+//! for every bundle, nonces are never reused.
+//!
+//! The content key is *wrapped* for each recipient and stored in the bundle header:
+//!
+//! - For a *shared key*, the content key is encrypted with AES-256-GCM using the shared
+//!   key and a random nonce.
+//! - For an *X25519 public key*, an ephemeral key pair is generated and the key used to
+//!   encrypt the content key is derived from the result of the key agreement with
+//!   HKDF-SHA256.
+//!
+//! Each wrapped key contains the id of the key it has been wrapped with, allowing devices
+//! to find the wrapped key they are able to unwrap. Key files contain the 32 bytes of a
+//! key, hex-encoded. As shared keys and X25519 secret keys have the same format, a key
+//! on a device can be used as either.
+
+use std::path::Path;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::aead::{Aead, KeyInit, OsRng};
+use aes_gcm::{Aes256Gcm, Key, Nonce};
+use hkdf::Hkdf;
+use reportify::{bail, ResultExt};
+use rugix_hashes::HashAlgorithm;
+use sha2::Sha256;
+use x25519_dalek::{PublicKey, StaticSecret};
+
+use crate::format::{Bytes, WrappedKey};
+use crate::BundleResult;
+
+/// Size of keys in bytes.
+pub const KEY_SIZE: usize = 32;
+
+/// Size of nonces in bytes.
+const NONCE_SIZE: usize = 12;
+
+/// Context of the hashes used as key ids.
+const KEY_ID_CONTEXT: &[u8] = b"rugix-bundle key id";
+
+/// Context of the key derivation for X25519 public keys.
+const KEY_WRAPPING_CONTEXT: &[u8] = b"rugix-bundle key wrapping";
+
+/// Algorithm used to encrypt blocks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum EncryptionAlgorithm {
+    Aes256Gcm,
+}
+
+impl EncryptionAlgorithm {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EncryptionAlgorithm::Aes256Gcm => "aes-256-gcm",
+        }
+    }
+}
+
+impl std::str::FromStr for EncryptionAlgorithm {
+    type Err = InvalidEncryptionAlgorithmError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "aes-256-gcm" => Ok(Self::Aes256Gcm),
+            _ => Err(InvalidEncryptionAlgorithmError {}),
+        }
+    }
+}
+
+#[derive(Debug)]
+#[non_exhaustive]
+pub struct InvalidEncryptionAlgorithmError {}
+
+impl std::fmt::Display for InvalidEncryptionAlgorithmError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("encryption algorithm is not valid")
+    }
+}
+
+impl std::error::Error for InvalidEncryptionAlgorithmError {}
+
+/// Key to wrap the content key of a bundle for.
+#[derive(Clone)]
+pub enum EncryptionKey {
+    /// Symmetric key shared with the devices.
+    Shared([u8; KEY_SIZE]),
+    /// X25519 public key of a device.
+    Public(PublicKey),
+}
+
+impl EncryptionKey {
+    /// Load a shared key from the given key file.
+    pub fn load_shared(path: &Path) -> BundleResult<Self> {
+        Ok(Self::Shared(read_key_file(path)?))
+    }
+
+    /// Load an X25519 public key from the given key file.
+    pub fn load_public(path: &Path) -> BundleResult<Self> {
+        Ok(Self::Public(PublicKey::from(read_key_file(path)?)))
+    }
+
+    /// Write the key to the given key file.
+    pub fn write(&self, path: &Path) -> BundleResult<()> {
+        let bytes = match self {
+            EncryptionKey::Shared(key) => key,
+            EncryptionKey::Public(public) => public.as_bytes(),
+        };
+        write_key_file(path, bytes)
+    }
+}
+
+impl std::fmt::Debug for EncryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Shared(_) => f.write_str("Shared(..)"),
+            Self::Public(public) => f.debug_tuple("Public").field(public).finish(),
+        }
+    }
+}
+
+/// Key of a device to unwrap the content key of a bundle with.
+///
+/// The key is used as a shared key or as an X25519 secret key, depending on the
+/// wrapped key.
+#[derive(Clone)]
+pub struct DecryptionKey {
+    key: [u8; KEY_SIZE],
+}
+
+impl DecryptionKey {
+    /// Generate a random key.
+    pub fn generate() -> Self {
+        Self {
+            key: random_bytes(),
+        }
+    }
+
+    /// Load a key from the given key file.
+    pub fn load(path: &Path) -> BundleResult<Self> {
+        Ok(Self {
+            key: read_key_file(path)?,
+        })
+    }
+
+    /// Write the key to the given key file.
+    pub fn write(&self, path: &Path) -> BundleResult<()> {
+        write_key_file(path, &self.key)
+    }
+
+    /// Key to wrap content keys for this key, when used as a shared key.
+    pub fn shared_key(&self) -> EncryptionKey {
+        EncryptionKey::Shared(self.key)
+    }
+
+    /// Key to wrap content keys for this key, when used as an X25519 secret key.
+    pub fn public_key(&self) -> EncryptionKey {
+        EncryptionKey::Public(PublicKey::from(&StaticSecret::from(self.key)))
+    }
+}
+
+impl std::fmt::Debug for DecryptionKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DecryptionKey").finish_non_exhaustive()
+    }
+}
+
+/// Content key used to encrypt the blocks of the payloads of a bundle.
+pub struct ContentKey {
+    key: [u8; KEY_SIZE],
+}
+
+impl ContentKey {
+    /// Generate a random content key.
+    pub fn generate() -> Self {
+        Self {
+            key: random_bytes(),
+        }
+    }
+
+    /// Wrap the content key for the given key.
+    pub fn wrap(&self, key: &EncryptionKey) -> BundleResult<WrappedKey> {
+        let nonce = random_bytes::<NONCE_SIZE>();
+        let (key_id, ephemeral_public_key, wrapping_key) = match key {
+            EncryptionKey::Shared(shared) => (key_id(shared), None, *shared),
+            EncryptionKey::Public(public) => {
+                let ephemeral_secret = StaticSecret::from(random_bytes());
+                let ephemeral_public = PublicKey::from(&ephemeral_secret);
+                let wrapping_key =
+                    derive_wrapping_key(&ephemeral_secret, public, &ephemeral_public, public)?;
+                (
+                    key_id(public.as_bytes()),
+                    Some(Bytes {
+                        raw: ephemeral_public.as_bytes().to_vec(),
+                    }),
+                    wrapping_key,
+                )
+            }
+        };
+        let ciphertext = new_cipher(&wrapping_key)
+            .encrypt(Nonce::from_slice(&nonce), self.key.as_slice())
+            .whatever("unable to wrap content key")?;
+        Ok(WrappedKey {
+            key_id: Bytes { raw: key_id },
+            ephemeral_public_key,
+            nonce: Bytes {
+                raw: nonce.to_vec(),
+            },
+            ciphertext: Bytes { raw: ciphertext },
+        })
+    }
+
+    /// Unwrap the content key with one of the given keys.
+    pub fn unwrap(wrapped_keys: &[WrappedKey], keys: &[DecryptionKey]) -> BundleResult<Self> {
+        for wrapped in wrapped_keys {
+            for key in keys {
+                let wrapping_key = match &wrapped.ephemeral_public_key {
+                    Some(ephemeral_public) => {
+                        let secret = StaticSecret::from(key.key);
+                        let public = PublicKey::from(&secret);
+                        if key_id(public.as_bytes()) != wrapped.key_id.raw {
+                            continue;
+                        }
+                        let Ok(ephemeral_public) =
+                            <[u8; KEY_SIZE]>::try_from(ephemeral_public.raw.as_slice())
+                        else {
+                            bail!("invalid ephemeral public key");
+                        };
+                        let ephemeral_public = PublicKey::from(ephemeral_public);
+                        derive_wrapping_key(&secret, &ephemeral_public, &ephemeral_public, &public)?
+                    }
+                    None => {
+                        if key_id(&key.key) != wrapped.key_id.raw {
+                            continue;
+                        }
+                        key.key
+                    }
+                };
+                if wrapped.nonce.raw.len() != NONCE_SIZE {
+                    bail!("invalid nonce of wrapped key");
+                }
+                let content_key = new_cipher(&wrapping_key)
+                    .decrypt(
+                        Nonce::from_slice(&wrapped.nonce.raw),
+                        wrapped.ciphertext.raw.as_slice(),
+                    )
+                    .whatever("unable to unwrap content key")?;
+                let Ok(key) = <[u8; KEY_SIZE]>::try_from(content_key.as_slice()) else {
+                    bail!("invalid size of content key");
+                };
+                return Ok(Self { key });
+            }
+        }
+        bail!("none of the available keys can decrypt the bundle")
+    }
+
+    /// Cipher for the blocks of the payload with the given index.
+    pub fn payload_cipher(&self, payload_idx: usize) -> BundleResult<PayloadCipher> {
+        Ok(PayloadCipher {
+            cipher: new_cipher(&self.key),
+            payload_idx: u32::try_from(payload_idx).whatever("payload index is too large")?,
+        })
+    }
+}
+
+impl std::fmt::Debug for ContentKey {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ContentKey").finish_non_exhaustive()
+    }
+}
+
+/// Cipher for the blocks of a payload.
+pub struct PayloadCipher {
+    cipher: Aes256Gcm,
+    payload_idx: u32,
+}
+
+impl PayloadCipher {
+    /// Encrypt the block with the given index among the stored blocks.
+    pub fn encrypt_block(&self, block_idx: usize, block: &[u8]) -> BundleResult<Vec<u8>> {
+        self.cipher
+            .encrypt(Nonce::from_slice(&self.nonce(block_idx)), block)
+            .whatever("unable to encrypt block")
+    }
+
+    /// Decrypt the block with the given index among the stored blocks.
+    pub fn decrypt_block(&self, block_idx: usize, block: &[u8]) -> BundleResult<Vec<u8>> {
+        self.cipher
+            .decrypt(Nonce::from_slice(&self.nonce(block_idx)), block)
+            .whatever_with(|_| format!("unable to decrypt block {block_idx}"))
+    }
+
+    fn nonce(&self, block_idx: usize) -> [u8; NONCE_SIZE] {
+        let mut nonce = [0; NONCE_SIZE];
+        nonce[..4].copy_from_slice(&self.payload_idx.to_be_bytes());
+        nonce[4..].copy_from_slice(&(block_idx as u64).to_be_bytes());
+        nonce
+    }
+}
+
+impl std::fmt::Debug for PayloadCipher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("PayloadCipher")
+            .field("payload_idx", &self.payload_idx)
+            .finish_non_exhaustive()
+    }
+}
+
+/// Derive the key to wrap the content key with from an X25519 key agreement.
+///
+/// The key agreement is between the ephemeral key pair and the recipient's key pair, so
+/// `secret` is the secret key of one of them and `public` the public key of the other.
+fn derive_wrapping_key(
+    secret: &StaticSecret,
+    public: &PublicKey,
+    ephemeral_public: &PublicKey,
+    recipient_public: &PublicKey,
+) -> BundleResult<[u8; KEY_SIZE]> {
+    let shared_secret = secret.diffie_hellman(public);
+    if !shared_secret.was_contributory() {
+        bail!("invalid public key for key agreement");
+    }
+    let mut salt = Vec::with_capacity(2 * KEY_SIZE);
+    salt.extend_from_slice(ephemeral_public.as_bytes());
+    salt.extend_from_slice(recipient_public.as_bytes());
+    let mut wrapping_key = [0; KEY_SIZE];
+    Hkdf::<Sha256>::new(Some(salt.as_slice()), shared_secret.as_bytes())
+        .expand(KEY_WRAPPING_CONTEXT, &mut wrapping_key)
+        .expect("key size should be a valid output length");
+    Ok(wrapping_key)
+}
+
+/// Id of the given key.
+fn key_id(key: &[u8]) -> Vec<u8> {
+    let mut hasher = HashAlgorithm::Sha256.hasher();
+    hasher.update(KEY_ID_CONTEXT);
+    hasher.update(key);
+    hasher.finalize().raw().to_vec()
+}
+
+fn new_cipher(key: &[u8; KEY_SIZE]) -> Aes256Gcm {
+    Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(key))
+}
+
+fn random_bytes<const N: usize>() -> [u8; N] {
+    let mut bytes = [0; N];
+    OsRng.fill_bytes(&mut bytes);
+    bytes
+}
+
+fn read_key_file(path: &Path) -> BundleResult<[u8; KEY_SIZE]> {
+    let contents = std::fs::read_to_string(path)
+        .whatever("unable to read key file")
+        .with_info(|_| format!("path: {path:?}"))?;
+    let bytes = hex::decode(contents.trim())
+        .whatever("key file must contain a hex-encoded key")
+        .with_info(|_| format!("path: {path:?}"))?;
+    let Ok(key) = <[u8; KEY_SIZE]>::try_from(bytes.as_slice()) else {
+        bail!("key in {path:?} must be {KEY_SIZE} bytes long");
+    };
+    Ok(key)
+}
+
+fn write_key_file(path: &Path, key: &[u8]) -> BundleResult<()> {
+    std::fs::write(path, format!("{}\n", hex::encode(key)))
+        .whatever("unable to write key file")
+        .with_info(|_| format!("path: {path:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_wrap_unwrap_content_key() {
+        let device_key = DecryptionKey::generate();
+        let other_key = DecryptionKey::generate();
+        let content_key = ContentKey::generate();
+        for recipient in [device_key.shared_key(), device_key.public_key()] {
+            let wrapped = [content_key.wrap(&recipient).unwrap()];
+            let unwrapped =
+                ContentKey::unwrap(&wrapped, &[other_key.clone(), device_key.clone()]).unwrap();
+            assert_eq!(unwrapped.key, content_key.key);
+            assert!(ContentKey::unwrap(&wrapped, std::slice::from_ref(&other_key)).is_err());
+        }
+    }
+
+    #[test]
+    fn test_block_nonces() {
+        let content_key = ContentKey::generate();
+        let cipher = content_key.payload_cipher(1).unwrap();
+        let encrypted = cipher.encrypt_block(3, b"block").unwrap();
+        assert_eq!(cipher.decrypt_block(3, &encrypted).unwrap(), b"block");
+        assert!(cipher.decrypt_block(4, &encrypted).is_err());
+        let other_cipher = content_key.payload_cipher(2).unwrap();
+        assert!(other_cipher.decrypt_block(3, &encrypted).is_err());
+    }
+}
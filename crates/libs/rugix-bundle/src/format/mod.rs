@@ -11,6 +11,7 @@ use rugix_chunker::ChunkerAlgorithm;
 use rugix_compression::CompressionFormat;
 use rugix_hashes::HashAlgorithm;
 
+use crate::encryption::EncryptionAlgorithm;
 use crate::source::BundleSource;
 use crate::BundleResult;
 
@@ -37,6 +38,26 @@ define_struct! {
         pub hash_algorithm[BUNDLE_HEADER_HASH_ALGORITHM]: HashAlgorithm,
         /// Payload index.
         pub payload_index[BUNDLE_HEADER_PAYLOAD_INDEX]: Vec<PayloadEntry>,
+        /// Security version of the bundle used for rollback protection.
+        pub security_version[BUNDLE_HEADER_SECURITY_VERSION]: Option<u64>,
+        /// Update channel of the bundle.
+        pub channel[BUNDLE_HEADER_CHANNEL]: Option<String>,
+        /// Content key of the bundle wrapped for each recipient.
+        pub encryption_keys[BUNDLE_HEADER_ENCRYPTION_KEY]: Vec<WrappedKey>,
+    }
+}
+
+define_struct! {
+    /// Content key of a bundle wrapped for a recipient.
+    pub struct WrappedKey {
+        /// Id of the key the content key has been wrapped with.
+        pub key_id[WRAPPED_KEY_ID]: Bytes,
+        /// Ephemeral public key, if the content key has been wrapped for a public key.
+        pub ephemeral_public_key[WRAPPED_KEY_EPHEMERAL_PUBLIC_KEY]: Option<Bytes>,
+        /// Nonce used to encrypt the content key.
+        pub nonce[WRAPPED_KEY_NONCE]: Bytes,
+        /// Encrypted content key.
+        pub ciphertext[WRAPPED_KEY_CIPHERTEXT]: Bytes,
     }
 }
 
@@ -46,10 +67,32 @@ define_struct! {
         /// Slot where the payload should be installed to.
         pub type_slot[PAYLOAD_ENTRY_TYPE_SLOT]: Option<SlotPayloadType>,
         pub type_execute[PAYLOAD_ENTRY_TYPE_EXECUTE]: Option<ExecutePayloadType>,
+        pub type_script[PAYLOAD_ENTRY_TYPE_SCRIPT]: Option<ScriptPayloadType>,
         /// Hash of the payload header.
         pub header_hash[PAYLOAD_ENTRY_HEADER_HASH]: Bytes,
         /// Hash of the payload file.
         pub file_hash[PAYLOAD_ENTRY_FILE_HASH]: Bytes,
+        /// Condition under which the payload should be installed.
+        pub condition[PAYLOAD_ENTRY_CONDITION]: Option<PayloadCondition>,
+        /// Offset of the payload segment relative to the start of the payloads
+        /// segment's content.
+        pub offset[PAYLOAD_ENTRY_OFFSET]: Option<u64>,
+        /// Size of the payload file.
+        pub file_size[PAYLOAD_ENTRY_FILE_SIZE]: Option<u64>,
+    }
+}
+
+define_struct! {
+    /// Condition under which a payload should be installed.
+    ///
+    /// All specified properties must match for the payload to be installed.
+    pub struct PayloadCondition {
+        /// Device tree `compatible` string of the device.
+        pub compatible[PAYLOAD_CONDITION_COMPATIBLE]: Option<String>,
+        /// DMI product name of the device.
+        pub dmi_product_name[PAYLOAD_CONDITION_DMI_PRODUCT_NAME]: Option<String>,
+        /// Variant of the system as configured on the device.
+        pub variant[PAYLOAD_CONDITION_VARIANT]: Option<String>,
     }
 }
 
@@ -67,6 +110,16 @@ define_struct! {
     }
 }
 
+define_struct! {
+    /// Script to run at a specific stage of the update process.
+    pub struct ScriptPayloadType {
+        /// Stage at which the script should run.
+        pub stage[PAYLOAD_TYPE_SCRIPT_STAGE]: String,
+        /// Interpreter for the script (empty if the script should be executed directly).
+        pub interpreter[PAYLOAD_TYPE_SCRIPT_INTERPRETER]: Vec<String>,
+    }
+}
+
 define_struct! {
     /// Header of a payload.
     pub struct PayloadHeader {
@@ -85,6 +138,8 @@ define_struct! {
         /// Whether blocks have been deduplicated.
         pub deduplicated[BLOCK_ENCODING_DEDUPLICATED]: bool,
         pub compression[BLOCK_ENCODING_COMPRESSION]: Option<CompressionFormat>,
+        /// Algorithm the stored blocks have been encrypted with.
+        pub encryption[BLOCK_ENCODING_ENCRYPTION]: Option<EncryptionAlgorithm>,
         /// Block index.
         pub block_hashes[BLOCK_ENCODING_BLOCK_HASHES]: Bytes,
         /// Block sizes.
@@ -170,3 +225,17 @@ impl Decode for CompressionFormat {
             .whatever("unknown compression format")
     }
 }
+
+impl Encode for EncryptionAlgorithm {
+    fn encode(&self, writer: &mut dyn Write, tag: Tag) -> io::Result<()> {
+        write_value(writer, tag, self.as_str().as_bytes())
+    }
+}
+
+impl Decode for EncryptionAlgorithm {
+    fn decode<S: BundleSource>(decoder: &mut Decoder<S>, atom: AtomHead) -> BundleResult<Self> {
+        String::decode(decoder, atom)?
+            .parse()
+            .whatever("unknown encryption algorithm")
+    }
+}
@@ -144,18 +144,45 @@ define_tags! {
     /// Slot where the payload should be installed to.
     PAYLOAD_ENTRY_TYPE_SLOT = 0x45ca7e7e,
     PAYLOAD_ENTRY_TYPE_EXECUTE = 0x3adf32f5,
+    PAYLOAD_ENTRY_TYPE_SCRIPT = 0x251af394,
     /// Hash of the payload's header.
     PAYLOAD_ENTRY_HEADER_HASH = 0x5f6a60b1,
     /// Hash of the payload's file.
     PAYLOAD_ENTRY_FILE_HASH = 0x0c8d1fd0,
+    /// Condition under which the payload should be installed.
+    PAYLOAD_ENTRY_CONDITION = 0x507d434f,
+    /// Offset of the payload relative to the start of the payloads segment.
+    PAYLOAD_ENTRY_OFFSET = 0xb43dca7b?,
+    /// Size of the payload's file.
+    PAYLOAD_ENTRY_FILE_SIZE = 0xee6f1d65?,
+
+    PAYLOAD_CONDITION_COMPATIBLE = 0x21673e01,
+    PAYLOAD_CONDITION_DMI_PRODUCT_NAME = 0x20b30b8f,
+    PAYLOAD_CONDITION_VARIANT = 0x7e02206c,
 
     PAYLOAD_TYPE_SLOT_SLOT = 0x1b231de7,
 
     PAYLOAD_TYPE_EXECUTE_HANDLER = 0x4b3836a2,
 
+    PAYLOAD_TYPE_SCRIPT_STAGE = 0x245cf38c,
+    PAYLOAD_TYPE_SCRIPT_INTERPRETER = 0x366a287e,
+
     BLOCK_INDEX = 0x1ae50c8e,
 
     BUNDLE_HEADER_IS_INCREMENTAL = 0x20f3d16b,
+    /// Security version of the bundle used for rollback protection.
+    BUNDLE_HEADER_SECURITY_VERSION = 0xcc684155?,
+    /// Update channel of the bundle.
+    BUNDLE_HEADER_CHANNEL = 0x9622de3d?,
+    /// Content key of the bundle, wrapped for a recipient.
+    BUNDLE_HEADER_ENCRYPTION_KEY = 0x057e3a0c,
+
+    /// Id of the key the content key has been wrapped with.
+    WRAPPED_KEY_ID = 0x76957fe6,
+    /// Ephemeral public key for the key agreement with the recipient.
+    WRAPPED_KEY_EPHEMERAL_PUBLIC_KEY = 0x50d9a2aa,
+    WRAPPED_KEY_NONCE = 0x63c5681b,
+    WRAPPED_KEY_CIPHERTEXT = 0x1d445f15,
 
     BLOCK_INDEX_CHUNKER = 0x5cdf21b0,
     BLOCK_INDEX_HASH_ALGORITHM = 0x1d92a080,
@@ -171,6 +198,8 @@ define_tags! {
     PAYLOAD_HEADER = 0x0959ca75,
     /// Data of the payload.
     PAYLOAD_DATA = 0x42fd641a,
+    /// Padding filling space reserved while writing a bundle, ignored by readers.
+    PADDING = 0xb7b17a73?,
 
     /// Payload block encoding.
     PAYLOAD_HEADER_BLOCK_ENCODING = 0x40ed9314,
@@ -181,6 +210,7 @@ define_tags! {
     BLOCK_ENCODING_DEDUPLICATED = 0x05902926,
     BLOCK_ENCODING_CHUNKER = 0x55872cf8,
     BLOCK_ENCODING_COMPRESSION = 0x783217c6,
+    BLOCK_ENCODING_ENCRYPTION = 0x09ef0ae9,
 
     /// Block index.
     BLOCK_ENCODING_BLOCK_HASHES = 0x76b3d7a0,
@@ -15,6 +15,7 @@ use source::FileSource;
 
 pub mod block_encoding;
 pub mod builder;
+pub mod encryption;
 pub mod format;
 pub mod manifest;
 pub mod reader;
@@ -4,3 +4,27 @@ sidex::include_bundle! {
 }
 // Re-export the generated data structures.
 pub use generated::manifest::*;
+
+impl ScriptStage {
+    /// Name of the stage as stored in the bundle.
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            ScriptStage::PreInstall => "pre-install",
+            ScriptStage::PostInstall => "post-install",
+            ScriptStage::FirstBoot => "first-boot",
+        }
+    }
+}
+
+impl std::str::FromStr for ScriptStage {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pre-install" => Ok(ScriptStage::PreInstall),
+            "post-install" => Ok(ScriptStage::PostInstall),
+            "first-boot" => Ok(ScriptStage::FirstBoot),
+            _ => Err(format!("unknown script stage {s:?}")),
+        }
+    }
+}
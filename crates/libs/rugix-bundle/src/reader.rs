@@ -1,3 +1,4 @@
+use std::collections::HashSet;
 use std::fs::File;
 use std::io::{Read, Seek, Write};
 
@@ -9,6 +10,7 @@ use rugix_hashes::HashDigest;
 
 use crate::block_encoding::block_index::{BlockId, RawBlockIndex};
 use crate::block_encoding::block_table::BlockTable;
+use crate::encryption::{ContentKey, DecryptionKey};
 use crate::format::decode::decode_slice;
 use crate::format::stlv::{read_atom_head, skip, write_atom_head, AtomHead, Tag};
 use crate::format::{self, tags};
@@ -16,15 +18,26 @@ use crate::source::BundleSource;
 use crate::{BundleResult, BUNDLE_HEADER_SIZE_LIMIT, PAYLOAD_HEADER_SIZE_LIMIT};
 
 pub mod block_provider;
+pub mod bundle_blocks;
+pub mod estimate;
+pub mod verify;
 
 pub struct BundleReader<S> {
-    source: S,
+    source: PositionSource<S>,
     header: format::BundleHeader,
     next_payload: usize,
+    /// Position of the content of the payloads segment.
+    payloads_start: NumBytes,
+    /// Content key to decrypt encrypted payloads with.
+    content_key: Option<ContentKey>,
 }
 
 impl<S: BundleSource> BundleReader<S> {
-    pub fn start(mut source: S, header_hash: Option<HashDigest>) -> BundleResult<Self> {
+    pub fn start(source: S, header_hash: Option<HashDigest>) -> BundleResult<Self> {
+        let mut source = PositionSource {
+            source,
+            position: NumBytes::ZERO,
+        };
         let _ = expect_start(&mut source, tags::BUNDLE);
         let mut bundle_header = Vec::new();
         let header_head = skip_until_start(&mut source, tags::BUNDLE_HEADER)?;
@@ -41,10 +54,13 @@ impl<S: BundleSource> BundleReader<S> {
         }
         let header = decode_slice::<format::BundleHeader>(&bundle_header)?;
         let _ = skip_until_start(&mut source, tags::PAYLOADS)?;
+        let payloads_start = source.position;
         Ok(Self {
             source,
             header,
             next_payload: 0,
+            payloads_start,
+            content_key: None,
         })
     }
 
@@ -52,12 +68,66 @@ impl<S: BundleSource> BundleReader<S> {
         &self.header
     }
 
+    /// Indicates whether the bundle has encrypted payloads.
+    pub fn is_encrypted(&self) -> bool {
+        !self.header.encryption_keys.is_empty()
+    }
+
+    /// Unwrap the content key of the bundle with one of the given keys.
+    ///
+    /// This is required to decode encrypted payloads.
+    pub fn unlock(&mut self, keys: &[DecryptionKey]) -> BundleResult<()> {
+        self.content_key = Some(ContentKey::unwrap(&self.header.encryption_keys, keys)?);
+        Ok(())
+    }
+
+    /// Indicates whether the reader can [seek to payloads][BundleReader::seek_payload].
+    ///
+    /// This requires a seekable source and a bundle with payload offsets.
+    pub fn can_seek(&self) -> bool {
+        self.source.source.is_seekable()
+            && self
+                .header
+                .payload_index
+                .iter()
+                .all(|entry| entry.offset.is_some())
+    }
+
+    /// Seek to the payload with the given index.
+    ///
+    /// The payload is returned by the next call to [`BundleReader::next_payload`].
+    pub fn seek_payload(&mut self, idx: usize) -> BundleResult<()> {
+        if idx >= self.header.payload_index.len() {
+            bail!("bundle has no payload {idx}");
+        }
+        if !self.source.source.is_seekable() {
+            bail!("bundle source does not support seeking");
+        }
+        let Some(position) = self.payload_position(idx) else {
+            bail!("bundle has no offset for payload {idx}");
+        };
+        self.source.seek(position)?;
+        self.next_payload = idx;
+        Ok(())
+    }
+
+    /// Position of the payload with the given index, if the bundle has payload offsets.
+    fn payload_position(&self, idx: usize) -> Option<NumBytes> {
+        let offset = self.header.payload_index.get(idx)?.offset?;
+        Some(self.payloads_start + NumBytes::new(offset))
+    }
+
     pub fn next_payload(&mut self) -> BundleResult<Option<PayloadReader<'_, S>>> {
         if self.next_payload >= self.header.payload_index.len() {
             return Ok(None);
         }
         let this_payload = self.next_payload;
         self.next_payload += 1;
+        if let Some(position) = self.payload_position(this_payload) {
+            if self.source.source.is_seekable() && position != self.source.position {
+                self.source.seek(position)?;
+            }
+        }
         let entry = &self.header.payload_index[this_payload];
         let _ = expect_start(&mut self.source, tags::PAYLOAD);
         let header_atom = skip_until_start(&mut self.source, tags::PAYLOAD_HEADER)?;
@@ -101,7 +171,52 @@ impl<'r, S: BundleSource> PayloadReader<'r, S> {
         &self.reader.header().payload_index[self.idx]
     }
 
+    /// Header of the bundle the payload belongs to.
+    pub fn bundle_header(&self) -> &format::BundleHeader {
+        self.reader.header()
+    }
+
+    /// Hashes of the blocks which are deduplicated in the payload data.
+    ///
+    /// When decoding, these blocks are read back from the target with
+    /// [`PayloadTarget::read_block`] at the offset of their first occurrence.
+    pub fn deduplicated_blocks(&self) -> BundleResult<HashSet<Vec<u8>>> {
+        let mut duplicates = HashSet::new();
+        let Some(block_encoding) = &self.header.block_encoding else {
+            return Ok(duplicates);
+        };
+        if !block_encoding.deduplicated {
+            return Ok(duplicates);
+        }
+        let decoded = DecodedBlockEncoding::new(block_encoding)?;
+        let mut seen = HashSet::new();
+        for hash in decoded
+            .block_hashes
+            .chunks_exact(block_encoding.hash_algorithm.hash_size())
+        {
+            if !seen.insert(hash) {
+                duplicates.insert(hash.to_vec());
+            }
+        }
+        Ok(duplicates)
+    }
+
+    /// Size of the remaining payload data in the bundle.
+    ///
+    /// Before decoding, this is the size of the payload file, if the payload does not
+    /// use a block encoding.
+    pub fn data_size(&self) -> NumBytes {
+        self.remaining_data
+    }
+
     pub fn skip(self) -> BundleResult<()> {
+        let next_idx = self.idx + 1;
+        if self.reader.source.source.is_seekable()
+            && self.reader.payload_position(next_idx).is_some()
+        {
+            // The next payload is reached by seeking to it.
+            return Ok(());
+        }
         self.reader.source.skip(self.remaining_data)?;
         skip_until_end(&mut self.reader.source, tags::PAYLOAD)?;
         Ok(())
@@ -122,30 +237,18 @@ impl<'r, S: BundleSource> PayloadReader<'r, S> {
         let mut buffer = vec![0; 8192];
         let mut payload_hasher = self.reader.header.hash_algorithm.hasher();
         if let Some(block_encoding) = self.header.block_encoding {
-            let mut block_index_raw = block_encoding.block_hashes.raw;
-            if let Some(format) = block_encoding.compression {
-                block_index_raw = uncompress_bytes(format, &block_index_raw);
-            }
-            let block_sizes = block_encoding.block_sizes.map(|block_sizes| {
-                let mut block_sizes = block_sizes.raw;
-                if let Some(format) = block_encoding.compression {
-                    block_sizes = uncompress_bytes(format, &block_sizes);
-                }
-                let mut decoded_sizes = Vec::new();
-                for chunk in block_sizes.chunks_exact(4) {
-                    decoded_sizes.push(u32::from_be_bytes(chunk.try_into().unwrap()))
-                }
-                decoded_sizes
-            });
-            let fixed_block_size = match block_encoding.chunker {
-                rugix_chunker::ChunkerAlgorithm::Casync { .. } => None,
-                rugix_chunker::ChunkerAlgorithm::Fixed { block_size_kib } => {
-                    Some((block_size_kib as u32) * 1024)
-                }
+            let DecodedBlockEncoding {
+                block_hashes: block_index_raw,
+                block_sizes,
+                fixed_block_size,
+            } = DecodedBlockEncoding::new(&block_encoding)?;
+            let cipher = match block_encoding.encryption {
+                Some(_) => match &self.reader.content_key {
+                    Some(content_key) => Some(content_key.payload_cipher(self.idx)?),
+                    None => bail!("payload is encrypted but the bundle has not been unlocked"),
+                },
+                None => None,
             };
-            if fixed_block_size.is_none() && block_sizes.is_none() {
-                bail!("variable-size index needs block sizes")
-            }
             let raw_index = RawBlockIndex::new(&block_index_raw, block_encoding.hash_algorithm);
             let mut table = BlockTable::new();
             let mut current_target_offset = NumBytes::ZERO;
@@ -160,36 +263,51 @@ impl<'r, S: BundleSource> PayloadReader<'r, S> {
                 let block_id = BlockId { raw: idx };
                 let is_fresh = table.insert_raw(&raw_index, block_id);
                 let first_idx = table.get_raw(&raw_index, block_hash).unwrap();
+                // Determine the size of the block in the encoding, if the block is
+                // contained in the source.
+                let source_block_size = if is_fresh || !block_encoding.deduplicated {
+                    let block_size =
+                        stored_block_size(block_sizes.as_deref(), fixed_block_size, next_size_idx)?
+                            .min(self.remaining_data.raw);
+                    next_size_idx += 1;
+                    Some(block_size)
+                } else {
+                    None
+                };
+                // Check whether the target already contains the block at the same offset.
+                let mut is_unchanged = false;
+                if let Some(size) = target.existing_block(current_target_offset, block_hash) {
+                    // Do not trust the target blindly, the block may have been modified.
+                    target.read_block(current_target_offset, size, &mut buffer)?;
+                    is_unchanged = block_encoding.hash_algorithm.hash(&buffer).raw() == block_hash;
+                }
                 // Get the data, afterwards buffer should contain the uncompressed block.
-                if is_fresh || !block_encoding.deduplicated {
+                if is_unchanged {
+                    // We already have the block in the target, let's skip it.
+                    if let Some(block_size) = source_block_size {
+                        self.reader.source.skip(block_size.into())?;
+                        self.remaining_data -= block_size;
+                    }
+                } else if let Some(block_size) = source_block_size {
                     // We need to read the block from the source.
-                    // Determine the size of the block in the encoding.
-                    let block_size = (block_sizes
-                        .as_ref()
-                        .map(|sizes| sizes[next_size_idx])
-                        .or(fixed_block_size)
-                        .unwrap() as u64)
-                        .min(self.remaining_data.raw);
-                    next_size_idx += 1;
                     if let Some(stored_block) = provider.and_then(|p| p.query(block_hash)) {
                         // We already have the block, let's skip it.
                         self.reader.source.skip(block_size.into())?;
                         self.remaining_data -= block_size;
-                        buffer.resize(stored_block.size.unwrap_usize(), 0);
-                        let mut source_file = std::fs::File::open(&stored_block.file)
-                            .whatever("unable to open file")?;
-                        source_file
-                            .seek(std::io::SeekFrom::Start(stored_block.offset.raw))
-                            .whatever("unable to seek")?;
-                        source_file
-                            .read_exact(&mut buffer)
-                            .whatever("unable to read block")?;
+                        stored_block.read(&mut buffer)?;
+                        if let Some(format) = stored_block.compression {
+                            buffer = uncompress_bytes(format, &buffer)?;
+                        }
                     } else {
                         buffer.resize(block_size.try_into().unwrap(), 0);
                         self.reader.source.read_exact(&mut buffer)?;
                         self.remaining_data -= buffer.byte_len();
+                        if let Some(cipher) = &cipher {
+                            // The index has already been advanced past the block.
+                            buffer = cipher.decrypt_block(next_size_idx - 1, &buffer)?;
+                        }
                         if let Some(format) = block_encoding.compression {
-                            buffer = uncompress_bytes(format, &buffer);
+                            buffer = uncompress_bytes(format, &buffer)?;
                         }
                     }
                 } else {
@@ -206,7 +324,11 @@ impl<'r, S: BundleSource> PayloadReader<'r, S> {
                 target_offsets.push(current_target_offset);
                 target_sizes.push(buffer.byte_len());
                 current_target_offset += buffer.byte_len();
-                target.write(&buffer)?;
+                if is_unchanged {
+                    target.skip(buffer.byte_len())?;
+                } else {
+                    target.write(&buffer)?;
+                }
                 payload_hasher.update(&buffer);
             }
         } else {
@@ -243,6 +365,23 @@ pub trait PayloadTarget: Sized {
         bail!("target does not support reading blocks");
     }
 
+    /// Size of the block with the given hash, if the target already contains it at the
+    /// given offset.
+    ///
+    /// Blocks reported here are read back and verified with [`PayloadTarget::read_block`]
+    /// and, if they are indeed unchanged, skipped with [`PayloadTarget::skip`] instead of
+    /// being written again.
+    #[expect(unused_variables)]
+    fn existing_block(&mut self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        None
+    }
+
+    /// Skip the given number of bytes, leaving the existing contents untouched.
+    #[expect(unused_variables)]
+    fn skip(&mut self, size: NumBytes) -> BundleResult<()> {
+        bail!("target does not support skipping blocks");
+    }
+
     fn finalize(self) -> BundleResult<()> {
         Ok(())
     }
@@ -270,6 +409,45 @@ impl PayloadTarget for File {
             .whatever("unable to seek")?;
         Ok(())
     }
+
+    fn skip(&mut self, size: NumBytes) -> BundleResult<()> {
+        let size = size.raw.try_into().whatever("block is too large")?;
+        self.seek(std::io::SeekFrom::Current(size))
+            .whatever("unable to seek")?;
+        Ok(())
+    }
+}
+
+/// Bundle source keeping track of the position in the underlying source.
+struct PositionSource<S> {
+    /// Underlying source.
+    source: S,
+    /// Current position.
+    position: NumBytes,
+}
+
+impl<S: BundleSource> BundleSource for PositionSource<S> {
+    fn read(&mut self, slice: &mut [u8]) -> BundleResult<usize> {
+        let read = self.source.read(slice)?;
+        self.position += NumBytes::from_usize(read);
+        Ok(read)
+    }
+
+    fn skip(&mut self, length: NumBytes) -> BundleResult<()> {
+        self.source.skip(length)?;
+        self.position += length;
+        Ok(())
+    }
+
+    fn is_seekable(&self) -> bool {
+        self.source.is_seekable()
+    }
+
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        self.source.seek(position)?;
+        self.position = position;
+        Ok(())
+    }
 }
 
 /// Read next segment or value into vector.
@@ -390,14 +568,350 @@ pub fn expect_value(source: &mut dyn BundleSource, tag: Tag) -> BundleResult<Num
     }
 }
 
-fn uncompress_bytes(format: CompressionFormat, bytes: &[u8]) -> Vec<u8> {
+/// Block hashes and sizes of a [block encoding][format::BlockEncoding].
+struct DecodedBlockEncoding {
+    /// Uncompressed block hashes.
+    block_hashes: Vec<u8>,
+    /// Sizes of the blocks stored in the payload data, if the encoding has any.
+    block_sizes: Option<Vec<u32>>,
+    /// Size of the blocks, if the chunker produces blocks of a fixed size.
+    fixed_block_size: Option<u32>,
+}
+
+impl DecodedBlockEncoding {
+    fn new(block_encoding: &format::BlockEncoding) -> BundleResult<Self> {
+        let mut block_hashes = block_encoding.block_hashes.raw.clone();
+        if let Some(format) = block_encoding.compression {
+            block_hashes = uncompress_bytes(format, &block_hashes)?;
+        }
+        let block_sizes = match &block_encoding.block_sizes {
+            Some(block_sizes) => {
+                let mut block_sizes = block_sizes.raw.clone();
+                if let Some(format) = block_encoding.compression {
+                    block_sizes = uncompress_bytes(format, &block_sizes)?;
+                }
+                Some(
+                    block_sizes
+                        .chunks_exact(4)
+                        .map(|chunk| u32::from_be_bytes(chunk.try_into().unwrap()))
+                        .collect(),
+                )
+            }
+            None => None,
+        };
+        let fixed_block_size = match block_encoding.chunker {
+            rugix_chunker::ChunkerAlgorithm::Casync { .. } => None,
+            rugix_chunker::ChunkerAlgorithm::Fixed { block_size_kib } => {
+                Some((block_size_kib as u32) * 1024)
+            }
+        };
+        if fixed_block_size.is_none() && block_sizes.is_none() {
+            bail!("variable-size index needs block sizes")
+        }
+        if block_encoding.encryption.is_some() && block_sizes.is_none() {
+            bail!("encrypted blocks need block sizes")
+        }
+        Ok(Self {
+            block_hashes,
+            block_sizes,
+            fixed_block_size,
+        })
+    }
+}
+
+impl format::BlockEncoding {
+    /// Number of blocks of the encoded payload file.
+    pub fn num_blocks(&self) -> BundleResult<usize> {
+        let decoded = DecodedBlockEncoding::new(self)?;
+        Ok(decoded.block_hashes.len() / self.hash_algorithm.hash_size())
+    }
+}
+
+/// Size of the `idx`-th block stored in the payload data.
+fn stored_block_size(
+    block_sizes: Option<&[u32]>,
+    fixed_block_size: Option<u32>,
+    idx: usize,
+) -> BundleResult<u64> {
+    match block_sizes {
+        Some(block_sizes) => match block_sizes.get(idx) {
+            Some(size) => Ok((*size).into()),
+            None => bail!("missing size of block {idx}"),
+        },
+        None => Ok(fixed_block_size
+            .expect("either block sizes or fixed block size must be present")
+            .into()),
+    }
+}
+
+fn uncompress_bytes(format: CompressionFormat, bytes: &[u8]) -> BundleResult<Vec<u8>> {
     match format {
         CompressionFormat::Xz => {
             let mut decoder = rugix_compression::XzDecoder::new();
             let mut output = Vec::new();
-            decoder.process(bytes, &mut output).unwrap();
-            decoder.finalize(&mut output).unwrap();
-            output
+            decoder
+                .process(bytes, &mut output)
+                .whatever("unable to decompress")?;
+            decoder
+                .finalize(&mut output)
+                .whatever("unable to decompress")?;
+            Ok(output)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use byte_calc::NumBytes;
+    use rugix_hashes::HashAlgorithm;
+
+    use super::{BundleReader, PayloadTarget};
+    use crate::builder::{write_bundle, PayloadFile};
+    use crate::manifest::BundleManifest;
+    use crate::source::{from_slice, BundleSource, FileSource, ReaderSource, SkipRead};
+    use crate::BundleResult;
+
+    /// Size of the blocks of block-encoded payloads.
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Manifest of a bundle with a block-encoded payload.
+    const BLOCK_ENCODED_MANIFEST: &str = r#"
+        update-type = "incremental"
+
+        [[payloads]]
+        filename = "app.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "app"
+        [payloads.block-encoding]
+        chunker = "fixed-4"
+        hash-algorithm = "sha256"
+    "#;
+
+    /// Build a bundle with the given manifest and payloads.
+    pub(super) fn build_bundle(manifest: &str, payloads: &[&[u8]]) -> Vec<u8> {
+        let manifest = toml::from_str::<BundleManifest>(manifest).unwrap();
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let payload_files = payloads
+            .iter()
+            .enumerate()
+            .map(|(idx, payload)| {
+                let path = dir.join(format!("payload-{idx}"));
+                std::fs::write(&path, payload).unwrap();
+                PayloadFile::new(&path).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut bundle = std::io::Cursor::new(Vec::new());
+        write_bundle(&manifest, &payload_files, &[], &mut bundle).unwrap();
+        bundle.into_inner()
+    }
+
+    /// Payload consisting of four distinct blocks.
+    fn payload() -> Vec<u8> {
+        (0..4u8).flat_map(|block| [block + 1; BLOCK_SIZE]).collect()
+    }
+
+    /// Overwrite the block with the given index.
+    fn modify_block(data: &mut [u8], block: usize) {
+        data[block * BLOCK_SIZE..(block + 1) * BLOCK_SIZE].fill(0xff);
+    }
+
+    /// Hashes of the blocks of the given data by their offsets.
+    fn block_hashes(data: &[u8]) -> HashMap<u64, Vec<u8>> {
+        data.chunks(BLOCK_SIZE)
+            .enumerate()
+            .map(|(idx, block)| {
+                let hash = HashAlgorithm::Sha256.hash(block).raw().to_vec();
+                ((idx * BLOCK_SIZE) as u64, hash)
+            })
+            .collect()
+    }
+
+    /// Target with existing contents recording the offsets of written blocks.
+    struct TestTarget {
+        data: Vec<u8>,
+        position: usize,
+        /// Hashes of the blocks the target claims to contain by their offsets.
+        index: HashMap<u64, Vec<u8>>,
+        written: Vec<usize>,
+    }
+
+    impl TestTarget {
+        fn new(data: Vec<u8>, index: HashMap<u64, Vec<u8>>) -> Self {
+            Self {
+                data,
+                position: 0,
+                index,
+                written: Vec::new(),
+            }
         }
+
+        /// Decode the block-encoded payload of the given bundle into the target.
+        fn decode(&mut self, bundle: &[u8]) {
+            let mut reader = BundleReader::start(from_slice(&bundle), None).unwrap();
+            let payload = reader.next_payload().unwrap().unwrap();
+            payload.decode_into(self, None).unwrap();
+        }
+    }
+
+    impl PayloadTarget for &mut TestTarget {
+        fn write(&mut self, bytes: &[u8]) -> BundleResult<()> {
+            self.written.push(self.position);
+            self.data[self.position..self.position + bytes.len()].copy_from_slice(bytes);
+            self.position += bytes.len();
+            Ok(())
+        }
+
+        fn read_block(
+            &mut self,
+            offset: NumBytes,
+            size: NumBytes,
+            buffer: &mut Vec<u8>,
+        ) -> BundleResult<()> {
+            buffer.clear();
+            buffer.extend_from_slice(
+                &self.data[offset.unwrap_usize()..(offset + size).unwrap_usize()],
+            );
+            Ok(())
+        }
+
+        fn existing_block(&mut self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+            (self.index.get(&offset.raw)? == hash).then_some(NumBytes::from_usize(BLOCK_SIZE))
+        }
+
+        fn skip(&mut self, size: NumBytes) -> BundleResult<()> {
+            self.position += size.unwrap_usize();
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_skip_unchanged_blocks() {
+        let payload = payload();
+        let bundle = build_bundle(BLOCK_ENCODED_MANIFEST, &[&payload]);
+        let mut data = payload.clone();
+        modify_block(&mut data, 2);
+        let index = block_hashes(&data);
+        let mut target = TestTarget::new(data, index);
+        target.decode(&bundle);
+        // Only the changed block has been written.
+        assert_eq!(target.written, [2 * BLOCK_SIZE]);
+        assert_eq!(target.data, payload);
+    }
+
+    #[test]
+    fn test_rewrite_stale_blocks() {
+        let payload = payload();
+        let bundle = build_bundle(BLOCK_ENCODED_MANIFEST, &[&payload]);
+        // The index claims that the target contains all blocks, however, one of them has
+        // been modified after the index has been created.
+        let index = block_hashes(&payload);
+        let mut data = payload.clone();
+        modify_block(&mut data, 1);
+        let mut target = TestTarget::new(data, index);
+        target.decode(&bundle);
+        // The modified block is detected when reading it back and is rewritten.
+        assert_eq!(target.written, [BLOCK_SIZE]);
+        assert_eq!(target.data, payload);
+    }
+
+    /// Manifest of a bundle with three payloads.
+    const THREE_PAYLOADS_MANIFEST: &str = r#"
+        update-type = "incremental"
+
+        [[payloads]]
+        filename = "first.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "first"
+
+        [[payloads]]
+        filename = "second.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "second"
+
+        [[payloads]]
+        filename = "third.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "third"
+    "#;
+
+    /// Source recording the positions it has been seeked to.
+    struct RecordingSource<S> {
+        source: S,
+        seeks: Vec<u64>,
+    }
+
+    impl<S: BundleSource> BundleSource for RecordingSource<S> {
+        fn read(&mut self, slice: &mut [u8]) -> BundleResult<usize> {
+            self.source.read(slice)
+        }
+
+        fn skip(&mut self, length: NumBytes) -> BundleResult<()> {
+            self.source.skip(length)
+        }
+
+        fn is_seekable(&self) -> bool {
+            self.source.is_seekable()
+        }
+
+        fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+            self.seeks.push(position.raw);
+            self.source.seek(position)
+        }
+    }
+
+    impl PayloadTarget for &mut Vec<u8> {
+        fn write(&mut self, bytes: &[u8]) -> BundleResult<()> {
+            self.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    /// Skip the first two payloads and return the third payload.
+    ///
+    /// Returns the expected positions of the second and third payload as well.
+    fn skip_to_third<S: BundleSource>(source: S) -> (Vec<u8>, RecordingSource<S>, [u64; 2]) {
+        let source = RecordingSource {
+            source,
+            seeks: Vec::new(),
+        };
+        let mut reader = BundleReader::start(source, None).unwrap();
+        let positions = [1, 2].map(|idx| reader.payload_position(idx).unwrap().raw);
+        reader.next_payload().unwrap().unwrap().skip().unwrap();
+        reader.next_payload().unwrap().unwrap().skip().unwrap();
+        let mut third = Vec::new();
+        let payload = reader.next_payload().unwrap().unwrap();
+        assert_eq!(payload.idx(), 2);
+        payload.decode_into(&mut third, None).unwrap();
+        assert!(reader.next_payload().unwrap().is_none());
+        (third, reader.source.source, positions)
+    }
+
+    #[test]
+    fn test_skip_payloads() {
+        let payloads = [vec![1; 3 * BLOCK_SIZE], vec![2; 100], vec![3; BLOCK_SIZE]];
+        let bundle = build_bundle(
+            THREE_PAYLOADS_MANIFEST,
+            &payloads.each_ref().map(Vec::as_slice),
+        );
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("skip.rugixb");
+        std::fs::write(&path, &bundle).unwrap();
+        let file_source = FileSource::from_unbuffered(std::fs::File::open(&path).unwrap());
+        let (third, source, positions) = skip_to_third(file_source);
+        // Skipped payloads are not read, instead, the reader seeks to the next payload.
+        assert_eq!(third, payloads[2]);
+        assert_eq!(source.seeks, positions);
+        // Without seeking, skipped payloads are skipped over.
+        let reader_source = ReaderSource::<_, SkipRead>::new(std::io::Cursor::new(&bundle));
+        let (third, source, _) = skip_to_third(reader_source);
+        assert_eq!(third, payloads[2]);
+        assert!(source.seeks.is_empty());
     }
 }
@@ -1,6 +1,11 @@
+use std::io::{Read, Seek};
 use std::path::Path;
 
 use byte_calc::NumBytes;
+use reportify::ResultExt;
+use rugix_compression::CompressionFormat;
+
+use crate::BundleResult;
 
 /// Provider for stored blocks.
 pub trait StoredBlockProvider {
@@ -13,10 +18,49 @@ pub trait StoredBlockProvider {
 /// Stored block.
 #[derive(Debug, Clone, Copy)]
 pub struct StoredBlock<'provider> {
-    /// File containing the block.
-    pub file: &'provider Path,
-    /// Offset of the block in the file.
+    /// Source containing the block.
+    pub source: BlockSource<'provider>,
+    /// Offset of the block in the source.
     pub offset: NumBytes,
-    /// Size of the block in the file.
+    /// Size of the block in the source.
     pub size: NumBytes,
+    /// Compression format of the block in the source, if it is compressed.
+    pub compression: Option<CompressionFormat>,
+}
+
+impl StoredBlock<'_> {
+    /// Read the (possibly compressed) block into the given buffer.
+    pub fn read(&self, buffer: &mut Vec<u8>) -> BundleResult<()> {
+        match self.source {
+            BlockSource::File(path) => {
+                buffer.resize(self.size.unwrap_usize(), 0);
+                let mut file = std::fs::File::open(path).whatever("unable to open file")?;
+                file.seek(std::io::SeekFrom::Start(self.offset.raw))
+                    .whatever("unable to seek")?;
+                file.read_exact(buffer).whatever("unable to read block")?;
+                Ok(())
+            }
+            BlockSource::Reader(reader) => reader.read_block(self.offset, self.size, buffer),
+        }
+    }
+}
+
+/// Source of a stored block.
+#[derive(Debug, Clone, Copy)]
+pub enum BlockSource<'provider> {
+    /// Block is stored in a file.
+    File(&'provider Path),
+    /// Block is read with a reader, e.g., from a slot with a custom update handler.
+    Reader(&'provider dyn BlockReader),
+}
+
+/// Reader for blocks which cannot be read from a file directly.
+pub trait BlockReader: std::fmt::Debug {
+    /// Read the block at the given offset into the buffer.
+    fn read_block(
+        &self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> BundleResult<()>;
 }
@@ -0,0 +1,294 @@
+//! Indices of the blocks stored in bundle files.
+//!
+//! Bundles kept on a system, e.g., for recovery, contain the blocks of their payloads and
+//! can thus serve as a source of blocks for later updates. To this end, the blocks stored
+//! in the payload data of a bundle are indexed with [sorted block
+//! indices][SortedBlockIndex]. As the blocks may be compressed, there is a separate index
+//! for each combination of hash algorithm and compression format used by the payloads.
+//! Encrypted payloads are not indexed, as their blocks cannot be used without the
+//! bundle's content key.
+
+use std::fs::File;
+use std::path::Path;
+
+use byte_calc::NumBytes;
+use reportify::ResultExt;
+use rugix_compression::CompressionFormat;
+use rugix_hashes::HashAlgorithm;
+use tracing::warn;
+
+use super::{stored_block_size, BundleReader, DecodedBlockEncoding};
+use crate::block_encoding::block_index::{BlockId, RawBlockIndex};
+use crate::block_encoding::block_table::BlockTable;
+use crate::block_encoding::sorted_index::SortedBlockIndex;
+use crate::source::FileSource;
+use crate::BundleResult;
+
+/// Extension of the index files.
+const INDEX_EXTENSION: &str = "rugix-sorted-index";
+
+/// Name used for blocks which are not compressed.
+const UNCOMPRESSED: &str = "uncompressed";
+
+/// Sorted index of blocks stored in a bundle.
+#[derive(Debug)]
+pub struct BundleBlockIndex {
+    /// Hash algorithm of the indexed blocks.
+    pub hash_algorithm: HashAlgorithm,
+    /// Compression format of the indexed blocks.
+    pub compression: Option<CompressionFormat>,
+    /// Index mapping block hashes to the blocks' offsets and sizes in the bundle file.
+    pub index: SortedBlockIndex,
+}
+
+/// Write indices of the blocks stored in the given bundle to the given directory.
+pub fn write_bundle_indices(bundle: &Path, index_dir: &Path) -> BundleResult<()> {
+    let file = File::open(bundle).whatever("unable to open bundle file")?;
+    let mut reader = BundleReader::start(FileSource::from_unbuffered(file), None)?;
+    let mut payloads = Vec::new();
+    while let Some(payload) = reader.next_payload()? {
+        let block_encoding = payload
+            .header
+            .block_encoding
+            .as_ref()
+            .filter(|block_encoding| block_encoding.encryption.is_none());
+        if let Some(block_encoding) = block_encoding {
+            let data_offset = payload.reader.source.position;
+            let decoded = DecodedBlockEncoding::new(block_encoding)?;
+            let blocks = stored_blocks(
+                &decoded,
+                block_encoding.hash_algorithm,
+                block_encoding.deduplicated,
+                data_offset,
+                payload.remaining_data,
+            )?;
+            payloads.push((
+                block_encoding.hash_algorithm,
+                block_encoding.compression,
+                decoded.block_hashes,
+                blocks,
+            ));
+        }
+        payload.skip()?;
+    }
+    let mut groups = Vec::<(HashAlgorithm, Option<CompressionFormat>, Vec<_>)>::new();
+    for (hash_algorithm, compression, block_hashes, blocks) in &payloads {
+        let group = match groups
+            .iter()
+            .position(|(h, c, _)| h == hash_algorithm && c == compression)
+        {
+            Some(group) => group,
+            None => {
+                groups.push((*hash_algorithm, *compression, Vec::new()));
+                groups.len() - 1
+            }
+        };
+        let hash_size = hash_algorithm.hash_size();
+        groups[group]
+            .2
+            .extend(blocks.iter().map(|(block, offset, size)| {
+                let hash = &block_hashes[block.raw * hash_size..(block.raw + 1) * hash_size];
+                (hash, *offset, *size)
+            }));
+    }
+    std::fs::create_dir_all(index_dir).whatever("unable to create index directory")?;
+    for (hash_algorithm, compression, entries) in groups {
+        let compression = compression.map(|c| c.as_str()).unwrap_or(UNCOMPRESSED);
+        let path = index_dir.join(format!(
+            "{}_{compression}.{INDEX_EXTENSION}",
+            hash_algorithm.name()
+        ));
+        SortedBlockIndex::write_entries(&path, hash_algorithm.hash_size(), entries)?;
+    }
+    Ok(())
+}
+
+/// Open the indices of the blocks of a bundle in the given directory.
+pub fn open_bundle_indices(index_dir: &Path) -> BundleResult<Vec<BundleBlockIndex>> {
+    let mut indices = Vec::new();
+    for dir_entry in std::fs::read_dir(index_dir).whatever("unable to list index directory")? {
+        let dir_entry = dir_entry.whatever("unable to list index directory")?;
+        let filename = dir_entry.file_name();
+        let filename = filename.to_string_lossy();
+        let Some(name) = filename
+            .strip_suffix(INDEX_EXTENSION)
+            .and_then(|name| name.strip_suffix('.'))
+        else {
+            continue;
+        };
+        let Some((hash_algorithm, compression)) = name.split_once('_') else {
+            warn!("invalid filename for bundle index: {filename:?}");
+            continue;
+        };
+        let Ok(hash_algorithm) = hash_algorithm.parse() else {
+            warn!("invalid hash algorithm: {hash_algorithm:?}");
+            continue;
+        };
+        let compression = if compression == UNCOMPRESSED {
+            None
+        } else if let Ok(compression) = compression.parse() {
+            Some(compression)
+        } else {
+            warn!("invalid compression format: {compression:?}");
+            continue;
+        };
+        indices.push(BundleBlockIndex {
+            hash_algorithm,
+            compression,
+            index: SortedBlockIndex::open(&dir_entry.path())?,
+        });
+    }
+    Ok(indices)
+}
+
+/// Determine the blocks stored in the payload data starting at the given offset.
+///
+/// Returns the id, the offset, and the size of every stored block.
+fn stored_blocks(
+    decoded: &DecodedBlockEncoding,
+    hash_algorithm: HashAlgorithm,
+    deduplicated: bool,
+    mut offset: NumBytes,
+    mut remaining_data: NumBytes,
+) -> BundleResult<Vec<(BlockId, NumBytes, NumBytes)>> {
+    let raw_index = RawBlockIndex::new(&decoded.block_hashes, hash_algorithm);
+    let num_blocks = decoded.block_hashes.len() / hash_algorithm.hash_size();
+    let mut table = BlockTable::new();
+    let mut blocks = Vec::new();
+    let mut next_size_idx = 0;
+    for idx in 0..num_blocks {
+        let block = BlockId { raw: idx };
+        let is_fresh = table.insert_raw(&raw_index, block);
+        if !is_fresh && deduplicated {
+            // The block is not stored again.
+            continue;
+        }
+        let size = NumBytes::new(
+            stored_block_size(
+                decoded.block_sizes.as_deref(),
+                decoded.fixed_block_size,
+                next_size_idx,
+            )?
+            .min(remaining_data.raw),
+        );
+        next_size_idx += 1;
+        if is_fresh {
+            blocks.push((block, offset, size));
+        }
+        offset += size;
+        remaining_data -= size;
+    }
+    Ok(blocks)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+    use std::io::BufWriter;
+
+    use rugix_hashes::HashAlgorithm;
+
+    use super::{open_bundle_indices, write_bundle_indices};
+    use crate::builder::{write_bundle, PayloadFile};
+    use crate::encryption::DecryptionKey;
+    use crate::manifest::BundleManifest;
+    use crate::reader::block_provider::{BlockSource, StoredBlock};
+
+    /// Size of the blocks of the payloads.
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Payload consisting of blocks filled with the given bytes.
+    fn payload(blocks: &[u8]) -> Vec<u8> {
+        blocks.iter().flat_map(|byte| [*byte; BLOCK_SIZE]).collect()
+    }
+
+    #[test]
+    fn test_index_bundle_blocks() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let dir = temp_dir.path();
+        let manifest = toml::from_str::<BundleManifest>(
+            r#"
+            update-type = "incremental"
+
+            [[payloads]]
+            filename = "deduplicated.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "deduplicated"
+            [payloads.block-encoding]
+            chunker = "fixed-4"
+            hash-algorithm = "sha256"
+            deduplicate = true
+
+            [[payloads]]
+            filename = "duplicated.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "duplicated"
+            [payloads.block-encoding]
+            chunker = "fixed-4"
+            hash-algorithm = "sha256"
+
+            [[payloads]]
+            filename = "encrypted.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "encrypted"
+            [payloads.block-encoding]
+            chunker = "fixed-4"
+            hash-algorithm = "sha256"
+            encrypt = true
+            "#,
+        )
+        .unwrap();
+        let payloads = [
+            payload(&[1, 2, 1, 3]),
+            payload(&[4, 5, 4, 6]),
+            payload(&[7]),
+        ];
+        let payload_files = payloads
+            .iter()
+            .enumerate()
+            .map(|(idx, payload)| {
+                let path = dir.join(format!("payload-{idx}"));
+                std::fs::write(&path, payload).unwrap();
+                PayloadFile::new(&path).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let bundle = dir.join("bundle.rugixb");
+        let encryption_key = DecryptionKey::generate().public_key();
+        write_bundle(
+            &manifest,
+            &payload_files,
+            &[encryption_key],
+            &mut BufWriter::new(File::create(&bundle).unwrap()),
+        )
+        .unwrap();
+        let index_dir = dir.join("index");
+        write_bundle_indices(&bundle, &index_dir).unwrap();
+        let indices = open_bundle_indices(&index_dir).unwrap();
+        assert_eq!(indices.len(), 1);
+        assert_eq!(indices[0].hash_algorithm, HashAlgorithm::Sha256);
+        assert_eq!(indices[0].compression, None);
+        assert_eq!(indices[0].index.len(), 6);
+        // The blocks after deduplicated and duplicated blocks are found at the right
+        // offsets and can be read back from the bundle.
+        for byte in 1..=6 {
+            let block = [byte; BLOCK_SIZE];
+            let hash = HashAlgorithm::Sha256.hash(&block);
+            let (offset, size) = indices[0].index.query(hash.raw()).unwrap();
+            let stored_block = StoredBlock {
+                source: BlockSource::File(&bundle),
+                offset,
+                size,
+                compression: None,
+            };
+            let mut buffer = Vec::new();
+            stored_block.read(&mut buffer).unwrap();
+            assert_eq!(buffer, block);
+        }
+        // Blocks of encrypted payloads are not indexed.
+        let hash = HashAlgorithm::Sha256.hash(&[7; BLOCK_SIZE]);
+        assert!(indices[0].index.query(hash.raw()).is_none());
+    }
+}
@@ -0,0 +1,200 @@
+//! Offline estimation of the data fetched when installing a bundle.
+//!
+//! Devices reuse blocks they already have, e.g., in their slots, instead of fetching them
+//! from the bundle. Given the block indices of a device, the estimation walks through the
+//! block encoding of a payload like [`decode_into`][super::PayloadReader::decode_into]
+//! does, without reading the blocks themselves.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use byte_calc::NumBytes;
+use reportify::ResultExt;
+
+use super::block_provider::{BlockSource, StoredBlock, StoredBlockProvider};
+use super::{stored_block_size, DecodedBlockEncoding, PayloadReader};
+use crate::block_encoding::block_index::BlockIndex;
+use crate::format::decode::decode_slice;
+use crate::source::BundleSource;
+use crate::{format, BundleResult};
+
+/// Estimated amount of data fetched when installing a payload.
+#[derive(Debug, Clone)]
+pub struct PayloadEstimate {
+    /// Size of the payload data fetched from the bundle.
+    pub fetched: NumBytes,
+    /// Size of the payload data not fetched because the blocks are reused.
+    pub reused: NumBytes,
+    /// Number of blocks fetched from the bundle.
+    pub num_fetched_blocks: u64,
+    /// Number of blocks reused.
+    pub num_reused_blocks: u64,
+}
+
+/// Load a block index from the given file, e.g., exported from a device.
+pub fn load_block_index(path: &Path) -> BundleResult<BlockIndex> {
+    let bytes = std::fs::read(path).whatever("unable to read block index")?;
+    BlockIndex::from_format(decode_slice::<format::BlockIndex>(&bytes)?)
+}
+
+/// Provider of the blocks contained in block indices.
+///
+/// The provider has no access to the blocks themselves, it only knows where they are
+/// stored. Hence, it is only useful for estimating which blocks are reused.
+#[derive(Debug, Default)]
+pub struct IndexBlockProvider<'idx> {
+    blocks: HashMap<&'idx [u8], StoredBlock<'idx>>,
+}
+
+impl<'idx> IndexBlockProvider<'idx> {
+    /// Create an empty provider.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add the blocks of the given index, which has been exported to the given file.
+    pub fn add_index(&mut self, index: &'idx BlockIndex, file: &'idx Path) {
+        for block in index.iter() {
+            let entry = index.entry(block);
+            self.blocks.entry(entry.hash).or_insert(StoredBlock {
+                source: BlockSource::File(file),
+                offset: entry.offset,
+                size: entry.size,
+                compression: None,
+            });
+        }
+    }
+}
+
+impl StoredBlockProvider for IndexBlockProvider<'_> {
+    fn query(&self, hash: &[u8]) -> Option<StoredBlock<'_>> {
+        self.blocks.get(hash).copied()
+    }
+
+    fn has_stored_blocks(&self) -> bool {
+        !self.blocks.is_empty()
+    }
+}
+
+/// Estimate the data fetched when installing the payload with the given provider.
+///
+/// Payloads without a block encoding are always fetched entirely. Note that sources may
+/// still fetch small gaps between the blocks they need, so the actual amount of data
+/// fetched can be somewhat larger.
+pub fn estimate_payload<S: BundleSource>(
+    payload: PayloadReader<'_, S>,
+    provider: Option<&dyn StoredBlockProvider>,
+) -> BundleResult<PayloadEstimate> {
+    let mut estimate = PayloadEstimate {
+        fetched: NumBytes::ZERO,
+        reused: NumBytes::ZERO,
+        num_fetched_blocks: 0,
+        num_reused_blocks: 0,
+    };
+    let mut remaining_data = payload.data_size();
+    if let Some(block_encoding) = &payload.header.block_encoding {
+        let DecodedBlockEncoding {
+            block_hashes,
+            block_sizes,
+            fixed_block_size,
+        } = DecodedBlockEncoding::new(block_encoding)?;
+        let mut seen = HashSet::new();
+        let mut next_size_idx = 0;
+        for block_hash in block_hashes.chunks_exact(block_encoding.hash_algorithm.hash_size()) {
+            let is_fresh = seen.insert(block_hash);
+            if !is_fresh && block_encoding.deduplicated {
+                // The block is restored from the target and not stored in the bundle.
+                continue;
+            }
+            let block_size = NumBytes::new(
+                stored_block_size(block_sizes.as_deref(), fixed_block_size, next_size_idx)?
+                    .min(remaining_data.raw),
+            );
+            next_size_idx += 1;
+            remaining_data -= block_size;
+            if provider.and_then(|p| p.query(block_hash)).is_some() {
+                estimate.reused += block_size;
+                estimate.num_reused_blocks += 1;
+            } else {
+                estimate.fetched += block_size;
+                estimate.num_fetched_blocks += 1;
+            }
+        }
+    } else {
+        estimate.fetched = remaining_data;
+    }
+    payload.skip()?;
+    Ok(estimate)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    use byte_calc::NumBytes;
+    use rugix_chunker::ChunkerAlgorithm;
+    use rugix_hashes::HashAlgorithm;
+
+    use super::{estimate_payload, IndexBlockProvider};
+    use crate::block_encoding::block_index::{BlockIndexBuilder, BlockIndexConfig};
+    use crate::reader::tests::build_bundle;
+    use crate::reader::BundleReader;
+    use crate::source::from_slice;
+
+    /// Size of the blocks of the payloads.
+    const BLOCK_SIZE: usize = 4096;
+
+    /// Data consisting of blocks filled with the given bytes.
+    fn blocks(blocks: &[u8]) -> Vec<u8> {
+        blocks.iter().flat_map(|byte| [*byte; BLOCK_SIZE]).collect()
+    }
+
+    #[test]
+    fn test_estimate_payload() {
+        let bundle = build_bundle(
+            r#"
+            update-type = "incremental"
+
+            [[payloads]]
+            filename = "system.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "system"
+            [payloads.block-encoding]
+            chunker = "fixed-4"
+            hash-algorithm = "sha256"
+            deduplicate = true
+
+            [[payloads]]
+            filename = "config.tar"
+            [payloads.delivery]
+            type = "slot"
+            slot = "config"
+            "#,
+            &[&blocks(&[1, 2, 3, 4, 1, 5, 6, 7]), &[0; 1000]],
+        );
+        // Index of the image the device already has.
+        let mut builder = BlockIndexBuilder::new(BlockIndexConfig {
+            hash_algorithm: HashAlgorithm::Sha256,
+            chunker: ChunkerAlgorithm::Fixed { block_size_kib: 4 },
+        })
+        .unwrap();
+        builder.process(&blocks(&[1, 2, 3, 9]));
+        let index = builder.finalize();
+        let mut provider = IndexBlockProvider::new();
+        provider.add_index(&index, Path::new("system.img"));
+        let mut reader = BundleReader::start(from_slice(&bundle), None).unwrap();
+        let payload = reader.next_payload().unwrap().unwrap();
+        let estimate = estimate_payload(payload, Some(&provider)).unwrap();
+        // The duplicate of block `1` is not stored in the bundle and not counted.
+        assert_eq!(estimate.num_reused_blocks, 3);
+        assert_eq!(estimate.reused, NumBytes::from_usize(3 * BLOCK_SIZE));
+        assert_eq!(estimate.num_fetched_blocks, 4);
+        assert_eq!(estimate.fetched, NumBytes::from_usize(4 * BLOCK_SIZE));
+        // Payloads without a block encoding are fetched entirely.
+        let payload = reader.next_payload().unwrap().unwrap();
+        let estimate = estimate_payload(payload, Some(&provider)).unwrap();
+        assert_eq!(estimate.fetched, NumBytes::new(1000));
+        assert_eq!(estimate.num_reused_blocks, 0);
+    }
+}
@@ -0,0 +1,265 @@
+//! Offline verification of bundles.
+//!
+//! Verification walks through the entire bundle and checks everything a device would
+//! check when installing the bundle, i.e., the hashes of the payload headers, the hashes
+//! of the individual blocks, and the hashes of the payload files. In addition, it checks
+//! that the bundle is well-formed, i.e., that all segments are balanced, that the bundle
+//! does not contain any unknown required tags, and that the payload offsets and sizes are
+//! correct.
+
+use std::collections::{HashMap, HashSet};
+
+use byte_calc::{ByteLen, NumBytes};
+use reportify::bail;
+
+use super::{skip_until_end, BundleReader, DecodedBlockEncoding, PayloadTarget};
+use crate::format::stlv::{read_atom_head, skip, AtomHead};
+use crate::format::tags;
+use crate::source::BundleSource;
+use crate::BundleResult;
+
+/// Statistics of a verified payload.
+#[derive(Debug, Clone)]
+pub struct PayloadStats {
+    /// Size of the payload file.
+    pub file_size: NumBytes,
+    /// Size of the payload data in the bundle.
+    pub data_size: NumBytes,
+    /// Statistics of the blocks, if the payload has a block encoding.
+    pub blocks: Option<BlockStats>,
+}
+
+/// Statistics of the blocks of a payload.
+#[derive(Debug, Clone)]
+pub struct BlockStats {
+    /// Number of blocks of the payload file.
+    pub num_blocks: u64,
+    /// Number of distinct blocks of the payload file.
+    pub num_unique_blocks: u64,
+    /// Total size of the distinct blocks.
+    pub unique_size: NumBytes,
+    /// Total size of the blocks stored in the payload data, before compression and
+    /// encryption.
+    pub stored_size: NumBytes,
+}
+
+impl BlockStats {
+    /// Ratio of the size of the payload file to the size of its distinct blocks.
+    pub fn dedup_ratio(&self, file_size: NumBytes) -> f64 {
+        ratio(file_size, self.unique_size)
+    }
+
+    /// Ratio of the size of the stored blocks to the size of the payload data.
+    pub fn compression_ratio(&self, data_size: NumBytes) -> f64 {
+        ratio(self.stored_size, data_size)
+    }
+}
+
+/// Check that the bundle is well-formed and does not contain unknown required tags.
+///
+/// The source must be positioned at the start of the bundle.
+pub fn check_structure(source: &mut dyn BundleSource) -> BundleResult<()> {
+    let mut segments = Vec::new();
+    while let Some(head) = read_atom_head(source)? {
+        if segments.is_empty() && !matches!(head, AtomHead::Start { tag } if tag == tags::BUNDLE) {
+            bail!("expected bundle segment, found {head:?}");
+        }
+        if tags::is_required(head.tag()) && !tags::is_know(head.tag()) {
+            bail!("found unknown required tag {}", head.tag());
+        }
+        match head {
+            AtomHead::Start { tag } => segments.push(tag),
+            AtomHead::End { tag } => {
+                if segments.pop() != Some(tag) {
+                    bail!("unbalanced segment end with tag {tag}");
+                }
+                if segments.is_empty() && read_atom_head(source)?.is_some() {
+                    bail!("found trailing data after the bundle");
+                }
+            }
+            AtomHead::Value { .. } => skip(source, head)?,
+        }
+    }
+    if let Some(tag) = segments.last() {
+        bail!("unexpected end of bundle, segment {tag} is not closed");
+    }
+    Ok(())
+}
+
+/// Verify all payloads of the bundle and return their statistics.
+///
+/// The reader must not have read any payloads yet. To verify encrypted payloads, the
+/// bundle must have been [unlocked][BundleReader::unlock].
+pub fn verify_payloads<S: BundleSource>(
+    mut reader: BundleReader<S>,
+) -> BundleResult<Vec<PayloadStats>> {
+    if reader.next_payload != 0 {
+        bail!("payloads have already been read");
+    }
+    let mut stats = Vec::new();
+    for idx in 0..reader.header.payload_index.len() {
+        if let Some(position) = reader.payload_position(idx) {
+            if position != reader.source.position {
+                bail!("invalid offset of payload {idx}");
+            }
+        }
+        let Some(payload) = reader.next_payload()? else {
+            bail!("missing payload {idx}");
+        };
+        let data_size = payload.data_size();
+        let mut duplicates = HashSet::new();
+        let mut block_stats = None;
+        if let Some(block_encoding) = &payload.header.block_encoding {
+            let decoded = DecodedBlockEncoding::new(block_encoding)?;
+            let hash_size = block_encoding.hash_algorithm.hash_size();
+            let mut unique = HashSet::new();
+            let mut num_blocks = 0;
+            for hash in decoded.block_hashes.chunks_exact(hash_size) {
+                num_blocks += 1;
+                if !unique.insert(hash) {
+                    duplicates.insert(hash.to_vec());
+                }
+            }
+            block_stats = Some((num_blocks, unique.len() as u64, block_encoding.deduplicated));
+        }
+        let file_size = payload.entry().file_size;
+        let mut target = VerifyTarget::new(duplicates);
+        payload.decode_into(&mut target, None)?;
+        if file_size.is_some_and(|file_size| file_size != target.size.raw) {
+            bail!("invalid file size of payload {idx}");
+        }
+        stats.push(PayloadStats {
+            file_size: target.size,
+            data_size,
+            blocks: block_stats.map(|(num_blocks, num_unique_blocks, deduplicated)| BlockStats {
+                num_blocks,
+                num_unique_blocks,
+                unique_size: target.unique_size,
+                stored_size: if deduplicated {
+                    target.unique_size
+                } else {
+                    target.size
+                },
+            }),
+        });
+    }
+    skip_until_end(&mut reader.source, tags::PAYLOADS)?;
+    skip_until_end(&mut reader.source, tags::BUNDLE)?;
+    Ok(stats)
+}
+
+/// Payload target discarding the payload file.
+///
+/// To restore deduplicated blocks, the first occurrences of blocks occurring multiple
+/// times are kept in memory.
+#[derive(Debug)]
+struct VerifyTarget {
+    /// Hashes of the blocks occurring multiple times in the payload file.
+    duplicates: HashSet<Vec<u8>>,
+    /// Hash of the block which is written next.
+    next_hash: Option<Vec<u8>>,
+    /// Hashes of the blocks seen so far.
+    seen: HashSet<Vec<u8>>,
+    /// First occurrences of blocks occurring multiple times indexed by their offset.
+    blocks: HashMap<NumBytes, Vec<u8>>,
+    /// Size of the payload file written so far.
+    size: NumBytes,
+    /// Size of the distinct blocks written so far.
+    unique_size: NumBytes,
+}
+
+impl VerifyTarget {
+    fn new(duplicates: HashSet<Vec<u8>>) -> Self {
+        Self {
+            duplicates,
+            next_hash: None,
+            seen: HashSet::new(),
+            blocks: HashMap::new(),
+            size: NumBytes::ZERO,
+            unique_size: NumBytes::ZERO,
+        }
+    }
+}
+
+impl PayloadTarget for &mut VerifyTarget {
+    fn write(&mut self, bytes: &[u8]) -> BundleResult<()> {
+        match self.next_hash.take() {
+            Some(hash) => {
+                if self.duplicates.contains(&hash) && !self.seen.contains(&hash) {
+                    self.blocks.insert(self.size, bytes.to_vec());
+                }
+                if self.seen.insert(hash) {
+                    self.unique_size += bytes.byte_len();
+                }
+            }
+            None => self.unique_size += bytes.byte_len(),
+        }
+        self.size += bytes.byte_len();
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> BundleResult<()> {
+        match self.blocks.get(&offset) {
+            Some(block) if block.byte_len() == size => {
+                buffer.clear();
+                buffer.extend_from_slice(block);
+                Ok(())
+            }
+            _ => bail!("unable to find deduplicated block at offset {offset}"),
+        }
+    }
+
+    fn existing_block(&mut self, _: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        // Every block is written, so we remember the hash of the next block here.
+        self.next_hash = Some(hash.to_vec());
+        None
+    }
+}
+
+fn ratio(numerator: NumBytes, denominator: NumBytes) -> f64 {
+    if denominator == NumBytes::ZERO {
+        1.0
+    } else {
+        numerator.raw as f64 / denominator.raw as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::format::stlv::{write_segment_end, write_segment_start, write_value, Tag};
+    use crate::format::tags;
+    use crate::source::from_slice;
+
+    use super::check_structure;
+
+    fn bundle_with_value(tag: Tag) -> Vec<u8> {
+        let mut bundle = Vec::new();
+        write_segment_start(&mut bundle, tags::BUNDLE).unwrap();
+        write_value(&mut bundle, tag, b"value").unwrap();
+        write_segment_end(&mut bundle, tags::BUNDLE).unwrap();
+        bundle
+    }
+
+    #[test]
+    fn test_check_structure() {
+        let bundle = bundle_with_value(tags::PADDING);
+        assert!(check_structure(&mut from_slice(&bundle)).is_ok());
+        // Unknown optional tags are fine, unknown required tags are not.
+        let bundle = bundle_with_value(Tag::from_bytes([0xde, 0xad, 0xbe, 0xef]));
+        assert!(check_structure(&mut from_slice(&bundle)).is_ok());
+        let bundle = bundle_with_value(Tag::from_bytes([0x5e, 0xad, 0xbe, 0xef]));
+        assert!(check_structure(&mut from_slice(&bundle)).is_err());
+        // Truncated bundles and trailing data are rejected.
+        let bundle = bundle_with_value(tags::PADDING);
+        assert!(check_structure(&mut from_slice(&&bundle[..bundle.len() - 1])).is_err());
+        let mut bundle = bundle_with_value(tags::PADDING);
+        write_segment_start(&mut bundle, tags::BUNDLE).unwrap();
+        write_segment_end(&mut bundle, tags::BUNDLE).unwrap();
+        assert!(check_structure(&mut from_slice(&bundle)).is_err());
+    }
+}
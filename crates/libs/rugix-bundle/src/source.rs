@@ -5,6 +5,10 @@
 //! the bytes that should be skipped. Reads should be buffered, as we are reading small
 //! slices at a time. In Rugix Ctrl, we will implement a bundle source for streaming via
 //! HTTP using range queries for efficient skipping.
+//!
+//! Sources may optionally support seeking to arbitrary positions. In combination with
+//! the payload offsets stored in the bundle header, this allows readers to jump directly
+//! to a specific payload.
 
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Read, Seek};
@@ -27,6 +31,17 @@ pub trait BundleSource {
     /// Skip the given number of bytes.
     fn skip(&mut self, length: NumBytes) -> BundleResult<()>;
 
+    /// Indicates whether the source supports [seeking][BundleSource::seek].
+    fn is_seekable(&self) -> bool {
+        false
+    }
+
+    /// Seek to the given position relative to the start of the bundle.
+    #[expect(unused_variables)]
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        bail!("source does not support seeking");
+    }
+
     /// Read an exact number of bytes into the provided slice.
     fn read_exact(&mut self, mut slice: &mut [u8]) -> BundleResult<()> {
         while slice.len() > 0 {
@@ -49,6 +64,14 @@ impl<S: BundleSource + ?Sized> BundleSource for &mut S {
         (*self).skip(length)
     }
 
+    fn is_seekable(&self) -> bool {
+        (**self).is_seekable()
+    }
+
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        (*self).seek(position)
+    }
+
     fn read_exact(&mut self, slice: &mut [u8]) -> BundleResult<()> {
         (*self).read_exact(slice)
     }
@@ -63,6 +86,14 @@ impl<S: BundleSource + ?Sized> BundleSource for Box<S> {
         (**self).skip(length)
     }
 
+    fn is_seekable(&self) -> bool {
+        (**self).is_seekable()
+    }
+
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        (**self).seek(position)
+    }
+
     fn read_exact(&mut self, slice: &mut [u8]) -> BundleResult<()> {
         (**self).read_exact(slice)
     }
@@ -108,12 +139,32 @@ impl<R: BufRead, S: Skip<R>> BundleSource for ReaderSource<R, S> {
     fn skip(&mut self, length: NumBytes) -> BundleResult<()> {
         S::skip(&mut self.reader, length).whatever("unable to skip bytes in reader")
     }
+
+    fn is_seekable(&self) -> bool {
+        S::SEEKABLE
+    }
+
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        if !S::SEEKABLE {
+            bail!("source does not support seeking");
+        }
+        S::seek(&mut self.reader, position).whatever("unable to seek in reader")
+    }
 }
 
 /// Trait for skipping bytes from a reader.
 pub trait Skip<R> {
+    /// Indicates whether the reader also supports seeking.
+    const SEEKABLE: bool = false;
+
     /// Skip the given number of bytes.
     fn skip(reader: &mut R, skip: NumBytes) -> io::Result<()>;
+
+    /// Seek to the given position, only called if [`Skip::SEEKABLE`] is `true`.
+    #[expect(unused_variables)]
+    fn seek(reader: &mut R, position: NumBytes) -> io::Result<()> {
+        Err(io::ErrorKind::Unsupported.into())
+    }
 }
 
 /// Skip bytes by reading.
@@ -138,10 +189,17 @@ impl<R: BufRead> Skip<R> for SkipRead {
 pub struct SkipSeek(());
 
 impl<R: Seek> Skip<R> for SkipSeek {
+    const SEEKABLE: bool = true;
+
     fn skip(reader: &mut R, skip: NumBytes) -> io::Result<()> {
         let skip = i64::try_from(skip.raw).expect("should fit");
         reader.seek_relative(skip)
     }
+
+    fn seek(reader: &mut R, position: NumBytes) -> io::Result<()> {
+        reader.seek(io::SeekFrom::Start(position.raw))?;
+        Ok(())
+    }
 }
 
 /// Bundle source backed by a [local file][File].
@@ -213,4 +271,16 @@ impl<S: BundleSource> BundleSource for SourceHasher<S> {
             self.source.skip(length)
         }
     }
+
+    fn is_seekable(&self) -> bool {
+        // Seeking would bypass the hasher.
+        self.hasher.is_none() && self.source.is_seekable()
+    }
+
+    fn seek(&mut self, position: NumBytes) -> BundleResult<()> {
+        if self.hasher.is_some() {
+            bail!("unable to seek while hashing");
+        }
+        self.source.seek(position)
+    }
 }
@@ -1,4 +1,5 @@
 use std::io;
+use std::path::Path;
 
 use crate::devices;
 
@@ -23,7 +24,8 @@ pub enum AutobootSection {
     Tryboot,
 }
 
-pub fn set_spare_flag() -> Result<(), io::Error> {
+/// Set the tryboot flag via the firmware interface at the given VCIO path.
+pub fn set_spare_flag(vcio: &Path) -> Result<(), io::Error> {
     // Instead of rebooting with `reboot "0 tryboot"`, we directly set the
     // required flag via Raspberry Pi's firmware interface. By default,
     // `reboot` should not set any reboot flags, hence, our flags wil not
@@ -31,13 +33,14 @@ pub fn set_spare_flag() -> Result<(), io::Error> {
     // kernel and a `reboot` binary that actually passes down the flags to
     // the kernel. This cannot be assumed on all systems. In particular, on
     // Alpine Linux, the `reboot`` binary does not pass down flags.
-    devices::rpi::set_tryboot_flag(true)?;
+    devices::rpi::set_tryboot_flag(vcio, true)?;
     Ok(())
 }
 
-pub fn clear_spare_flag() -> Result<(), io::Error> {
-    if devices::rpi::get_tryboot_flag()? {
-        devices::rpi::set_tryboot_flag(false)?;
+/// Clear the tryboot flag via the firmware interface at the given VCIO path.
+pub fn clear_spare_flag(vcio: &Path) -> Result<(), io::Error> {
+    if devices::rpi::get_tryboot_flag(vcio)? {
+        devices::rpi::set_tryboot_flag(vcio, false)?;
     }
     Ok(())
 }
@@ -2,7 +2,7 @@
 
 use std::io;
 use std::os::fd::{AsRawFd, FromRawFd, OwnedFd};
-use std::path::Path;
+use std::path::{Path, PathBuf};
 
 use nix::fcntl;
 use nix::libc::{c_char, c_int};
@@ -17,8 +17,10 @@ pub fn is_raspberry_pi() -> bool {
 }
 
 /// Retrieve the tryboot flag from Raspberry Pi's firmware.
-pub fn get_tryboot_flag() -> io::Result<bool> {
-    let vcio = Vcio::open()?;
+///
+/// The firmware is accessed via the VCIO device at the given path, usually [`VCIO_PATH`].
+pub fn get_tryboot_flag(vcio: &Path) -> io::Result<bool> {
+    let vcio = Vcio::open(vcio)?;
     Ok(get_reboot_flags(&vcio)? & 1 != 0)
 }
 
@@ -26,8 +28,8 @@ pub fn get_tryboot_flag() -> io::Result<bool> {
 ///
 /// This function is based on the implementation of Raspberry Pi's Linux driver:
 /// <https://github.com/raspberrypi/linux/blob/085e8b4e0e1268ab82245e3433fb33399720b7ff/drivers/firmware/raspberrypi.c#L191>
-pub fn set_tryboot_flag(tryboot: bool) -> io::Result<()> {
-    let vcio = Vcio::open()?;
+pub fn set_tryboot_flag(vcio: &Path, tryboot: bool) -> io::Result<()> {
+    let vcio = Vcio::open(vcio)?;
     if tryboot {
         set_reboot_flags(&vcio, 1)?;
     } else {
@@ -104,13 +106,15 @@ fn encode_reboot_flags_request(tag: u32, flags: u32) -> [u32; 7] {
 }
 
 /// Path to the VCIO device for communicating with Raspberry Pi's firmware.
-const VCIO_PATH: &str = "/dev/vcio";
+pub const VCIO_PATH: &str = "/dev/vcio";
 
 /// Handle to the VCIO device.
 #[derive(Debug)]
-struct Vcio {
-    /// Underlying file descriptor.
-    fd: OwnedFd,
+enum Vcio {
+    /// Underlying file descriptor of the device.
+    Device(OwnedFd),
+    /// Regular file emulating the firmware's reboot flags.
+    Emulated(PathBuf),
 }
 
 impl Vcio {
@@ -119,17 +123,21 @@ impl Vcio {
         Path::new(VCIO_PATH).exists()
     }
 
-    /// Open a handle to the VCIO device.
-    pub fn open() -> io::Result<Self> {
+    /// Open a handle to the VCIO device at the given path.
+    ///
+    /// If the path is a regular file, the file emulates the firmware's reboot flags. This
+    /// allows testing without a Raspberry Pi.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        if path.is_file() {
+            return Ok(Self::Emulated(path.to_path_buf()));
+        }
         let flags = fcntl::OFlag::O_NONBLOCK;
         let mode = stat::Mode::empty();
-        let fd = fcntl::open(VCIO_PATH, flags, mode)?;
-        Ok(Self {
-            fd: unsafe {
-                // SAFETY: We own the file descriptor.
-                OwnedFd::from_raw_fd(fd)
-            },
-        })
+        let fd = fcntl::open(path, flags, mode)?;
+        Ok(Self::Device(unsafe {
+            // SAFETY: We own the file descriptor.
+            OwnedFd::from_raw_fd(fd)
+        }))
     }
 
     /// Perform an `ioctl` call to the VCIO property interface using the provided buffer.
@@ -144,6 +152,11 @@ impl Vcio {
             "Invalid buffer size. Buffer is smaller than indicated."
         );
 
+        let fd = match self {
+            Self::Device(fd) => fd,
+            Self::Emulated(path) => return emulate_property(path, buffer),
+        };
+
         /// The `ioctl` identifier of the property interface.
         const IOCTL_IDENTIFIER: u8 = 100;
         /// The `ioctl` sequence number of the property interface.
@@ -166,8 +179,33 @@ impl Vcio {
         };
 
         Ok(ioctl_property(
-            self.fd.as_raw_fd(),
+            fd.as_raw_fd(),
             buffer.as_mut_ptr() as *mut c_char,
         )?)
     }
 }
+
+/// Emulate a request to the VCIO property interface with reboot flags stored in a file.
+fn emulate_property(path: &Path, buffer: &mut [u32]) -> io::Result<c_int> {
+    match buffer[2] {
+        RPI_FIRMWARE_GET_REBOOT_FLAGS => {
+            let flags = std::fs::read_to_string(path)?;
+            buffer[BUFFER_REBOOT_FLAGS_OFFSET] = match flags.trim() {
+                "" => 0,
+                flags => flags
+                    .parse()
+                    .map_err(|_| io::Error::other("invalid emulated reboot flags"))?,
+            };
+        }
+        RPI_FIRMWARE_SET_REBOOT_FLAGS => {
+            std::fs::write(path, buffer[BUFFER_REBOOT_FLAGS_OFFSET].to_string())?;
+        }
+        tag => {
+            return Err(io::Error::other(format!(
+                "unsupported emulated firmware request (0x{tag:08X})"
+            )))
+        }
+    }
+    buffer[BUFFER_STATUS_OFFSET] = RPI_FIRMWARE_STATUS_SUCCESS;
+    Ok(0)
+}
@@ -118,7 +118,7 @@ pub fn repart(
                 start,
                 size,
                 ty,
-                name: None,
+                name: partition.name.clone(),
                 gpt_id: None,
             })
         }
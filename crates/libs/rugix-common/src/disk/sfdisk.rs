@@ -3,7 +3,7 @@ use std::os::unix::fs::{FileTypeExt, MetadataExt};
 use std::path::Path;
 use std::str::FromStr;
 
-use reportify::{whatever, Report, ResultExt};
+use reportify::{bail, whatever, Report, ResultExt};
 use serde::Deserialize;
 use xscript::{read_str, run, Run};
 
@@ -95,7 +95,7 @@ pub(crate) fn sfdisk_read(dev: &Path) -> Result<PartitionTable, Report<DiskError
                 start: NumBlocks::from_raw(partition.start),
                 size: NumBlocks::from_raw(partition.size),
                 ty,
-                name: Some(partition.node),
+                name: partition.name,
                 gpt_id,
             })
         })
@@ -129,6 +129,12 @@ pub(crate) fn sfdisk_write(table: &PartitionTable, dev: &Path) -> Result<(), Rep
         if let Some(gpt_id) = partition.gpt_id {
             write!(&mut script, ",uuid={}", gpt_id).unwrap();
         }
+        if let (DiskId::Gpt(_), Some(name)) = (&table.disk_id, &partition.name) {
+            if name.contains('"') {
+                bail!("invalid partition name {name:?}");
+            }
+            write!(&mut script, ",name=\"{name}\"").unwrap();
+        }
         script.push('\n');
     }
 
@@ -177,4 +183,6 @@ pub struct SfdiskJsonPartition {
     #[serde(rename = "type")]
     ty: String,
     uuid: Option<String>,
+    /// Name of the partition (GPT only).
+    name: Option<String>,
 }
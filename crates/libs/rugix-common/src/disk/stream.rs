@@ -7,7 +7,8 @@ use std::io::{self, Read};
 use thiserror::Error;
 
 use super::gpt::Guid;
-use super::PartitionType;
+use super::{PartitionTableType, PartitionType};
+use crate::sparse::MappedRead;
 
 /// Standard sector size is 512 bytes.
 const SECTOR_SIZE: usize = 512;
@@ -31,6 +32,10 @@ pub struct ImgStream<R> {
     pending: VecDeque<PartitionEntry>,
     /// The extended partition entry of the MBR.
     extended: Option<PartitionEntry>,
+    /// The number of the next logical partition.
+    next_logical: u32,
+    /// The type of the partition table.
+    table_type: PartitionTableType,
 }
 
 impl<R: Read> ImgStream<R> {
@@ -41,6 +46,9 @@ impl<R: Read> ImgStream<R> {
             buffer: vec![0; BUFFER_SIZE],
             pending: VecDeque::new(),
             extended: None,
+            // Logical partitions are numbered starting from 5.
+            next_logical: 5,
+            table_type: PartitionTableType::Mbr,
         };
         this.read_next_sector()?;
         if this.buffer[SECTOR_SIZE - 2..SECTOR_SIZE] != [0x55, 0xAA] {
@@ -60,6 +68,7 @@ impl<R: Read> ImgStream<R> {
         // If there is just one partition
         if this.pending.len() == 1 && this.pending[0].is_gpt_protective_mbr() {
             this.pending.clear();
+            this.table_type = PartitionTableType::Gpt;
             this.read_next_sector()?;
             if !this.buffer.starts_with(b"EFI PART") {
                 return Err(ImgStreamError::Invalid("invalid GPT signature"));
@@ -74,7 +83,7 @@ impl<R: Read> ImgStream<R> {
             }
             let num_sectors = num_partitions.div_ceil(4);
             let mut entries = Vec::new();
-            for _ in 0..num_sectors {
+            for sector_idx in 0..num_sectors {
                 this.read_next_sector()?;
                 for idx in 0..4 {
                     let entry = &this.buffer[idx * 128..(idx + 1) * 128];
@@ -83,21 +92,36 @@ impl<R: Read> ImgStream<R> {
                         continue;
                     }
                     let start = u64::from_le_bytes(entry[32..32 + 8].try_into().unwrap());
+                    // The last sector is inclusive.
                     let end = u64::from_le_bytes(entry[40..40 + 8].try_into().unwrap());
+                    if end < start {
+                        return Err(ImgStreamError::Invalid("invalid GPT partition entry"));
+                    }
+                    let name = (56..128)
+                        .step_by(2)
+                        .map(|offset| u16::from_le_bytes([entry[offset], entry[offset + 1]]))
+                        .take_while(|unit| *unit != 0)
+                        .collect::<Vec<_>>();
                     entries.push(PartitionEntry {
                         ty,
                         start,
-                        size: end - start,
+                        size: end - start + 1,
+                        number: sector_idx * 4 + idx as u32 + 1,
+                        name: Some(String::from_utf16_lossy(&name)),
                     });
                 }
             }
-            println!("{entries:#?}");
             entries.sort_by_key(|entry| entry.start);
             this.pending = entries.into();
         }
         Ok(this)
     }
 
+    /// The type of the partition table of the image.
+    pub fn table_type(&self) -> PartitionTableType {
+        self.table_type
+    }
+
     pub fn into_inner(self) -> R {
         self.reader
     }
@@ -152,6 +176,8 @@ impl<R: Read> ImgStream<R> {
                     }
                     // Address of partition is relative to this EBR.
                     first.start += entry.start;
+                    first.number = self.next_logical;
+                    self.next_logical += 1;
                     self.pending.push_back(first);
                 }
                 if let Some(mut second) = entries.next() {
@@ -212,6 +238,25 @@ impl<R: Read> Read for PartitionStream<'_, R> {
     }
 }
 
+impl<R: MappedRead> MappedRead for PartitionStream<'_, R> {
+    fn unmapped(&mut self) -> io::Result<u64> {
+        Ok(self.stream.reader.unmapped()?.min(self.remaining))
+    }
+
+    fn skip_unmapped(&mut self, size: u64) -> io::Result<()> {
+        if size > self.remaining {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "unable to skip beyond the end of the partition",
+            ));
+        }
+        self.stream.reader.skip_unmapped(size)?;
+        self.remaining -= size;
+        self.stream.position += size;
+        Ok(())
+    }
+}
+
 /// Error reading an image stream.
 #[derive(Debug, Error)]
 pub enum ImgStreamError {
@@ -244,7 +289,7 @@ fn parse_partition_table(record: &[u8]) -> impl '_ + Iterator<Item = PartitionEn
             let entry_start = PARTITION_ENTRIES_OFFSET + entry_idx * PARTITION_ENTRY_SIZE;
             let entry_end = entry_start + PARTITION_ENTRY_SIZE;
             let entry_bytes = &record[entry_start..entry_end];
-            PartitionEntry::from_bytes_mbr(entry_bytes)
+            PartitionEntry::from_bytes_mbr(entry_bytes, entry_idx as u32 + 1)
         })
         .filter(|entry| !entry.is_free())
 }
@@ -258,6 +303,10 @@ pub struct PartitionEntry {
     start: u64,
     /// The size of the partition in sectors.
     size: u64,
+    /// The number of the partition.
+    number: u32,
+    /// The name of the partition (GPT only).
+    name: Option<String>,
 }
 
 impl PartitionEntry {
@@ -266,7 +315,7 @@ impl PartitionEntry {
     /// # Panics
     ///
     /// Panics in case the given slice does not consist of exactly 16 bytes.
-    fn from_bytes_mbr(entry: &[u8]) -> Self {
+    fn from_bytes_mbr(entry: &[u8], number: u32) -> Self {
         assert_eq!(
             entry.len(),
             PARTITION_ENTRY_SIZE,
@@ -279,6 +328,8 @@ impl PartitionEntry {
             ty: PartitionType::Mbr(ty),
             start: start.into(),
             size: size.into(),
+            number,
+            name: None,
         }
     }
 
@@ -306,6 +357,16 @@ impl PartitionEntry {
         &self.ty
     }
 
+    /// The number of the partition, as used for the partition devices.
+    pub fn number(&self) -> u32 {
+        self.number
+    }
+
+    /// The name of the partition, if the partition table is a GPT.
+    pub fn name(&self) -> Option<&str> {
+        self.name.as_deref()
+    }
+
     /// The size of the partition in bytes.
     pub fn size_bytes(&self) -> u64 {
         self.size * SECTOR_SIZE_U64
@@ -320,8 +381,61 @@ impl PartitionEntry {
 impl Display for PartitionEntry {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         f.write_fmt(format_args!(
-            "type: {}, start: {}, size: {}",
-            self.ty, self.start, self.size
-        ))
+            "number: {}, type: {}, start: {}, size: {}",
+            self.number, self.ty, self.start, self.size
+        ))?;
+        if let Some(name) = &self.name {
+            f.write_fmt(format_args!(", name: {name:?}"))?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Read;
+
+    use super::*;
+
+    #[test]
+    fn test_gpt_partitions() {
+        let mut image = vec![0; 8 * SECTOR_SIZE];
+        // Protective MBR.
+        image[PARTITION_ENTRIES_OFFSET + 4] = 0xEE;
+        image[PARTITION_ENTRIES_OFFSET + 8] = 1;
+        image[PARTITION_ENTRIES_OFFSET + 12] = 7;
+        image[510..512].copy_from_slice(&[0x55, 0xAA]);
+        // GPT header with four partition entries.
+        let header = &mut image[SECTOR_SIZE..2 * SECTOR_SIZE];
+        header[..8].copy_from_slice(b"EFI PART");
+        header[80..84].copy_from_slice(&4u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        for (idx, start, end, name) in [(0, 4, 5, "boot"), (2, 6, 7, "system")] {
+            let entry = &mut image[2 * SECTOR_SIZE + idx * 128..2 * SECTOR_SIZE + (idx + 1) * 128];
+            entry[..16].fill(0xAB);
+            entry[32..40].copy_from_slice(&u64::to_le_bytes(start));
+            entry[40..48].copy_from_slice(&u64::to_le_bytes(end));
+            for (unit_idx, unit) in name.encode_utf16().enumerate() {
+                entry[56 + 2 * unit_idx..58 + 2 * unit_idx].copy_from_slice(&unit.to_le_bytes());
+            }
+        }
+        image[4 * SECTOR_SIZE..6 * SECTOR_SIZE].fill(1);
+        image[6 * SECTOR_SIZE..8 * SECTOR_SIZE].fill(2);
+        let mut stream = ImgStream::new(image.as_slice()).unwrap();
+        assert_eq!(stream.table_type(), PartitionTableType::Gpt);
+        let mut partitions = Vec::new();
+        while let Some(mut partition) = stream.next_partition().unwrap() {
+            let mut data = Vec::new();
+            partition.read_to_end(&mut data).unwrap();
+            let entry = partition.entry();
+            partitions.push((entry.number(), entry.name().unwrap().to_owned(), data));
+        }
+        assert_eq!(
+            partitions,
+            [
+                (1, "boot".to_owned(), vec![1; 2 * SECTOR_SIZE]),
+                (3, "system".to_owned(), vec![2; 2 * SECTOR_SIZE]),
+            ]
+        );
     }
 }
@@ -27,6 +27,23 @@ impl Mounted {
         Ok(Mounted { path: dst.into() })
     }
 
+    /// Mount the given device or image with the given mount options, e.g., `ro,loop`.
+    pub fn mount_with_options(
+        dev: impl AsRef<Path>,
+        dst: impl AsRef<Path>,
+        options: &str,
+    ) -> Result<Self, Report<MountError>> {
+        let dst = dst.as_ref();
+        let dev = dev.as_ref();
+        debug!("Mounting {dev:?} to {dst:?} with options {options:?}.");
+        run!(["/usr/bin/mount", "-o", options, dev, dst])
+            .whatever("unable to mount filesystem")
+            .with_info(|_| format!("dev: {dev:?}"))
+            .with_info(|_| format!("dst: {dst:?}"))
+            .with_info(|_| format!("options: {options}"))?;
+        Ok(Mounted { path: dst.into() })
+    }
+
     pub fn path(&self) -> &Path {
         &self.path
     }
@@ -0,0 +1,633 @@
+//! Images with unmapped ranges, i.e., Android sparse images and images with a bmap.
+//!
+//! Unmapped ranges of an image do not carry any meaningful data. When writing an image,
+//! they can be skipped instead of being written, which speeds up writing images with
+//! filesystems that are mostly empty.
+
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::maybe_compressed::PeekReader;
+
+/// Magic bytes of Android sparse images.
+pub const SPARSE_MAGIC: [u8; 4] = 0xED26FF3Au32.to_le_bytes();
+
+/// Size of the file header of Android sparse images.
+pub const SPARSE_HEADER_SIZE: usize = 28;
+
+/// Size of the chunk headers of Android sparse images.
+pub const CHUNK_HEADER_SIZE: usize = 12;
+
+/// Reader of an image which knows about the unmapped ranges of the image.
+pub trait MappedRead: Read {
+    /// Number of unmapped bytes at the current position.
+    ///
+    /// Reads never cross the boundary between mapped and unmapped ranges.
+    fn unmapped(&mut self) -> io::Result<u64>;
+
+    /// Skip the given number of unmapped bytes at the current position.
+    fn skip_unmapped(&mut self, size: u64) -> io::Result<()>;
+}
+
+/// Copy an image to the given writer, skipping unmapped ranges.
+///
+/// Returns the size of the image.
+pub fn copy_mapped<R: MappedRead, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+) -> io::Result<u64> {
+    let mut buffer = vec![0; 64 * 1024];
+    let mut copied = 0;
+    let mut trailing_unmapped = false;
+    loop {
+        let unmapped = reader.unmapped()?;
+        if unmapped > 0 {
+            reader.skip_unmapped(unmapped)?;
+            writer.seek(SeekFrom::Current(seek_offset(unmapped)?))?;
+            copied += unmapped;
+            trailing_unmapped = true;
+            continue;
+        }
+        let read = reader.read(&mut buffer)?;
+        if read == 0 {
+            break;
+        }
+        writer.write_all(&buffer[..read])?;
+        copied += read as u64;
+        trailing_unmapped = false;
+    }
+    if trailing_unmapped {
+        // Make sure that files have the size of the image.
+        writer.seek(SeekFrom::Current(-1))?;
+        writer.write_all(&[0])?;
+    }
+    Ok(copied)
+}
+
+fn seek_offset(size: u64) -> io::Result<i64> {
+    size.try_into()
+        .map_err(|_| invalid_data("unmapped range is too large"))
+}
+
+/// Header of an Android sparse image.
+#[derive(Debug, Clone)]
+pub struct SparseHeader {
+    /// Size of the file header.
+    pub header_size: u16,
+    /// Size of the chunk headers.
+    pub chunk_header_size: u16,
+    /// Size of the blocks.
+    pub block_size: u32,
+    /// Number of blocks of the image.
+    pub num_blocks: u32,
+    /// Number of chunks of the image.
+    pub num_chunks: u32,
+}
+
+impl SparseHeader {
+    /// Parse the header from the first [`SPARSE_HEADER_SIZE`] bytes of an image.
+    pub fn parse(bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < SPARSE_HEADER_SIZE || bytes[..4] != SPARSE_MAGIC {
+            return Err(invalid_data("invalid sparse image header"));
+        }
+        if u16_at(bytes, 4) != 1 {
+            return Err(invalid_data("unsupported sparse image version"));
+        }
+        let header = Self {
+            header_size: u16_at(bytes, 8),
+            chunk_header_size: u16_at(bytes, 10),
+            block_size: u32_at(bytes, 12),
+            num_blocks: u32_at(bytes, 16),
+            num_chunks: u32_at(bytes, 20),
+        };
+        if usize::from(header.header_size) < SPARSE_HEADER_SIZE
+            || usize::from(header.chunk_header_size) < CHUNK_HEADER_SIZE
+            || header.block_size == 0
+            || header.block_size % 4 != 0
+        {
+            return Err(invalid_data("invalid sparse image header"));
+        }
+        Ok(header)
+    }
+}
+
+/// Chunk of an Android sparse image.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ChunkKind {
+    /// Data stored in the image.
+    Raw,
+    /// Data filled with a 4 byte pattern stored in the image.
+    Fill,
+    /// Unmapped range.
+    DontCare,
+    /// Checksum of the data so far.
+    Crc32,
+}
+
+/// Header of a chunk of an Android sparse image.
+#[derive(Debug, Clone)]
+pub struct ChunkHeader {
+    /// Kind of the chunk.
+    pub kind: ChunkKind,
+    /// Number of blocks of the image covered by the chunk.
+    pub num_blocks: u32,
+    /// Size of the data of the chunk following the chunk header.
+    pub data_size: u64,
+}
+
+impl ChunkHeader {
+    /// Parse a chunk header of an image with the given header.
+    pub fn parse(header: &SparseHeader, bytes: &[u8]) -> io::Result<Self> {
+        if bytes.len() < CHUNK_HEADER_SIZE {
+            return Err(invalid_data("invalid sparse chunk header"));
+        }
+        let kind = match u16_at(bytes, 0) {
+            0xCAC1 => ChunkKind::Raw,
+            0xCAC2 => ChunkKind::Fill,
+            0xCAC3 => ChunkKind::DontCare,
+            0xCAC4 => ChunkKind::Crc32,
+            _ => return Err(invalid_data("invalid sparse chunk type")),
+        };
+        let num_blocks = u32_at(bytes, 4);
+        let total_size = u64::from(u32_at(bytes, 8));
+        let data_size = total_size
+            .checked_sub(u64::from(header.chunk_header_size))
+            .ok_or_else(|| invalid_data("invalid sparse chunk size"))?;
+        let expected_data_size = match kind {
+            ChunkKind::Raw => u64::from(num_blocks) * u64::from(header.block_size),
+            ChunkKind::Fill | ChunkKind::Crc32 => 4,
+            ChunkKind::DontCare => 0,
+        };
+        if data_size != expected_data_size {
+            return Err(invalid_data("invalid sparse chunk size"));
+        }
+        Ok(Self {
+            kind,
+            num_blocks,
+            data_size,
+        })
+    }
+
+    /// Size of the range of the image covered by the chunk.
+    pub fn size(&self, header: &SparseHeader) -> u64 {
+        u64::from(self.num_blocks) * u64::from(header.block_size)
+    }
+}
+
+/// Reader expanding an Android sparse image.
+///
+/// Unmapped ranges read as zeros.
+pub struct SparseReader<R> {
+    reader: R,
+    header: SparseHeader,
+    /// Number of chunks which have not been started yet.
+    remaining_chunks: u32,
+    /// Number of blocks of the image which have not been started yet.
+    remaining_blocks: u32,
+    /// Kind of the current chunk.
+    chunk: ChunkKind,
+    /// Fill pattern of the current chunk.
+    pattern: [u8; 4],
+    /// Number of remaining bytes of the current chunk.
+    remaining: u64,
+    /// Current position in the image.
+    position: u64,
+}
+
+impl<R: Read> SparseReader<R> {
+    /// Start reading a sparse image from the given reader.
+    pub fn new(mut reader: R) -> io::Result<Self> {
+        let mut bytes = [0; SPARSE_HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let header = SparseHeader::parse(&bytes)?;
+        skip_bytes(
+            &mut reader,
+            u64::from(header.header_size) - SPARSE_HEADER_SIZE as u64,
+        )?;
+        Ok(Self {
+            reader,
+            remaining_chunks: header.num_chunks,
+            remaining_blocks: header.num_blocks,
+            header,
+            chunk: ChunkKind::DontCare,
+            pattern: [0; 4],
+            remaining: 0,
+            position: 0,
+        })
+    }
+
+    /// Header of the image.
+    pub fn header(&self) -> &SparseHeader {
+        &self.header
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Advance to the next chunk with data, if the current chunk has been read.
+    ///
+    /// Returns `false` at the end of the image.
+    fn advance(&mut self) -> io::Result<bool> {
+        while self.remaining == 0 {
+            if self.remaining_chunks == 0 {
+                if self.remaining_blocks != 0 {
+                    return Err(invalid_data("sparse image is truncated"));
+                }
+                return Ok(false);
+            }
+            self.remaining_chunks -= 1;
+            let mut bytes = vec![0; usize::from(self.header.chunk_header_size)];
+            self.reader.read_exact(&mut bytes)?;
+            let chunk = ChunkHeader::parse(&self.header, &bytes)?;
+            self.remaining_blocks = self
+                .remaining_blocks
+                .checked_sub(chunk.num_blocks)
+                .ok_or_else(|| invalid_data("sparse image has too many blocks"))?;
+            match chunk.kind {
+                ChunkKind::Fill => self.reader.read_exact(&mut self.pattern)?,
+                ChunkKind::Crc32 => skip_bytes(&mut self.reader, chunk.data_size)?,
+                ChunkKind::Raw | ChunkKind::DontCare => { /* nothing to do */ }
+            }
+            self.chunk = chunk.kind;
+            self.remaining = chunk.size(&self.header);
+        }
+        Ok(true)
+    }
+}
+
+impl<R: Read> Read for SparseReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.advance()? || buf.is_empty() {
+            return Ok(0);
+        }
+        let size = buf
+            .len()
+            .min(usize::try_from(self.remaining).unwrap_or(usize::MAX));
+        let read = match self.chunk {
+            ChunkKind::Raw => self.reader.read(&mut buf[..size])?,
+            ChunkKind::Fill => {
+                for (idx, byte) in buf[..size].iter_mut().enumerate() {
+                    // Chunks are aligned to blocks, which are multiples of four bytes.
+                    *byte = self.pattern[(self.position as usize + idx) % 4];
+                }
+                size
+            }
+            ChunkKind::DontCare | ChunkKind::Crc32 => {
+                buf[..size].fill(0);
+                size
+            }
+        };
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        self.remaining -= read as u64;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> MappedRead for SparseReader<R> {
+    fn unmapped(&mut self) -> io::Result<u64> {
+        if self.advance()? && self.chunk == ChunkKind::DontCare {
+            Ok(self.remaining)
+        } else {
+            Ok(0)
+        }
+    }
+
+    fn skip_unmapped(&mut self, size: u64) -> io::Result<()> {
+        if size > self.unmapped()? {
+            return Err(invalid_data("unable to skip mapped range"));
+        }
+        self.remaining -= size;
+        self.position += size;
+        Ok(())
+    }
+}
+
+/// Block map of an image as created by `bmaptool`.
+#[derive(Debug, Clone)]
+pub struct Bmap {
+    /// Size of the image.
+    pub image_size: u64,
+    /// Size of the blocks.
+    pub block_size: u64,
+    /// Mapped ranges of blocks, sorted and inclusive.
+    pub ranges: Vec<(u64, u64)>,
+}
+
+impl Bmap {
+    /// Load a block map from the given file.
+    pub fn load(path: &Path) -> io::Result<Self> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Parse a block map in the XML format of `bmaptool`.
+    pub fn parse(bmap: &str) -> io::Result<Self> {
+        let element = |name: &str| -> io::Result<u64> {
+            xml_elements(bmap, name)
+                .next()
+                .and_then(|value| value.trim().parse().ok())
+                .ok_or_else(|| invalid_data(format!("invalid or missing `{name}` in bmap")))
+        };
+        let image_size = element("ImageSize")?;
+        let block_size = element("BlockSize")?;
+        if block_size == 0 {
+            return Err(invalid_data("invalid block size in bmap"));
+        }
+        let mut ranges = Vec::new();
+        for range in xml_elements(bmap, "Range") {
+            let range = range.trim();
+            let (first, last) = range.split_once('-').unwrap_or((range, range));
+            let (Ok(first), Ok(last)) = (first.trim().parse(), last.trim().parse()) else {
+                return Err(invalid_data(format!("invalid range {range:?} in bmap")));
+            };
+            if first > last || ranges.last().is_some_and(|(_, prev)| *prev >= first) {
+                return Err(invalid_data("ranges in bmap must be sorted"));
+            }
+            ranges.push((first, last));
+        }
+        Ok(Self {
+            image_size,
+            block_size,
+            ranges,
+        })
+    }
+
+    /// Mapped ranges of the image in bytes, as half-open intervals.
+    pub fn byte_ranges(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.ranges.iter().map(|(first, last)| {
+            let start = (first * self.block_size).min(self.image_size);
+            let end = ((last + 1) * self.block_size).min(self.image_size);
+            (start, end)
+        })
+    }
+}
+
+/// Contents of the XML elements with the given name.
+///
+/// Block maps are simple enough that we do not need a full XML parser.
+fn xml_elements<'xml>(xml: &'xml str, name: &str) -> impl Iterator<Item = &'xml str> {
+    let open = format!("<{name}");
+    let close = format!("</{name}>");
+    let mut rest = xml;
+    std::iter::from_fn(move || loop {
+        let start = rest.find(&open)?;
+        rest = &rest[start + open.len()..];
+        // Skip elements whose name merely starts with the given name.
+        if !rest.starts_with(['>', ' ', '\t', '\r', '\n']) {
+            continue;
+        }
+        let content_start = rest.find('>')? + 1;
+        let content_end = rest.find(&close)?;
+        let content = rest.get(content_start..content_end)?;
+        rest = &rest[content_end + close.len()..];
+        return Some(content);
+    })
+}
+
+/// Reader of a raw image applying the mapped ranges of a block map.
+///
+/// Unmapped ranges read as the contents of the image.
+pub struct BmapReader<R> {
+    reader: R,
+    /// Mapped ranges in bytes.
+    ranges: Vec<(u64, u64)>,
+    /// Index of the next mapped range which has not been read entirely.
+    next_range: usize,
+    /// Size of the image.
+    image_size: u64,
+    /// Current position in the image.
+    position: u64,
+}
+
+impl<R: Read> BmapReader<R> {
+    /// Read the image from the given reader with the given block map.
+    pub fn new(reader: R, bmap: &Bmap) -> Self {
+        Self {
+            reader,
+            ranges: bmap.byte_ranges().collect(),
+            next_range: 0,
+            image_size: bmap.image_size,
+            position: 0,
+        }
+    }
+
+    pub fn into_inner(self) -> R {
+        self.reader
+    }
+
+    /// Number of bytes until the next boundary between mapped and unmapped ranges.
+    fn until_boundary(&mut self) -> (bool, u64) {
+        while self
+            .ranges
+            .get(self.next_range)
+            .is_some_and(|(_, end)| *end <= self.position)
+        {
+            self.next_range += 1;
+        }
+        match self.ranges.get(self.next_range) {
+            Some((start, end)) if *start <= self.position => (true, end - self.position),
+            Some((start, _)) => (false, start - self.position),
+            None if self.position < self.image_size => (false, self.image_size - self.position),
+            // Data beyond the image size is passed through.
+            None => (true, u64::MAX),
+        }
+    }
+}
+
+impl<R: Read> Read for BmapReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let (_, size) = self.until_boundary();
+        let size = buf.len().min(usize::try_from(size).unwrap_or(usize::MAX));
+        let read = self.reader.read(&mut buf[..size])?;
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl<R: Read> MappedRead for BmapReader<R> {
+    fn unmapped(&mut self) -> io::Result<u64> {
+        match self.until_boundary() {
+            (false, size) => Ok(size),
+            (true, _) => Ok(0),
+        }
+    }
+
+    fn skip_unmapped(&mut self, size: u64) -> io::Result<()> {
+        if size > self.unmapped()? {
+            return Err(invalid_data("unable to skip mapped range"));
+        }
+        skip_bytes(&mut self.reader, size)?;
+        self.position += size;
+        Ok(())
+    }
+}
+
+/// Image which may be an Android sparse image or have a block map.
+pub struct MaybeSparse<R: Read> {
+    inner: MaybeSparseInner<R>,
+}
+
+enum MaybeSparseInner<R: Read> {
+    Raw(PeekReader<R>),
+    Sparse(SparseReader<PeekReader<R>>),
+    Bmap(BmapReader<PeekReader<R>>),
+}
+
+impl<R: Read> MaybeSparse<R> {
+    /// Detect Android sparse images and apply the block map, if any, to raw images.
+    pub fn new(reader: R, bmap: Option<&Bmap>) -> io::Result<Self> {
+        let mut reader = PeekReader::new(reader);
+        let inner = if reader.peek(SPARSE_MAGIC.len())? == SPARSE_MAGIC {
+            if bmap.is_some() {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    "block maps cannot be used with sparse images",
+                ));
+            }
+            MaybeSparseInner::Sparse(SparseReader::new(reader)?)
+        } else if let Some(bmap) = bmap {
+            MaybeSparseInner::Bmap(BmapReader::new(reader, bmap))
+        } else {
+            MaybeSparseInner::Raw(reader)
+        };
+        Ok(Self { inner })
+    }
+
+    pub fn into_inner(self) -> R {
+        match self.inner {
+            MaybeSparseInner::Raw(reader) => reader.into_inner(),
+            MaybeSparseInner::Sparse(reader) => reader.into_inner().into_inner(),
+            MaybeSparseInner::Bmap(reader) => reader.into_inner().into_inner(),
+        }
+    }
+}
+
+impl<R: Read> Read for MaybeSparse<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match &mut self.inner {
+            MaybeSparseInner::Raw(reader) => reader.read(buf),
+            MaybeSparseInner::Sparse(reader) => reader.read(buf),
+            MaybeSparseInner::Bmap(reader) => reader.read(buf),
+        }
+    }
+}
+
+impl<R: Read> MappedRead for MaybeSparse<R> {
+    fn unmapped(&mut self) -> io::Result<u64> {
+        match &mut self.inner {
+            MaybeSparseInner::Raw(_) => Ok(0),
+            MaybeSparseInner::Sparse(reader) => reader.unmapped(),
+            MaybeSparseInner::Bmap(reader) => reader.unmapped(),
+        }
+    }
+
+    fn skip_unmapped(&mut self, size: u64) -> io::Result<()> {
+        match &mut self.inner {
+            MaybeSparseInner::Raw(_) if size == 0 => Ok(()),
+            MaybeSparseInner::Raw(_) => Err(invalid_data("unable to skip mapped range")),
+            MaybeSparseInner::Sparse(reader) => reader.skip_unmapped(size),
+            MaybeSparseInner::Bmap(reader) => reader.skip_unmapped(size),
+        }
+    }
+}
+
+fn skip_bytes(reader: &mut impl Read, size: u64) -> io::Result<()> {
+    let skipped = io::copy(&mut reader.take(size), &mut io::sink())?;
+    if skipped != size {
+        return Err(io::ErrorKind::UnexpectedEof.into());
+    }
+    Ok(())
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes(bytes[offset..offset + 2].try_into().unwrap())
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap())
+}
+
+fn invalid_data(message: impl Into<String>) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.into())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{Cursor, Read};
+
+    use super::{copy_mapped, Bmap, BmapReader, MappedRead, SparseReader};
+
+    fn chunk(kind: u16, num_blocks: u32, data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&kind.to_le_bytes());
+        chunk.extend_from_slice(&[0; 2]);
+        chunk.extend_from_slice(&num_blocks.to_le_bytes());
+        chunk.extend_from_slice(&(12 + data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_sparse_reader() {
+        let mut image = Vec::new();
+        image.extend_from_slice(&0xED26FF3Au32.to_le_bytes());
+        for value in [1u16, 0, 28, 12] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [8u32, 4, 4, 0] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        image.extend(chunk(0xCAC1, 1, b"abcdefgh"));
+        image.extend(chunk(0xCAC3, 2, &[]));
+        image.extend(chunk(0xCAC4, 0, &[0; 4]));
+        image.extend(chunk(0xCAC2, 1, b"xyzw"));
+        let mut expanded = Vec::new();
+        SparseReader::new(Cursor::new(&image))
+            .unwrap()
+            .read_to_end(&mut expanded)
+            .unwrap();
+        assert_eq!(
+            expanded,
+            b"abcdefgh\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0\0xyzwxyzw"
+        );
+        // Unmapped ranges are skipped when copying.
+        let mut target = Cursor::new(vec![1; 32]);
+        let mut reader = SparseReader::new(Cursor::new(&image)).unwrap();
+        assert_eq!(copy_mapped(&mut reader, &mut target).unwrap(), 32);
+        assert_eq!(
+            target.into_inner(),
+            b"abcdefgh\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01xyzwxyzw"
+        );
+    }
+
+    #[test]
+    fn test_bmap_reader() {
+        let bmap = Bmap::parse(
+            r#"<?xml version="1.0" ?>
+            <bmap version="2.0">
+                <ImageSize> 40 </ImageSize>
+                <BlockSize> 8 </BlockSize>
+                <BlocksCount> 5 </BlocksCount>
+                <MappedBlocksCount> 3 </MappedBlocksCount>
+                <ChecksumType> sha256 </ChecksumType>
+                <BlockMap>
+                    <Range chksum="00"> 0-1 </Range>
+                    <Range chksum="00"> 4 </Range>
+                </BlockMap>
+            </bmap>"#,
+        )
+        .unwrap();
+        assert_eq!(bmap.ranges, [(0, 1), (4, 4)]);
+        let image = (0..40u8).collect::<Vec<_>>();
+        let mut reader = BmapReader::new(Cursor::new(&image), &bmap);
+        assert_eq!(reader.unmapped().unwrap(), 0);
+        let mut target = Cursor::new(vec![0xff; 40]);
+        assert_eq!(copy_mapped(&mut reader, &mut target).unwrap(), 40);
+        let mut expected = image.clone();
+        expected[16..32].fill(0xff);
+        assert_eq!(target.into_inner(), expected);
+    }
+}
@@ -210,7 +210,7 @@ pub fn bake_bundle(
     if let Some(parent) = output.parent() {
         std::fs::create_dir_all(parent).ok();
     }
-    rugix_bundle::builder::pack(bundle_dir, output).whatever("unable to create bundle")?;
+    rugix_bundle::builder::pack(bundle_dir, output, &[]).whatever("unable to create bundle")?;
     Ok(())
 }
 
@@ -0,0 +1,218 @@
+//! Creation of bundles from system images.
+
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use clap::Parser;
+use reportify::{bail, ResultExt};
+
+use rugix_bundle::builder::{write_bundle_file, PayloadFile};
+use rugix_bundle::manifest::{self, BundleManifest, ChunkerAlgorithm};
+use rugix_bundle::BundleResult;
+use rugix_common::disk::PartitionTable;
+use rugix_hashes::HashAlgorithm;
+
+#[derive(Debug, Parser)]
+pub struct FromImageCmd {
+    /// System image to create the bundle from.
+    image: PathBuf,
+    /// Install a partition of the image to a slot, e.g., `2=boot`.
+    #[clap(long = "map", required = true)]
+    mappings: Vec<PartitionMapping>,
+    /// Output bundle file.
+    #[clap(short, long)]
+    output: PathBuf,
+    #[clap(flatten)]
+    encoding: EncodingArgs,
+}
+
+/// Options for the block encoding of payloads created from other formats.
+#[derive(Debug, Parser)]
+pub struct EncodingArgs {
+    /// Chunker to split the payloads into blocks with.
+    #[clap(long, default_value = "casync-64")]
+    chunker: ChunkerAlgorithm,
+    /// Hash algorithm to use for the blocks.
+    #[clap(long)]
+    pub hash_algorithm: Option<HashAlgorithm>,
+    /// Disable compression of the blocks.
+    #[clap(long)]
+    without_compression: bool,
+}
+
+impl EncodingArgs {
+    /// Deduplicated block encoding of payloads.
+    pub fn block_encoding(&self) -> manifest::BlockEncoding {
+        let compression = if self.without_compression {
+            None
+        } else {
+            Some(manifest::Compression::Xz(manifest::XzCompression::new()))
+        };
+        manifest::BlockEncoding::new(self.chunker.clone())
+            .with_hash_algorithm(self.hash_algorithm)
+            .with_deduplicate(Some(true))
+            .with_compression(compression)
+    }
+}
+
+/// Mapping of a partition of an image to a slot.
+#[derive(Debug, Clone)]
+pub struct PartitionMapping {
+    /// Number of the partition.
+    partition: u8,
+    /// Slot to install the partition to.
+    slot: String,
+}
+
+impl FromStr for PartitionMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((partition, slot)) = s.split_once('=') else {
+            return Err(format!("expected `<partition>=<slot>`, found {s:?}"));
+        };
+        let partition = partition
+            .parse()
+            .map_err(|_| format!("invalid partition number {partition:?}"))?;
+        if slot.is_empty() {
+            return Err("slot must not be empty".to_owned());
+        }
+        Ok(Self {
+            partition,
+            slot: slot.to_owned(),
+        })
+    }
+}
+
+/// Create a bundle from the partitions of a system image.
+///
+/// The partitions are read directly from the image without extracting them first.
+pub fn from_image(cmd: &FromImageCmd) -> BundleResult<()> {
+    let table = PartitionTable::read(&cmd.image).whatever("unable to read partition table")?;
+    write_image_bundle(cmd, &table)
+}
+
+/// Create a bundle from the partitions of an image with the given partition table.
+fn write_image_bundle(cmd: &FromImageCmd, table: &PartitionTable) -> BundleResult<()> {
+    let mut payloads = Vec::new();
+    let mut payload_files = Vec::new();
+    for mapping in &cmd.mappings {
+        let Some(partition) = table
+            .partitions
+            .iter()
+            .find(|partition| partition.number == mapping.partition)
+        else {
+            bail!("image has no partition {}", mapping.partition);
+        };
+        payload_files.push(PayloadFile::region(
+            &cmd.image,
+            table.blocks_to_bytes(partition.start).into_raw(),
+            table.blocks_to_bytes(partition.size).into_raw(),
+        ));
+        payloads.push(
+            manifest::Payload::new(
+                manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
+                    slot: mapping.slot.clone(),
+                }),
+                format!("partition-{}.img", partition.number),
+            )
+            .with_block_encoding(Some(cmd.encoding.block_encoding())),
+        );
+    }
+    let manifest = BundleManifest::new(manifest::UpdateType::Full, payloads)
+        .with_hash_algorithm(cmd.encoding.hash_algorithm);
+    write_bundle_file(&manifest, &payload_files, &[], &cmd.output)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs::File;
+
+    use rugix_bundle::reader::BundleReader;
+    use rugix_bundle::source::FileSource;
+    use rugix_common::disk::{DiskId, NumBlocks, Partition, PartitionTable, PartitionType};
+
+    use super::{write_image_bundle, EncodingArgs, FromImageCmd};
+
+    #[test]
+    fn test_payload_layout() {
+        let dir = std::env::temp_dir().join(format!("rugix-from-image-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let image = dir.join("system.img");
+        let output = dir.join("system.rugixb");
+        // Two partitions of 64 blocks each with distinct contents.
+        let mut table = PartitionTable::new(DiskId::random_mbr(), NumBlocks::from_raw(256));
+        let mut contents = vec![0; table.size().into_raw() as usize];
+        for (number, start) in [(1, 8), (2, 72)] {
+            let partition = Partition {
+                number,
+                start: NumBlocks::from_raw(start),
+                size: NumBlocks::from_raw(64),
+                ty: PartitionType::Mbr(0x83),
+                name: None,
+                gpt_id: None,
+            };
+            let start = table.blocks_to_bytes(partition.start).into_raw() as usize;
+            let end = start + table.blocks_to_bytes(partition.size).into_raw() as usize;
+            for (idx, byte) in contents[start..end].iter_mut().enumerate() {
+                *byte = (idx * 7 + usize::from(number) * 13) as u8;
+            }
+            table.partitions.push(partition);
+        }
+        std::fs::write(&image, &contents).unwrap();
+
+        let cmd = FromImageCmd {
+            image: image.clone(),
+            mappings: vec!["2=system".parse().unwrap(), "1=boot".parse().unwrap()],
+            output: output.clone(),
+            encoding: EncodingArgs {
+                chunker: "fixed-4".parse().unwrap(),
+                hash_algorithm: None,
+                without_compression: false,
+            },
+        };
+        write_image_bundle(&cmd, &table).unwrap();
+
+        let file = File::open(&output).unwrap();
+        let mut reader = BundleReader::start(FileSource::from_unbuffered(file), None).unwrap();
+        assert!(!reader.header().is_incremental);
+        let slots = reader
+            .header()
+            .payload_index
+            .iter()
+            .map(|entry| entry.type_slot.as_ref().unwrap().slot.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(slots, ["system", "boot"]);
+        let mut decoded = Vec::new();
+        while let Some(payload) = reader.next_payload().unwrap() {
+            let block_encoding = payload.header().block_encoding.as_ref().unwrap();
+            assert!(block_encoding.deduplicated);
+            assert!(block_encoding.compression.is_some());
+            // Deduplicated blocks are read back from the target, hence, it must be readable.
+            let target = dir.join(format!("payload-{}.img", payload.idx()));
+            let file = File::options()
+                .read(true)
+                .write(true)
+                .create(true)
+                .truncate(true)
+                .open(&target)
+                .unwrap();
+            payload.decode_into(file, None).unwrap();
+            decoded.push(target);
+        }
+        let partition = |number: u8| {
+            let partition = table
+                .partitions
+                .iter()
+                .find(|p| p.number == number)
+                .unwrap();
+            let start = table.blocks_to_bytes(partition.start).into_raw() as usize;
+            let size = table.blocks_to_bytes(partition.size).into_raw() as usize;
+            &contents[start..start + size]
+        };
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(std::fs::read(&decoded[0]).unwrap(), partition(2));
+        assert_eq!(std::fs::read(&decoded[1]).unwrap(), partition(1));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
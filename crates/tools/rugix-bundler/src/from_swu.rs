@@ -0,0 +1,630 @@
+//! Creation of bundles from SWUpdate archives.
+//!
+//! SWUpdate archives (`.swu`) are CPIO archives in the `newc` format whose first file is
+//! the `sw-description`, which describes the update in the libconfig or JSON format. The
+//! images of the update are converted into slot payloads. As the files of the archive are
+//! stored contiguously, the images are read directly from the archive.
+
+use std::fs::File;
+use std::io::{BufReader, Read};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use clap::Parser;
+use reportify::{bail, ResultExt};
+
+use rugix_bundle::builder::{write_bundle_file, PayloadFile};
+use rugix_bundle::manifest::{self, BundleManifest};
+use rugix_bundle::BundleResult;
+use rugix_hashes::HashAlgorithm;
+
+use crate::from_image::EncodingArgs;
+
+#[derive(Debug, Parser)]
+pub struct FromSwuCmd {
+    /// SWUpdate archive to create the bundle from.
+    archive: PathBuf,
+    /// Install an image to a slot by device or filename, e.g., `/dev/mmcblk0p2=system`.
+    #[clap(long = "map", required = true)]
+    mappings: Vec<ImageMapping>,
+    /// Selection of the images in the `sw-description`, e.g., `stable,copy1`.
+    #[clap(long)]
+    selection: Option<String>,
+    /// Output bundle file.
+    #[clap(short, long)]
+    output: PathBuf,
+    #[clap(flatten)]
+    encoding: EncodingArgs,
+}
+
+/// Mapping of an image of an SWUpdate archive to a slot.
+#[derive(Debug, Clone)]
+pub struct ImageMapping {
+    /// Device or filename of the image.
+    image: String,
+    /// Slot to install the image to.
+    slot: String,
+}
+
+impl FromStr for ImageMapping {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((image, slot)) = s.rsplit_once('=') else {
+            return Err(format!(
+                "expected `<device or filename>=<slot>`, found {s:?}"
+            ));
+        };
+        if image.is_empty() || slot.is_empty() {
+            return Err("device or filename and slot must not be empty".to_owned());
+        }
+        Ok(Self {
+            image: image.to_owned(),
+            slot: slot.to_owned(),
+        })
+    }
+}
+
+/// Create a bundle from the images of an SWUpdate archive.
+pub fn from_swu(cmd: &FromSwuCmd) -> BundleResult<()> {
+    let files = read_archive_index(&cmd.archive)?;
+    let Some(description_file) = files.first().filter(|file| file.name == "sw-description") else {
+        bail!("archive does not start with a `sw-description`");
+    };
+    let mut description = String::new();
+    description_file
+        .payload_file(&cmd.archive)
+        .reader()?
+        .read_to_string(&mut description)
+        .whatever("unable to read `sw-description`")?;
+    let description = parse_description(&description)?;
+    let Some(mut node) = description.get("software") else {
+        bail!("`sw-description` has no `software` section");
+    };
+    if let Some(selection) = &cmd.selection {
+        for name in selection.split(',') {
+            let Some(child) = node.get(name.trim()) else {
+                bail!("selection {selection:?} not found in `sw-description`");
+            };
+            node = child;
+        }
+    }
+    if node.get("ref").is_some() {
+        bail!("links in `sw-description` are not supported");
+    }
+    let Some(Value::List(images)) = node.get("images") else {
+        bail!("no images found in `sw-description`, use `--selection` to select them");
+    };
+    for ignored in ["files", "scripts", "partitions", "bootenv", "uboot"] {
+        if node.get(ignored).is_some() {
+            eprintln!("Warning: ignoring `{ignored}` of `sw-description`");
+        }
+    }
+    let mut payloads = Vec::new();
+    let mut payload_files = Vec::new();
+    for image in images {
+        let Some(filename) = image.get("filename").and_then(Value::as_str) else {
+            bail!("image without filename in `sw-description`");
+        };
+        let image_type = image.get("type").and_then(Value::as_str).unwrap_or("raw");
+        if !matches!(image_type, "raw" | "archive") {
+            bail!("unsupported type {image_type:?} of image {filename:?}");
+        }
+        if image
+            .get("compressed")
+            .is_some_and(|compressed| *compressed != Value::Bool(false))
+        {
+            bail!("compressed image {filename:?} is not supported");
+        }
+        if image.get("encrypted") == Some(&Value::Bool(true)) {
+            bail!("encrypted image {filename:?} is not supported");
+        }
+        if image.get("offset").is_some() {
+            bail!("offset of image {filename:?} is not supported");
+        }
+        let device = image.get("device").and_then(Value::as_str).map(device_path);
+        let Some(mapping) = cmd.mappings.iter().find(|mapping| {
+            mapping.image == filename || device.as_ref() == Some(&device_path(&mapping.image))
+        }) else {
+            bail!("no slot for image {filename:?}, use `--map` to specify one");
+        };
+        let Some(file) = files.iter().find(|file| file.name == filename) else {
+            bail!("image {filename:?} not found in archive");
+        };
+        let payload_file = file.payload_file(&cmd.archive);
+        check_image(
+            &payload_file,
+            filename,
+            image.get("sha256").and_then(Value::as_str),
+        )?;
+        payload_files.push(payload_file);
+        payloads.push(
+            manifest::Payload::new(
+                manifest::DeliveryConfig::Slot(manifest::SlotDeliveryConfig {
+                    slot: mapping.slot.clone(),
+                }),
+                filename.to_owned(),
+            )
+            .with_block_encoding(Some(cmd.encoding.block_encoding())),
+        );
+    }
+    let manifest = BundleManifest::new(manifest::UpdateType::Full, payloads)
+        .with_hash_algorithm(cmd.encoding.hash_algorithm);
+    write_bundle_file(&manifest, &payload_files, &[], &cmd.output)
+}
+
+/// Path of a device, SWUpdate prepends `/dev/` to devices without an absolute path.
+fn device_path(device: &str) -> String {
+    if device.starts_with('/') {
+        device.to_owned()
+    } else {
+        format!("/dev/{device}")
+    }
+}
+
+/// Check that the image is complete and, if given, has the expected SHA256 hash.
+fn check_image(
+    payload_file: &PayloadFile,
+    filename: &str,
+    sha256: Option<&str>,
+) -> BundleResult<()> {
+    let mut reader = payload_file.reader()?;
+    let mut hasher = HashAlgorithm::Sha256.hasher();
+    let mut buffer = vec![0; 64 * 1024];
+    let mut size = 0;
+    loop {
+        let read = reader.read(&mut buffer).whatever("unable to read image")?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buffer[..read]);
+        size += read as u64;
+    }
+    if size != payload_file.size {
+        bail!("image {filename:?} is truncated");
+    }
+    if let Some(expected) = sha256 {
+        if !hasher
+            .finalize()
+            .raw_hex_string()
+            .eq_ignore_ascii_case(expected)
+        {
+            bail!("hash mismatch of image {filename:?}");
+        }
+    }
+    Ok(())
+}
+
+/// File of a CPIO archive.
+#[derive(Debug)]
+struct ArchiveFile {
+    /// Name of the file.
+    name: String,
+    /// Offset of the file's data in the archive.
+    offset: u64,
+    /// Size of the file.
+    size: u64,
+}
+
+impl ArchiveFile {
+    fn payload_file(&self, archive: &Path) -> PayloadFile {
+        PayloadFile::region(archive, self.offset, self.size)
+    }
+}
+
+/// Size of the header of CPIO archives in the `newc` format.
+const CPIO_HEADER_SIZE: u64 = 110;
+
+/// Read the files of a CPIO archive in the `newc` format.
+fn read_archive_index(archive: &Path) -> BundleResult<Vec<ArchiveFile>> {
+    let mut reader = BufReader::new(File::open(archive).whatever("unable to open archive")?);
+    let mut files = Vec::new();
+    let mut offset = 0;
+    loop {
+        let mut header = [0; CPIO_HEADER_SIZE as usize];
+        reader
+            .read_exact(&mut header)
+            .whatever("unable to read CPIO header")?;
+        if &header[..6] != b"070701" && &header[..6] != b"070702" {
+            bail!("invalid CPIO header at offset {offset}, expected `newc` format");
+        }
+        let size = parse_hex_field(&header[54..62])?;
+        let name_size = parse_hex_field(&header[94..102])?;
+        let mut name = vec![0; name_size as usize];
+        reader
+            .read_exact(&mut name)
+            .whatever("unable to read CPIO file name")?;
+        let name = String::from_utf8_lossy(name.strip_suffix(&[0]).unwrap_or(&name)).into_owned();
+        offset += CPIO_HEADER_SIZE + name_size;
+        // The header and name are padded to a multiple of four bytes.
+        let name_padding = padding(offset);
+        reader
+            .seek_relative(name_padding as i64)
+            .whatever("unable to skip CPIO padding")?;
+        offset += name_padding;
+        if name == "TRAILER!!!" {
+            break;
+        }
+        files.push(ArchiveFile { name, offset, size });
+        // The data is padded to a multiple of four bytes.
+        let data_size = size + padding(offset + size);
+        reader
+            .seek_relative(data_size as i64)
+            .whatever("unable to skip CPIO file")?;
+        offset += data_size;
+    }
+    Ok(files)
+}
+
+fn parse_hex_field(field: &[u8]) -> BundleResult<u64> {
+    let Some(value) = std::str::from_utf8(field)
+        .ok()
+        .and_then(|field| u64::from_str_radix(field, 16).ok())
+    else {
+        bail!("invalid CPIO header field {field:?}");
+    };
+    Ok(value)
+}
+
+fn padding(offset: u64) -> u64 {
+    (4 - offset % 4) % 4
+}
+
+/// Value of a setting of a `sw-description`.
+#[derive(Debug, Clone, PartialEq)]
+enum Value {
+    Bool(bool),
+    Integer(i64),
+    Float(f64),
+    String(String),
+    Group(Vec<(String, Value)>),
+    List(Vec<Value>),
+}
+
+impl Value {
+    /// Setting with the given name, if the value is a group.
+    fn get(&self, name: &str) -> Option<&Value> {
+        match self {
+            Value::Group(settings) => settings
+                .iter()
+                .find(|(setting, _)| setting == name)
+                .map(|(_, value)| value),
+            _ => None,
+        }
+    }
+
+    fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::String(value) => Some(value),
+            _ => None,
+        }
+    }
+}
+
+/// Parse a `sw-description` in the libconfig or JSON format.
+fn parse_description(description: &str) -> BundleResult<Value> {
+    if description.trim_start().starts_with('{') {
+        let json = serde_json::from_str(description).whatever("invalid `sw-description`")?;
+        from_json(json)
+    } else {
+        let mut parser = LibconfigParser {
+            src: description.as_bytes(),
+            pos: 0,
+        };
+        Ok(Value::Group(parser.parse_settings(None)?))
+    }
+}
+
+fn from_json(value: serde_json::Value) -> BundleResult<Value> {
+    Ok(match value {
+        serde_json::Value::Null => bail!("null values in `sw-description` are not supported"),
+        serde_json::Value::Bool(value) => Value::Bool(value),
+        serde_json::Value::Number(value) => match value.as_i64() {
+            Some(value) => Value::Integer(value),
+            None => Value::Float(value.as_f64().unwrap_or(f64::NAN)),
+        },
+        serde_json::Value::String(value) => Value::String(value),
+        serde_json::Value::Array(values) => Value::List(
+            values
+                .into_iter()
+                .map(from_json)
+                .collect::<Result<_, _>>()?,
+        ),
+        serde_json::Value::Object(settings) => Value::Group(
+            settings
+                .into_iter()
+                .map(|(name, value)| Ok((name, from_json(value)?)))
+                .collect::<BundleResult<_>>()?,
+        ),
+    })
+}
+
+/// Parser for the subset of the libconfig format used by `sw-description` files.
+struct LibconfigParser<'src> {
+    src: &'src [u8],
+    pos: usize,
+}
+
+impl LibconfigParser<'_> {
+    /// Parse settings until the given end byte or the end of the input.
+    fn parse_settings(&mut self, end: Option<u8>) -> BundleResult<Vec<(String, Value)>> {
+        let mut settings = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                None if end.is_none() => break,
+                None => bail!("unexpected end of `sw-description`"),
+                Some(byte) if Some(byte) == end => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(_) => { /* setting */ }
+            }
+            let name = self.parse_name()?;
+            self.skip_whitespace();
+            if !matches!(self.next(), Some(b'=' | b':')) {
+                bail!(
+                    "expected `=` or `:` in line {} of `sw-description`",
+                    self.line()
+                );
+            }
+            let value = self.parse_value()?;
+            self.skip_whitespace();
+            if matches!(self.peek(), Some(b';' | b',')) {
+                self.pos += 1;
+            }
+            settings.push((name, value));
+        }
+        Ok(settings)
+    }
+
+    fn parse_name(&mut self) -> BundleResult<String> {
+        let start = self.pos;
+        while let Some(byte) = self.peek() {
+            let is_name_byte = match self.pos == start {
+                true => byte.is_ascii_alphabetic() || byte == b'*',
+                false => byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'*'),
+            };
+            if !is_name_byte {
+                break;
+            }
+            self.pos += 1;
+        }
+        if self.pos == start {
+            bail!("expected name in line {} of `sw-description`", self.line());
+        }
+        Ok(String::from_utf8_lossy(&self.src[start..self.pos]).into_owned())
+    }
+
+    fn parse_value(&mut self) -> BundleResult<Value> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'{') => {
+                self.pos += 1;
+                Ok(Value::Group(self.parse_settings(Some(b'}'))?))
+            }
+            Some(b'(') => {
+                self.pos += 1;
+                Ok(Value::List(self.parse_list(b')')?))
+            }
+            Some(b'[') => {
+                self.pos += 1;
+                Ok(Value::List(self.parse_list(b']')?))
+            }
+            Some(b'"') => {
+                // Adjacent strings are concatenated.
+                let mut value = self.parse_string()?;
+                self.skip_whitespace();
+                while self.peek() == Some(b'"') {
+                    value.push_str(&self.parse_string()?);
+                    self.skip_whitespace();
+                }
+                Ok(Value::String(value))
+            }
+            Some(_) => self.parse_scalar(),
+            None => bail!("unexpected end of `sw-description`"),
+        }
+    }
+
+    fn parse_list(&mut self, end: u8) -> BundleResult<Vec<Value>> {
+        let mut values = Vec::new();
+        loop {
+            self.skip_whitespace();
+            if self.peek() == Some(end) {
+                self.pos += 1;
+                return Ok(values);
+            }
+            values.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.next() {
+                Some(b',') => { /* next value */ }
+                Some(byte) if byte == end => return Ok(values),
+                _ => bail!(
+                    "expected `,` or `{}` in line {} of `sw-description`",
+                    end as char,
+                    self.line()
+                ),
+            }
+        }
+    }
+
+    fn parse_string(&mut self) -> BundleResult<String> {
+        let line = self.line();
+        // Skip the opening quote.
+        self.pos += 1;
+        let mut value = Vec::new();
+        loop {
+            match self.next() {
+                Some(b'"') => break,
+                Some(b'\\') => match self.next() {
+                    Some(b'n') => value.push(b'\n'),
+                    Some(b't') => value.push(b'\t'),
+                    Some(b'r') => value.push(b'\r'),
+                    Some(b'f') => value.push(b'\x0c'),
+                    Some(b'\\') => value.push(b'\\'),
+                    Some(b'"') => value.push(b'"'),
+                    Some(b'x') => {
+                        let digits = self.src.get(self.pos..self.pos + 2);
+                        let Some(byte) = digits
+                            .and_then(|digits| std::str::from_utf8(digits).ok())
+                            .and_then(|digits| u8::from_str_radix(digits, 16).ok())
+                        else {
+                            bail!("invalid escape in line {line} of `sw-description`");
+                        };
+                        self.pos += 2;
+                        value.push(byte);
+                    }
+                    _ => bail!("invalid escape in line {line} of `sw-description`"),
+                },
+                Some(byte) => value.push(byte),
+                None => bail!("unterminated string in line {line} of `sw-description`"),
+            }
+        }
+        String::from_utf8(value).whatever("invalid string in `sw-description`")
+    }
+
+    fn parse_scalar(&mut self) -> BundleResult<Value> {
+        let start = self.pos;
+        while self
+            .peek()
+            .is_some_and(|byte| byte.is_ascii_alphanumeric() || matches!(byte, b'+' | b'-' | b'.'))
+        {
+            self.pos += 1;
+        }
+        let token = std::str::from_utf8(&self.src[start..self.pos]).unwrap_or_default();
+        if token.eq_ignore_ascii_case("true") {
+            return Ok(Value::Bool(true));
+        }
+        if token.eq_ignore_ascii_case("false") {
+            return Ok(Value::Bool(false));
+        }
+        let integer = token.trim_end_matches('L');
+        let parsed = match integer
+            .strip_prefix("0x")
+            .or_else(|| integer.strip_prefix("0X"))
+        {
+            Some(hex) => i64::from_str_radix(hex, 16).ok(),
+            None => integer.parse().ok(),
+        };
+        if let Some(value) = parsed {
+            return Ok(Value::Integer(value));
+        }
+        match token.parse() {
+            Ok(value) if !token.is_empty() => Ok(Value::Float(value)),
+            _ => bail!("invalid value in line {} of `sw-description`", self.line()),
+        }
+    }
+
+    /// Skip whitespace and comments.
+    fn skip_whitespace(&mut self) {
+        loop {
+            let rest = &self.src[self.pos..];
+            if rest.first().is_some_and(|byte| byte.is_ascii_whitespace()) {
+                self.pos += 1;
+            } else if rest.starts_with(b"#") || rest.starts_with(b"//") {
+                while self.next().is_some_and(|byte| byte != b'\n') {}
+            } else if rest.starts_with(b"/*") {
+                self.pos = match rest.windows(2).position(|window| window == b"*/") {
+                    Some(end) => self.pos + end + 2,
+                    None => self.src.len(),
+                };
+            } else {
+                break;
+            }
+        }
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.src.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Option<u8> {
+        let byte = self.peek()?;
+        self.pos += 1;
+        Some(byte)
+    }
+
+    fn line(&self) -> usize {
+        self.src[..self.pos]
+            .iter()
+            .filter(|byte| **byte == b'\n')
+            .count()
+            + 1
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_description, Value};
+
+    #[test]
+    fn test_parse_description() {
+        let libconfig = parse_description(
+            r#"
+            # Comment
+            software = {
+                version = "1.0" ".1";
+                hardware-compatibility: [ "1.0", "1.2" ];
+                stable: {
+                    copy1: {
+                        images: (
+                            {
+                                filename = "rootfs.ext4";
+                                device = "/dev/mmcblk0p2";
+                                sha256 = "abcd"; /* Inline comment. */
+                                installed-directly = true;
+                                size = 0x10;
+                            },
+                        );
+                    };
+                };
+            };
+            "#,
+        )
+        .unwrap();
+        let json = parse_description(
+            r#"{
+                "software": {
+                    "version": "1.0.1",
+                    "hardware-compatibility": ["1.0", "1.2"],
+                    "stable": {
+                        "copy1": {
+                            "images": [{
+                                "filename": "rootfs.ext4",
+                                "device": "/dev/mmcblk0p2",
+                                "sha256": "abcd",
+                                "installed-directly": true,
+                                "size": 16
+                            }]
+                        }
+                    }
+                }
+            }"#,
+        )
+        .unwrap();
+        // JSON objects are not ordered, hence, we compare the sorted settings.
+        assert_eq!(sorted(libconfig.clone()), sorted(json));
+        let software = libconfig.get("software").unwrap();
+        assert_eq!(
+            software.get("version"),
+            Some(&Value::String("1.0.1".to_owned()))
+        );
+        assert!(parse_description("software = { version = \"1.0; };").is_err());
+        assert!(parse_description("software = ( 1 2 );").is_err());
+    }
+
+    fn sorted(value: Value) -> Value {
+        match value {
+            Value::Group(settings) => {
+                let mut settings = settings
+                    .into_iter()
+                    .map(|(name, value)| (name, sorted(value)))
+                    .collect::<Vec<_>>();
+                settings.sort_by(|a, b| a.0.cmp(&b.0));
+                Value::Group(settings)
+            }
+            Value::List(values) => Value::List(values.into_iter().map(sorted).collect()),
+            value => value,
+        }
+    }
+}
@@ -0,0 +1,264 @@
+//! Inspection of bundles.
+
+use std::fs::File;
+use std::path::Path;
+
+use byte_calc::NumBytes;
+use reportify::ResultExt;
+use serde::Serialize;
+
+use rugix_bundle::format::{BlockEncoding, PayloadEntry};
+use rugix_bundle::reader::BundleReader;
+use rugix_bundle::source::FileSource;
+use rugix_bundle::BundleResult;
+use rugix_hashes::{HashAlgorithm, HashDigest};
+
+/// Information about a bundle.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BundleInfo {
+    /// Hash of the bundle header.
+    pub header_hash: String,
+    /// Manifest the bundle has been created from, if it is included in the bundle.
+    pub manifest: Option<serde_json::Value>,
+    /// Indicates whether the update is incremental.
+    pub is_incremental: bool,
+    /// Hash algorithm used for the hashes of the bundle.
+    pub hash_algorithm: String,
+    /// Security version of the bundle.
+    pub security_version: Option<u64>,
+    /// Update channel of the bundle.
+    pub channel: Option<String>,
+    /// Hex-encoded ids of the keys the content key of the bundle has been wrapped for.
+    pub encryption_keys: Vec<String>,
+    /// Payloads of the bundle.
+    pub payloads: Vec<PayloadInfo>,
+}
+
+/// Information about a payload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct PayloadInfo {
+    /// Index of the payload.
+    pub idx: usize,
+    /// How the payload is delivered.
+    pub delivery: DeliveryInfo,
+    /// Condition under which the payload is installed.
+    pub condition: Option<ConditionInfo>,
+    /// Hash of the payload file.
+    pub file_hash: String,
+    /// Hash of the payload header.
+    pub header_hash: String,
+    /// Offset of the payload in the payloads segment.
+    pub offset: Option<u64>,
+    /// Size of the payload file, if recorded in the bundle.
+    pub file_size: Option<u64>,
+    /// Size of the payload data in the bundle.
+    pub data_size: u64,
+    /// Block encoding of the payload.
+    pub block_encoding: Option<BlockEncodingInfo>,
+}
+
+/// How a payload is delivered.
+#[derive(Debug, Serialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum DeliveryInfo {
+    Slot {
+        slot: String,
+    },
+    Execute {
+        handler: Vec<String>,
+    },
+    Script {
+        stage: String,
+        interpreter: Vec<String>,
+    },
+    /// Payload type unknown to this version of Rugix Bundler.
+    Unknown,
+}
+
+/// Condition under which a payload is installed.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct ConditionInfo {
+    pub compatible: Option<String>,
+    pub dmi_product_name: Option<String>,
+    pub variant: Option<String>,
+}
+
+/// Block encoding of a payload.
+#[derive(Debug, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub struct BlockEncodingInfo {
+    pub chunker: String,
+    pub hash_algorithm: String,
+    pub deduplicated: bool,
+    pub compression: Option<String>,
+    pub encryption: Option<String>,
+    /// Number of blocks of the payload file.
+    pub num_blocks: usize,
+    /// Size of the encoded blocks in the bundle.
+    pub encoded_size: u64,
+    /// Size of the decoded payload file, if recorded in the bundle.
+    pub decoded_size: Option<u64>,
+}
+
+/// Gather information about the given bundle.
+pub fn inspect(bundle: &Path, verify_bundle: Option<HashDigest>) -> BundleResult<BundleInfo> {
+    let header_hash = rugix_bundle::bundle_hash(bundle)?;
+    let file = File::open(bundle).whatever("unable to open bundle")?;
+    let mut reader = BundleReader::start(FileSource::from_unbuffered(file), verify_bundle)?;
+    let header = reader.header();
+    let hash_algorithm = header.hash_algorithm;
+    let manifest = match &header.manifest {
+        Some(manifest) => {
+            Some(serde_json::from_str(manifest).whatever("unable to parse bundle manifest")?)
+        }
+        None => None,
+    };
+    let mut info = BundleInfo {
+        header_hash: header_hash.to_string(),
+        manifest,
+        is_incremental: header.is_incremental,
+        hash_algorithm: hash_algorithm.name().to_owned(),
+        security_version: header.security_version,
+        channel: header.channel.clone(),
+        encryption_keys: header
+            .encryption_keys
+            .iter()
+            .map(|key| hex::encode(&key.key_id.raw))
+            .collect(),
+        payloads: Vec::new(),
+    };
+    while let Some(payload) = reader.next_payload()? {
+        let entry = payload.entry();
+        let block_encoding = match &payload.header().block_encoding {
+            Some(block_encoding) => Some(block_encoding_info(
+                block_encoding,
+                entry,
+                payload.data_size(),
+            )?),
+            None => None,
+        };
+        info.payloads.push(PayloadInfo {
+            idx: payload.idx(),
+            delivery: delivery_info(entry),
+            condition: entry.condition.as_ref().map(|condition| ConditionInfo {
+                compatible: condition.compatible.clone(),
+                dmi_product_name: condition.dmi_product_name.clone(),
+                variant: condition.variant.clone(),
+            }),
+            file_hash: digest(hash_algorithm, &entry.file_hash.raw),
+            header_hash: digest(hash_algorithm, &entry.header_hash.raw),
+            offset: entry.offset,
+            file_size: entry.file_size,
+            data_size: payload.data_size().raw,
+            block_encoding,
+        });
+        payload.skip()?;
+    }
+    Ok(info)
+}
+
+/// Print the information about a bundle in a human-readable format.
+pub fn print_info(info: &BundleInfo) {
+    println!("Header hash: {}", info.header_hash);
+    println!(
+        "Update type: {}",
+        if info.is_incremental {
+            "incremental"
+        } else {
+            "full"
+        }
+    );
+    if let Some(security_version) = info.security_version {
+        println!("Security version: {security_version}");
+    }
+    if let Some(channel) = &info.channel {
+        println!("Channel: {channel}");
+    }
+    if !info.encryption_keys.is_empty() {
+        println!("Encrypted for {} key(s)", info.encryption_keys.len());
+    }
+    println!("Payloads:");
+    for payload in &info.payloads {
+        let idx = payload.idx;
+        let file = &payload.file_hash;
+        match &payload.delivery {
+            DeliveryInfo::Slot { slot } => println!("  {idx}: slot={slot:?} file={file}"),
+            DeliveryInfo::Execute { handler } => {
+                println!("  {idx}: execute({}) file={file}", handler.join(" "))
+            }
+            DeliveryInfo::Script { stage, .. } => println!("  {idx}: script({stage}) file={file}"),
+            DeliveryInfo::Unknown => println!("  {idx}: unknown file={file}"),
+        }
+        match payload.file_size {
+            Some(file_size) => println!(
+                "     size={:.2} data={:.2}",
+                NumBytes::new(file_size),
+                NumBytes::new(payload.data_size)
+            ),
+            None => println!("     data={:.2}", NumBytes::new(payload.data_size)),
+        }
+        if let Some(block_encoding) = &payload.block_encoding {
+            let mut details = vec![
+                block_encoding.chunker.clone(),
+                block_encoding.hash_algorithm.clone(),
+            ];
+            if block_encoding.deduplicated {
+                details.push("deduplicated".to_owned());
+            }
+            details.extend(block_encoding.compression.clone());
+            details.extend(block_encoding.encryption.clone());
+            println!(
+                "     blocks={} ({})",
+                block_encoding.num_blocks,
+                details.join(", ")
+            );
+        }
+    }
+}
+
+fn block_encoding_info(
+    block_encoding: &BlockEncoding,
+    entry: &PayloadEntry,
+    data_size: NumBytes,
+) -> BundleResult<BlockEncodingInfo> {
+    Ok(BlockEncodingInfo {
+        chunker: block_encoding.chunker.to_string(),
+        hash_algorithm: block_encoding.hash_algorithm.name().to_owned(),
+        deduplicated: block_encoding.deduplicated,
+        compression: block_encoding
+            .compression
+            .map(|compression| compression.as_str().to_owned()),
+        encryption: block_encoding
+            .encryption
+            .map(|encryption| encryption.as_str().to_owned()),
+        num_blocks: block_encoding.num_blocks()?,
+        encoded_size: data_size.raw,
+        decoded_size: entry.file_size,
+    })
+}
+
+fn delivery_info(entry: &PayloadEntry) -> DeliveryInfo {
+    if let Some(slot_type) = &entry.type_slot {
+        DeliveryInfo::Slot {
+            slot: slot_type.slot.clone(),
+        }
+    } else if let Some(execute_type) = &entry.type_execute {
+        DeliveryInfo::Execute {
+            handler: execute_type.handler.clone(),
+        }
+    } else if let Some(script_type) = &entry.type_script {
+        DeliveryInfo::Script {
+            stage: script_type.stage.clone(),
+            interpreter: script_type.interpreter.clone(),
+        }
+    } else {
+        DeliveryInfo::Unknown
+    }
+}
+
+fn digest(algorithm: HashAlgorithm, raw: &[u8]) -> String {
+    HashDigest::new_unchecked(algorithm, raw).to_string()
+}
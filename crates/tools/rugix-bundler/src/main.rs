@@ -1,15 +1,24 @@
 use std::fs::File;
 use std::path::PathBuf;
 
+use byte_calc::NumBytes;
 use clap::Parser;
 
 use reportify::{bail, ResultExt};
+use rugix_bundle::encryption::{DecryptionKey, EncryptionKey};
 use rugix_bundle::format::tags::TagNameResolver;
+use rugix_bundle::reader::estimate::{estimate_payload, load_block_index, IndexBlockProvider};
+use rugix_bundle::reader::verify::{check_structure, verify_payloads};
 use rugix_bundle::reader::BundleReader;
-use rugix_bundle::source::FileSource;
+use rugix_bundle::source::{BundleSource, FileSource};
 use rugix_bundle::BundleResult;
 use rugix_hashes::HashDigest;
 
+mod from_image;
+mod from_swu;
+mod inspect;
+mod sparse;
+
 #[derive(Debug, Parser)]
 pub struct Args {
     #[clap(subcommand)]
@@ -20,11 +29,24 @@ pub struct Args {
 pub enum Cmd {
     /// Create a bundle from a bundle directory.
     Bundle(BundleCmd),
+    /// Create a bundle from the partitions of a system image.
+    FromImage(from_image::FromImageCmd),
+    /// Create a bundle from the images of an SWUpdate archive.
+    FromSwu(from_swu::FromSwuCmd),
     /// Hash the header of a bundle.
     Hash(HashCmd),
     /// Unpack a payload from a bundle.
     Unpack(UnpackCmd),
+    /// Verify the integrity of an entire bundle.
+    Verify(VerifyCmd),
+    /// Estimate the data fetched by a device when installing a bundle.
+    Estimate(EstimateCmd),
+    /// Show information about a bundle and its payloads.
     Inspect(InspectCmd),
+    /// Generate a key for encrypting payloads.
+    GenerateKey(GenerateKeyCmd),
+    /// Convert an image with a block map into an Android sparse image.
+    Sparse(sparse::SparseCmd),
     /// Print the low-level structure of a bundle.
     #[clap(hide(true))]
     PrintStructure(PrintCmd),
@@ -41,26 +63,62 @@ pub struct BundleCmd {
     src: PathBuf,
     /// Output bundle file.
     dst: PathBuf,
+    /// Shared key to encrypt payloads for.
+    #[clap(long)]
+    encryption_key: Vec<PathBuf>,
+    /// X25519 public key of a device to encrypt payloads for.
+    #[clap(long)]
+    recipient: Vec<PathBuf>,
 }
 
 #[derive(Debug, Parser)]
-pub struct ListCmd {
-    bundle: PathBuf,
+pub struct GenerateKeyCmd {
+    /// Output file for the key.
+    key: PathBuf,
+    /// Output file for the public key, when using the key as an X25519 secret key.
+    #[clap(long)]
+    public_key: Option<PathBuf>,
 }
 
 #[derive(Debug, Parser)]
 pub struct UnpackCmd {
     #[clap(long)]
     verify_bundle: Option<HashDigest>,
+    /// Key to decrypt encrypted payloads with.
+    #[clap(long)]
+    decryption_key: Vec<PathBuf>,
     bundle: PathBuf,
     payload: usize,
     dst: PathBuf,
 }
 
+#[derive(Debug, Parser)]
+pub struct VerifyCmd {
+    #[clap(long)]
+    verify_bundle: Option<HashDigest>,
+    /// Key to decrypt encrypted payloads with.
+    #[clap(long)]
+    decryption_key: Vec<PathBuf>,
+    bundle: PathBuf,
+}
+
+#[derive(Debug, Parser)]
+pub struct EstimateCmd {
+    #[clap(long)]
+    verify_bundle: Option<HashDigest>,
+    /// Block index exported from the device.
+    #[clap(long)]
+    index: Vec<PathBuf>,
+    bundle: PathBuf,
+}
+
 #[derive(Debug, Parser)]
 pub struct InspectCmd {
     #[clap(long)]
     verify_bundle: Option<HashDigest>,
+    /// Output the information as JSON.
+    #[clap(long)]
+    json: bool,
     bundle: PathBuf,
 }
 
@@ -73,11 +131,32 @@ fn main() -> BundleResult<()> {
     let args = Args::parse();
     match args.cmd {
         Cmd::Bundle(create_cmd) => {
-            rugix_bundle::builder::pack(&create_cmd.src, &create_cmd.dst)?;
+            let mut encryption_keys = Vec::new();
+            for path in &create_cmd.encryption_key {
+                encryption_keys.push(EncryptionKey::load_shared(path)?);
+            }
+            for path in &create_cmd.recipient {
+                encryption_keys.push(EncryptionKey::load_public(path)?);
+            }
+            rugix_bundle::builder::pack(&create_cmd.src, &create_cmd.dst, &encryption_keys)?;
+        }
+        Cmd::FromImage(from_image_cmd) => {
+            from_image::from_image(&from_image_cmd)?;
+        }
+        Cmd::FromSwu(from_swu_cmd) => {
+            from_swu::from_swu(&from_swu_cmd)?;
+        }
+        Cmd::Sparse(sparse_cmd) => {
+            sparse::sparse(&sparse_cmd)?;
         }
         Cmd::Unpack(unpack_cmd) => {
             let source = FileSource::from_unbuffered(File::open(&unpack_cmd.bundle).unwrap());
             let mut reader = BundleReader::start(source, unpack_cmd.verify_bundle)?;
+            unlock(&mut reader, &unpack_cmd.decryption_key)?;
+            if reader.can_seek() && unpack_cmd.payload < reader.header().payload_index.len() {
+                // Jump directly to the payload instead of skipping the previous ones.
+                reader.seek_payload(unpack_cmd.payload)?;
+            }
             let mut did_read = false;
             while let Some(payload_reader) = reader.next_payload()? {
                 if payload_reader.idx() != unpack_cmd.payload {
@@ -100,6 +179,81 @@ fn main() -> BundleResult<()> {
                 bail!("not enough payloads");
             }
         }
+        Cmd::Verify(verify_cmd) => {
+            let open_source = || -> BundleResult<FileSource> {
+                let file = File::open(&verify_cmd.bundle).whatever("unable to open bundle")?;
+                Ok(FileSource::from_unbuffered(file))
+            };
+            check_structure(&mut open_source()?)?;
+            let mut reader = BundleReader::start(open_source()?, verify_cmd.verify_bundle)?;
+            unlock(&mut reader, &verify_cmd.decryption_key)?;
+            let stats = verify_payloads(reader)?;
+            for (idx, stats) in stats.iter().enumerate() {
+                println!("Payload {idx}:");
+                println!("  file size: {:.2}", stats.file_size);
+                println!("  data size: {:.2}", stats.data_size);
+                if let Some(blocks) = &stats.blocks {
+                    println!(
+                        "  blocks: {} ({} unique)",
+                        blocks.num_blocks, blocks.num_unique_blocks
+                    );
+                    println!("  dedup ratio: {:.2}", blocks.dedup_ratio(stats.file_size));
+                    println!(
+                        "  compression ratio: {:.2}",
+                        blocks.compression_ratio(stats.data_size)
+                    );
+                }
+            }
+            println!("Bundle is valid.");
+        }
+        Cmd::Estimate(estimate_cmd) => {
+            let mut indices = Vec::new();
+            for path in &estimate_cmd.index {
+                indices.push((load_block_index(path)?, path));
+            }
+            let source = FileSource::from_unbuffered(
+                File::open(&estimate_cmd.bundle).whatever("unable to open bundle")?,
+            );
+            let mut reader = BundleReader::start(source, estimate_cmd.verify_bundle)?;
+            let mut total_fetched = NumBytes::ZERO;
+            let mut total_reused = NumBytes::ZERO;
+            while let Some(payload) = reader.next_payload()? {
+                let idx = payload.idx();
+                let mut provider = IndexBlockProvider::new();
+                if let Some(block_encoding) = &payload.header().block_encoding {
+                    // Only slot payloads reuse blocks and only indices using the same
+                    // algorithms are considered, just like on the device.
+                    if payload.entry().type_slot.is_some() {
+                        for (index, path) in &indices {
+                            let config = index.config();
+                            if config.hash_algorithm == block_encoding.hash_algorithm
+                                && config.chunker == block_encoding.chunker
+                            {
+                                provider.add_index(index, path);
+                            }
+                        }
+                    }
+                }
+                let estimate = estimate_payload(payload, Some(&provider))?;
+                println!(
+                    "Payload {idx}: fetched={:.2} ({} blocks) reused={:.2} ({} blocks)",
+                    estimate.fetched,
+                    estimate.num_fetched_blocks,
+                    estimate.reused,
+                    estimate.num_reused_blocks,
+                );
+                total_fetched += estimate.fetched;
+                total_reused += estimate.reused;
+            }
+            println!("Total: fetched={total_fetched:.2} reused={total_reused:.2}");
+        }
+        Cmd::GenerateKey(generate_cmd) => {
+            let key = DecryptionKey::generate();
+            key.write(&generate_cmd.key)?;
+            if let Some(public_key) = &generate_cmd.public_key {
+                key.public_key().write(public_key)?;
+            }
+        }
         Cmd::PrintStructure(print_cmd) => {
             let mut source = FileSource::from_unbuffered(File::open(&print_cmd.bundle).unwrap());
             rugix_bundle::format::stlv::pretty_print(&mut source, Some(&TagNameResolver)).unwrap();
@@ -109,32 +263,31 @@ fn main() -> BundleResult<()> {
             println!("{hash}");
         }
         Cmd::Inspect(inspect_cmd) => {
-            let source = FileSource::from_unbuffered(File::open(&inspect_cmd.bundle).unwrap());
-            let reader = BundleReader::start(source, inspect_cmd.verify_bundle)?;
-            println!("Payloads:");
-            for (idx, entry) in reader.header().payload_index.iter().enumerate() {
-                if let Some(slot_type) = &entry.type_slot {
-                    println!(
-                        "  {idx}: slot={:?} file={}",
-                        slot_type.slot,
-                        HashDigest::new_unchecked(
-                            reader.header().hash_algorithm,
-                            &entry.file_hash.raw
-                        )
-                    );
-                }
-                if let Some(type_execute) = &entry.type_execute {
-                    let command = type_execute.handler.join(" ");
-                    println!(
-                        "  {idx}: execute({command}) file={}",
-                        HashDigest::new_unchecked(
-                            reader.header().hash_algorithm,
-                            &entry.file_hash.raw
-                        )
-                    );
-                }
+            let info = inspect::inspect(&inspect_cmd.bundle, inspect_cmd.verify_bundle)?;
+            if inspect_cmd.json {
+                println!(
+                    "{}",
+                    serde_json::to_string_pretty(&info).whatever("unable to serialize info")?
+                );
+            } else {
+                inspect::print_info(&info);
             }
         }
     }
     Ok(())
 }
+
+/// Unlock the bundle with the given key files, if it is encrypted.
+fn unlock<S: BundleSource>(
+    reader: &mut BundleReader<S>,
+    key_files: &[PathBuf],
+) -> BundleResult<()> {
+    if !reader.is_encrypted() || key_files.is_empty() {
+        return Ok(());
+    }
+    let mut keys = Vec::new();
+    for key_file in key_files {
+        keys.push(DecryptionKey::load(key_file)?);
+    }
+    reader.unlock(&keys)
+}
@@ -0,0 +1,128 @@
+//! Conversion of images with a block map into Android sparse images.
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+
+use clap::Parser;
+use reportify::{bail, ResultExt};
+
+use rugix_bundle::BundleResult;
+use rugix_common::sparse::{Bmap, SPARSE_MAGIC};
+
+/// Maximal size of the data of a single raw chunk.
+const MAX_RAW_CHUNK_SIZE: u64 = 64 * 1024 * 1024;
+
+#[derive(Debug, Parser)]
+pub struct SparseCmd {
+    /// Raw image to convert.
+    image: PathBuf,
+    /// Block map of the image as created by `bmaptool`.
+    #[clap(long)]
+    bmap: PathBuf,
+    /// Output sparse image.
+    #[clap(short, long)]
+    output: PathBuf,
+}
+
+/// Chunk of the sparse image.
+enum Chunk {
+    /// Blocks stored in the image.
+    Raw { first: u64, num_blocks: u64 },
+    /// Unmapped blocks.
+    DontCare { num_blocks: u64 },
+}
+
+pub fn sparse(cmd: &SparseCmd) -> BundleResult<()> {
+    let bmap = Bmap::load(&cmd.bmap)
+        .whatever("unable to load block map")
+        .with_info(|_| format!("path: {:?}", cmd.bmap))?;
+    let block_size = bmap.block_size;
+    if block_size % 4 != 0 || block_size > MAX_RAW_CHUNK_SIZE {
+        bail!("block size {block_size} is not supported by sparse images");
+    }
+    let mut image = File::open(&cmd.image)
+        .whatever("unable to open image")
+        .with_info(|_| format!("path: {:?}", cmd.image))?;
+    let image_size = image.metadata().whatever("unable to get image size")?.len();
+    if image_size != bmap.image_size {
+        bail!("image size {image_size} does not match block map");
+    }
+    // The last block is padded with zeros, if the image size is not a multiple of it.
+    let num_blocks = image_size.div_ceil(block_size);
+    let max_raw_blocks = (MAX_RAW_CHUNK_SIZE / block_size).max(1);
+    let mut chunks = Vec::new();
+    let mut next_block = 0;
+    for &(first, last) in &bmap.ranges {
+        let first = first.min(num_blocks);
+        let end = (last + 1).min(num_blocks);
+        if first > next_block {
+            chunks.push(Chunk::DontCare {
+                num_blocks: first - next_block,
+            });
+        }
+        let mut start = first;
+        while start < end {
+            let chunk_blocks = (end - start).min(max_raw_blocks);
+            chunks.push(Chunk::Raw {
+                first: start,
+                num_blocks: chunk_blocks,
+            });
+            start += chunk_blocks;
+        }
+        next_block = next_block.max(end);
+    }
+    if next_block < num_blocks {
+        chunks.push(Chunk::DontCare {
+            num_blocks: num_blocks - next_block,
+        });
+    }
+    let num_blocks = u32::try_from(num_blocks).whatever("image is too large for a sparse image")?;
+    let num_chunks = u32::try_from(chunks.len()).whatever("image has too many chunks")?;
+
+    let mut output = BufWriter::new(
+        File::create(&cmd.output)
+            .whatever("unable to create output file")
+            .with_info(|_| format!("path: {:?}", cmd.output))?,
+    );
+    let mut header = Vec::new();
+    header.extend_from_slice(&SPARSE_MAGIC);
+    for value in [1u16, 0, 28, 12] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    for value in [block_size as u32, num_blocks, num_chunks, 0] {
+        header.extend_from_slice(&value.to_le_bytes());
+    }
+    output
+        .write_all(&header)
+        .whatever("unable to write sparse image")?;
+    let mut buffer = Vec::new();
+    for chunk in &chunks {
+        let (kind, num_blocks, data_size) = match chunk {
+            Chunk::Raw { num_blocks, .. } => (0xCAC1u16, *num_blocks, num_blocks * block_size),
+            Chunk::DontCare { num_blocks } => (0xCAC3u16, *num_blocks, 0),
+        };
+        output
+            .write_all(&kind.to_le_bytes())
+            .and_then(|_| output.write_all(&[0; 2]))
+            .and_then(|_| output.write_all(&(num_blocks as u32).to_le_bytes()))
+            .and_then(|_| output.write_all(&(12 + data_size as u32).to_le_bytes()))
+            .whatever("unable to write sparse image")?;
+        if let Chunk::Raw { first, .. } = chunk {
+            image
+                .seek(SeekFrom::Start(first * block_size))
+                .whatever("unable to seek in image")?;
+            buffer.clear();
+            (&mut image)
+                .take(data_size)
+                .read_to_end(&mut buffer)
+                .whatever("unable to read image")?;
+            buffer.resize(data_size as usize, 0);
+            output
+                .write_all(&buffer)
+                .whatever("unable to write sparse image")?;
+        }
+    }
+    output.flush().whatever("unable to write sparse image")?;
+    Ok(())
+}
@@ -0,0 +1,66 @@
+//! Installation of payloads into block slots.
+//!
+//! Optionally, blocks which are already present at the same offset of the block device
+//! are skipped instead of being rewritten. This reduces the wear of flash storage, e.g.,
+//! SD cards, when most of the data is unchanged.
+
+use std::fs;
+
+use byte_calc::NumBytes;
+use reportify::ResultExt;
+use rugix_bundle::reader::PayloadTarget;
+
+use crate::slot_db::SlotBlocks;
+use crate::system::slots::BlockSlot;
+use crate::system::SystemResult;
+
+/// Payload target for block slots.
+#[derive(Debug)]
+pub struct BlockTarget {
+    /// Block device of the slot.
+    file: fs::File,
+    /// Blocks which are already present on the block device.
+    existing: Option<SlotBlocks>,
+}
+
+impl BlockTarget {
+    /// Open the block device of the given slot.
+    ///
+    /// Blocks in `existing` that are already present at the same offset are not
+    /// written again.
+    pub fn new(slot: &BlockSlot, existing: Option<SlotBlocks>) -> SystemResult<Self> {
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(slot.device())
+            .whatever("unable to open payload target")?;
+        Ok(Self { file, existing })
+    }
+}
+
+impl PayloadTarget for BlockTarget {
+    fn write(&mut self, bytes: &[u8]) -> rugix_bundle::BundleResult<()> {
+        PayloadTarget::write(&mut self.file, bytes)
+    }
+
+    fn read_block(
+        &mut self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> rugix_bundle::BundleResult<()> {
+        PayloadTarget::read_block(&mut self.file, offset, size, buffer)
+    }
+
+    fn existing_block(&mut self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        self.existing.as_ref()?.query_at(offset, hash)
+    }
+
+    fn skip(&mut self, size: NumBytes) -> rugix_bundle::BundleResult<()> {
+        PayloadTarget::skip(&mut self.file, size)
+    }
+
+    fn finalize(self) -> rugix_bundle::BundleResult<()> {
+        self.file.sync_all().whatever("unable to persist payload")
+    }
+}
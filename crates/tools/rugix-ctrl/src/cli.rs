@@ -1,13 +1,18 @@
 //! Definition of the command line interface (CLI).
 
+use std::cell::RefCell;
+use std::collections::{HashMap, HashSet};
 use std::fs::{self, File};
 use std::io::{self, Read, Write};
-use std::path::Path;
-use std::process::Child;
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout};
 
-use rugix_bundle::manifest::ChunkerAlgorithm;
-use rugix_bundle::reader::block_provider::StoredBlockProvider;
-use rugix_bundle::reader::PayloadTarget;
+use byte_calc::{ByteLen, NumBytes};
+use rugix_bundle::block_encoding::block_index::{BlockIndexBuilder, BlockIndexConfig};
+use rugix_bundle::encryption::DecryptionKey;
+use rugix_bundle::manifest::{ChunkerAlgorithm, ScriptStage};
+use rugix_bundle::reader::block_provider::{BlockReader, StoredBlockProvider};
+use rugix_bundle::reader::{PayloadReader, PayloadTarget};
 use rugix_bundle::source::{BundleSource, ReaderSource, SkipRead};
 use rugix_bundle::BUNDLE_MAGIC;
 use rugix_hashes::{HashAlgorithm, HashDigest};
@@ -15,34 +20,47 @@ use rugix_hooks::HooksLoader;
 use tracing::{error, info};
 
 use crate::system::boot_groups::{BootGroup, BootGroupIdx};
-use crate::system::slots::SlotKind;
-use crate::system::{System, SystemResult};
+use crate::system::check::{CheckOptions, ConfigCheck};
+use crate::system::slots::{BlockSlot, SlotKind};
+use crate::system::{paths, System, SystemResult};
 use clap::{Parser, ValueEnum};
 use reportify::{bail, whatever, ErrorExt, ResultExt};
-use rugix_common::disk::stream::ImgStream;
+use rugix_common::disk::stream::{ImgStream, PartitionEntry};
+use rugix_common::disk::PartitionTableType;
 use rugix_common::maybe_compressed::{MaybeCompressed, PeekReader};
+use rugix_common::sparse::{copy_mapped, Bmap, MaybeSparse};
 use rugix_common::stream_hasher::StreamHasher;
 use xscript::{vars, Vars};
 
+use crate::block_slot::BlockTarget;
+use crate::conditions::condition_matches;
+use crate::directory_slot::DirectoryTarget;
+use crate::file_slot::FileTarget;
 use crate::http_source::HttpSource;
 use crate::overlay::overlay_dir;
-use crate::slot_db::{self, BlockProvider};
-use crate::system_state;
+use crate::rollback::RollbackProtection;
+use crate::slot_db::{self, BlockProvider, SlotBlocks};
+use crate::sparse_target::SparseTarget;
+use crate::update_scripts::{self, UpdateScript};
 use crate::utils::{clear_flag, reboot, set_flag, DEFERRED_SPARE_REBOOT_FLAG};
+use crate::{rauc, system_state};
+
+/// Directory with the state flags of Rugix Ctrl.
+const RUGIX_STATE_FLAGS_DIR: &str = "/run/rugix/state/.rugix";
 
 fn create_rugix_state_directory() -> SystemResult<()> {
-    fs::create_dir_all("/run/rugix/state/.rugix")
+    fs::create_dir_all(paths::prefixed(RUGIX_STATE_FLAGS_DIR))
         .whatever("unable to create `/run/rugix/state/.rugix`")
 }
 
 fn set_rugix_state_flag(name: &str) -> SystemResult<()> {
-    fs::write(Path::new("/run/rugix/state/.rugix").join(name), "")
+    fs::write(paths::prefixed(RUGIX_STATE_FLAGS_DIR).join(name), "")
         .whatever("unable to write state flag")
         .with_info(|_| format!("name: {name}"))
 }
 
 fn clear_rugix_state_flag(name: &str) -> SystemResult<()> {
-    let path = Path::new("/run/rugix/state/.rugix").join(name);
+    let path = paths::prefixed(RUGIX_STATE_FLAGS_DIR).join(name);
     fs::remove_file(&path).or_else(|error| match error.kind() {
         io::ErrorKind::NotFound => Ok(()),
         _ => Err(error
@@ -59,11 +77,24 @@ pub fn main() -> SystemResult<()> {
     rugix_cli::CliBuilder::new().init();
 
     let args = Args::parse();
+    // Checking the configuration must work even if the system cannot be initialized.
+    if let Command::System(SystemCommand::CheckConfig {
+        root,
+        disk,
+        config_dir,
+    }) = &args.command
+    {
+        return check_config(&CheckOptions {
+            root: root.clone(),
+            disk: disk.clone(),
+            config_dir: config_dir.clone(),
+        });
+    }
     let system = System::initialize()?;
     match &args.command {
         Command::State(state_cmd) => match state_cmd {
             StateCommand::Reset => {
-                let reset_hooks = HooksLoader::default()
+                let reset_hooks = HooksLoader::new(paths::prefixed(paths::HOOKS_DIR))
                     .load_hooks("state-reset")
                     .whatever("unable to load `state-reset` hooks")?;
 
@@ -95,6 +126,7 @@ pub fn main() -> SystemResult<()> {
                     check_hash,
                     verify_bundle,
                     boot_group,
+                    bmap,
                 } => {
                     let check_hash = check_hash.as_deref()
                         .map(|encoded_hash| -> SystemResult<ImageHash> {
@@ -140,7 +172,7 @@ pub fn main() -> SystemResult<()> {
                         }
                     }
 
-                    let hooks = HooksLoader::default()
+                    let hooks = HooksLoader::new(paths::prefixed(paths::HOOKS_DIR))
                         .load_hooks("update-install")
                         .whatever("unable to load `update-install` hooks")?;
 
@@ -164,6 +196,7 @@ pub fn main() -> SystemResult<()> {
                         image,
                         check_hash,
                         verify_bundle,
+                        bmap.as_deref(),
                         boot_group.as_ref(),
                     )?;
 
@@ -229,7 +262,7 @@ pub fn main() -> SystemResult<()> {
             }
             SystemCommand::Commit => {
                 if system.needs_commit()? {
-                    let hooks = HooksLoader::default()
+                    let hooks = HooksLoader::new(paths::prefixed(paths::HOOKS_DIR))
                         .load_hooks("system-commit")
                         .whatever("unable to load `system-commit` hooks")?;
                     hooks
@@ -242,6 +275,9 @@ pub fn main() -> SystemResult<()> {
                 } else {
                     println!("Active boot group is already the default!");
                 }
+                // Updates installed to the active boot group without a reboot are
+                // committed as well, hence, we always advance the counter here.
+                RollbackProtection::new(&system)?.commit()?;
             }
             SystemCommand::Reboot { spare } => {
                 if *spare {
@@ -254,6 +290,9 @@ pub fn main() -> SystemResult<()> {
                 }
                 reboot()?;
             }
+            SystemCommand::CheckConfig { .. } => {
+                unreachable!("configuration is checked before initializing the system")
+            }
         },
         Command::Unstable(command) => match command {
             UnstableCommand::SetDeferredSpareReboot { value } => match value {
@@ -304,11 +343,31 @@ pub fn main() -> SystemResult<()> {
                     SlotKind::File { path } => {
                         slot_db::add_index(slot.name(), path, chunker_algorithm, hash_algorithm)?;
                     }
+                    SlotKind::Directory { .. } => {
+                        bail!("cannot create indices on directory slots");
+                    }
                     SlotKind::Custom { .. } => {
                         bail!("cannot create indices on custom slots");
                     }
                 }
             }
+            SlotsCommand::ExportIndex {
+                slot,
+                chunker: chunker_algorithm,
+                hash_algorithm,
+                output,
+            } => {
+                let Some(index) = slot_db::get_stored_indices(slot)?
+                    .into_iter()
+                    .find(|index| {
+                        &index.chunker_algorithm == chunker_algorithm
+                            && &index.hash_algorithm == hash_algorithm
+                    })
+                else {
+                    bail!("slot {slot} has no index with the given algorithms");
+                };
+                fs::copy(&index.index_file, output).whatever("unable to export index")?;
+            }
         },
     }
     Ok(())
@@ -366,9 +425,13 @@ fn install_update_stream(
     image: &String,
     check_hash: Option<ImageHash>,
     verify_bundle: &Option<HashDigest>,
+    bmap: Option<&Path>,
     boot_group: Option<&(BootGroupIdx, &BootGroup)>,
 ) -> SystemResult<UpdateRebootType> {
     if image.starts_with("http") {
+        if bmap.is_some() {
+            bail!("--bmap is only supported for images, use sparse payloads for bundles");
+        }
         if check_hash.is_some() {
             bail!("--check-hash is not supported for update bundles, use --verify-bundle");
         }
@@ -399,6 +462,9 @@ fn install_update_stream(
         .peek(BUNDLE_MAGIC.len())
         .whatever("error reading bundle magic")?;
 
+    if bmap.is_some() && (magic == BUNDLE_MAGIC || magic.starts_with(rauc::SQUASHFS_MAGIC)) {
+        bail!("--bmap is only supported for images, use sparse payloads for bundles");
+    }
     if magic == BUNDLE_MAGIC {
         if check_hash.is_some() {
             bail!("--check-hash is not supported for update bundles, use --verify-bundle");
@@ -406,6 +472,16 @@ fn install_update_stream(
         let bundle_source = ReaderSource::<_, SkipRead>::from_unbuffered(update_stream);
         return install_update_bundle(system, bundle_source, verify_bundle, boot_group);
     }
+    if magic.starts_with(rauc::SQUASHFS_MAGIC) {
+        if image == "-" {
+            bail!("RAUC bundles must be installed from a file");
+        }
+        if check_hash.is_some() || verify_bundle.is_some() {
+            bail!("--check-hash and --verify-bundle are not supported for RAUC bundles");
+        }
+        rauc::install_bundle(system, Path::new(image), boot_group)?;
+        return Ok(UpdateRebootType::Yes);
+    }
     if verify_bundle.is_some() {
         bail!("--verify-bundle is not supported on images, use --check-hash");
     }
@@ -414,68 +490,105 @@ fn install_update_stream(
         bail!("for image updates, you need to specify a boot group");
     };
 
+    // Images do not carry a security version and are, hence, treated as having version
+    // `0`. This refuses them once a security version has been committed.
+    let rollback_protection = RollbackProtection::new(system)?;
+    rollback_protection.check(None, None)?;
+
+    let bmap = bmap
+        .map(|path| {
+            Bmap::load(path)
+                .whatever("unable to load block map")
+                .with_info(|_| format!("path: {path:?}"))
+        })
+        .transpose()?;
     let update_stream =
         MaybeCompressed::new(update_stream).whatever("error decompressing stream")?;
+    let update_stream =
+        MaybeSparse::new(update_stream, bmap.as_ref()).whatever("error reading sparse image")?;
+
+    let mut img_stream =
+        ImgStream::new(update_stream).whatever("error reading image partitions")?;
+
+    // Resolve all slots before anything is written.
+    let mut targets = Vec::new();
+    match &system.config().image_update {
+        Some(config) => {
+            for partition in &config.partitions {
+                if partition.partition.is_none() == partition.label.is_none() {
+                    bail!("image partitions must have either a number or a label");
+                }
+                targets.push(ImagePartitionTarget::new(
+                    system,
+                    entry,
+                    partition.partition,
+                    partition.label.as_deref(),
+                    &partition.slot,
+                )?);
+            }
+        }
+        None => {
+            let defaults = match img_stream.table_type() {
+                PartitionTableType::Mbr => DEFAULT_MBR_IMAGE_PARTITIONS,
+                PartitionTableType::Gpt => DEFAULT_GPT_IMAGE_PARTITIONS,
+            };
+            for (partition, slot) in defaults {
+                targets.push(ImagePartitionTarget::new(
+                    system,
+                    entry,
+                    Some(*partition),
+                    None,
+                    slot,
+                )?);
+            }
+        }
+    }
 
     system
         .boot_flow()
         .pre_install(system, *entry_idx)
         .whatever("error executing pre-install step")?;
 
-    let boot_slot = entry.get_slot("boot").unwrap();
-    let system_slot = entry.get_slot("system").unwrap();
-
-    let SlotKind::Block(raw_boot_slot) = system.slots()[boot_slot].kind() else {
-        bail!("boot slot must be a block device");
-    };
-    let SlotKind::Block(raw_system_slot) = system.slots()[system_slot].kind() else {
-        bail!("system slot must be a block device");
-    };
-
-    let mut img_stream =
-        ImgStream::new(update_stream).whatever("error reading image partitions")?;
-    let mut partition_idx = 0;
     while let Some(mut partition) = img_stream
         .next_partition()
         .whatever("error reading next partition")?
     {
-        let partition_name = match partition_idx {
-            0 => "CONFIG",
-            1 => "BOOT-A",
-            2 => "BOOT-B",
-            3 => "system-a",
-            4 => "system-b",
-            5 => "data",
-            _ => "<unknown>",
+        println!("Found partition {}", partition.entry());
+        let Some(target) = targets
+            .iter_mut()
+            .find(|target| target.matches(partition.entry()))
+        else {
+            continue;
         };
-        println!(
-            "Found {partition_idx},{partition_name} {}",
-            partition.entry()
-        );
-        match partition_idx {
-            1 => {
-                io::copy(
-                    &mut partition,
-                    &mut fs::File::create(raw_boot_slot.device())
-                        .whatever("error opening boot partition file")?,
-                )
-                .whatever("error copying boot partition")?;
-            }
-            3 => {
-                io::copy(
-                    &mut partition,
-                    &mut fs::File::create(raw_system_slot.device())
-                        .whatever("error opening system partition file")?,
-                )
-                .whatever("error copying system partition")?;
-            }
-            _ => { /* Nothing to do! */ }
+        if target.installed {
+            bail!(
+                "multiple partitions of the image match slot {:?}",
+                target.slot
+            );
         }
-
-        partition_idx += 1;
+        println!("Installing partition to slot {:?}", target.slot);
+        copy_mapped(
+            &mut partition,
+            &mut fs::File::create(target.block_slot.device())
+                .whatever("error opening partition file")
+                .with_info(|_| format!("slot: {}", target.slot))?,
+        )
+        .whatever("error copying partition")
+        .with_info(|_| format!("slot: {}", target.slot))?;
+        target.installed = true;
+    }
+    if let Some(target) = targets.iter().find(|target| !target.installed) {
+        bail!(
+            "image does not contain a partition for slot {:?}",
+            target.slot
+        );
     }
 
-    let mut hashed_stream = img_stream.into_inner().into_inner().into_inner();
+    let mut hashed_stream = img_stream
+        .into_inner()
+        .into_inner()
+        .into_inner()
+        .into_inner();
     // Make sure that the entire stream has been consumed. Otherwise, the hash
     // may not be match the file.
     loop {
@@ -491,29 +604,23 @@ fn install_update_stream(
 
     if let Err(error) = hashed_stream.verify() {
         error!("hash verification failed");
-        if let Err(error) =
-            rugix_fs::File::open_write(raw_boot_slot.device().path()).and_then(|mut device| {
-                device.write_zeros(
-                    byte_calc::NumBytes::new(0),
-                    byte_calc::NumBytes::mebibytes(1),
-                )
-            })
-        {
-            error!("error overwriting boot partition: {error:?}");
-        }
-        if let Err(error) =
-            rugix_fs::File::open_write(raw_system_slot.device().path()).and_then(|mut device| {
-                device.write_zeros(
-                    byte_calc::NumBytes::new(0),
-                    byte_calc::NumBytes::mebibytes(1),
-                )
-            })
-        {
-            error!("error overwriting system partition: {error:?}");
+        for target in &targets {
+            if let Err(error) = rugix_fs::File::open_write(target.block_slot.device().path())
+                .and_then(|mut device| {
+                    device.write_zeros(
+                        byte_calc::NumBytes::new(0),
+                        byte_calc::NumBytes::mebibytes(1),
+                    )
+                })
+            {
+                error!("error overwriting slot {:?}: {error:?}", target.slot);
+            }
         }
         return Err(error);
     }
 
+    rollback_protection.set_pending(entry, None)?;
+
     system
         .boot_flow()
         .post_install(system, *entry_idx)
@@ -521,6 +628,59 @@ fn install_update_stream(
     Ok(UpdateRebootType::Yes)
 }
 
+/// Default partitions of MBR-partitioned images and the slots they are installed to.
+const DEFAULT_MBR_IMAGE_PARTITIONS: &[(u32, &str)] = &[(2, "boot"), (5, "system")];
+
+/// Default partitions of GPT-partitioned images and the slots they are installed to.
+const DEFAULT_GPT_IMAGE_PARTITIONS: &[(u32, &str)] = &[(2, "boot"), (4, "system")];
+
+/// Partition of an image which is installed to a block slot.
+struct ImagePartitionTarget<'system> {
+    /// Number of the partition in the image.
+    partition: Option<u32>,
+    /// Name of the partition in the image.
+    label: Option<&'system str>,
+    /// Slot alias of the boot group.
+    slot: &'system str,
+    /// Slot the partition is installed to.
+    block_slot: &'system BlockSlot,
+    /// Indicates whether the partition has been installed.
+    installed: bool,
+}
+
+impl<'system> ImagePartitionTarget<'system> {
+    fn new(
+        system: &'system System,
+        entry: &BootGroup,
+        partition: Option<u32>,
+        label: Option<&'system str>,
+        slot: &'system str,
+    ) -> SystemResult<Self> {
+        let Some(slot_idx) = entry.get_slot(slot) else {
+            bail!("boot group {:?} has no slot {slot:?}", entry.name());
+        };
+        let SlotKind::Block(block_slot) = system.slots()[slot_idx].kind() else {
+            bail!("slot {slot:?} must be a block device to install images");
+        };
+        Ok(Self {
+            partition,
+            label,
+            slot,
+            block_slot,
+            installed: false,
+        })
+    }
+
+    /// Indicates whether the given partition of the image is installed to the slot.
+    fn matches(&self, entry: &PartitionEntry) -> bool {
+        match (self.partition, self.label) {
+            (Some(partition), _) => entry.number() == partition,
+            (None, Some(label)) => entry.name() == Some(label),
+            (None, None) => false,
+        }
+    }
+}
+
 fn install_update_bundle<R: BundleSource>(
     system: &System,
     bundle_source: R,
@@ -531,21 +691,124 @@ fn install_update_bundle<R: BundleSource>(
         rugix_bundle::reader::BundleReader::start(bundle_source, verify_bundle.clone())
             .whatever("unable to read bundle")?;
 
-    if !bundle_reader.header().is_incremental {
-        let Some((entry_idx, _)) = boot_group else {
-            bail!("full system updates require teh specification of a boot group");
+    if bundle_reader.is_encrypted() {
+        let Some(key_files) = &system.config().decryption_keys else {
+            bail!("bundle is encrypted but no decryption keys are configured");
         };
-        system
-            .boot_flow()
-            .pre_install(system, *entry_idx)
-            .whatever("error executing pre-install step")?;
+        let mut keys = Vec::new();
+        for key_file in key_files {
+            keys.push(
+                DecryptionKey::load(Path::new(key_file))
+                    .whatever("unable to load decryption key")?,
+            );
+        }
+        bundle_reader
+            .unlock(&keys)
+            .whatever("unable to decrypt bundle")?;
+    }
+
+    let rollback_protection = RollbackProtection::new(system)?;
+    rollback_protection.check(
+        bundle_reader.header().security_version,
+        bundle_reader.header().channel.as_deref(),
+    )?;
+
+    let is_incremental = bundle_reader.header().is_incremental;
+    if !is_incremental && boot_group.is_none() {
+        bail!("full system updates require teh specification of a boot group");
+    }
+
+    let target_group = boot_group
+        .map(|(_, group)| *group)
+        .or_else(|| Some(&system.boot_entries()[system.active_boot_entry()?]));
+    // Handlers get the same variables as `update-install` hooks.
+    let handler_boot_group = boot_group.map(|g| g.1.name()).unwrap_or("");
+    // Scripts of all stages get the boot group the update is installed to, which is the
+    // boot group first-boot scripts are stored for.
+    let script_boot_group = target_group.map(|group| group.name()).unwrap_or("");
+
+    // Pre-install scripts must run before any slot is written.
+    let mut seen_other_payload = false;
+    for entry in &bundle_reader.header().payload_index {
+        let is_pre_install = match &entry.type_script {
+            Some(script_type) => matches!(
+                update_scripts::parse_stage(script_type)?,
+                ScriptStage::PreInstall
+            ),
+            None => false,
+        };
+        if is_pre_install && seen_other_payload {
+            bail!("pre-install scripts must come before all other payloads");
+        }
+        seen_other_payload |= !is_pre_install;
     }
 
+    let scripts_dir = tempfile::tempdir().whatever("unable to create scripts directory")?;
+    let mut post_install_scripts = Vec::new();
+    let mut install_started = false;
+    let start_install = || -> SystemResult<()> {
+        if let Some(target_group) = target_group {
+            update_scripts::clear_first_boot_scripts(target_group.name())?;
+        }
+        if !is_incremental {
+            system
+                .boot_flow()
+                .pre_install(system, boot_group.unwrap().0)
+                .whatever("error executing pre-install step")?;
+        }
+        Ok(())
+    };
+
     while let Some(payload) = bundle_reader
         .next_payload()
         .whatever("unable to read payload")?
     {
         let payload_entry = payload.entry();
+        if let Some(condition) = &payload_entry.condition {
+            if !condition_matches(system, condition)? {
+                eprintln!(
+                    "Skipping bundle payload {}, condition does not match",
+                    payload.idx()
+                );
+                payload.skip().whatever("unable to skip payload")?;
+                continue;
+            }
+        }
+        if let Some(script_type) = &payload_entry.type_script {
+            let stage = update_scripts::parse_stage(script_type)?;
+            let script_path = match stage {
+                ScriptStage::FirstBoot => {
+                    let Some(target_group) = target_group else {
+                        bail!("first-boot scripts require a boot group");
+                    };
+                    if !install_started {
+                        start_install()?;
+                        install_started = true;
+                    }
+                    update_scripts::first_boot_script_path(
+                        target_group.name(),
+                        payload.idx(),
+                        script_type,
+                    )?
+                }
+                _ => scripts_dir.path().join(format!("{}.script", payload.idx())),
+            };
+            let script = UpdateScript::new(script_path, script_type);
+            let target = File::create(&script.path).whatever("unable to create script file")?;
+            payload
+                .decode_into(target, None)
+                .whatever("unable to decode script payload")?;
+            match stage {
+                ScriptStage::PreInstall => script.run(&stage, script_boot_group)?,
+                ScriptStage::PostInstall => post_install_scripts.push(script),
+                ScriptStage::FirstBoot => { /* runs on first boot */ }
+            }
+            continue;
+        }
+        if !install_started {
+            start_install()?;
+            install_started = true;
+        }
         if let Some(slot_type) = &payload_entry.type_slot {
             let slot = boot_group
                 .and_then(|(_, entry)| entry.get_slot(&slot_type.slot))
@@ -557,7 +820,31 @@ fn install_update_bundle<R: BundleSource>(
                     payload.idx(),
                     slot.name()
                 );
-                slot_db::erase(slot.name())?;
+                // File slots are replaced atomically after the payload has been installed,
+                // hence, their previous blocks remain usable while installing the payload.
+                let keeps_previous = matches!(slot.kind(), SlotKind::File { .. });
+                // Algorithms of the block encoding to index the slot with after installing.
+                let mut reindex_with = None;
+                let mut existing_blocks = None;
+                if let (SlotKind::Block(block_slot), Some(block_encoding)) =
+                    (slot.kind(), &payload.header().block_encoding)
+                {
+                    if block_slot.skip_unchanged_blocks() {
+                        // Must be loaded before the indices of the slot are erased.
+                        existing_blocks = SlotBlocks::load(
+                            slot.name(),
+                            &block_encoding.chunker,
+                            block_encoding.hash_algorithm,
+                        )?;
+                        reindex_with = Some((
+                            block_encoding.chunker.clone(),
+                            block_encoding.hash_algorithm,
+                        ));
+                    }
+                }
+                if !keeps_previous {
+                    slot_db::erase(slot.name())?;
+                }
                 let mut block_provider = None;
                 if let Some(block_encoding) = &payload.header().block_encoding {
                     let mut provider = BlockProvider::new(
@@ -565,8 +852,8 @@ fn install_update_bundle<R: BundleSource>(
                         block_encoding.hash_algorithm,
                     );
                     for (_, slot) in system.slots().iter() {
-                        // Since we erased all the indices of the target slot, it
-                        // is fine to also add the target slot here.
+                        // Since we erased all the indices of the target slot, unless it
+                        // keeps its previous version, it is fine to also add it here.
                         match slot.kind() {
                             SlotKind::Block(block_slot) => {
                                 provider.add_slot(
@@ -577,18 +864,31 @@ fn install_update_bundle<R: BundleSource>(
                             SlotKind::File { path } => {
                                 provider.add_slot(slot.name(), path.to_path_buf())?;
                             }
-                            SlotKind::Custom { .. } => { /* nothing to do */ }
+                            SlotKind::Custom { handler } => {
+                                let reader = CustomSlotReader::new(
+                                    handler.iter().map(|arg| arg.as_str()),
+                                    vec![("RUGIX_SLOT_NAME", slot.name().to_owned())],
+                                )?;
+                                provider.add_slot_reader(slot.name(), Box::new(reader))?;
+                            }
+                            SlotKind::Directory { .. } => { /* nothing to do */ }
                         }
                     }
+                    if let Some(cached_bundles) = &system.config().cached_bundles {
+                        let cached_bundles =
+                            cached_bundles.iter().map(PathBuf::from).collect::<Vec<_>>();
+                        provider.add_bundles(&cached_bundles)?;
+                    }
                     block_provider = Some(provider);
                 }
+                if keeps_previous {
+                    // The indices have been loaded and become stale with the update.
+                    slot_db::erase(slot.name())?;
+                }
                 match slot.kind() {
                     SlotKind::Block(block_slot) => {
-                        let target = std::fs::OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .open(block_slot.device())
-                            .whatever("unable to open payload target")?;
+                        let target =
+                            SparseTarget::new(BlockTarget::new(block_slot, existing_blocks)?);
                         payload
                             .decode_into(
                                 target,
@@ -597,15 +897,43 @@ fn install_update_bundle<R: BundleSource>(
                                     .map(|p| p as &dyn StoredBlockProvider),
                             )
                             .whatever("unable to decode payload")?;
+                        if let Some((chunker_algorithm, hash_algorithm)) = &reindex_with {
+                            // Index the new contents such that the next update can skip
+                            // unchanged blocks.
+                            slot_db::add_index(
+                                slot.name(),
+                                block_slot.device().path(),
+                                chunker_algorithm,
+                                hash_algorithm,
+                            )?;
+                        }
                     }
                     SlotKind::File { path } => {
-                        let target = std::fs::OpenOptions::new()
-                            .read(true)
-                            .write(true)
-                            .create(true)
-                            .truncate(true)
-                            .open(path)
-                            .whatever("unable to open payload target")?;
+                        let target = FileTarget::new(path)?;
+                        payload
+                            .decode_into(
+                                target,
+                                block_provider
+                                    .as_ref()
+                                    .map(|p| p as &dyn StoredBlockProvider),
+                            )
+                            .whatever("unable to decode payload")?;
+                    }
+                    SlotKind::Directory { path } => {
+                        if payload
+                            .header()
+                            .block_encoding
+                            .as_ref()
+                            .is_some_and(|block_encoding| block_encoding.deduplicated)
+                        {
+                            // Deduplicated blocks would have to be read back from the
+                            // extracted archive, which is not possible.
+                            bail!(
+                                "directory slot {:?} does not support payloads with deduplicated blocks",
+                                slot.name()
+                            );
+                        }
+                        let target = DirectoryTarget::new(path)?;
                         payload
                             .decode_into(
                                 target,
@@ -616,7 +944,24 @@ fn install_update_bundle<R: BundleSource>(
                             .whatever("unable to decode payload")?;
                     }
                     SlotKind::Custom { handler } => {
-                        let target = CustomTarget::new(handler.iter().map(|arg| arg.as_str()))?;
+                        let mut target = CustomTarget::new(
+                            handler.iter().map(|arg| arg.as_str()),
+                            handler_env(&payload, Some(slot.name()), handler_boot_group),
+                        )?
+                        .with_deduplicated_blocks(
+                            payload
+                                .deduplicated_blocks()
+                                .whatever("unable to decode block encoding")?,
+                        );
+                        if let Some(block_encoding) = &payload.header().block_encoding {
+                            target = target.with_index(
+                                slot.name(),
+                                BlockIndexConfig {
+                                    hash_algorithm: block_encoding.hash_algorithm,
+                                    chunker: block_encoding.chunker.clone(),
+                                },
+                            )?;
+                        }
                         payload
                             .decode_into(
                                 target,
@@ -637,7 +982,15 @@ fn install_update_bundle<R: BundleSource>(
             }
         } else if let Some(type_execute) = &payload_entry.type_execute {
             eprintln!("executing update payload {}", payload.idx(),);
-            let target = CustomTarget::new(type_execute.handler.iter().map(|arg| arg.as_str()))?;
+            let target = CustomTarget::new(
+                type_execute.handler.iter().map(|arg| arg.as_str()),
+                handler_env(&payload, None, handler_boot_group),
+            )?
+            .with_deduplicated_blocks(
+                payload
+                    .deduplicated_blocks()
+                    .whatever("unable to decode block encoding")?,
+            );
             payload
                 .decode_into(target, None)
                 .whatever("unable to decode payload")?;
@@ -646,7 +999,19 @@ fn install_update_bundle<R: BundleSource>(
         payload.skip().whatever("unable to skip payload")?;
     }
 
-    if !bundle_reader.header().is_incremental {
+    if !install_started {
+        start_install()?;
+    }
+
+    for script in &post_install_scripts {
+        script.run(&ScriptStage::PostInstall, script_boot_group)?;
+    }
+
+    if let Some(target_group) = target_group {
+        rollback_protection.set_pending(target_group, bundle_reader.header().security_version)?;
+    }
+
+    if !is_incremental {
         system
             .boot_flow()
             .post_install(system, boot_group.unwrap().0)
@@ -657,27 +1022,111 @@ fn install_update_bundle<R: BundleSource>(
     }
 }
 
+/// Environment variables passed to custom update handlers for the given payload.
+fn handler_env<S: BundleSource>(
+    payload: &PayloadReader<'_, S>,
+    slot: Option<&str>,
+    boot_group: &str,
+) -> Vec<(&'static str, String)> {
+    let bundle_header = payload.bundle_header();
+    let payload_hash =
+        HashDigest::new_unchecked(bundle_header.hash_algorithm, &payload.entry().file_hash.raw);
+    let mut env = vec![
+        ("RUGIX_BOOT_GROUP", boot_group.to_owned()),
+        ("RUGIX_PAYLOAD_INDEX", payload.idx().to_string()),
+        ("RUGIX_PAYLOAD_HASH", payload_hash.to_string()),
+    ];
+    if let Some(file_size) = payload.entry().file_size {
+        env.push(("RUGIX_PAYLOAD_SIZE", file_size.to_string()));
+    } else if payload.header().block_encoding.is_none() {
+        // Without block encoding, the payload data is the payload file itself.
+        env.push(("RUGIX_PAYLOAD_SIZE", payload.data_size().raw.to_string()));
+    }
+    if let Some(slot) = slot {
+        env.push(("RUGIX_SLOT_NAME", slot.to_owned()));
+    }
+    if let Some(security_version) = bundle_header.security_version {
+        env.push((
+            "RUGIX_BUNDLE_SECURITY_VERSION",
+            security_version.to_string(),
+        ));
+    }
+    if let Some(channel) = &bundle_header.channel {
+        env.push(("RUGIX_BUNDLE_CHANNEL", channel.clone()));
+    }
+    env
+}
+
 #[derive(Debug)]
 pub struct CustomTarget {
     child: Child,
+    /// Slot and builder for indexing the installed payload.
+    index: Option<(String, BlockIndexBuilder)>,
+    /// Hashes of the deduplicated blocks which have not been written yet.
+    duplicates: HashSet<Vec<u8>>,
+    /// Hash of the block which is written next.
+    next_hash: Option<Vec<u8>>,
+    /// First occurrences of deduplicated blocks indexed by their offset.
+    ///
+    /// The handler may not have installed the blocks written so far, hence, we keep
+    /// them in memory instead of reading them back with the `read` subcommand.
+    blocks: HashMap<NumBytes, Vec<u8>>,
+    /// Size of the payload written so far.
+    size: NumBytes,
 }
 
 impl CustomTarget {
-    pub fn new<'arg>(mut command: impl Iterator<Item = &'arg str>) -> SystemResult<Self> {
-        let Some(prog) = command.next() else {
+    pub fn new<'arg>(
+        command: impl Iterator<Item = &'arg str>,
+        env: Vec<(&'static str, String)>,
+    ) -> SystemResult<Self> {
+        let command = command.collect::<Vec<_>>();
+        if command.is_empty() {
             bail!("custom update handler cannot be an empty sequence");
-        };
-        let child = std::process::Command::new(prog)
-            .args(command)
+        }
+        let child = std::process::Command::new(command[0])
+            .args(&command[1..])
+            .envs(env)
             .stdin(std::process::Stdio::piped())
             .spawn()
             .whatever("unable to spawn custom update handler")?;
-        Ok(Self { child })
+        Ok(Self {
+            child,
+            index: None,
+            duplicates: HashSet::new(),
+            next_hash: None,
+            blocks: HashMap::new(),
+            size: NumBytes::ZERO,
+        })
+    }
+
+    /// Index the installed payload and store the index for the given slot.
+    ///
+    /// This allows using the slot as a source of blocks for later updates.
+    pub fn with_index(mut self, slot_name: &str, config: BlockIndexConfig) -> SystemResult<Self> {
+        let builder = BlockIndexBuilder::new(config).whatever("unable to create block index")?;
+        self.index = Some((slot_name.to_owned(), builder));
+        Ok(self)
+    }
+
+    /// Keep the first occurrences of the given deduplicated blocks.
+    pub fn with_deduplicated_blocks(mut self, duplicates: HashSet<Vec<u8>>) -> Self {
+        self.duplicates = duplicates;
+        self
     }
 }
 
 impl PayloadTarget for CustomTarget {
     fn write(&mut self, bytes: &[u8]) -> rugix_bundle::BundleResult<()> {
+        if let Some((_, builder)) = &mut self.index {
+            builder.process(bytes);
+        }
+        if let Some(hash) = self.next_hash.take() {
+            if self.duplicates.remove(&hash) {
+                self.blocks.insert(self.size, bytes.to_vec());
+            }
+        }
+        self.size += bytes.byte_len();
         self.child
             .stdin
             .as_mut()
@@ -686,6 +1135,30 @@ impl PayloadTarget for CustomTarget {
             .whatever("unable to write payload to custom handler")
     }
 
+    fn read_block(
+        &mut self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> rugix_bundle::BundleResult<()> {
+        match self.blocks.get(&offset) {
+            Some(block) if block.byte_len() == size => {
+                buffer.clear();
+                buffer.extend_from_slice(block);
+                Ok(())
+            }
+            _ => bail!("unable to find deduplicated block at offset {offset}"),
+        }
+    }
+
+    fn existing_block(&mut self, _: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        // Every block is written, so we remember the hash of the next block here.
+        if !self.duplicates.is_empty() {
+            self.next_hash = Some(hash.to_vec());
+        }
+        None
+    }
+
     fn finalize(mut self) -> rugix_bundle::BundleResult<()> {
         info!("waiting on custom update handler to finalize");
         // Flush all bytes and close stdin.
@@ -700,10 +1173,114 @@ impl PayloadTarget for CustomTarget {
                 status.code()
             )
         }
+        if let Some((slot_name, builder)) = self.index.take() {
+            slot_db::store_index(&slot_name, &builder.finalize())
+                .whatever("unable to store index of custom slot")?;
+        }
         Ok(())
     }
 }
 
+impl Drop for CustomTarget {
+    fn drop(&mut self) {
+        // Kill the handler before closing stdin, such that it does not mistake an
+        // aborted installation, e.g., due to a hash mismatch, for a complete payload.
+        // This has no effect if the handler has already been waited for.
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// Reader for blocks of a slot with a custom update handler.
+///
+/// Blocks are read with the `read` subcommand of the handler. The handler is spawned
+/// once and receives the requested ranges as `<offset> <size>` lines on stdin, for each
+/// of which it must write exactly the requested range of the installed payload to stdout.
+#[derive(Debug)]
+pub struct CustomSlotReader {
+    command: Vec<String>,
+    env: Vec<(&'static str, String)>,
+    /// Running `read` subcommand, spawned when the first block is read.
+    process: RefCell<Option<CustomReadProcess>>,
+}
+
+impl CustomSlotReader {
+    pub fn new<'arg>(
+        command: impl Iterator<Item = &'arg str>,
+        env: Vec<(&'static str, String)>,
+    ) -> SystemResult<Self> {
+        let command = command.map(str::to_owned).collect::<Vec<_>>();
+        if command.is_empty() {
+            bail!("custom update handler cannot be an empty sequence");
+        }
+        Ok(Self {
+            command,
+            env,
+            process: RefCell::new(None),
+        })
+    }
+
+    fn spawn(&self) -> SystemResult<CustomReadProcess> {
+        let mut child = std::process::Command::new(&self.command[0])
+            .args(&self.command[1..])
+            .arg("read")
+            .envs(self.env.iter().map(|(name, value)| (name, value)))
+            .stdin(std::process::Stdio::piped())
+            .stdout(std::process::Stdio::piped())
+            .stderr(std::process::Stdio::inherit())
+            .spawn()
+            .whatever("unable to spawn custom update handler")?;
+        Ok(CustomReadProcess {
+            stdin: child.stdin.take(),
+            stdout: child.stdout.take().unwrap(),
+            child,
+        })
+    }
+}
+
+impl BlockReader for CustomSlotReader {
+    fn read_block(
+        &self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> rugix_bundle::BundleResult<()> {
+        let mut process = self.process.borrow_mut();
+        if process.is_none() {
+            *process = Some(self.spawn().whatever("unable to read block")?);
+        }
+        let process = process.as_mut().unwrap();
+        let stdin = process.stdin.as_mut().unwrap();
+        writeln!(stdin, "{} {}", offset.raw, size.raw)
+            .and_then(|_| stdin.flush())
+            .whatever("unable to request block from custom update handler")?;
+        buffer.resize(size.unwrap_usize(), 0);
+        process
+            .stdout
+            .read_exact(buffer)
+            .whatever("unable to read block from custom update handler")?;
+        Ok(())
+    }
+}
+
+/// Running `read` subcommand of a custom update handler.
+#[derive(Debug)]
+struct CustomReadProcess {
+    child: Child,
+    stdin: Option<ChildStdin>,
+    stdout: ChildStdout,
+}
+
+impl Drop for CustomReadProcess {
+    fn drop(&mut self) {
+        // Closing stdin signals the handler that no further blocks are requested.
+        drop(self.stdin.take());
+        if let Err(error) = self.child.wait() {
+            error!("error waiting for custom update handler: {error}");
+        }
+    }
+}
+
 #[derive(Debug, Clone, ValueEnum)]
 pub enum Boolean {
     True,
@@ -756,6 +1333,14 @@ pub enum SlotsCommand {
         chunker: ChunkerAlgorithm,
         hash_algorithm: HashAlgorithm,
     },
+    /// Export an index of a slot, e.g., for estimating the size of updates.
+    ExportIndex {
+        slot: String,
+        chunker: ChunkerAlgorithm,
+        hash_algorithm: HashAlgorithm,
+        /// Output file for the index.
+        output: PathBuf,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -785,6 +1370,11 @@ pub enum UpdateCommand {
         /// Boot group to install the update to.
         #[clap(long)]
         boot_group: Option<String>,
+        /// Block map of the image, unmapped ranges are not written.
+        ///
+        /// Only supported for images. Payloads of bundles can be sparse images instead.
+        #[clap(long)]
+        bmap: Option<PathBuf>,
     },
 }
 
@@ -795,6 +1385,45 @@ pub enum UpdateRebootType {
     Deferred,
 }
 
+fn check_config(options: &CheckOptions) -> SystemResult<()> {
+    let check = ConfigCheck::run(options);
+    eprintln!("Configuration Files:");
+    for file in &check.files {
+        eprintln!("  {}", file.display());
+    }
+    eprintln!("Partitions:");
+    for (name, description) in &check.partitions {
+        eprintln!("  {name}: {description}");
+    }
+    eprintln!("Slots:");
+    for (name, description) in &check.slots {
+        eprintln!("  {name:?}: {description}");
+    }
+    eprintln!("Boot Groups:");
+    for (name, slots) in &check.boot_groups {
+        let slots = slots
+            .iter()
+            .map(|(alias, slot)| format!("{alias} = {slot:?}"))
+            .collect::<Vec<_>>();
+        eprintln!("  {name:?}: {}", slots.join(", "));
+    }
+    eprintln!(
+        "Boot Flow: {}",
+        check.boot_flow.as_deref().unwrap_or("<unknown>")
+    );
+    if !check.problems.is_empty() {
+        eprintln!();
+        for problem in &check.problems {
+            eprintln!("{problem}");
+        }
+    }
+    let num_errors = check.num_errors();
+    if num_errors > 0 {
+        bail!("found {num_errors} error(s) in the configuration");
+    }
+    Ok(())
+}
+
 #[derive(Debug, Parser)]
 pub enum SystemCommand {
     Info {
@@ -810,6 +1439,18 @@ pub enum SystemCommand {
         #[clap(long)]
         spare: bool,
     },
+    /// Check the system configuration and show how it is resolved.
+    CheckConfig {
+        /// Root filesystem to load the configuration files from.
+        #[clap(long, default_value = "/")]
+        root: PathBuf,
+        /// Disk or disk image to resolve partitions with instead of the root device.
+        #[clap(long)]
+        disk: Option<PathBuf>,
+        /// Contents of the config partition to detect the boot flow with.
+        #[clap(long)]
+        config_dir: Option<PathBuf>,
+    },
 }
 
 #[derive(Debug, Parser)]
@@ -0,0 +1,120 @@
+//! Evaluation of payload conditions for hardware-variant selection.
+
+use std::path::Path;
+
+use reportify::ResultExt;
+use rugix_bundle::format::PayloadCondition;
+use tracing::debug;
+
+use crate::system::{paths, System, SystemResult};
+
+/// Path of the device tree `compatible` property relative to the root.
+const DEVICE_TREE_COMPATIBLE: &str = "proc/device-tree/compatible";
+
+/// Path of the DMI product name relative to the root.
+const DMI_PRODUCT_NAME: &str = "sys/class/dmi/id/product_name";
+
+/// Check whether the given payload condition matches the system.
+///
+/// All properties specified in the condition must match.
+pub fn condition_matches(system: &System, condition: &PayloadCondition) -> SystemResult<bool> {
+    let root = paths::root_prefix();
+    if let Some(compatible) = &condition.compatible {
+        if !compatible_matches(root, compatible)? {
+            return Ok(false);
+        }
+    }
+    if let Some(product_name) = &condition.dmi_product_name {
+        if !dmi_product_name_matches(root, product_name)? {
+            return Ok(false);
+        }
+    }
+    if let Some(variant) = &condition.variant {
+        if system.config().variant.as_ref() != Some(variant) {
+            return Ok(false);
+        }
+    }
+    Ok(true)
+}
+
+/// Check whether the device tree of the device under the given root is compatible.
+fn compatible_matches(root: &Path, compatible: &str) -> SystemResult<bool> {
+    let device_compatible = read_device_tree_compatible(root)?;
+    debug!("device tree compatible: {device_compatible:?}");
+    Ok(device_compatible.iter().any(|value| value == compatible))
+}
+
+/// Check whether the DMI product name of the device under the given root matches.
+fn dmi_product_name_matches(root: &Path, product_name: &str) -> SystemResult<bool> {
+    let device_product_name = read_dmi_product_name(root)?;
+    debug!("DMI product name: {device_product_name:?}");
+    Ok(device_product_name.as_deref() == Some(product_name))
+}
+
+/// Read the device tree `compatible` strings of the device.
+fn read_device_tree_compatible(root: &Path) -> SystemResult<Vec<String>> {
+    let path = &root.join(DEVICE_TREE_COMPATIBLE);
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    let raw = std::fs::read(path).whatever("unable to read device tree `compatible`")?;
+    Ok(raw
+        .split(|byte| *byte == 0)
+        .filter(|value| !value.is_empty())
+        .map(|value| String::from_utf8_lossy(value).into_owned())
+        .collect())
+}
+
+/// Read the DMI product name of the device.
+fn read_dmi_product_name(root: &Path) -> SystemResult<Option<String>> {
+    let path = &root.join(DMI_PRODUCT_NAME);
+    if !path.exists() {
+        return Ok(None);
+    }
+    let product_name = std::fs::read_to_string(path).whatever("unable to read DMI product name")?;
+    Ok(Some(product_name.trim().to_owned()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use super::{
+        compatible_matches, dmi_product_name_matches, DEVICE_TREE_COMPATIBLE, DMI_PRODUCT_NAME,
+    };
+
+    fn write(root: &Path, path: &str, contents: &[u8]) {
+        let path = root.join(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    #[test]
+    fn test_device_tree_compatible() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        // Without a device tree, conditions on `compatible` never match.
+        assert!(!compatible_matches(root, "acme,board-a").unwrap());
+        write(
+            root,
+            DEVICE_TREE_COMPATIBLE,
+            b"acme,board-a-rev2\0acme,board-a\0acme,soc\0",
+        );
+        assert!(compatible_matches(root, "acme,board-a").unwrap());
+        assert!(compatible_matches(root, "acme,soc").unwrap());
+        assert!(!compatible_matches(root, "acme,board-b").unwrap());
+        // Prefixes of `compatible` strings do not match.
+        assert!(!compatible_matches(root, "acme,board").unwrap());
+    }
+
+    #[test]
+    fn test_dmi_product_name() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        assert!(!dmi_product_name_matches(root, "Gateway 3000").unwrap());
+        write(root, DMI_PRODUCT_NAME, b"Gateway 3000\n");
+        assert!(dmi_product_name_matches(root, "Gateway 3000").unwrap());
+        assert!(!dmi_product_name_matches(root, "Gateway").unwrap());
+    }
+}
@@ -0,0 +1,317 @@
+//! Installation of Tar payloads into directory slots.
+//!
+//! A directory slot is updated by extracting the payload into a fresh sibling directory
+//! and, after the payload has been verified, atomically swapping it with the previous
+//! version. The previous version is kept next to the new version, however, it is not
+//! restored automatically: The new version becomes active at installation time,
+//! independently of the boot group, and stays active on a fallback to the previous
+//! boot group.
+//!
+//! If the slot's path does not exist or is a symbolic link, the new version is
+//! activated by atomically replacing the symbolic link. If the slot's path is a
+//! directory, the new version is activated by exchanging both directories with
+//! `renameat2(RENAME_EXCHANGE)`.
+
+use std::ffi::CString;
+use std::fs;
+use std::io::{self, Write};
+use std::os::unix::ffi::OsStrExt;
+use std::path::{Path, PathBuf};
+use std::process::Child;
+
+use reportify::{bail, ResultExt};
+use rugix_bundle::reader::PayloadTarget;
+use tracing::{info, warn};
+
+use crate::system::SystemResult;
+
+/// Payload target for directory slots.
+#[derive(Debug)]
+pub struct DirectoryTarget {
+    /// Path of the slot.
+    path: PathBuf,
+    /// Fresh directory the payload is extracted into.
+    staging: PathBuf,
+    /// Tar process extracting the payload.
+    child: Child,
+    /// Indicates whether the new version has been activated.
+    activated: bool,
+}
+
+impl DirectoryTarget {
+    /// Start extracting a payload for the directory slot with the given path.
+    pub fn new(path: &Path) -> SystemResult<Self> {
+        let (Some(parent), Some(_)) = (path.parent(), path.file_name()) else {
+            bail!("invalid directory slot path {path:?}");
+        };
+        fs::create_dir_all(parent).whatever("unable to create parent of directory slot")?;
+        let staging = parent.join(format!("{}{}", version_prefix(path), uuid::Uuid::new_v4()));
+        fs::create_dir(&staging).whatever("unable to create staging directory")?;
+        let child = std::process::Command::new("tar")
+            .arg("-x")
+            .arg("-C")
+            .arg(&staging)
+            .stdin(std::process::Stdio::piped())
+            .spawn()
+            .whatever("unable to spawn `tar`")?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            staging,
+            child,
+            activated: false,
+        })
+    }
+}
+
+impl PayloadTarget for DirectoryTarget {
+    fn write(&mut self, bytes: &[u8]) -> rugix_bundle::BundleResult<()> {
+        self.child
+            .stdin
+            .as_mut()
+            .unwrap()
+            .write_all(bytes)
+            .whatever("unable to write payload to `tar`")
+    }
+
+    fn finalize(mut self) -> rugix_bundle::BundleResult<()> {
+        // Flush all bytes and close stdin.
+        drop(self.child.stdin.take());
+        let status = self.child.wait().whatever("error waiting for `tar`")?;
+        if !status.success() {
+            bail!("error extracting payload, code {:?}", status.code());
+        }
+        // Make sure that the extracted files are persisted before the swap.
+        sync_tree(&self.staging).whatever("unable to synchronize extracted files")?;
+        let previous =
+            activate(&self.path, &self.staging).whatever("unable to activate new version")?;
+        self.activated = true;
+        info!(
+            "activated new version {:?} of {:?}",
+            self.staging, self.path
+        );
+        if let Err(error) = remove_old_versions(&self.path, previous.as_deref()) {
+            warn!("unable to remove old versions of {:?}: {error}", self.path);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for DirectoryTarget {
+    fn drop(&mut self) {
+        if !self.activated {
+            // The payload has not been verified, discard everything.
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            let _ = fs::remove_dir_all(&self.staging);
+        }
+    }
+}
+
+/// Prefix of the sibling directories holding the versions of a directory slot.
+fn version_prefix(path: &Path) -> String {
+    let name = path.file_name().unwrap_or_default();
+    format!(".{}.", name.to_string_lossy())
+}
+
+/// Synchronize the files and directories of the given tree with the disk.
+///
+/// Symbolic links are persisted by synchronizing the directories containing them.
+fn sync_tree(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            sync_tree(&entry?.path())?;
+        }
+    } else if !metadata.is_file() {
+        return Ok(());
+    }
+    fs::File::open(path)?.sync_all()
+}
+
+/// Atomically activate the new version in `staging`.
+///
+/// Returns the path of the previous version, if there is any.
+fn activate(path: &Path, staging: &Path) -> io::Result<Option<PathBuf>> {
+    let parent = path.parent().expect("path must have a parent");
+    // Persist the entry of the staging directory before activating it.
+    fs::File::open(parent)?.sync_all()?;
+    let previous = match fs::symlink_metadata(path) {
+        Ok(metadata) if metadata.file_type().is_dir() => {
+            // After the exchange, `staging` contains the previous version.
+            rename_exchange(staging, path)?;
+            Some(staging.to_path_buf())
+        }
+        Ok(metadata) if !metadata.file_type().is_symlink() => {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "slot path exists but is neither a directory nor a symbolic link",
+            ));
+        }
+        _ => {
+            let previous = fs::read_link(path).ok().map(|target| parent.join(target));
+            let link = parent.join(format!("{}link", version_prefix(path)));
+            match fs::remove_file(&link) {
+                Err(error) if error.kind() != io::ErrorKind::NotFound => return Err(error),
+                _ => { /* link does not exist (anymore) */ }
+            }
+            // Use a relative link such that it works independently of mount points.
+            std::os::unix::fs::symlink(staging.file_name().unwrap(), &link)?;
+            fs::rename(&link, path)?;
+            previous
+        }
+    };
+    fs::File::open(parent)?.sync_all()?;
+    Ok(previous)
+}
+
+/// Remove all versions except for the current and the previous one.
+fn remove_old_versions(path: &Path, previous: Option<&Path>) -> io::Result<()> {
+    let parent = path.parent().expect("path must have a parent");
+    let prefix = version_prefix(path);
+    let current = fs::read_link(path).ok().map(|target| parent.join(target));
+    for entry in fs::read_dir(parent)? {
+        let entry = entry?;
+        if !entry.file_name().to_string_lossy().starts_with(&prefix) || !entry.file_type()?.is_dir()
+        {
+            continue;
+        }
+        let version = entry.path();
+        if Some(version.as_path()) == current.as_deref() || Some(version.as_path()) == previous {
+            continue;
+        }
+        fs::remove_dir_all(version)?;
+    }
+    Ok(())
+}
+
+/// Atomically exchange the two given paths.
+fn rename_exchange(old: &Path, new: &Path) -> io::Result<()> {
+    /// Flag of `renameat2` to atomically exchange both paths.
+    const RENAME_EXCHANGE: nix::libc::c_uint = 1 << 1;
+    let old = CString::new(old.as_os_str().as_bytes())?;
+    let new = CString::new(new.as_os_str().as_bytes())?;
+    let result = unsafe {
+        // SAFETY: The provided paths are proper `\0`-terminated strings.
+        nix::libc::syscall(
+            nix::libc::SYS_renameat2,
+            nix::libc::AT_FDCWD,
+            old.as_ptr(),
+            nix::libc::AT_FDCWD,
+            new.as_ptr(),
+            RENAME_EXCHANGE,
+        )
+    };
+    if result != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+    use std::process::Command;
+
+    use rugix_bundle::reader::PayloadTarget;
+
+    use super::{version_prefix, DirectoryTarget};
+
+    /// Create a Tar archive with a file `version` with the given contents.
+    fn archive(version: &str) -> Vec<u8> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join("version"), version).unwrap();
+        fs::create_dir(temp_dir.path().join("data")).unwrap();
+        fs::write(temp_dir.path().join("data/file"), version).unwrap();
+        let output = Command::new("tar")
+            .arg("-c")
+            .arg("-C")
+            .arg(temp_dir.path())
+            .arg(".")
+            .output()
+            .unwrap();
+        assert!(output.status.success());
+        output.stdout
+    }
+
+    fn install(path: &Path, payload: &[u8]) -> rugix_bundle::BundleResult<()> {
+        let mut target = DirectoryTarget::new(path).unwrap();
+        PayloadTarget::write(&mut target, payload)?;
+        target.finalize()
+    }
+
+    /// Names of the sibling directories holding versions of the slot.
+    fn versions(path: &Path) -> Vec<String> {
+        let prefix = version_prefix(path);
+        let mut versions = fs::read_dir(path.parent().unwrap())
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().to_string_lossy().into_owned())
+            .filter(|name| name.starts_with(&prefix))
+            .collect::<Vec<_>>();
+        versions.sort();
+        versions
+    }
+
+    #[test]
+    fn test_symlink_swap() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("app");
+        install(&path, &archive("v1")).unwrap();
+        assert!(path.symlink_metadata().unwrap().is_symlink());
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v1");
+        assert_eq!(fs::read_to_string(path.join("data/file")).unwrap(), "v1");
+        let v1 = fs::read_link(&path).unwrap();
+        install(&path, &archive("v2")).unwrap();
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v2");
+        // The previous version is kept.
+        let v2 = fs::read_link(&path).unwrap();
+        let previous = temp_dir.path().join(&v1);
+        assert_eq!(fs::read_to_string(previous.join("version")).unwrap(), "v1");
+        install(&path, &archive("v3")).unwrap();
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v3");
+        // Only the current and the previous version are kept.
+        let v3 = fs::read_link(&path).unwrap();
+        let mut expected = [v2, v3]
+            .iter()
+            .map(|version| version.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        expected.sort();
+        assert_eq!(versions(&path), expected);
+    }
+
+    #[test]
+    fn test_directory_exchange() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("app");
+        fs::create_dir(&path).unwrap();
+        fs::write(path.join("version"), "v1").unwrap();
+        install(&path, &archive("v2")).unwrap();
+        // The directory has been exchanged with the new version in place.
+        assert!(path.symlink_metadata().unwrap().is_dir());
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v2");
+        let kept = versions(&path);
+        assert_eq!(kept.len(), 1);
+        let previous = temp_dir.path().join(&kept[0]);
+        assert_eq!(fs::read_to_string(previous.join("version")).unwrap(), "v1");
+        install(&path, &archive("v3")).unwrap();
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v3");
+        // The first version has been removed, the second one is kept.
+        let kept = versions(&path);
+        assert_eq!(kept.len(), 1);
+        let previous = temp_dir.path().join(&kept[0]);
+        assert_eq!(fs::read_to_string(previous.join("version")).unwrap(), "v2");
+    }
+
+    #[test]
+    fn test_invalid_archive() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("app");
+        install(&path, &archive("v1")).unwrap();
+        let current = fs::read_link(&path).unwrap();
+        assert!(install(&path, b"not a tar archive").is_err());
+        // The current version stays active and the staging directory is removed.
+        assert_eq!(fs::read_link(&path).unwrap(), current);
+        assert_eq!(fs::read_to_string(path.join("version")).unwrap(), "v1");
+        assert_eq!(versions(&path), [current.to_string_lossy()]);
+    }
+}
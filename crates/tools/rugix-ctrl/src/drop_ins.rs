@@ -0,0 +1,310 @@
+//! Loading of configuration files with drop-in directories.
+//!
+//! A configuration file `<name>.toml` in `/etc/rugix` can be extended by drop-in files in
+//! `/usr/lib/rugix/<name>.d` and `/etc/rugix/<name>.d`. Drop-in files are applied in
+//! lexical order of their file names after the main configuration file. If both
+//! directories contain a drop-in file with the same name, the one in `/etc/rugix` is
+//! used. This allows different layers of an image, e.g., a board and a product layer, to
+//! each contribute parts of the configuration.
+
+use std::collections::BTreeMap;
+use std::ffi::OsString;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reportify::ResultExt;
+use serde::de::DeserializeOwned;
+
+use crate::system::{paths, SystemResult};
+
+/// Directory with the main configuration files.
+pub const CONFIG_DIR: &str = "/etc/rugix";
+
+/// Directories with drop-in directories in order of increasing precedence.
+pub const DROP_IN_DIRS: &[&str] = &["/usr/lib/rugix", CONFIG_DIR];
+
+/// Rules for merging configuration files.
+///
+/// Paths are sequences of keys separated by `.` where `*` matches any key. Tables are
+/// merged recursively and all other values of later files replace those of earlier files,
+/// unless specified otherwise by the rules.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct MergeRules {
+    /// Tables which are replaced as a whole instead of being merged.
+    pub replace: &'static [&'static str],
+    /// Arrays to which the values of later files are appended.
+    pub append: &'static [&'static str],
+}
+
+impl MergeRules {
+    fn is_replace(&self, path: &[String]) -> bool {
+        self.replace
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+
+    fn is_append(&self, path: &[String]) -> bool {
+        self.append
+            .iter()
+            .any(|pattern| path_matches(pattern, path))
+    }
+}
+
+/// Check whether the given path matches the pattern.
+fn path_matches(pattern: &str, path: &[String]) -> bool {
+    let mut segments = pattern.split('.');
+    for key in path {
+        match segments.next() {
+            Some(segment) if segment == "*" || segment == key => { /* continue */ }
+            _ => return false,
+        }
+    }
+    segments.next().is_none()
+}
+
+/// Find the configuration files with the given name in order of application.
+pub fn config_files(name: &str) -> SystemResult<Vec<PathBuf>> {
+    config_files_in(paths::root_prefix(), name)
+}
+
+/// Find the configuration files with the given name in the given root filesystem.
+pub fn config_files_in(root: &Path, name: &str) -> SystemResult<Vec<PathBuf>> {
+    let prefixed = |dir: &str| root.join(dir.trim_start_matches('/'));
+    let dirs = DROP_IN_DIRS
+        .iter()
+        .map(|dir| prefixed(dir))
+        .collect::<Vec<_>>();
+    let dirs = dirs.iter().map(PathBuf::as_path).collect::<Vec<_>>();
+    find_config_files(&prefixed(CONFIG_DIR), &dirs, name)
+}
+
+fn find_config_files(config_dir: &Path, dirs: &[&Path], name: &str) -> SystemResult<Vec<PathBuf>> {
+    let mut files = Vec::new();
+    let main_file = config_dir.join(format!("{name}.toml"));
+    if main_file.exists() {
+        files.push(main_file);
+    }
+    let mut drop_ins = BTreeMap::<OsString, PathBuf>::new();
+    for dir in dirs {
+        let drop_in_dir = dir.join(format!("{name}.d"));
+        if !drop_in_dir.is_dir() {
+            continue;
+        }
+        let read_dir = fs::read_dir(&drop_in_dir)
+            .whatever("unable to read drop-in directory")
+            .with_info(|_| format!("path: {drop_in_dir:?}"))?;
+        for entry in read_dir {
+            let entry = entry.whatever("unable to read drop-in directory entry")?;
+            let path = entry.path();
+            if path.extension().is_some_and(|ext| ext == "toml") && path.is_file() {
+                drop_ins.insert(entry.file_name(), path);
+            }
+        }
+    }
+    files.extend(drop_ins.into_values());
+    Ok(files)
+}
+
+/// Load the configuration files with the given name and merge them.
+pub fn load_config<T: DeserializeOwned + Default>(
+    name: &str,
+    rules: MergeRules,
+) -> SystemResult<T> {
+    load_config_files(&config_files(name)?, rules)
+}
+
+/// Load and merge the given configuration files.
+pub fn load_config_files<T: DeserializeOwned + Default>(
+    files: &[PathBuf],
+    rules: MergeRules,
+) -> SystemResult<T> {
+    if files.is_empty() {
+        return Ok(T::default());
+    }
+    let mut merged = toml::Table::new();
+    for path in files {
+        let table = fs::read_to_string(path)
+            .whatever("unable to read configuration file")
+            .and_then(|source| {
+                toml::from_str::<toml::Table>(&source)
+                    .whatever("unable to parse configuration file")
+            })
+            .with_info(|_| format!("path: {path:?}"))?;
+        merge_tables(&mut merged, table, &rules, &mut Vec::new());
+    }
+    toml::Value::Table(merged)
+        .try_into()
+        .whatever("invalid configuration")
+        .with_info(|_| format!("files: {files:?}"))
+}
+
+/// Merge the `other` table into the `target` table.
+fn merge_tables(
+    target: &mut toml::Table,
+    other: toml::Table,
+    rules: &MergeRules,
+    path: &mut Vec<String>,
+) {
+    for (key, value) in other {
+        path.push(key.clone());
+        match (target.get_mut(&key), value) {
+            (Some(toml::Value::Table(existing)), toml::Value::Table(table))
+                if !rules.is_replace(path) =>
+            {
+                merge_tables(existing, table, rules, path);
+            }
+            (Some(toml::Value::Array(existing)), toml::Value::Array(array))
+                if rules.is_append(path) =>
+            {
+                existing.extend(array);
+            }
+            (_, value) => {
+                target.insert(key, value);
+            }
+        }
+        path.pop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use indoc::indoc;
+
+    use super::{find_config_files, load_config_files, MergeRules};
+    use crate::config::system::{SlotConfig, SystemConfig};
+    use crate::system::config::SYSTEM_MERGE_RULES;
+
+    #[test]
+    fn test_find_config_files() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let etc = temp_dir.path().join("etc");
+        let usr = temp_dir.path().join("usr");
+        fs::create_dir_all(etc.join("system.d")).unwrap();
+        fs::create_dir_all(usr.join("system.d")).unwrap();
+        for path in [
+            etc.join("system.toml"),
+            etc.join("system.d/20-product.toml"),
+            etc.join("system.d/10-board.toml"),
+            etc.join("system.d/README"),
+            usr.join("system.d/10-board.toml"),
+            usr.join("system.d/00-base.toml"),
+        ] {
+            fs::write(path, "").unwrap();
+        }
+        let files = find_config_files(&etc, &[&usr, &etc], "system").unwrap();
+        assert_eq!(
+            files,
+            [
+                etc.join("system.toml"),
+                usr.join("system.d/00-base.toml"),
+                etc.join("system.d/10-board.toml"),
+                etc.join("system.d/20-product.toml"),
+            ]
+        );
+        assert!(
+            find_config_files(&etc, &[Path::new("/nonexistent")], "state")
+                .unwrap()
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn test_merge_system_config() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let board = temp_dir.path().join("10-board.toml");
+        let product = temp_dir.path().join("20-product.toml");
+        fs::write(
+            &board,
+            indoc! {r#"
+                [data-partition]
+                partition = 7
+                protected = true
+
+                [slots.system-a]
+                type = "block"
+                partition = 2
+
+                [slots.app]
+                type = "block"
+                partition = 5
+            "#},
+        )
+        .unwrap();
+        fs::write(
+            &product,
+            indoc! {r#"
+                [data-partition]
+                protected = false
+
+                [slots.app]
+                type = "file"
+                path = "/data/app.img"
+            "#},
+        )
+        .unwrap();
+        let config: SystemConfig =
+            load_config_files(&[board, product], SYSTEM_MERGE_RULES).unwrap();
+        let data_partition = config.data_partition.unwrap();
+        assert_eq!(data_partition.partition, Some(7));
+        assert_eq!(data_partition.protected, Some(false));
+        let slots = config.slots.unwrap();
+        assert!(matches!(slots["system-a"], SlotConfig::Block(_)));
+        assert!(matches!(slots["app"], SlotConfig::File(_)));
+        let default: SystemConfig = load_config_files(&[], MergeRules::default()).unwrap();
+        assert!(default.slots.is_none());
+    }
+
+    #[test]
+    fn test_merge_preserves_order() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let board = temp_dir.path().join("10-board.toml");
+        let product = temp_dir.path().join("20-product.toml");
+        fs::write(
+            &board,
+            indoc! {r#"
+                [slots.system-b]
+                type = "block"
+                partition = 3
+
+                [slots.system-a]
+                type = "block"
+                partition = 2
+
+                [boot-groups.primary]
+                slots = { system = "system-b" }
+
+                [boot-groups.fallback]
+                slots = { system = "system-a" }
+            "#},
+        )
+        .unwrap();
+        fs::write(
+            &product,
+            indoc! {r#"
+                [slots.app]
+                type = "file"
+                path = "/data/app.img"
+
+                [boot-groups.primary]
+                slots = { system = "system-b", app = "app" }
+            "#},
+        )
+        .unwrap();
+        let config: SystemConfig =
+            load_config_files(&[board, product], SYSTEM_MERGE_RULES).unwrap();
+        // The first two boot groups are used as A and B, hence, the order matters.
+        let boot_groups = config.boot_groups.unwrap();
+        assert_eq!(
+            boot_groups.keys().collect::<Vec<_>>(),
+            ["primary", "fallback"]
+        );
+        let slots = config.slots.unwrap();
+        assert_eq!(
+            slots.keys().collect::<Vec<_>>(),
+            ["system-b", "system-a", "app"]
+        );
+    }
+}
@@ -0,0 +1,140 @@
+//! Crash-safe installation of payloads into file slots.
+//!
+//! The payload is written to a temporary file in the same directory as the slot's file.
+//! Only after the payload has been verified, the temporary file is persisted and
+//! atomically renamed over the slot's file. Until then, the previous file remains intact
+//! and can be used as a source of blocks for deduplication.
+
+use std::os::unix::fs::MetadataExt;
+use std::path::{Path, PathBuf};
+use std::{fs, io};
+
+use byte_calc::NumBytes;
+use reportify::{bail, ResultExt};
+use rugix_bundle::reader::PayloadTarget;
+
+use crate::system::SystemResult;
+
+/// Payload target for file slots.
+#[derive(Debug)]
+pub struct FileTarget {
+    /// Path of the slot.
+    path: PathBuf,
+    /// Temporary file the payload is written to.
+    tmp_path: PathBuf,
+    /// Handle of the temporary file.
+    file: Option<fs::File>,
+    /// Indicates whether the temporary file has been renamed over the slot's file.
+    installed: bool,
+}
+
+impl FileTarget {
+    /// Create a payload target for the file slot with the given path.
+    pub fn new(path: &Path) -> SystemResult<Self> {
+        let (Some(parent), Some(name)) = (path.parent(), path.file_name()) else {
+            bail!("invalid file slot path {path:?}");
+        };
+        let tmp_path = parent.join(format!(
+            ".{}.{}.tmp",
+            name.to_string_lossy(),
+            uuid::Uuid::new_v4()
+        ));
+        let file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&tmp_path)
+            .whatever("unable to create temporary file")
+            .with_info(|_| format!("path: {tmp_path:?}"))?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            tmp_path,
+            file: Some(file),
+            installed: false,
+        })
+    }
+
+    fn file(&mut self) -> &mut fs::File {
+        self.file.as_mut().expect("file has not been finalized")
+    }
+}
+
+impl PayloadTarget for FileTarget {
+    fn write(&mut self, bytes: &[u8]) -> rugix_bundle::BundleResult<()> {
+        PayloadTarget::write(self.file(), bytes)
+    }
+
+    fn read_block(
+        &mut self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> rugix_bundle::BundleResult<()> {
+        PayloadTarget::read_block(self.file(), offset, size, buffer)
+    }
+
+    fn finalize(mut self) -> rugix_bundle::BundleResult<()> {
+        let file = self.file.take().expect("file has not been finalized");
+        copy_metadata(&self.path, &file)
+            .whatever("unable to copy permissions of slot file")
+            .with_info(|_| format!("path: {:?}", self.path))?;
+        file.sync_all()
+            .whatever("unable to persist temporary file")?;
+        drop(file);
+        fs::rename(&self.tmp_path, &self.path)
+            .whatever("unable to replace slot file")
+            .with_info(|_| format!("path: {:?}", self.path))?;
+        self.installed = true;
+        let parent = self.path.parent().expect("path must have a parent");
+        fs::File::open(parent)
+            .and_then(|parent| parent.sync_all())
+            .whatever("unable to persist slot directory")?;
+        Ok(())
+    }
+}
+
+impl Drop for FileTarget {
+    fn drop(&mut self) {
+        if !self.installed {
+            // The payload has not been installed, remove the temporary file.
+            let _ = fs::remove_file(&self.tmp_path);
+        }
+    }
+}
+
+/// Copy the permissions and ownership of the slot's file, if it exists, to the new file.
+fn copy_metadata(path: &Path, file: &fs::File) -> io::Result<()> {
+    let metadata = match fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(error) => return Err(error),
+    };
+    std::os::unix::fs::fchown(file, Some(metadata.uid()), Some(metadata.gid()))?;
+    // Set the permissions after changing the ownership as `fchown` may clear the
+    // set-user-ID and set-group-ID bits.
+    file.set_permissions(metadata.permissions())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::os::unix::fs::PermissionsExt;
+
+    use rugix_bundle::reader::PayloadTarget;
+
+    use super::FileTarget;
+
+    #[test]
+    fn test_preserve_permissions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let path = temp_dir.path().join("slot.img");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o750)).unwrap();
+        let mut target = FileTarget::new(&path).unwrap();
+        PayloadTarget::write(&mut target, b"new").unwrap();
+        target.finalize().unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "new");
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(mode & 0o7777, 0o750);
+    }
+}
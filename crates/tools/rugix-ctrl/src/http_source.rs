@@ -48,6 +48,10 @@ impl HttpSource {
 
 impl BundleSource for HttpSource {
     fn read(&mut self, slice: &mut [u8]) -> rugix_bundle::BundleResult<usize> {
+        if slice.is_empty() {
+            // Reading into an empty slice is reported as a stalled body by `ureq`.
+            return Ok(0);
+        }
         if self.current_skipped > 0 {
             self.current_position += self.current_skipped;
             if self.current_skipped > NumBytes::kibibytes(32) && self.supports_range {
@@ -90,4 +94,176 @@ impl BundleSource for HttpSource {
         self.current_skipped += length.raw;
         Ok(())
     }
+
+    fn is_seekable(&self) -> bool {
+        self.supports_range
+    }
+
+    fn seek(&mut self, position: byte_calc::NumBytes) -> rugix_bundle::BundleResult<()> {
+        let target = self.current_position + self.current_skipped;
+        if position.raw >= target {
+            // Seeking forward is equivalent to skipping.
+            self.current_skipped += position.raw - target;
+            return Ok(());
+        }
+        if !self.supports_range {
+            bail!("server does not support range requests");
+        }
+        self.current_response = ureq::get(&self.url)
+            .header("Range", format!("bytes={}-", position.raw))
+            .call()
+            .whatever("unable to get bundle from URL")?;
+        self.current_position = position.raw;
+        self.current_skipped = 0;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Write};
+    use std::net::TcpListener;
+    use std::sync::{Arc, Mutex};
+
+    use rugix_bundle::reader::{BundleReader, PayloadTarget};
+
+    use super::HttpSource;
+
+    /// Serve the given bundle via HTTP, returning the URL and the requested ranges.
+    ///
+    /// Range requests are only supported, if `supports_range` is set.
+    fn serve(bundle: Vec<u8>, supports_range: bool) -> (String, Arc<Mutex<Vec<u64>>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/bundle.rugixb", listener.local_addr().unwrap());
+        let ranges = Arc::new(Mutex::new(Vec::new()));
+        let requested = ranges.clone();
+        std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let mut stream = stream.unwrap();
+                let mut start = 0;
+                for line in BufReader::new(&stream).lines() {
+                    let line = line.unwrap();
+                    if line.is_empty() {
+                        break;
+                    }
+                    if let Some(range) = line.to_ascii_lowercase().strip_prefix("range: bytes=") {
+                        start = range.trim_end_matches('-').parse::<usize>().unwrap();
+                        requested.lock().unwrap().push(start as u64);
+                    }
+                }
+                let (status, headers) = if start > 0 {
+                    let end = bundle.len() - 1;
+                    (
+                        "206 Partial Content",
+                        format!("Content-Range: bytes {start}-{end}/{}\r\n", bundle.len()),
+                    )
+                } else if supports_range {
+                    ("200 OK", "Accept-Ranges: bytes\r\n".to_owned())
+                } else {
+                    ("200 OK", String::new())
+                };
+                let body = &bundle[start..];
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {status}\r\n{headers}Content-Length: {}\r\nConnection: close\r\n\r\n",
+                    body.len()
+                );
+                let _ = stream.write_all(body);
+            }
+        });
+        (url, ranges)
+    }
+
+    /// Create a bundle with a large first payload and a small second payload.
+    fn create_bundle(payloads: [&[u8]; 2]) -> Vec<u8> {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let manifest = toml::from_str(
+            r#"
+            update-type = "incremental"
+
+            [[payloads]]
+            filename = "first.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "first"
+
+            [[payloads]]
+            filename = "second.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "second"
+            "#,
+        )
+        .unwrap();
+        let payload_files = payloads
+            .iter()
+            .enumerate()
+            .map(|(idx, payload)| {
+                let path = temp_dir.path().join(format!("payload-{idx}"));
+                std::fs::write(&path, payload).unwrap();
+                rugix_bundle::builder::PayloadFile::new(&path).unwrap()
+            })
+            .collect::<Vec<_>>();
+        let mut bundle = std::io::Cursor::new(Vec::new());
+        rugix_bundle::builder::write_bundle(&manifest, &payload_files, &[], &mut bundle).unwrap();
+        bundle.into_inner()
+    }
+
+    /// Target collecting the payload in memory.
+    struct Collect<'v>(&'v mut Vec<u8>);
+
+    impl PayloadTarget for Collect<'_> {
+        fn write(&mut self, bytes: &[u8]) -> rugix_bundle::BundleResult<()> {
+            self.0.extend_from_slice(bytes);
+            Ok(())
+        }
+    }
+
+    /// Skip the first payload and return the second payload as well as the source.
+    fn skip_first(url: &str) -> (Vec<u8>, HttpSource) {
+        let mut source = HttpSource::new(url).unwrap();
+        let mut reader = BundleReader::start(&mut source, None).unwrap();
+        reader.next_payload().unwrap().unwrap().skip().unwrap();
+        let mut second = Vec::new();
+        reader
+            .next_payload()
+            .unwrap()
+            .unwrap()
+            .decode_into(Collect(&mut second), None)
+            .unwrap();
+        drop(reader);
+        (second, source)
+    }
+
+    #[test]
+    fn test_skip_with_range_requests() {
+        let first = vec![1; 256 * 1024];
+        let bundle = create_bundle([&first, b"second"]);
+        // The data of the second payload comes last, the name also appears in the header.
+        let second_start = bundle
+            .windows(6)
+            .rposition(|window| window == b"second")
+            .unwrap() as u64;
+        let (url, ranges) = serve(bundle, true);
+        let (second, source) = skip_first(&url);
+        assert_eq!(second, b"second");
+        // The first payload has been skipped with a range request landing before the
+        // header of the second payload.
+        let ranges = ranges.lock().unwrap().clone();
+        assert_eq!(ranges.len(), 1);
+        assert!(ranges[0] >= first.len() as u64 && ranges[0] < second_start);
+        assert!(source.bytes_skipped >= first.len() as u64);
+    }
+
+    #[test]
+    fn test_skip_without_range_requests() {
+        let first = vec![1; 256 * 1024];
+        let bundle = create_bundle([&first, b"second"]);
+        let (url, ranges) = serve(bundle, false);
+        let (second, source) = skip_first(&url);
+        assert_eq!(second, b"second");
+        // Without range requests, the first payload is read and discarded.
+        assert!(ranges.lock().unwrap().is_empty());
+        assert_eq!(source.bytes_skipped, 0);
+    }
 }
@@ -8,18 +8,19 @@ use nix::mount::MntFlags;
 use reportify::{bail, ensure, ErrorExt, ResultExt};
 use rugix_common::disk::blkdev::BlockDevice;
 
-use tracing::{info, warn};
+use tracing::{error, info, warn};
 
 use crate::config::bootstrapping::{BootstrappingConfig, DefaultLayoutConfig, SystemLayoutConfig};
 use crate::config::state::{
     OverlayConfig, PersistConfig, PersistDirectoryConfig, PersistFileConfig, StateConfig,
 };
 use crate::config::system::PartitionConfig;
+use crate::drop_ins::{load_config, MergeRules};
 use crate::state::load_state_config;
 use crate::system::config::load_system_config;
 use crate::system::partitions::resolve_data_partition;
-use crate::system::paths::{MOUNT_POINT_CONFIG, MOUNT_POINT_DATA, MOUNT_POINT_SYSTEM};
-use crate::system::root::{find_system_device, SystemRoot};
+use crate::system::paths::{self, MOUNT_POINT_CONFIG, MOUNT_POINT_DATA, MOUNT_POINT_SYSTEM};
+use crate::system::root::{find_partlabel, find_partuuid, find_system_device, SystemRoot};
 use crate::system::{System, SystemError, SystemResult};
 use rugix_common::disk::blkpg::update_kernel_partitions;
 use rugix_common::disk::repart::{
@@ -31,6 +32,7 @@ use rugix_common::partitions::mkfs_ext4;
 use rugix_hooks::HooksLoader;
 use xscript::{run, Run, Vars};
 
+use crate::update_scripts::run_first_boot_scripts;
 use crate::utils::{clear_flag, is_flag_set, is_init_process, reboot, DEFERRED_SPARE_REBOOT_FLAG};
 
 pub fn main() -> SystemResult<()> {
@@ -84,6 +86,10 @@ fn init() -> SystemResult<()> {
                 root.resolve_partition(partition)
             } else if let Some(device) = partition.device {
                 Some(BlockDevice::new(device).whatever("unable to find config partition device")?)
+            } else if let Some(partlabel) = &partition.partlabel {
+                find_partlabel(Some(&root), partlabel)
+            } else if let Some(partuuid) = &partition.partuuid {
+                find_partuuid(Some(&root), partuuid)
             } else {
                 None
             }
@@ -158,7 +164,7 @@ fn init() -> SystemResult<()> {
     // 6️⃣ Setup state in `/run/rugix/state`.
     let state_profile = Path::new(DEFAULT_STATE_DIR);
     if state_profile.join(".rugix/reset-state").exists() {
-        let reset_hooks = HooksLoader::default()
+        let reset_hooks = HooksLoader::new(paths::prefixed(paths::HOOKS_DIR))
             .load_hooks("state-reset")
             .whatever("unable to load `state-reset` hooks")?;
 
@@ -182,6 +188,15 @@ fn init() -> SystemResult<()> {
     // 8️⃣ Setup the bind mounts for the persistent state.
     setup_persistent_state(&root_dir, state_profile, &state_config)?;
 
+    if let Err(error) = run_first_boot_scripts(&system) {
+        error!("error running first-boot scripts: {error:?}");
+        // If the active boot group has not been committed yet, rebooting will bring
+        // us back to the default boot group.
+        if system.needs_commit().unwrap_or(false) {
+            reboot()?;
+        }
+    }
+
     // 9️⃣ Restore the machine id and hand off to Systemd.
     exec_chroot_init(&root_dir)?;
 
@@ -194,22 +209,18 @@ pub fn state_dir() -> &'static Path {
     Path::new(STATE_DIR)
 }
 
-const BOOTSTRAP_CONFIG_PATH: &str = "/etc/rugix/bootstrapping.toml";
+/// Rules for merging the bootstrapping configuration with drop-in files.
+pub const BOOTSTRAP_MERGE_RULES: MergeRules = MergeRules {
+    replace: &["layout"],
+    append: &[],
+};
 
 fn load_bootstrap_config() -> SystemResult<BootstrappingConfig> {
-    Ok(if Path::new(BOOTSTRAP_CONFIG_PATH).exists() {
-        toml::from_str(
-            &fs::read_to_string(BOOTSTRAP_CONFIG_PATH)
-                .whatever("unable to read system configuration file")?,
-        )
-        .whatever("unable to parse system configuration file")?
-    } else {
-        BootstrappingConfig::default()
-    })
+    load_config("bootstrapping", BOOTSTRAP_MERGE_RULES)
 }
 
 fn bootstrap(root: &SystemRoot) -> SystemResult<()> {
-    let bootstrap_hooks = HooksLoader::default()
+    let bootstrap_hooks = HooksLoader::new(paths::prefixed(paths::HOOKS_DIR))
         .load_hooks("bootstrap")
         .whatever("unable to load bootstrap hooks")?;
 
@@ -1,13 +1,22 @@
+pub mod block_slot;
 pub mod boot;
 pub mod cli;
+pub mod conditions;
 pub mod config;
+pub mod directory_slot;
+pub mod drop_ins;
+pub mod file_slot;
 pub mod http_source;
 pub mod init;
 pub mod overlay;
+pub mod rauc;
+pub mod rollback;
 pub mod slot_db;
+pub mod sparse_target;
 pub mod state;
 pub mod system;
 pub mod system_state;
+pub mod update_scripts;
 pub mod utils;
 
 pub fn main() {
@@ -1,8 +1,9 @@
-use std::path::{Path, PathBuf};
+use std::path::PathBuf;
 
 use crate::system::boot_groups::BootGroup;
+use crate::system::paths;
 
 /// Get the overlay directory for the given partition set.
 pub fn overlay_dir(entry: &BootGroup) -> PathBuf {
-    Path::new("/run/rugix/state/overlay").join(entry.name())
+    paths::prefixed("/run/rugix/state/overlay").join(entry.name())
 }
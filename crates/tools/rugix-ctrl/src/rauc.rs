@@ -0,0 +1,511 @@
+//! Installation of RAUC bundles.
+//!
+//! RAUC bundles in the `plain` format consist of a SquashFS image, containing the
+//! manifest and the images of the bundle, followed by a detached CMS signature of the
+//! SquashFS image and the size of the signature as a big-endian 64-bit integer. The
+//! signature is verified with `openssl` before the SquashFS image is mounted. The images
+//! are then installed to the slots configured for their RAUC slot classes.
+//!
+//! To ensure that the mounted image is the verified one, the bundle is opened only once
+//! and mounted via its file descriptor. In addition, bundles must be owned by root and
+//! must not be writable by others, as the bundle could otherwise be modified after its
+//! signature has been verified.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Seek, SeekFrom};
+use std::os::fd::AsRawFd;
+use std::os::unix::fs::MetadataExt;
+use std::path::{Component, Path};
+use std::process::{Command, Stdio};
+
+use indexmap::IndexMap;
+use reportify::{bail, ResultExt};
+use rugix_bundle::reader::PayloadTarget;
+use rugix_common::maybe_compressed::MaybeCompressed;
+use rugix_common::mount::Mounted;
+use rugix_common::stream_hasher::StreamHasher;
+use sha2::Sha256;
+
+use crate::block_slot::BlockTarget;
+use crate::cli::CustomTarget;
+use crate::config::system::RaucConfig;
+use crate::directory_slot::DirectoryTarget;
+use crate::file_slot::FileTarget;
+use crate::rollback::RollbackProtection;
+use crate::system::boot_groups::{BootGroup, BootGroupIdx};
+use crate::system::slots::{Slot, SlotKind};
+use crate::system::{System, SystemResult};
+use crate::{slot_db, update_scripts};
+
+/// Magic bytes at the start of a SquashFS image and, hence, a RAUC bundle.
+pub const SQUASHFS_MAGIC: &[u8] = b"hsqs";
+
+/// Maximal size of the signature of a bundle.
+const MAX_SIGNATURE_SIZE: u64 = 1024 * 1024;
+
+/// Install the RAUC bundle with the given path to the given boot group.
+pub fn install_bundle(
+    system: &System,
+    bundle: &Path,
+    boot_group: Option<&(BootGroupIdx, &BootGroup)>,
+) -> SystemResult<()> {
+    let Some(config) = &system.config().rauc else {
+        bail!("RAUC bundles are not enabled, configure `rauc` in the system configuration");
+    };
+    let Some((entry_idx, entry)) = boot_group else {
+        bail!("RAUC bundles require the specification of a boot group");
+    };
+
+    // RAUC bundles do not carry a security version and are, hence, treated as having
+    // version `0`. This refuses them once a security version has been committed.
+    let rollback_protection = RollbackProtection::new(system)?;
+    rollback_protection.check(None, None)?;
+
+    let bundle_dir = tempfile::tempdir().whatever("unable to create bundle directory")?;
+    let mut bundle_file = open_bundle(bundle)?;
+    let squashfs_size = verify_signature(config, &mut bundle_file, bundle_dir.path())?;
+    let mount_point = bundle_dir.path().join("bundle");
+    fs::create_dir(&mount_point).whatever("unable to create bundle mount point")?;
+    // Mount the verified file via its file descriptor, which is not inherited by `mount`.
+    let mounted = Mounted::mount_with_options(
+        format!(
+            "/proc/{}/fd/{}",
+            std::process::id(),
+            bundle_file.as_raw_fd()
+        ),
+        mount_point,
+        &format!("ro,loop,sizelimit={squashfs_size}"),
+    )
+    .whatever("unable to mount RAUC bundle")?;
+    // The loop device keeps a reference to the bundle.
+    drop(bundle_file);
+
+    let manifest = fs::read_to_string(mounted.path().join("manifest.raucm"))
+        .whatever("unable to read RAUC manifest")?;
+    let manifest = RaucManifest::parse(&manifest)?;
+    if manifest.format != "plain" {
+        bail!("unsupported RAUC bundle format {:?}", manifest.format);
+    }
+    if let Some(compatible) = &config.compatible {
+        if manifest.compatible != *compatible {
+            bail!(
+                "RAUC bundle is not compatible, expected {compatible:?}, found {:?}",
+                manifest.compatible
+            );
+        }
+    }
+    if manifest.has_hooks {
+        bail!("RAUC bundles with hooks are not supported");
+    }
+
+    // Resolve all slots before anything is written.
+    let mut installs = Vec::new();
+    for image in manifest.select_images(system.config().variant.as_deref()) {
+        if image.hooks.is_some() {
+            bail!("RAUC images with hooks are not supported");
+        }
+        let Some(slot_name) = config.slots.get(&image.slot_class) else {
+            bail!(
+                "no slot configured for RAUC slot class {:?}",
+                image.slot_class
+            );
+        };
+        let Some(slot) = entry
+            .get_slot(slot_name)
+            .or_else(|| system.slots().find_by_name(slot_name).map(|e| e.0))
+        else {
+            bail!(
+                "slot {slot_name:?} for RAUC slot class {:?} not found",
+                image.slot_class
+            );
+        };
+        installs.push((image, &system.slots()[slot]));
+    }
+
+    update_scripts::clear_first_boot_scripts(entry.name())?;
+    system
+        .boot_flow()
+        .pre_install(system, *entry_idx)
+        .whatever("error executing pre-install step")?;
+
+    for (image, slot) in installs {
+        eprintln!(
+            "Installing RAUC image {:?} to slot {}",
+            image.filename,
+            slot.name()
+        );
+        install_image(mounted.path(), image, slot, entry.name())?;
+    }
+    drop(mounted);
+
+    rollback_protection.set_pending(entry, None)?;
+
+    system
+        .boot_flow()
+        .post_install(system, *entry_idx)
+        .whatever("error running post-install step")?;
+    Ok(())
+}
+
+/// Open the bundle with the given path and check that it cannot be modified by others.
+fn open_bundle(bundle: &Path) -> SystemResult<File> {
+    let file = File::open(bundle).whatever("unable to open RAUC bundle")?;
+    let metadata = file
+        .metadata()
+        .whatever("unable to read metadata of RAUC bundle")?;
+    if !metadata.is_file() {
+        bail!("RAUC bundle must be a regular file");
+    }
+    check_bundle_owner(metadata.uid(), metadata.mode())?;
+    Ok(file)
+}
+
+/// Check that a bundle with the given owner and mode is not writable by non-root users.
+fn check_bundle_owner(uid: u32, mode: u32) -> SystemResult<()> {
+    if uid != 0 {
+        bail!("RAUC bundle must be owned by root");
+    }
+    if mode & 0o022 != 0 {
+        bail!("RAUC bundle must not be writable by group or others");
+    }
+    Ok(())
+}
+
+/// Verify the signature of the bundle and return the size of its SquashFS image.
+fn verify_signature(config: &RaucConfig, file: &mut File, work_dir: &Path) -> SystemResult<u64> {
+    let bundle_size = file
+        .metadata()
+        .whatever("unable to read metadata of RAUC bundle")?
+        .len();
+    if bundle_size < 8 {
+        bail!("RAUC bundle is truncated");
+    }
+    let mut size_bytes = [0; 8];
+    file.seek(SeekFrom::Start(bundle_size - 8))
+        .and_then(|_| file.read_exact(&mut size_bytes))
+        .whatever("unable to read signature size")?;
+    let signature_size = u64::from_be_bytes(size_bytes);
+    if signature_size == 0 || signature_size > MAX_SIGNATURE_SIZE {
+        bail!("invalid signature size {signature_size}");
+    }
+    let Some(squashfs_size) = (bundle_size - 8).checked_sub(signature_size) else {
+        bail!("invalid signature size {signature_size}");
+    };
+    let mut signature = vec![0; signature_size as usize];
+    file.seek(SeekFrom::Start(squashfs_size))
+        .and_then(|_| file.read_exact(&mut signature))
+        .whatever("unable to read signature")?;
+    let signature_path = work_dir.join("signature.cms");
+    fs::write(&signature_path, signature).whatever("unable to write signature")?;
+    file.seek(SeekFrom::Start(0))
+        .whatever("unable to seek to start of RAUC bundle")?;
+
+    let keyring = Path::new(&config.keyring);
+    let mut command = Command::new("openssl");
+    command
+        .args(["cms", "-verify", "-binary", "-inform", "DER", "-in"])
+        .arg(signature_path)
+        .args(["-content", "/dev/stdin", "-out", "/dev/null"])
+        .arg(if keyring.is_dir() {
+            "-CApath"
+        } else {
+            "-CAfile"
+        })
+        .arg(keyring);
+    if let Some(purpose) = &config.check_purpose {
+        command.arg("-purpose").arg(purpose);
+    }
+    let mut child = command
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .whatever("unable to spawn `openssl`")?;
+    let copied = io::copy(
+        &mut (&mut *file).take(squashfs_size),
+        child.stdin.as_mut().unwrap(),
+    );
+    // Close stdin such that `openssl` sees the end of the content.
+    drop(child.stdin.take());
+    let output = child
+        .wait_with_output()
+        .whatever("error waiting for `openssl`")?;
+    if !output.status.success() {
+        bail!(
+            "unable to verify signature of RAUC bundle: {}",
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    copied.whatever("unable to verify signature of RAUC bundle")?;
+    Ok(squashfs_size)
+}
+
+/// Install an image of the bundle to the given slot.
+fn install_image(
+    bundle_dir: &Path,
+    image: &RaucImage,
+    slot: &Slot,
+    boot_group: &str,
+) -> SystemResult<()> {
+    let filename = Path::new(&image.filename);
+    if !filename
+        .components()
+        .all(|component| matches!(component, Component::Normal(_)))
+    {
+        bail!("invalid filename of RAUC image {:?}", image.filename);
+    }
+    if image.filename.ends_with(".caibx") || image.filename.ends_with(".caidx") {
+        bail!("casync images are not supported");
+    }
+    let is_archive = image.filename.contains(".tar");
+    let file = File::open(bundle_dir.join(filename)).whatever("unable to open RAUC image")?;
+    let size = file
+        .metadata()
+        .whatever("unable to read metadata of RAUC image")?
+        .len();
+    if image.size.is_some_and(|expected| expected != size) {
+        bail!("invalid size of RAUC image {:?}", image.filename);
+    }
+    let hasher = StreamHasher::<_, Sha256>::new(file);
+    slot_db::erase(slot.name())?;
+    match slot.kind() {
+        SlotKind::Block(block_slot) => {
+            if is_archive {
+                bail!("archives cannot be installed to block slots");
+            }
+            write_image(BlockTarget::new(block_slot, None)?, image, hasher, false)
+        }
+        SlotKind::File { path } => {
+            if is_archive {
+                bail!("archives cannot be installed to file slots");
+            }
+            write_image(FileTarget::new(path)?, image, hasher, false)
+        }
+        SlotKind::Directory { path } => {
+            if !is_archive {
+                bail!("only archives can be installed to directory slots");
+            }
+            write_image(DirectoryTarget::new(path)?, image, hasher, true)
+        }
+        SlotKind::Custom { handler } => {
+            let target = CustomTarget::new(
+                handler.iter().map(|arg| arg.as_str()),
+                vec![
+                    ("RUGIX_BOOT_GROUP", boot_group.to_owned()),
+                    ("RUGIX_SLOT_NAME", slot.name().to_owned()),
+                    ("RUGIX_PAYLOAD_SIZE", size.to_string()),
+                ],
+            )?;
+            write_image(target, image, hasher, false)
+        }
+    }
+}
+
+/// Write an image to the target and check its hash before finalizing the target.
+fn write_image<T: PayloadTarget>(
+    mut target: T,
+    image: &RaucImage,
+    mut hasher: StreamHasher<File, Sha256>,
+    decompress: bool,
+) -> SystemResult<()> {
+    {
+        let mut reader: Box<dyn Read + '_> = if decompress {
+            Box::new(MaybeCompressed::new(&mut hasher).whatever("unable to decompress image")?)
+        } else {
+            Box::new(&mut hasher)
+        };
+        let mut buffer = vec![0; 64 * 1024];
+        loop {
+            let read = reader.read(&mut buffer).whatever("unable to read image")?;
+            if read == 0 {
+                break;
+            }
+            target
+                .write(&buffer[..read])
+                .whatever("unable to write image")?;
+        }
+    }
+    // Decompression may stop before the end of the file, hence, we hash the rest.
+    io::copy(&mut hasher, &mut io::sink()).whatever("unable to read image")?;
+    if !hex::encode(hasher.finalize()).eq_ignore_ascii_case(&image.sha256) {
+        bail!("hash mismatch of RAUC image {:?}", image.filename);
+    }
+    target.finalize().whatever("unable to finalize image")?;
+    Ok(())
+}
+
+/// Manifest of a RAUC bundle.
+#[derive(Debug)]
+pub struct RaucManifest {
+    /// Compatible string of the bundle.
+    pub compatible: String,
+    /// Version of the bundle.
+    pub version: Option<String>,
+    /// Format of the bundle.
+    pub format: String,
+    /// Images of the bundle.
+    pub images: Vec<RaucImage>,
+    /// Indicates whether the bundle has a hook script.
+    pub has_hooks: bool,
+}
+
+/// Image of a RAUC bundle.
+#[derive(Debug)]
+pub struct RaucImage {
+    /// Slot class the image is installed to.
+    pub slot_class: String,
+    /// Variant the image is specific to.
+    pub variant: Option<String>,
+    /// Filename of the image in the bundle.
+    pub filename: String,
+    /// Hex-encoded SHA256 hash of the image.
+    pub sha256: String,
+    /// Size of the image.
+    pub size: Option<u64>,
+    /// Hooks of the image.
+    pub hooks: Option<String>,
+}
+
+impl RaucManifest {
+    /// Parse a manifest in the key file format.
+    pub fn parse(manifest: &str) -> SystemResult<Self> {
+        let mut groups = IndexMap::<&str, IndexMap<&str, &str>>::new();
+        let mut group = None;
+        for line in manifest.lines() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some(name) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+                groups.entry(name).or_default();
+                group = Some(name);
+                continue;
+            }
+            let (Some(group), Some((key, value))) = (group, line.split_once('=')) else {
+                bail!("invalid line in RAUC manifest: {line:?}");
+            };
+            groups[group].insert(key.trim(), value.trim());
+        }
+        let Some(compatible) = groups.get("update").and_then(|g| g.get("compatible")) else {
+            bail!("RAUC manifest has no compatible string");
+        };
+        let mut images = Vec::new();
+        for (name, entries) in &groups {
+            let Some(image) = name.strip_prefix("image.") else {
+                continue;
+            };
+            let (slot_class, variant) = match image.split_once('.') {
+                Some((slot_class, variant)) => (slot_class, Some(variant.to_owned())),
+                None => (image, None),
+            };
+            let (Some(filename), Some(sha256)) = (entries.get("filename"), entries.get("sha256"))
+            else {
+                bail!("RAUC image {name:?} requires a filename and a hash");
+            };
+            let size = match entries.get("size") {
+                Some(size) => Some(
+                    size.parse()
+                        .whatever("invalid size of RAUC image")
+                        .with_info(|_| format!("image: {name:?}"))?,
+                ),
+                None => None,
+            };
+            images.push(RaucImage {
+                slot_class: slot_class.to_owned(),
+                variant,
+                filename: filename.to_string(),
+                sha256: sha256.to_string(),
+                size,
+                hooks: entries.get("hooks").map(|hooks| hooks.to_string()),
+            });
+        }
+        Ok(Self {
+            compatible: compatible.to_string(),
+            version: groups
+                .get("update")
+                .and_then(|g| g.get("version"))
+                .map(|version| version.to_string()),
+            format: groups
+                .get("bundle")
+                .and_then(|g| g.get("format"))
+                .map(|format| format.to_string())
+                .unwrap_or_else(|| "plain".to_owned()),
+            images,
+            has_hooks: groups
+                .get("hooks")
+                .is_some_and(|g| g.contains_key("filename")),
+        })
+    }
+
+    /// Select the images to install on a system with the given variant.
+    ///
+    /// For each slot class, an image specific to the variant takes precedence over an
+    /// image without a variant.
+    pub fn select_images(&self, variant: Option<&str>) -> Vec<&RaucImage> {
+        let mut selected = IndexMap::<&str, &RaucImage>::new();
+        for image in &self.images {
+            match image.variant.as_deref() {
+                None => {
+                    selected.entry(image.slot_class.as_str()).or_insert(image);
+                }
+                Some(image_variant) if Some(image_variant) == variant => {
+                    selected.insert(image.slot_class.as_str(), image);
+                }
+                Some(_) => { /* image is for a different variant */ }
+            }
+        }
+        selected.into_values().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{check_bundle_owner, RaucManifest};
+
+    #[test]
+    fn test_check_bundle_owner() {
+        assert!(check_bundle_owner(0, 0o100644).is_ok());
+        assert!(check_bundle_owner(0, 0o100400).is_ok());
+        assert!(check_bundle_owner(1000, 0o100444).is_err());
+        assert!(check_bundle_owner(0, 0o100664).is_err());
+        assert!(check_bundle_owner(0, 0o100646).is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let manifest = RaucManifest::parse(
+            "[update]\n\
+            compatible=rugix-test\n\
+            version = 1.0\n\
+            \n\
+            # Comment\n\
+            [image.rootfs]\n\
+            filename=rootfs.ext4\n\
+            sha256=abcd\n\
+            size=1024\n\
+            \n\
+            [image.rootfs.variant-b]\n\
+            filename=rootfs-b.ext4\n\
+            sha256=ef01\n\
+            \n\
+            [image.appfs]\n\
+            filename=appfs.tar.xz\n\
+            sha256=2345\n",
+        )
+        .unwrap();
+        assert_eq!(manifest.compatible, "rugix-test");
+        assert_eq!(manifest.version.as_deref(), Some("1.0"));
+        assert_eq!(manifest.format, "plain");
+        assert!(!manifest.has_hooks);
+        let files = |variant| {
+            manifest
+                .select_images(variant)
+                .iter()
+                .map(|image| image.filename.as_str())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(files(None), ["rootfs.ext4", "appfs.tar.xz"]);
+        assert_eq!(files(Some("variant-b")), ["rootfs-b.ext4", "appfs.tar.xz"]);
+        assert!(RaucManifest::parse("[image.rootfs]\nfilename=rootfs.ext4\n").is_err());
+    }
+}
@@ -0,0 +1,308 @@
+//! Rollback protection based on security versions.
+//!
+//! The highest security version that has ever been committed on the device is kept in a
+//! monotonic counter on the config or data partition. When installing an update, the
+//! security version of the update is recorded as *pending* for the boot group the update
+//! is installed to. Only when the system is committed, the counter advances to the
+//! pending version of the active boot group. This ensures that a failed update, which
+//! is rolled back by the boot flow, does not lock the device out of the previous version.
+
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+
+use reportify::{bail, ResultExt};
+use tracing::{info, warn};
+
+use crate::config::system::{CounterPartition, RollbackProtectionConfig};
+use crate::system::boot_groups::BootGroup;
+use crate::system::partitions::ConfigPartition;
+use crate::system::paths::{self, MOUNT_POINT_DATA};
+use crate::system::{System, SystemResult};
+
+/// Name of the file storing the highest committed security version.
+const COUNTER_FILE: &str = "security-version";
+
+/// Name of the directory storing the pending security versions of the boot groups.
+const PENDING_DIR: &str = "pending";
+
+/// Rollback protection of a system.
+pub struct RollbackProtection<'s> {
+    config: Option<&'s RollbackProtectionConfig>,
+    /// Directory with the counter and the pending security versions.
+    dir: PathBuf,
+    /// Name of the active boot group.
+    active_group: Option<&'s str>,
+    /// Config partition, which must be made writable if it contains the directory.
+    config_partition: Option<&'s ConfigPartition>,
+}
+
+impl<'s> RollbackProtection<'s> {
+    /// Create the rollback protection for the given system.
+    pub fn new(system: &'s System) -> SystemResult<Self> {
+        let config = system.config().rollback_protection.as_ref();
+        let counter_partition = config.and_then(|config| config.counter_partition.as_ref());
+        let dir = match counter_partition {
+            Some(CounterPartition::Data) => paths::prefixed(MOUNT_POINT_DATA),
+            Some(CounterPartition::Config) | None => {
+                system.require_config_partition()?.path().to_path_buf()
+            }
+        }
+        .join("rugix/rollback");
+        let active_group = system
+            .active_boot_entry()
+            .map(|active| system.boot_entries()[active].name());
+        Ok(Self {
+            config,
+            dir,
+            active_group,
+            config_partition: system.config_partition(),
+        })
+    }
+
+    /// Indicates whether rollback protection is enabled.
+    pub fn is_enabled(&self) -> bool {
+        !self
+            .config
+            .and_then(|config| config.disabled)
+            .unwrap_or(false)
+    }
+
+    /// Highest security version that has been committed.
+    pub fn committed_version(&self) -> SystemResult<u64> {
+        read_version(&self.dir.join(COUNTER_FILE))
+            .map(|version| version.unwrap_or(0))
+            .whatever("unable to read security version counter")
+    }
+
+    /// Check whether an update with the given security version and channel may be
+    /// installed.
+    ///
+    /// Updates without a security version, which includes system images and RAUC
+    /// bundles, are treated as having version `0`. Hence, they are refused once a
+    /// security version has been committed, unless downgrades are allowed.
+    pub fn check(&self, security_version: Option<u64>, channel: Option<&str>) -> SystemResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let committed_version = self.committed_version()?;
+        if security_version.unwrap_or(0) >= committed_version {
+            return Ok(());
+        }
+        if self.allow_downgrade(channel) {
+            warn!(
+                "installing update with security version {} lower than committed version {committed_version}",
+                security_version.unwrap_or(0)
+            );
+            return Ok(());
+        }
+        match security_version {
+            Some(security_version) => bail!(
+                "security version {security_version} of update is lower than committed version {committed_version}"
+            ),
+            None => bail!(
+                "update without security version is refused as security version {committed_version} has been committed"
+            ),
+        }
+    }
+
+    /// Record the security version of an update installed to the given boot group.
+    ///
+    /// The version becomes effective once the boot group is committed.
+    pub fn set_pending(
+        &self,
+        boot_group: &BootGroup,
+        security_version: Option<u64>,
+    ) -> SystemResult<()> {
+        self.set_pending_version(boot_group.name(), security_version)
+    }
+
+    fn set_pending_version(
+        &self,
+        boot_group: &str,
+        security_version: Option<u64>,
+    ) -> SystemResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let path = self.pending_path(boot_group);
+        self.with_writable(|| match security_version {
+            Some(version) => write_version(&path, version),
+            None => match fs::remove_file(&path) {
+                Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(error),
+                _ => Ok(()),
+            },
+        })?
+        .whatever("unable to record pending security version")
+    }
+
+    /// Advance the counter to the pending security version of the active boot group.
+    ///
+    /// The counter never decreases.
+    pub fn commit(&self) -> SystemResult<()> {
+        if !self.is_enabled() {
+            return Ok(());
+        }
+        let Some(active_group) = self.active_group else {
+            warn!("unable to advance security version counter: unknown active boot group");
+            return Ok(());
+        };
+        let pending_path = self.pending_path(active_group);
+        let Some(pending_version) =
+            read_version(&pending_path).whatever("unable to read pending security version")?
+        else {
+            return Ok(());
+        };
+        let committed_version = self.committed_version()?;
+        self.with_writable(|| {
+            if pending_version > committed_version {
+                write_version(&self.dir.join(COUNTER_FILE), pending_version)?;
+            }
+            fs::remove_file(&pending_path)
+        })?
+        .whatever("unable to advance security version counter")?;
+        if pending_version > committed_version {
+            info!("advanced security version from {committed_version} to {pending_version}");
+        }
+        Ok(())
+    }
+
+    fn allow_downgrade(&self, channel: Option<&str>) -> bool {
+        let Some(config) = self.config else {
+            return false;
+        };
+        channel
+            .and_then(|channel| config.channels.as_ref()?.get(channel))
+            .and_then(|channel| channel.allow_downgrade)
+            .or(config.allow_downgrade)
+            .unwrap_or(false)
+    }
+
+    fn pending_path(&self, boot_group: &str) -> PathBuf {
+        self.dir.join(PENDING_DIR).join(boot_group)
+    }
+
+    fn with_writable<U>(&self, closure: impl FnOnce() -> U) -> SystemResult<U> {
+        match self.config_partition {
+            Some(partition) if self.dir.starts_with(partition.path()) => {
+                partition.ensure_writable(closure)
+            }
+            _ => Ok(closure()),
+        }
+    }
+}
+
+fn read_version(path: &Path) -> std::io::Result<Option<u64>> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|error| std::io::Error::new(std::io::ErrorKind::InvalidData, error)),
+        Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(None),
+        Err(error) => Err(error),
+    }
+}
+
+fn write_version(path: &Path, version: u64) -> std::io::Result<()> {
+    let parent = path.parent().expect("path must have a parent");
+    fs::create_dir_all(parent)?;
+    let tmp_path = path.with_extension("tmp");
+    let mut file = fs::File::create(&tmp_path)?;
+    writeln!(file, "{version}")?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)?;
+    fs::File::open(parent)?.sync_all()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::Path;
+
+    use indoc::indoc;
+
+    use super::{RollbackProtection, COUNTER_FILE, PENDING_DIR};
+    use crate::config::system::RollbackProtectionConfig;
+
+    fn rollback_protection<'c>(
+        config: Option<&'c RollbackProtectionConfig>,
+        dir: &Path,
+        active_group: Option<&'c str>,
+    ) -> RollbackProtection<'c> {
+        RollbackProtection {
+            config,
+            dir: dir.to_path_buf(),
+            active_group,
+            config_partition: None,
+        }
+    }
+
+    #[test]
+    fn test_check_allow_downgrade() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        fs::write(temp_dir.path().join(COUNTER_FILE), "5\n").unwrap();
+        let unconfigured = rollback_protection(None, temp_dir.path(), None);
+        assert_eq!(unconfigured.committed_version().unwrap(), 5);
+        assert!(unconfigured.check(Some(5), None).is_ok());
+        assert!(unconfigured.check(Some(6), Some("stable")).is_ok());
+        assert!(unconfigured.check(Some(4), None).is_err());
+        // Updates without a security version are treated as having version 0.
+        assert!(unconfigured.check(None, None).is_err());
+        let config: RollbackProtectionConfig = toml::from_str(indoc! {r#"
+            allow-downgrade = true
+
+            [channels.stable]
+            allow-downgrade = false
+
+            [channels.beta]
+        "#})
+        .unwrap();
+        let configured = rollback_protection(Some(&config), temp_dir.path(), None);
+        assert!(configured.check(Some(4), None).is_ok());
+        assert!(configured.check(Some(4), Some("unknown")).is_ok());
+        // Channel-specific settings take precedence over the global setting.
+        assert!(configured.check(Some(4), Some("stable")).is_err());
+        assert!(configured.check(Some(5), Some("stable")).is_ok());
+        assert!(configured.check(Some(4), Some("beta")).is_ok());
+        let config: RollbackProtectionConfig = toml::from_str("disabled = true").unwrap();
+        let disabled = rollback_protection(Some(&config), temp_dir.path(), None);
+        assert!(disabled.check(Some(4), None).is_ok());
+    }
+
+    #[test]
+    fn test_commit_pending_version() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let pending_b = temp_dir.path().join(PENDING_DIR).join("b");
+        let active_a = rollback_protection(None, temp_dir.path(), Some("a"));
+        let active_b = rollback_protection(None, temp_dir.path(), Some("b"));
+        active_a.set_pending_version("b", Some(7)).unwrap();
+        assert_eq!(fs::read_to_string(&pending_b).unwrap().trim(), "7");
+        // Installing does not advance the counter.
+        assert_eq!(active_a.committed_version().unwrap(), 0);
+        // Only the pending version of the active boot group is committed.
+        active_a.commit().unwrap();
+        assert_eq!(active_a.committed_version().unwrap(), 0);
+        assert!(pending_b.exists());
+        active_b.commit().unwrap();
+        assert_eq!(active_b.committed_version().unwrap(), 7);
+        assert!(!pending_b.exists());
+        // The counter never decreases.
+        active_a.set_pending_version("b", Some(3)).unwrap();
+        active_b.commit().unwrap();
+        assert_eq!(active_b.committed_version().unwrap(), 7);
+        assert!(!pending_b.exists());
+        // Installing an update without a security version clears the pending version.
+        active_a.set_pending_version("b", Some(9)).unwrap();
+        active_a.set_pending_version("b", None).unwrap();
+        assert!(!pending_b.exists());
+        active_b.commit().unwrap();
+        assert_eq!(active_b.committed_version().unwrap(), 7);
+        // Without an active boot group, the counter is not advanced.
+        active_a.set_pending_version("b", Some(9)).unwrap();
+        rollback_protection(None, temp_dir.path(), None)
+            .commit()
+            .unwrap();
+        assert_eq!(active_b.committed_version().unwrap(), 7);
+    }
+}
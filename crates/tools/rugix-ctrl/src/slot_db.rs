@@ -1,21 +1,32 @@
 //! Slot database.
 
-use std::hash::BuildHasher;
 use std::path::{Path, PathBuf};
 
-use crate::system::SystemResult;
+use crate::system::{paths, SystemResult};
 use byte_calc::NumBytes;
-use hashbrown::{DefaultHashBuilder, HashTable};
 use reportify::{whatever, ResultExt};
-use rugix_bundle::block_encoding::block_index::{compute_block_index, BlockIndexConfig};
+use rugix_bundle::block_encoding::block_index::{self, compute_block_index, BlockIndexConfig};
+use rugix_bundle::block_encoding::offset_index::OffsetBlockIndex;
+use rugix_bundle::block_encoding::sorted_index::SortedBlockIndex;
 use rugix_bundle::format::decode::{Decode, Decoder};
 use rugix_bundle::format::{self, BlockIndex};
 use rugix_bundle::manifest::ChunkerAlgorithm;
-use rugix_bundle::reader::block_provider::{StoredBlock, StoredBlockProvider};
+use rugix_bundle::reader::block_provider::{
+    BlockReader, BlockSource, StoredBlock, StoredBlockProvider,
+};
+use rugix_bundle::reader::bundle_blocks::{
+    open_bundle_indices, write_bundle_indices, BundleBlockIndex,
+};
 use rugix_bundle::source::FileSource;
 use rugix_hashes::HashAlgorithm;
 use tracing::warn;
 
+/// Extension of the files containing sorted block indices.
+const SORTED_INDEX_EXTENSION: &str = "rugix-sorted-index";
+
+/// Extension of the files containing offset block indices.
+const OFFSET_INDEX_EXTENSION: &str = "rugix-offset-index";
+
 /// Stored block index.
 #[derive(Debug)]
 pub struct StoredBlockIndex {
@@ -27,15 +38,90 @@ pub struct StoredBlockIndex {
     pub index_file: PathBuf,
 }
 
+impl StoredBlockIndex {
+    /// Load the index from its file.
+    ///
+    /// Returns [`None`] if the file does not contain a valid index.
+    pub fn load(&self) -> SystemResult<Option<BlockIndex>> {
+        let source = FileSource::from_unbuffered(
+            std::fs::File::open(&self.index_file).whatever("unable to open index file")?,
+        );
+        let mut decoder = Decoder::new(source, 16, NumBytes::new(u64::MAX));
+        let atom = decoder
+            .next_atom_head()
+            .whatever("unable to decode bundle")?;
+        if !atom.is_start() || atom.tag() != format::tags::BLOCK_INDEX {
+            warn!("invalid block index file");
+            return Ok(None);
+        }
+        BlockIndex::decode(&mut decoder, atom)
+            .map(Some)
+            .whatever("unable to decode block index")
+    }
+
+    /// Path to the file containing the sorted version of the index.
+    pub fn sorted_index_file(&self) -> PathBuf {
+        self.index_file.with_extension(SORTED_INDEX_EXTENSION)
+    }
+
+    /// Open the sorted version of the index.
+    ///
+    /// The sorted index is created, if it does not exist yet, e.g., because the index
+    /// has been created by an older version of Rugix Ctrl.
+    pub fn open_sorted(&self) -> SystemResult<Option<SortedBlockIndex>> {
+        let sorted_index_file = self.sorted_index_file();
+        if !sorted_index_file.exists() {
+            let Some(index) = self.load()? else {
+                return Ok(None);
+            };
+            let index = block_index::BlockIndex::from_format(index)
+                .whatever("unable to load block index")?;
+            SortedBlockIndex::write(&index, &sorted_index_file)
+                .whatever("unable to write sorted block index")?;
+        }
+        SortedBlockIndex::open(&sorted_index_file)
+            .map(Some)
+            .whatever("unable to open sorted block index")
+    }
+
+    /// Path to the file containing the offset version of the index.
+    pub fn offset_index_file(&self) -> PathBuf {
+        self.index_file.with_extension(OFFSET_INDEX_EXTENSION)
+    }
+
+    /// Open the offset version of the index.
+    ///
+    /// The offset index is created, if it does not exist yet, e.g., because the index
+    /// has been created by an older version of Rugix Ctrl.
+    pub fn open_offsets(&self) -> SystemResult<Option<OffsetBlockIndex>> {
+        let offset_index_file = self.offset_index_file();
+        if !offset_index_file.exists() {
+            let Some(index) = self.load()? else {
+                return Ok(None);
+            };
+            let index = block_index::BlockIndex::from_format(index)
+                .whatever("unable to load block index")?;
+            OffsetBlockIndex::write(&index, &offset_index_file)
+                .whatever("unable to write offset block index")?;
+        }
+        OffsetBlockIndex::open(&offset_index_file)
+            .map(Some)
+            .whatever("unable to open offset block index")
+    }
+}
+
+/// Provider of the blocks stored in slots and cached bundles.
+///
+/// Blocks are looked up in the sorted indices of the slots and bundles, which are stored
+/// on disk, such that memory usage stays bounded even for very large slots.
 #[derive(Debug)]
 pub struct BlockProvider {
     chunker_algorithm: ChunkerAlgorithm,
     hash_algorithm: HashAlgorithm,
-    table: HashTable<(usize, usize)>,
-    table_hasher: DefaultHashBuilder,
-    hashes: Vec<u8>,
-    dimensions: Vec<(NumBytes, NumBytes)>,
-    files: Vec<PathBuf>,
+    /// Sorted indices and the slots containing the respective blocks.
+    indices: Vec<(SortedBlockIndex, SlotSource)>,
+    /// Indices of the blocks stored in cached bundles and the respective bundle files.
+    bundles: Vec<(BundleBlockIndex, PathBuf)>,
 }
 
 impl BlockProvider {
@@ -43,15 +129,25 @@ impl BlockProvider {
         Self {
             chunker_algorithm,
             hash_algorithm,
-            table: HashTable::new(),
-            table_hasher: DefaultHashBuilder::default(),
-            hashes: Vec::new(),
-            dimensions: Vec::new(),
-            files: Vec::new(),
+            indices: Vec::new(),
+            bundles: Vec::new(),
         }
     }
 
     pub fn add_slot(&mut self, slot_name: &str, slot_file: PathBuf) -> SystemResult<()> {
+        self.add_slot_source(slot_name, SlotSource::File(slot_file))
+    }
+
+    /// Add a slot whose blocks are read with the given reader.
+    pub fn add_slot_reader(
+        &mut self,
+        slot_name: &str,
+        reader: Box<dyn BlockReader>,
+    ) -> SystemResult<()> {
+        self.add_slot_source(slot_name, SlotSource::Reader(reader))
+    }
+
+    fn add_slot_source(&mut self, slot_name: &str, source: SlotSource) -> SystemResult<()> {
         for index in get_stored_indices(slot_name)? {
             if index.hash_algorithm != self.hash_algorithm {
                 continue;
@@ -59,75 +155,160 @@ impl BlockProvider {
             if index.chunker_algorithm != self.chunker_algorithm {
                 continue;
             }
-            // Load the index.
-            let source = FileSource::from_unbuffered(
-                std::fs::File::open(&index.index_file).whatever("unable to open index file")?,
-            );
-            let mut decoder = Decoder::new(source, 16, NumBytes::new(u64::MAX));
-            let atom = decoder
-                .next_atom_head()
-                .whatever("unable to decode bundle")?;
-            if !atom.is_start() || atom.tag() != format::tags::BLOCK_INDEX {
-                warn!("invalid block index file");
+            let Some(sorted_index) = index.open_sorted()? else {
                 continue;
-            }
-            let index =
-                BlockIndex::decode(&mut decoder, atom).whatever("unable to decode block index")?;
-            let file_idx = self.files.len();
-            self.files.push(slot_file);
-            let mut next_block_idx = self.hashes.len();
-            self.hashes.extend_from_slice(&index.block_hashes.raw);
-            let mut current_offset = NumBytes::ZERO;
-            for size in index.block_sizes.raw.chunks_exact(4) {
-                let size = NumBytes::new(u32::from_be_bytes(size.try_into().unwrap()).into());
-                self.dimensions.push((current_offset, size));
-                current_offset += size;
-                let block = next_block_idx;
-                let table_hash = self.table_hasher.hash_one(self.get_hash(block));
-                self.table
-                    .entry(
-                        table_hash,
-                        |(other, _)| {
-                            self.hashes[*other * self.hash_algorithm.hash_size()
-                                ..(*other + 1) * self.hash_algorithm.hash_size()]
-                                == self.hashes[block * self.hash_algorithm.hash_size()
-                                    ..(block + 1) * self.hash_algorithm.hash_size()]
-                        },
-                        |(other, _)| {
-                            self.table_hasher.hash_one(
-                                &self.hashes[*other * self.hash_algorithm.hash_size()
-                                    ..(*other + 1) * self.hash_algorithm.hash_size()],
-                            )
-                        },
-                    )
-                    .or_insert_with(|| (block, file_idx));
-                next_block_idx += 1;
-            }
+            };
+            self.indices.push((sorted_index, source));
             break;
         }
         Ok(())
     }
 
-    fn get_hash(&self, block: usize) -> &[u8] {
-        &self.hashes
-            [block * self.hash_algorithm.hash_size()..(block + 1) * self.hash_algorithm.hash_size()]
+    /// Add the blocks stored in the given cached bundles.
+    ///
+    /// Bundles are indexed when they are added for the first time. As blocks are looked
+    /// up by their hashes, the chunker used for the payloads of the bundles does not
+    /// matter. Indices of bundles which are no longer cached are removed.
+    pub fn add_bundles(&mut self, bundles: &[PathBuf]) -> SystemResult<()> {
+        let bundles_dir = bundles_db_dir();
+        let mut index_dirs = Vec::new();
+        for bundle in bundles {
+            if !bundle.exists() {
+                continue;
+            }
+            let bundle_hash = match rugix_bundle::bundle_hash(bundle) {
+                Ok(bundle_hash) => bundle_hash,
+                Err(error) => {
+                    warn!("unable to read cached bundle {bundle:?}: {error:?}");
+                    continue;
+                }
+            };
+            let index_dir = bundles_dir.join(bundle_hash.to_string());
+            if !index_dir.exists() {
+                // Write indices into a temporary directory first such that indexing
+                // is not considered to be complete, when it is interrupted.
+                let tmp_dir = bundles_dir.join(format!(".{bundle_hash}.tmp"));
+                std::fs::remove_dir_all(&tmp_dir).ok();
+                if let Err(error) = write_bundle_indices(bundle, &tmp_dir) {
+                    warn!("unable to index cached bundle {bundle:?}: {error:?}");
+                    std::fs::remove_dir_all(&tmp_dir).ok();
+                    continue;
+                }
+                std::fs::rename(&tmp_dir, &index_dir).whatever("unable to store bundle indices")?;
+            }
+            for index in
+                open_bundle_indices(&index_dir).whatever("unable to open bundle indices")?
+            {
+                if index.hash_algorithm == self.hash_algorithm {
+                    self.bundles.push((index, bundle.clone()));
+                }
+            }
+            index_dirs.push(index_dir);
+        }
+        if bundles_dir.exists() {
+            for dir_entry in
+                std::fs::read_dir(bundles_dir).whatever("unable to list bundle indices")?
+            {
+                let dir_entry = dir_entry.whatever("unable to list bundle indices")?;
+                if !index_dirs.contains(&dir_entry.path()) {
+                    std::fs::remove_dir_all(dir_entry.path())
+                        .whatever("unable to remove stale bundle indices")?;
+                }
+            }
+        }
+        Ok(())
     }
 }
 
 impl StoredBlockProvider for BlockProvider {
     fn query(&self, hash: &[u8]) -> Option<StoredBlock<'_>> {
-        let table_hash = self.table_hasher.hash_one(hash);
-        self.table
-            .find(table_hash, |(block, _)| self.get_hash(*block) == hash)
-            .map(|(block, file)| StoredBlock {
-                file: &self.files[*file],
-                offset: self.dimensions[*block].0,
-                size: self.dimensions[*block].1,
+        self.indices
+            .iter()
+            .find_map(|(index, source)| {
+                let (offset, size) = index.query(hash)?;
+                Some(StoredBlock {
+                    source: source.block_source(),
+                    offset,
+                    size,
+                    compression: None,
+                })
+            })
+            .or_else(|| {
+                self.bundles.iter().find_map(|(bundle_index, file)| {
+                    let (offset, size) = bundle_index.index.query(hash)?;
+                    Some(StoredBlock {
+                        source: BlockSource::File(file),
+                        offset,
+                        size,
+                        compression: bundle_index.compression,
+                    })
+                })
             })
     }
 
     fn has_stored_blocks(&self) -> bool {
-        !self.hashes.is_empty()
+        self.indices.iter().any(|(index, _)| !index.is_empty())
+            || self
+                .bundles
+                .iter()
+                .any(|(bundle_index, _)| !bundle_index.index.is_empty())
+    }
+}
+
+/// Slot containing stored blocks.
+#[derive(Debug)]
+enum SlotSource {
+    /// Blocks are read from the slot's file or block device.
+    File(PathBuf),
+    /// Blocks are read with a reader, e.g., via the slot's custom update handler.
+    Reader(Box<dyn BlockReader>),
+}
+
+impl SlotSource {
+    fn block_source(&self) -> BlockSource<'_> {
+        match self {
+            SlotSource::File(path) => BlockSource::File(path),
+            SlotSource::Reader(reader) => BlockSource::Reader(reader.as_ref()),
+        }
+    }
+}
+
+/// Blocks stored at specific offsets of a slot.
+///
+/// Used to skip writing blocks which are already present at the same offset. Blocks are
+/// looked up in the offset index of the slot, which is stored on disk, such that memory
+/// usage stays bounded even for very large slots.
+#[derive(Debug)]
+pub struct SlotBlocks {
+    index: OffsetBlockIndex,
+}
+
+impl SlotBlocks {
+    /// Load the blocks of the given slot from its stored index.
+    ///
+    /// Returns [`None`] if there is no index for the given algorithms.
+    pub fn load(
+        slot_name: &str,
+        chunker_algorithm: &ChunkerAlgorithm,
+        hash_algorithm: HashAlgorithm,
+    ) -> SystemResult<Option<Self>> {
+        for index in get_stored_indices(slot_name)? {
+            if index.hash_algorithm != hash_algorithm
+                || &index.chunker_algorithm != chunker_algorithm
+            {
+                continue;
+            }
+            let Some(index) = index.open_offsets()? else {
+                continue;
+            };
+            return Ok(Some(Self { index }));
+        }
+        Ok(None)
+    }
+
+    /// Size of the block with the given hash at the given offset, if there is any.
+    pub fn query_at(&self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        self.index.query(offset, hash)
     }
 }
 
@@ -144,18 +325,29 @@ pub fn add_index(
     chunker_algorithm: &ChunkerAlgorithm,
     hash_algorithm: &HashAlgorithm,
 ) -> SystemResult<()> {
-    let path = db_dir().join(format!(
-        "{slot_name}/{chunker_algorithm}_{}.rugix-block-index",
-        hash_algorithm.name(),
-    ));
-    std::fs::create_dir_all(path.parent().unwrap()).ok();
     let index_config = BlockIndexConfig {
         hash_algorithm: *hash_algorithm,
         chunker: chunker_algorithm.clone(),
     };
     let block_index =
         compute_block_index(index_config, slot_file).whatever("unable to compute block index")?;
-    std::fs::write(path, &block_index.encode()).whatever("unable to write block index")?;
+    store_index(slot_name, &block_index)
+}
+
+/// Store the given block index of a slot, replacing any index with the same algorithms.
+pub fn store_index(slot_name: &str, block_index: &block_index::BlockIndex) -> SystemResult<()> {
+    let config = block_index.config();
+    let path = db_dir().join(format!(
+        "{slot_name}/{}_{}.rugix-block-index",
+        config.chunker,
+        config.hash_algorithm.name(),
+    ));
+    std::fs::create_dir_all(path.parent().unwrap()).ok();
+    SortedBlockIndex::write(block_index, &path.with_extension(SORTED_INDEX_EXTENSION))
+        .whatever("unable to write sorted block index")?;
+    OffsetBlockIndex::write(block_index, &path.with_extension(OFFSET_INDEX_EXTENSION))
+        .whatever("unable to write offset block index")?;
+    std::fs::write(path, block_index.encode()).whatever("unable to write block index")?;
     Ok(())
 }
 
@@ -194,12 +386,17 @@ pub fn get_stored_indices(slot: &str) -> SystemResult<Vec<StoredBlockIndex>> {
 }
 
 /// Directory with the slot database.
-pub fn db_dir() -> &'static Path {
+pub fn db_dir() -> PathBuf {
     const DATA_PATH: &str = "/run/rugix/mounts/data/rugix/slots";
     const VAR_PATH: &str = "/var/rugix/slots";
-    if Path::new("/run/rugix/mounts/data").exists() {
-        Path::new(DATA_PATH)
+    if paths::prefixed(paths::MOUNT_POINT_DATA).exists() {
+        paths::prefixed(DATA_PATH)
     } else {
-        Path::new(VAR_PATH)
+        paths::prefixed(VAR_PATH)
     }
 }
+
+/// Directory with the indices of cached bundles.
+fn bundles_db_dir() -> PathBuf {
+    db_dir().with_file_name("bundles")
+}
@@ -0,0 +1,445 @@
+//! Expansion of Android sparse images into block slots.
+//!
+//! Payloads for block slots may be Android sparse images. Such payloads are expanded
+//! while they are written. Unmapped ranges are skipped and leave the existing contents
+//! of the block device untouched. Other payloads are passed through unchanged.
+
+use byte_calc::NumBytes;
+use reportify::{bail, ResultExt};
+use rugix_bundle::reader::PayloadTarget;
+use rugix_bundle::BundleResult;
+use rugix_common::sparse::{
+    ChunkHeader, ChunkKind, SparseHeader, SPARSE_HEADER_SIZE, SPARSE_MAGIC,
+};
+
+/// Size of the buffer used for writing fill chunks.
+const FILL_BUFFER_SIZE: u64 = 64 * 1024;
+
+/// Payload target expanding Android sparse images.
+#[derive(Debug)]
+pub struct SparseTarget<T> {
+    /// Target the expanded image is written to.
+    inner: T,
+    /// State of the expansion.
+    state: State,
+    /// Bytes of the header which is currently read.
+    pending: Vec<u8>,
+    /// Header of the sparse image.
+    header: Option<SparseHeader>,
+    /// Number of chunks which have not been started yet.
+    remaining_chunks: u32,
+    /// Current offset in the payload.
+    payload_offset: u64,
+    /// Current offset in the target.
+    target_offset: u64,
+    /// Segments of the payload written so far, sorted by their payload offset.
+    ///
+    /// Required to read back blocks of the payload for deduplication.
+    segments: Vec<Segment>,
+    /// Bytes of the payload not written to the target, i.e., headers and patterns.
+    memory: Vec<u8>,
+}
+
+#[derive(Debug)]
+enum State {
+    /// Reading the magic bytes to decide whether the payload is a sparse image.
+    Detect,
+    /// The payload is not a sparse image and is passed through.
+    Passthrough,
+    /// Reading the file header.
+    FileHeader,
+    /// Reading a chunk header.
+    ChunkHeader,
+    /// Reading the data of a raw chunk.
+    RawData { remaining: u64 },
+    /// Reading the pattern of a fill chunk of the given size.
+    FillPattern { size: u64 },
+    /// Ignoring the given number of bytes, i.e., checksums and extra header bytes.
+    Ignore { remaining: u64 },
+    /// All chunks have been read.
+    Done,
+}
+
+/// Segment of the payload.
+#[derive(Debug)]
+struct Segment {
+    /// Offset of the segment in the payload.
+    payload_offset: u64,
+    /// Size of the segment.
+    size: u64,
+    /// Location where the segment is stored.
+    location: Location,
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Location {
+    /// Segment is stored in memory at the given offset.
+    Memory(usize),
+    /// Segment has been written to the target at the given offset.
+    Target(u64),
+}
+
+impl<T: PayloadTarget> SparseTarget<T> {
+    /// Wrap the given target.
+    pub fn new(inner: T) -> Self {
+        Self {
+            inner,
+            state: State::Detect,
+            pending: Vec::new(),
+            header: None,
+            remaining_chunks: 0,
+            payload_offset: 0,
+            target_offset: 0,
+            segments: Vec::new(),
+            memory: Vec::new(),
+        }
+    }
+
+    /// Move bytes into the pending bytes until there are `size` pending bytes.
+    ///
+    /// Returns whether there are `size` pending bytes.
+    fn collect(&mut self, bytes: &mut &[u8], size: usize) -> bool {
+        let take = size.saturating_sub(self.pending.len()).min(bytes.len());
+        self.pending.extend_from_slice(&bytes[..take]);
+        self.record_memory(&bytes[..take]);
+        *bytes = &bytes[take..];
+        self.pending.len() == size
+    }
+
+    fn record_memory(&mut self, bytes: &[u8]) {
+        if bytes.is_empty() {
+            return;
+        }
+        self.segments.push(Segment {
+            payload_offset: self.payload_offset,
+            size: bytes.len() as u64,
+            location: Location::Memory(self.memory.len()),
+        });
+        self.memory.extend_from_slice(bytes);
+        self.payload_offset += bytes.len() as u64;
+    }
+
+    fn record_target(&mut self, size: u64) {
+        match self.segments.last_mut() {
+            Some(Segment {
+                payload_offset,
+                size: segment_size,
+                location: Location::Target(target_offset),
+            }) if *payload_offset + *segment_size == self.payload_offset
+                && *target_offset + *segment_size == self.target_offset =>
+            {
+                *segment_size += size;
+            }
+            _ => self.segments.push(Segment {
+                payload_offset: self.payload_offset,
+                size,
+                location: Location::Target(self.target_offset),
+            }),
+        }
+        self.payload_offset += size;
+        self.target_offset += size;
+    }
+
+    fn header(&self) -> &SparseHeader {
+        self.header.as_ref().expect("header has been read")
+    }
+
+    fn next_chunk(&mut self) {
+        self.pending.clear();
+        self.state = if self.remaining_chunks == 0 {
+            State::Done
+        } else {
+            State::ChunkHeader
+        };
+    }
+
+    fn start_chunk(&mut self, chunk: ChunkHeader) -> BundleResult<()> {
+        self.remaining_chunks -= 1;
+        let size = chunk.size(self.header());
+        self.pending.clear();
+        match chunk.kind {
+            ChunkKind::Raw => {
+                self.state = State::RawData { remaining: size };
+            }
+            ChunkKind::Fill => {
+                self.state = State::FillPattern { size };
+            }
+            ChunkKind::DontCare => {
+                self.inner.skip(NumBytes::new(size))?;
+                self.target_offset += size;
+                self.next_chunk();
+            }
+            ChunkKind::Crc32 => {
+                self.state = State::Ignore {
+                    remaining: chunk.data_size,
+                };
+            }
+        }
+        Ok(())
+    }
+
+    fn write_fill(&mut self, size: u64) -> BundleResult<()> {
+        let pattern = self.pending.clone();
+        let mut buffer = pattern
+            .iter()
+            .copied()
+            .cycle()
+            .take(FILL_BUFFER_SIZE.min(size) as usize)
+            .collect::<Vec<_>>();
+        let mut remaining = size;
+        while remaining > 0 {
+            // The buffer size is a multiple of four, hence, the pattern stays aligned.
+            buffer.truncate(remaining.min(FILL_BUFFER_SIZE) as usize);
+            self.inner.write(&buffer)?;
+            remaining -= buffer.len() as u64;
+        }
+        self.target_offset += size;
+        Ok(())
+    }
+}
+
+impl<T: PayloadTarget> PayloadTarget for SparseTarget<T> {
+    fn write(&mut self, mut bytes: &[u8]) -> BundleResult<()> {
+        while !bytes.is_empty() {
+            match self.state {
+                State::Detect => {
+                    if self.collect(&mut bytes, SPARSE_MAGIC.len()) {
+                        if self.pending == SPARSE_MAGIC {
+                            self.state = State::FileHeader;
+                        } else {
+                            self.inner.write(&self.pending)?;
+                            self.state = State::Passthrough;
+                        }
+                    }
+                }
+                State::Passthrough => {
+                    self.inner.write(bytes)?;
+                    bytes = &[];
+                }
+                State::FileHeader => {
+                    if self.collect(&mut bytes, SPARSE_HEADER_SIZE) {
+                        let header = SparseHeader::parse(&self.pending)
+                            .whatever("invalid sparse image header")?;
+                        self.remaining_chunks = header.num_chunks;
+                        let extra = u64::from(header.header_size) - SPARSE_HEADER_SIZE as u64;
+                        self.header = Some(header);
+                        self.pending.clear();
+                        self.state = State::Ignore { remaining: extra };
+                    }
+                }
+                State::ChunkHeader => {
+                    let size = usize::from(self.header().chunk_header_size);
+                    if self.collect(&mut bytes, size) {
+                        let chunk = ChunkHeader::parse(self.header(), &self.pending)
+                            .whatever("invalid sparse chunk header")?;
+                        self.start_chunk(chunk)?;
+                    }
+                }
+                State::RawData { remaining } => {
+                    let take = remaining.min(bytes.len() as u64);
+                    self.inner.write(&bytes[..take as usize])?;
+                    self.record_target(take);
+                    bytes = &bytes[take as usize..];
+                    if take == remaining {
+                        self.next_chunk();
+                    } else {
+                        self.state = State::RawData {
+                            remaining: remaining - take,
+                        };
+                    }
+                }
+                State::FillPattern { size } => {
+                    if self.collect(&mut bytes, 4) {
+                        self.write_fill(size)?;
+                        self.next_chunk();
+                    }
+                }
+                State::Ignore { remaining } => {
+                    let take = remaining.min(bytes.len() as u64) as usize;
+                    self.record_memory(&bytes[..take]);
+                    bytes = &bytes[take..];
+                    if take as u64 == remaining {
+                        self.next_chunk();
+                    } else {
+                        self.state = State::Ignore {
+                            remaining: remaining - take as u64,
+                        };
+                    }
+                }
+                State::Done => {
+                    bail!("unexpected data after the end of the sparse image");
+                }
+            }
+        }
+        // Empty chunks are complete without any further data.
+        while let State::RawData { remaining: 0 } | State::Ignore { remaining: 0 } = self.state {
+            self.next_chunk();
+        }
+        Ok(())
+    }
+
+    fn read_block(
+        &mut self,
+        offset: NumBytes,
+        size: NumBytes,
+        buffer: &mut Vec<u8>,
+    ) -> BundleResult<()> {
+        if matches!(self.state, State::Passthrough) {
+            return self.inner.read_block(offset, size, buffer);
+        }
+        let end = offset.raw + size.raw;
+        buffer.clear();
+        let mut position = offset.raw;
+        let mut idx = self
+            .segments
+            .partition_point(|segment| segment.payload_offset + segment.size <= position);
+        let mut target_buffer = Vec::new();
+        while position < end {
+            let Some(segment) = self.segments.get(idx) else {
+                bail!("block has not been written to the target");
+            };
+            let delta = position - segment.payload_offset;
+            let length = (segment.size - delta).min(end - position);
+            match segment.location {
+                Location::Memory(start) => {
+                    let start = start + delta as usize;
+                    buffer.extend_from_slice(&self.memory[start..start + length as usize]);
+                }
+                Location::Target(target_offset) => {
+                    self.inner.read_block(
+                        NumBytes::new(target_offset + delta),
+                        NumBytes::new(length),
+                        &mut target_buffer,
+                    )?;
+                    buffer.extend_from_slice(&target_buffer);
+                }
+            }
+            position += length;
+            idx += 1;
+        }
+        Ok(())
+    }
+
+    fn existing_block(&mut self, offset: NumBytes, hash: &[u8]) -> Option<NumBytes> {
+        // Offsets of sparse images do not correspond to offsets of the target.
+        if matches!(self.state, State::Passthrough) {
+            self.inner.existing_block(offset, hash)
+        } else {
+            None
+        }
+    }
+
+    fn skip(&mut self, size: NumBytes) -> BundleResult<()> {
+        if !matches!(self.state, State::Passthrough) {
+            bail!("unable to skip blocks of sparse images");
+        }
+        self.inner.skip(size)
+    }
+
+    fn finalize(mut self) -> BundleResult<()> {
+        match self.state {
+            State::Detect => {
+                // The payload is shorter than the magic bytes.
+                self.inner.write(&self.pending)?;
+            }
+            State::Passthrough | State::Done => { /* nothing to do */ }
+            _ => bail!("sparse image is truncated"),
+        }
+        if let Some(header) = &self.header {
+            let expected = u64::from(header.num_blocks) * u64::from(header.block_size);
+            if self.target_offset != expected {
+                bail!("size of sparse image does not match its header");
+            }
+        }
+        self.inner.finalize()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use byte_calc::NumBytes;
+    use rugix_bundle::reader::PayloadTarget;
+    use rugix_bundle::BundleResult;
+
+    use super::SparseTarget;
+
+    /// Target writing into memory.
+    #[derive(Debug, Default)]
+    struct MemoryTarget {
+        data: Vec<u8>,
+        position: usize,
+    }
+
+    impl PayloadTarget for MemoryTarget {
+        fn write(&mut self, bytes: &[u8]) -> BundleResult<()> {
+            let end = self.position + bytes.len();
+            if self.data.len() < end {
+                self.data.resize(end, 0);
+            }
+            self.data[self.position..end].copy_from_slice(bytes);
+            self.position = end;
+            Ok(())
+        }
+
+        fn read_block(
+            &mut self,
+            offset: NumBytes,
+            size: NumBytes,
+            buffer: &mut Vec<u8>,
+        ) -> BundleResult<()> {
+            let start = offset.unwrap_usize();
+            buffer.clear();
+            buffer.extend_from_slice(&self.data[start..start + size.unwrap_usize()]);
+            Ok(())
+        }
+
+        fn skip(&mut self, size: NumBytes) -> BundleResult<()> {
+            self.position += size.unwrap_usize();
+            Ok(())
+        }
+    }
+
+    fn chunk(kind: u16, num_blocks: u32, data: &[u8]) -> Vec<u8> {
+        let mut chunk = Vec::new();
+        chunk.extend_from_slice(&kind.to_le_bytes());
+        chunk.extend_from_slice(&[0; 2]);
+        chunk.extend_from_slice(&num_blocks.to_le_bytes());
+        chunk.extend_from_slice(&(12 + data.len() as u32).to_le_bytes());
+        chunk.extend_from_slice(data);
+        chunk
+    }
+
+    #[test]
+    fn test_sparse_target() {
+        let mut image = Vec::new();
+        image.extend_from_slice(&0xED26FF3Au32.to_le_bytes());
+        for value in [1u16, 0, 28, 12] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        for value in [8u32, 4, 3, 0] {
+            image.extend_from_slice(&value.to_le_bytes());
+        }
+        image.extend(chunk(0xCAC1, 1, b"abcdefgh"));
+        image.extend(chunk(0xCAC3, 2, &[]));
+        image.extend(chunk(0xCAC2, 1, b"xyzw"));
+        let mut target = SparseTarget::new(MemoryTarget {
+            data: vec![1; 32],
+            position: 0,
+        });
+        // Write the image in small pieces to exercise all intermediate states.
+        for piece in image.chunks(5) {
+            target.write(piece).unwrap();
+        }
+        // Blocks of the payload must be readable for deduplication.
+        let mut buffer = Vec::new();
+        target
+            .read_block(NumBytes::new(20), NumBytes::new(30), &mut buffer)
+            .unwrap();
+        assert_eq!(buffer, image[20..50]);
+        assert_eq!(
+            target.inner.data,
+            b"abcdefgh\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01\x01xyzwxyzw"
+        );
+        target.finalize().unwrap();
+    }
+}
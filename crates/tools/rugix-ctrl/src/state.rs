@@ -1,31 +1,33 @@
 use std::fs;
 
-use reportify::ResultExt;
 use tracing::warn;
 
 use crate::config::state::StateConfig;
-use crate::system::SystemResult;
+use crate::drop_ins::{load_config, MergeRules};
+use crate::system::{paths, SystemResult};
 
 /// The default directory with the configurations for state management.
 pub const STATE_CONFIG_DIR: &str = "/etc/rugix/state";
 pub const STATE_CONFIG_PATH: &str = "/etc/rugix/state.toml";
 
+/// Rules for merging the state configuration with drop-in files.
+pub const STATE_MERGE_RULES: MergeRules = MergeRules {
+    replace: &[],
+    append: &["persist"],
+};
+
 /// Loads the state configuration from the provided directory.
 pub fn load_state_config() -> SystemResult<StateConfig> {
-    let mut combined = StateConfig::new();
-
-    if let Ok(state) = fs::read_to_string(STATE_CONFIG_PATH) {
-        merge(
-            &mut combined,
-            toml::from_str(&state).whatever("unable to load state config")?,
-        );
-    }
+    let mut combined = load_config::<StateConfig>("state", STATE_MERGE_RULES)?;
 
-    if let Ok(read_dir) = fs::read_dir(STATE_CONFIG_DIR) {
-        for entry in read_dir {
-            if let Some(config) = entry
+    if let Ok(read_dir) = fs::read_dir(paths::prefixed(STATE_CONFIG_DIR)) {
+        let mut paths = read_dir
+            .filter_map(|entry| entry.ok().map(|entry| entry.path()))
+            .collect::<Vec<_>>();
+        paths.sort();
+        for path in paths {
+            if let Some(config) = fs::read_to_string(path)
                 .ok()
-                .and_then(|entry| fs::read_to_string(entry.path()).ok())
                 .and_then(|config| toml::from_str(&config).ok())
             {
                 merge(&mut combined, config);
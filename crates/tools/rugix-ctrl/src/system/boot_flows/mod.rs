@@ -4,6 +4,7 @@ use std::collections::HashMap;
 use std::fmt::Debug;
 use std::fs::File;
 use std::io::Write;
+use std::path::Path;
 
 use custom::CustomBootFlow;
 use reportify::{bail, Report, ResultExt};
@@ -11,13 +12,14 @@ use serde::{Deserialize, Serialize};
 use tempfile::tempdir;
 
 use super::boot_groups::{BootGroupIdx, BootGroups};
-use super::slots::SlotIdx;
-use super::{ConfigPartition, System};
+use super::slots::{Slot, SlotIdx};
+use super::{paths, ConfigPartition, System};
 use crate::config::system::BootFlowConfig;
 use crate::system::slots::SlotKind;
 use rugix_common::boot::grub::{load_grub_env, write_with_hash, RUGIX_BOOTPART};
 use rugix_common::boot::tryboot::{self, AutobootSection, AUTOBOOT_A, AUTOBOOT_B};
 use rugix_common::boot::uboot::UBootEnv;
+use rugix_common::devices::rpi::VCIO_PATH;
 use rugix_common::mount::Mounted;
 use rugix_common::partitions::get_disk_id;
 use rugix_common::utils::ascii_numbers;
@@ -79,40 +81,43 @@ pub fn from_config(
     config_partition: &ConfigPartition,
     boot_entries: &BootGroups,
 ) -> BootFlowResult<Box<dyn BootFlow>> {
-    if let Some(config) = config {
-        return Ok(match config {
-            BootFlowConfig::Tryboot => Box::new(Tryboot {
-                inner: rugix_boot_flow(boot_entries)?,
-            }),
-            BootFlowConfig::UBoot => Box::new(UBoot {
-                inner: rugix_boot_flow(boot_entries)?,
-            }),
-            BootFlowConfig::GrubEfi => Box::new(GrubEfi {
-                inner: rugix_boot_flow(boot_entries)?,
-            }),
-            BootFlowConfig::Custom(custom_boot_flow_config) => Box::new(CustomBootFlow {
-                controller: custom_boot_flow_config.controller.clone().into(),
-            }),
-        });
-    }
-    let inner = rugix_boot_flow(boot_entries)?;
-    if config_partition.path().join("autoboot.txt").exists() {
-        Ok(Box::new(Tryboot { inner }))
-    } else if config_partition
-        .path()
-        .join("bootpart.default.env")
-        .exists()
-    {
-        Ok(Box::new(UBoot { inner }))
-    } else if config_partition
-        .path()
-        .join("rugpi/primary.grubenv")
-        .exists()
-        && config_partition.path().join("EFI").is_dir()
-    {
-        Ok(Box::new(GrubEfi { inner }))
+    let detected;
+    let config = match config {
+        Some(config) => config,
+        None => {
+            let Some(config) = detect_boot_flow(config_partition.path()) else {
+                bail!("unable to detect boot flow");
+            };
+            detected = config;
+            &detected
+        }
+    };
+    Ok(match config {
+        BootFlowConfig::Tryboot => Box::new(Tryboot {
+            inner: rugix_boot_flow(boot_entries)?,
+        }),
+        BootFlowConfig::UBoot => Box::new(UBoot {
+            inner: rugix_boot_flow(boot_entries)?,
+        }),
+        BootFlowConfig::GrubEfi => Box::new(GrubEfi {
+            inner: rugix_boot_flow(boot_entries)?,
+        }),
+        BootFlowConfig::Custom(custom_boot_flow_config) => Box::new(CustomBootFlow {
+            controller: paths::prefixed(&custom_boot_flow_config.controller),
+        }),
+    })
+}
+
+/// Detect the boot flow based on the contents of the config partition.
+pub fn detect_boot_flow(config_dir: &Path) -> Option<BootFlowConfig> {
+    if config_dir.join("autoboot.txt").exists() {
+        Some(BootFlowConfig::Tryboot)
+    } else if config_dir.join("bootpart.default.env").exists() {
+        Some(BootFlowConfig::UBoot)
+    } else if config_dir.join("rugpi/primary.grubenv").exists() && config_dir.join("EFI").is_dir() {
+        Some(BootFlowConfig::GrubEfi)
     } else {
-        bail!("unable to detect boot flow");
+        None
     }
 }
 
@@ -163,10 +168,11 @@ struct Tryboot {
 
 impl BootFlow for Tryboot {
     fn set_try_next(&self, system: &System, entry: BootGroupIdx) -> BootFlowResult<()> {
+        let vcio = paths::prefixed(VCIO_PATH);
         if entry != self.get_default(system)? {
-            tryboot::set_spare_flag().whatever("unable to set tryboot flag")?;
+            tryboot::set_spare_flag(&vcio).whatever("unable to set tryboot flag")?;
         } else {
-            tryboot::clear_spare_flag().whatever("unable to clear tryboot flag")?;
+            tryboot::clear_spare_flag(&vcio).whatever("unable to clear tryboot flag")?;
         }
         Ok(())
     }
@@ -334,11 +340,7 @@ fn tryboot_uboot_post_install(
     };
     let boot_slot = &system.slots()[boot_slot];
     let _system_slot = &system.slots()[system_slot];
-    let SlotKind::Block(boot_raw) = boot_slot.kind() else {
-        bail!("boot slot must be of type `block`")
-    };
-    let _mounted_boot = Mounted::mount(boot_raw.device(), temp_dir_spare)
-        .whatever("unable to mount boot device")?;
+    let _mounted_boot = mount_boot_slot(boot_slot, temp_dir_spare)?;
     let Some(root) = &system.root else {
         bail!("no parent block device");
     };
@@ -359,6 +361,18 @@ fn tryboot_uboot_post_install(
     Ok(())
 }
 
+/// Mount the filesystem of a boot slot.
+///
+/// Boot slots of type `file` are mounted as filesystem images.
+fn mount_boot_slot(boot_slot: &Slot, path: &Path) -> BootFlowResult<Mounted> {
+    let boot_dev = match boot_slot.kind() {
+        SlotKind::Block(boot_raw) => boot_raw.device().path(),
+        SlotKind::File { path } => path.as_path(),
+        _ => bail!("boot slot must be of type `block` or `file`"),
+    };
+    Mounted::mount(boot_dev, path).whatever("unable to mount boot slot")
+}
+
 #[derive(Debug)]
 struct GrubEfi {
     inner: RugixBootFlow,
@@ -435,11 +449,7 @@ impl BootFlow for GrubEfi {
         };
         let boot_slot = &system.slots()[boot_slot];
         let _system_slot = &system.slots()[system_slot];
-        let SlotKind::Block(boot_raw) = boot_slot.kind() else {
-            bail!("boot slot must be of type `block`")
-        };
-        let _mounted_boot = Mounted::mount(boot_raw.device(), temp_dir_spare)
-            .whatever("unable to mount boot partition")?;
+        let _mounted_boot = mount_boot_slot(boot_slot, temp_dir_spare)?;
         let Some(table) = system.root.as_ref().and_then(|root| root.table.as_ref()) else {
             bail!("no partition table");
         };
@@ -16,6 +16,12 @@ pub struct BootGroupIdx {
     idx: usize,
 }
 
+/// Default boot groups with the slots for the respective aliases.
+pub const DEFAULT_BOOT_GROUPS: [(&str, [(&str, &str); 2]); 2] = [
+    ("a", [("boot", "boot-a"), ("system", "system-a")]),
+    ("b", [("boot", "boot-b"), ("system", "system-b")]),
+];
+
 #[derive(Debug)]
 pub struct BootGroups {
     groups: Vec<BootGroup>,
@@ -46,10 +52,7 @@ impl BootGroups {
             }
             None => {
                 // Create Rugix default boot groups.
-                for (group_name, group_slots) in [
-                    ("a", [("boot", "boot-a"), ("system", "system-a")]),
-                    ("b", [("boot", "boot-b"), ("system", "system-b")]),
-                ] {
+                for (group_name, group_slots) in DEFAULT_BOOT_GROUPS {
                     let mut map = IndexMap::new();
                     for (alias, name) in group_slots {
                         let Some((idx, _)) = slots.find_by_name(name) else {
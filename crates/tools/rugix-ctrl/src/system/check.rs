@@ -0,0 +1,744 @@
+//! Checking of the system configuration without initializing the system.
+//!
+//! In contrast to [`System::initialize`][super::System::initialize], which fails on the
+//! first problem, checking collects all problems of the configuration. Partitions can be
+//! resolved against the partition table of a disk image instead of the root device,
+//! which allows checking the configuration of an image before flashing it.
+
+use std::collections::HashMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use serde::de::DeserializeOwned;
+
+use crate::config::bootstrapping::BootstrappingConfig;
+use crate::config::state::StateConfig;
+use crate::config::system::{BootFlowConfig, PartitionConfig, SlotConfig, SystemConfig};
+use crate::drop_ins::{config_files_in, load_config_files, MergeRules};
+use crate::init::BOOTSTRAP_MERGE_RULES;
+use crate::state::STATE_MERGE_RULES;
+
+use super::boot_flows::detect_boot_flow;
+use super::boot_groups::DEFAULT_BOOT_GROUPS;
+use super::config::SYSTEM_MERGE_RULES;
+use super::paths;
+use super::root::{
+    find_partlabel, find_partuuid, find_system_device, find_table_partlabel, find_table_partuuid,
+    SystemRoot,
+};
+use super::slots::{DEFAULT_GPT_SLOTS, DEFAULT_MBR_SLOTS};
+use rugix_common::disk::blkdev::BlockDevice;
+use rugix_common::disk::{Partition, PartitionTable};
+
+/// Options for checking the configuration.
+#[derive(Debug, Clone)]
+pub struct CheckOptions {
+    /// Root filesystem containing the configuration files.
+    pub root: PathBuf,
+    /// Disk or disk image used to resolve partitions instead of the root device.
+    pub disk: Option<PathBuf>,
+    /// Directory with the contents of the config partition to detect the boot flow.
+    pub config_dir: Option<PathBuf>,
+}
+
+impl CheckOptions {
+    /// Indicates whether the configuration of the running system is checked.
+    fn is_live(&self) -> bool {
+        self.root == Path::new("/") && self.disk.is_none()
+    }
+}
+
+/// Severity of a problem.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Severity {
+    /// Problem which may lead to unexpected behavior.
+    Warning,
+    /// Problem which prevents the system from working.
+    Error,
+}
+
+impl fmt::Display for Severity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Severity::Warning => f.write_str("warning"),
+            Severity::Error => f.write_str("error"),
+        }
+    }
+}
+
+/// Problem found in the configuration.
+#[derive(Debug, Clone)]
+pub struct Problem {
+    /// Severity of the problem.
+    pub severity: Severity,
+    /// Location of the problem, i.e., the file and the key.
+    pub location: String,
+    /// Description of the problem.
+    pub message: String,
+}
+
+impl fmt::Display for Problem {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}: {}: {}", self.severity, self.location, self.message)
+    }
+}
+
+/// Result of checking the configuration.
+#[derive(Debug, Default)]
+pub struct ConfigCheck {
+    /// Configuration files which have been loaded.
+    pub files: Vec<PathBuf>,
+    /// Resolved config and data partitions.
+    pub partitions: Vec<(String, String)>,
+    /// Resolved slots.
+    pub slots: Vec<(String, String)>,
+    /// Boot groups with their slots.
+    pub boot_groups: Vec<(String, Vec<(String, String)>)>,
+    /// Configured or detected boot flow.
+    pub boot_flow: Option<String>,
+    /// Problems found in the configuration.
+    pub problems: Vec<Problem>,
+}
+
+impl ConfigCheck {
+    /// Check the configuration.
+    pub fn run(options: &CheckOptions) -> Self {
+        let mut checker = Checker::new(options);
+        checker.check();
+        checker.check
+    }
+
+    /// Number of problems with error severity.
+    pub fn num_errors(&self) -> usize {
+        self.problems
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .count()
+    }
+}
+
+/// Files in which the individual configuration keys have been defined.
+type Origins = HashMap<String, PathBuf>;
+
+/// Partition addressed by the configuration.
+#[derive(Debug, Clone, Copy)]
+struct PartitionRef<'c> {
+    device: Option<&'c str>,
+    partition: Option<u32>,
+    partlabel: Option<&'c str>,
+    partuuid: Option<&'c str>,
+}
+
+impl<'c> PartitionRef<'c> {
+    fn from_partition_config(config: &'c PartitionConfig) -> Self {
+        Self {
+            device: config.device.as_deref(),
+            partition: config.partition,
+            partlabel: config.partlabel.as_deref(),
+            partuuid: config.partuuid.as_deref(),
+        }
+    }
+
+    /// Names of the settings which are set.
+    fn settings(&self) -> Vec<&'static str> {
+        let mut settings = Vec::new();
+        if self.device.is_some() {
+            settings.push("device");
+        }
+        if self.partition.is_some() {
+            settings.push("partition");
+        }
+        if self.partlabel.is_some() {
+            settings.push("partlabel");
+        }
+        if self.partuuid.is_some() {
+            settings.push("partuuid");
+        }
+        settings
+    }
+}
+
+struct Checker<'o> {
+    options: &'o CheckOptions,
+    check: ConfigCheck,
+    origins: Origins,
+    /// Name of the disk used to resolve partitions.
+    disk_name: Option<String>,
+    /// Partition table used to resolve partitions.
+    table: Option<PartitionTable>,
+    /// Root device of the running system.
+    root: Option<SystemRoot>,
+    /// Resolved partitions and the settings resolving to them.
+    resolved: HashMap<String, String>,
+}
+
+impl<'o> Checker<'o> {
+    fn new(options: &'o CheckOptions) -> Self {
+        Self {
+            options,
+            check: ConfigCheck::default(),
+            origins: Origins::new(),
+            disk_name: None,
+            table: None,
+            root: None,
+            resolved: HashMap::new(),
+        }
+    }
+
+    fn report(&mut self, severity: Severity, location: String, message: impl Into<String>) {
+        self.check.problems.push(Problem {
+            severity,
+            location,
+            message: message.into(),
+        });
+    }
+
+    fn error(&mut self, location: String, message: impl Into<String>) {
+        self.report(Severity::Error, location, message)
+    }
+
+    fn warning(&mut self, location: String, message: impl Into<String>) {
+        self.report(Severity::Warning, location, message)
+    }
+
+    /// Location of the given key of the system configuration.
+    fn location(&self, key: &str) -> String {
+        let mut prefix = key;
+        loop {
+            if let Some(path) = self.origins.get(prefix) {
+                return format!("{}: `{key}`", path.display());
+            }
+            match prefix.rsplit_once('.') {
+                Some((parent, _)) => prefix = parent,
+                None => return format!("<default>: `{key}`"),
+            }
+        }
+    }
+
+    fn check(&mut self) {
+        self.load_disk();
+        let _ = self.load::<StateConfig>("state", STATE_MERGE_RULES);
+        let _ = self.load::<BootstrappingConfig>("bootstrapping", BOOTSTRAP_MERGE_RULES);
+        let Some((config, origins)) = self.load::<SystemConfig>("system", SYSTEM_MERGE_RULES)
+        else {
+            return;
+        };
+        self.origins = origins;
+        self.check_partitions(&config);
+        let slots = self.check_slots(&config);
+        self.check_boot_groups(&config, &slots);
+        self.check_boot_flow(&config);
+        self.check_image_update(&config);
+        self.check_decryption_keys(&config);
+    }
+
+    /// Load the partition table used to resolve partitions.
+    fn load_disk(&mut self) {
+        if let Some(disk) = &self.options.disk {
+            match PartitionTable::read(disk) {
+                Ok(table) => self.table = Some(table),
+                Err(error) => self.error(
+                    format!("{}", disk.display()),
+                    format!("unable to read partition table: {error:?}"),
+                ),
+            }
+            self.disk_name = Some(disk.display().to_string());
+        } else if self.options.is_live() {
+            let root = find_system_device()
+                .as_ref()
+                .and_then(SystemRoot::from_system_device);
+            match &root {
+                Some(root) => {
+                    self.disk_name = Some(root.device.path().display().to_string());
+                    self.table = root.table.clone();
+                }
+                None => self.warning(
+                    "<system>".to_owned(),
+                    "unable to determine root device, partitions cannot be resolved",
+                ),
+            }
+            self.root = root;
+        }
+    }
+
+    /// Load and validate the configuration files with the given name.
+    fn load<T: DeserializeOwned + Default>(
+        &mut self,
+        name: &str,
+        rules: MergeRules,
+    ) -> Option<(T, Origins)> {
+        let files = match config_files_in(&self.options.root, name) {
+            Ok(files) => files,
+            Err(error) => {
+                self.error(format!("{name}.d"), format!("{error:?}"));
+                return None;
+            }
+        };
+        let mut origins = Origins::new();
+        let mut valid = true;
+        for path in &files {
+            let location = path.display().to_string();
+            let source = match fs::read_to_string(path) {
+                Ok(source) => source,
+                Err(error) => {
+                    self.error(location, format!("unable to read file: {error}"));
+                    valid = false;
+                    continue;
+                }
+            };
+            // Each file must be valid on its own, which allows us to report errors with
+            // their precise location in the file.
+            let table = match toml::from_str::<toml::Table>(&source) {
+                Ok(table) => table,
+                Err(error) => {
+                    self.error(location, error.to_string().trim_end());
+                    valid = false;
+                    continue;
+                }
+            };
+            if let Err(error) = toml::from_str::<T>(&source) {
+                self.error(location, error.to_string().trim_end());
+                valid = false;
+                continue;
+            }
+            for (key, value) in table {
+                if let toml::Value::Table(table) = value {
+                    for nested in table.keys() {
+                        origins.insert(format!("{key}.{nested}"), path.clone());
+                    }
+                }
+                origins.insert(key, path.clone());
+            }
+        }
+        self.check.files.extend(files.iter().cloned());
+        if !valid {
+            return None;
+        }
+        match load_config_files(&files, rules) {
+            Ok(config) => Some((config, origins)),
+            Err(error) => {
+                self.error(name.to_owned(), format!("{error:?}"));
+                None
+            }
+        }
+    }
+
+    fn check_partitions(&mut self, config: &SystemConfig) {
+        let default_config = PartitionConfig::new();
+        let config_partition = config.config_partition.as_ref().unwrap_or(&default_config);
+        if config_partition.disabled.unwrap_or(false) {
+            self.error(
+                self.location("config-partition.disabled"),
+                "config partition cannot currently be disabled",
+            );
+        } else {
+            self.check_partition("config", "config-partition", config_partition, 1);
+        }
+        let data_partition = config.data_partition.as_ref().unwrap_or(&default_config);
+        if data_partition.disabled.unwrap_or(false) {
+            self.check
+                .partitions
+                .push(("data".to_owned(), "disabled".to_owned()));
+        } else {
+            let default = match &self.table {
+                Some(table) if table.is_mbr() => 7,
+                _ => 6,
+            };
+            self.check_partition("data", "data-partition", data_partition, default);
+        }
+    }
+
+    fn check_partition(&mut self, name: &str, key: &str, config: &PartitionConfig, default: u32) {
+        let mut reference = PartitionRef::from_partition_config(config);
+        if reference.settings().is_empty() {
+            reference.partition = Some(default);
+        }
+        if let Some(description) = self.resolve(key, reference) {
+            self.check.partitions.push((name.to_owned(), description));
+        }
+    }
+
+    /// Check the slots and return their names.
+    fn check_slots(&mut self, config: &SystemConfig) -> Vec<String> {
+        let slots = match &config.slots {
+            Some(slots) => slots
+                .iter()
+                .map(|(name, config)| (name.clone(), config.clone()))
+                .collect::<Vec<_>>(),
+            None => {
+                let Some(table) = &self.table else {
+                    self.error(
+                        self.location("slots"),
+                        "no slots configured and no partition table to derive default slots",
+                    );
+                    return Vec::new();
+                };
+                let default_slots = if table.is_mbr() {
+                    DEFAULT_MBR_SLOTS
+                } else {
+                    DEFAULT_GPT_SLOTS
+                };
+                default_slots
+                    .iter()
+                    .map(|(name, config)| (name.to_string(), config.clone()))
+                    .collect()
+            }
+        };
+        for (name, config) in &slots {
+            let key = format!("slots.{name}");
+            let description = match config {
+                SlotConfig::Block(block_config) => self.resolve(
+                    &key,
+                    PartitionRef {
+                        device: block_config.device.as_deref(),
+                        partition: block_config.partition,
+                        partlabel: block_config.partlabel.as_deref(),
+                        partuuid: block_config.partuuid.as_deref(),
+                    },
+                ),
+                SlotConfig::File(file_config) => {
+                    self.check_path(&key, &file_config.path);
+                    Some(format!("file {:?}", file_config.path))
+                }
+                SlotConfig::Directory(directory_config) => {
+                    self.check_path(&key, &directory_config.path);
+                    Some(format!("directory {:?}", directory_config.path))
+                }
+                SlotConfig::Custom(custom_config) => {
+                    if custom_config.handler.is_empty() {
+                        self.error(self.location(&key), "custom slot handler must not be empty");
+                    }
+                    Some(format!("custom {:?}", custom_config.handler))
+                }
+            };
+            if let Some(description) = description {
+                self.check.slots.push((name.clone(), description));
+            }
+        }
+        slots.into_iter().map(|(name, _)| name).collect()
+    }
+
+    fn check_path(&mut self, key: &str, path: &str) {
+        if !Path::new(path).is_absolute() {
+            self.error(self.location(key), format!("path {path:?} is not absolute"));
+        }
+    }
+
+    /// Resolve a partition and return a description of it.
+    fn resolve(&mut self, key: &str, reference: PartitionRef) -> Option<String> {
+        let settings = reference.settings();
+        if settings.is_empty() {
+            self.error(
+                self.location(key),
+                "one of `device`, `partition`, `partlabel`, or `partuuid` must be set",
+            );
+            return None;
+        }
+        if settings.len() > 1 {
+            self.warning(
+                self.location(key),
+                format!(
+                    "`{}` are set, only `{}` is used",
+                    settings.join("`, `"),
+                    settings[0]
+                ),
+            );
+        }
+        let resolved = if let Some(device) = reference.device {
+            if self.options.is_live() {
+                if let Err(error) = BlockDevice::new(device) {
+                    self.error(
+                        self.location(&format!("{key}.device")),
+                        format!("{device:?} is not a block device: {error}"),
+                    );
+                    return None;
+                }
+            }
+            Ok((device.to_owned(), format!("device {device:?}")))
+        } else if let Some(number) = reference.partition {
+            self.resolve_partition(|table| {
+                table
+                    .partitions
+                    .iter()
+                    .find(|partition| u32::from(partition.number) == number)
+            })
+            .ok_or_else(|| {
+                (
+                    format!("{key}.partition"),
+                    format!("partition {number} not found"),
+                )
+            })
+        } else if let Some(label) = reference.partlabel {
+            self.resolve_partition(|table| find_table_partlabel(table, label))
+                .or_else(|| self.resolve_link(|root| find_partlabel(root, label)))
+                .ok_or_else(|| {
+                    (
+                        format!("{key}.partlabel"),
+                        format!("partition with label {label:?} not found"),
+                    )
+                })
+        } else if let Some(uuid) = reference.partuuid {
+            self.resolve_partition(|table| find_table_partuuid(table, uuid))
+                .or_else(|| self.resolve_link(|root| find_partuuid(root, uuid)))
+                .ok_or_else(|| {
+                    (
+                        format!("{key}.partuuid"),
+                        format!("partition with UUID {uuid:?} not found"),
+                    )
+                })
+        } else {
+            unreachable!("at least one setting is set")
+        };
+        match resolved {
+            Ok((id, description)) => {
+                if let Some(other) = self.resolved.insert(id, key.to_owned()) {
+                    self.error(
+                        self.location(key),
+                        format!("refers to the same partition as `{other}`"),
+                    );
+                }
+                Some(description)
+            }
+            Err((key, message)) => {
+                let message = match &self.disk_name {
+                    Some(disk) => format!("{message} on {disk}"),
+                    None => format!("{message}: no partition table"),
+                };
+                self.error(self.location(&key), message);
+                None
+            }
+        }
+    }
+
+    /// Resolve a partition of the partition table.
+    fn resolve_partition<'t>(
+        &'t self,
+        find: impl FnOnce(&'t PartitionTable) -> Option<&'t Partition>,
+    ) -> Option<(String, String)> {
+        let table = self.table.as_ref()?;
+        let partition = find(table)?;
+        let size = table.blocks_to_bytes(partition.size);
+        let disk = self.disk_name.as_deref().unwrap_or("<unknown>");
+        let mut description = format!("partition {} of {disk} ({size}", partition.number);
+        if let Some(name) = &partition.name {
+            description.push_str(&format!(", name {name:?}"));
+        }
+        description.push(')');
+        Some((format!("partition {}", partition.number), description))
+    }
+
+    /// Resolve a partition of the running system, e.g., via `/dev/disk/by-partlabel`.
+    fn resolve_link(
+        &self,
+        find: impl FnOnce(Option<&SystemRoot>) -> Option<BlockDevice>,
+    ) -> Option<(String, String)> {
+        if !self.options.is_live() {
+            return None;
+        }
+        let device = find(self.root.as_ref())?;
+        let path = device.path().display().to_string();
+        Some((path.clone(), format!("device {path:?}")))
+    }
+
+    fn check_boot_groups(&mut self, config: &SystemConfig, slots: &[String]) {
+        let groups = match &config.boot_groups {
+            Some(groups) => groups
+                .iter()
+                .map(|(name, group)| {
+                    let slots = group
+                        .slots
+                        .iter()
+                        .map(|(alias, slot)| (alias.clone(), slot.clone()))
+                        .collect::<Vec<_>>();
+                    (name.clone(), slots)
+                })
+                .collect::<Vec<_>>(),
+            None => DEFAULT_BOOT_GROUPS
+                .iter()
+                .map(|(name, group_slots)| {
+                    let slots = group_slots
+                        .iter()
+                        .map(|(alias, slot)| (alias.to_string(), slot.to_string()))
+                        .collect();
+                    (name.to_string(), slots)
+                })
+                .collect(),
+        };
+        for (name, group_slots) in &groups {
+            for (alias, slot) in group_slots {
+                if !slots.contains(slot) {
+                    self.error(
+                        self.location(&format!("boot-groups.{name}")),
+                        format!("slot {slot:?} of alias {alias:?} does not exist"),
+                    );
+                }
+            }
+        }
+        self.check.boot_groups = groups;
+    }
+
+    fn check_boot_flow(&mut self, config: &SystemConfig) {
+        let (boot_flow, detected) = match &config.boot_flow {
+            Some(boot_flow) => (boot_flow.clone(), false),
+            None => {
+                let config_dir = match &self.options.config_dir {
+                    Some(config_dir) => config_dir.clone(),
+                    None => {
+                        let path = config
+                            .config_partition
+                            .as_ref()
+                            .and_then(|config| config.path.as_deref())
+                            .unwrap_or(paths::MOUNT_POINT_CONFIG);
+                        self.options.root.join(path.trim_start_matches('/'))
+                    }
+                };
+                let Some(boot_flow) = detect_boot_flow(&config_dir) else {
+                    self.error(
+                        self.location("boot-flow"),
+                        format!(
+                            "unable to detect boot flow from {}, set `boot-flow` explicitly",
+                            config_dir.display()
+                        ),
+                    );
+                    return;
+                };
+                (boot_flow, true)
+            }
+        };
+        let name = match &boot_flow {
+            BootFlowConfig::Tryboot => "tryboot",
+            BootFlowConfig::UBoot => "u-boot",
+            BootFlowConfig::GrubEfi => "grub-efi",
+            BootFlowConfig::Custom(_) => "custom",
+        };
+        self.check.boot_flow = Some(if detected {
+            format!("{name} (detected)")
+        } else {
+            name.to_owned()
+        });
+        if !matches!(boot_flow, BootFlowConfig::Custom(_)) {
+            // The builtin boot flows require two boot groups with `boot` and `system` slots.
+            let groups = self.check.boot_groups.clone();
+            if groups.len() < 2 {
+                self.error(
+                    self.location("boot-groups"),
+                    format!("boot flow {name:?} requires two boot groups"),
+                );
+            }
+            for (group, slots) in groups.iter().take(2) {
+                for alias in ["boot", "system"] {
+                    if !slots.iter().any(|(slot_alias, _)| slot_alias == alias) {
+                        self.error(
+                            self.location(&format!("boot-groups.{group}")),
+                            format!("boot flow {name:?} requires a slot with alias {alias:?}"),
+                        );
+                    }
+                }
+            }
+        }
+    }
+
+    fn check_image_update(&mut self, config: &SystemConfig) {
+        let Some(image_update) = &config.image_update else {
+            return;
+        };
+        let groups = self.check.boot_groups.clone();
+        for (idx, partition) in image_update.partitions.iter().enumerate() {
+            let key = format!("image-update.partitions[{idx}]");
+            if partition.partition.is_none() && partition.label.is_none() {
+                self.error(
+                    self.location(&key),
+                    "one of `partition` or `label` must be set",
+                );
+            }
+            for (group, slots) in &groups {
+                if !slots.iter().any(|(alias, _)| alias == &partition.slot) {
+                    self.error(
+                        self.location(&key),
+                        format!(
+                            "boot group {group:?} has no slot with alias {:?}",
+                            partition.slot
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    fn check_decryption_keys(&mut self, config: &SystemConfig) {
+        for key_file in config.decryption_keys.iter().flatten() {
+            let path = self.options.root.join(key_file.trim_start_matches('/'));
+            if !path.exists() {
+                self.warning(
+                    self.location("decryption-keys"),
+                    format!("key file {key_file:?} does not exist"),
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use indoc::indoc;
+
+    use super::{CheckOptions, ConfigCheck, Severity};
+
+    #[test]
+    fn test_check_reports_all_problems() {
+        let root = tempfile::tempdir().unwrap();
+        let config_dir = root.path().join("etc/rugix");
+        fs::create_dir_all(config_dir.join("system.d")).unwrap();
+        fs::write(
+            config_dir.join("system.toml"),
+            indoc! {r#"
+                boot-flow = { type = "custom", controller = "/usr/bin/boot-flow" }
+
+                [config-partition]
+                device = "/dev/mmcblk0p1"
+
+                [data-partition]
+                disabled = true
+
+                [slots.system-a]
+                type = "file"
+                path = "system-a.img"
+            "#},
+        )
+        .unwrap();
+        fs::write(
+            config_dir.join("system.d/10-groups.toml"),
+            indoc! {r#"
+                [boot-groups.a]
+                slots = { system = "system-b" }
+            "#},
+        )
+        .unwrap();
+        fs::write(config_dir.join("state.toml"), "overlay = 42").unwrap();
+        let check = ConfigCheck::run(&CheckOptions {
+            root: root.path().to_path_buf(),
+            disk: None,
+            config_dir: None,
+        });
+        let system_toml = config_dir.join("system.toml").display().to_string();
+        let groups_toml = config_dir.join("system.d/10-groups.toml");
+        let problems = check
+            .problems
+            .iter()
+            .filter(|problem| problem.severity == Severity::Error)
+            .map(|problem| problem.location.as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            problems,
+            [
+                config_dir.join("state.toml").display().to_string(),
+                format!("{system_toml}: `slots.system-a`"),
+                format!("{}: `boot-groups.a`", groups_toml.display()),
+            ]
+        );
+        assert_eq!(check.boot_flow.as_deref(), Some("custom"));
+    }
+}
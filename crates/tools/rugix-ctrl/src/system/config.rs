@@ -1,28 +1,25 @@
 //! System configuration.
 
-use std::fs;
-use std::path::Path;
-
-use reportify::ResultExt;
-
 use crate::config::system::SystemConfig;
+use crate::drop_ins::{load_config, MergeRules};
 
 use super::SystemResult;
 
 /// Path of the system configuration file.
 pub const SYSTEM_CONFIG_PATH: &str = "/etc/rugix/system.toml";
 
-/// Load the system configuration.
+/// Rules for merging the system configuration with drop-in files.
+///
+/// Slots, boot groups, and the boot flow are replaced as a whole such that their
+/// configuration is never composed of different files.
+pub const SYSTEM_MERGE_RULES: MergeRules = MergeRules {
+    replace: &["slots.*", "boot-groups.*", "boot-flow"],
+    append: &[],
+};
+
+/// Load the system configuration including any drop-in files.
 pub fn load_system_config() -> SystemResult<SystemConfig> {
-    Ok(if Path::new(SYSTEM_CONFIG_PATH).exists() {
-        toml::from_str(
-            &fs::read_to_string(SYSTEM_CONFIG_PATH)
-                .whatever("unable to read system configuration file")?,
-        )
-        .whatever("unable to parse system configuration file")?
-    } else {
-        SystemConfig::default()
-    })
+    load_config("system", SYSTEM_MERGE_RULES)
 }
 
 #[cfg(test)]
@@ -13,6 +13,7 @@ use crate::config::system::{PartitionConfig, SystemConfig};
 
 pub mod boot_flows;
 pub mod boot_groups;
+pub mod check;
 pub mod config;
 pub mod partitions;
 pub mod paths;
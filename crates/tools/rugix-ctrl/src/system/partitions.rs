@@ -9,7 +9,7 @@ use xscript::{run, Run};
 
 use crate::config::system::PartitionConfig;
 
-use super::root::SystemRoot;
+use super::root::{find_partlabel, find_partuuid, SystemRoot};
 use super::{paths, SystemResult};
 use rugix_common::disk::blkdev::BlockDevice;
 
@@ -58,6 +58,16 @@ fn resolve_partition(
         BlockDevice::new(device)
             .whatever("partition is not a block device")
             .with_info(|_| format!("device: {device:?}"))?
+    } else if let (None, Some(partlabel)) = (config.partition, &config.partlabel) {
+        match find_partlabel(root, partlabel) {
+            Some(device) => device,
+            None => bail!("unable to resolve partition with label {partlabel:?}"),
+        }
+    } else if let (None, Some(partuuid)) = (config.partition, &config.partuuid) {
+        match find_partuuid(root, partuuid) {
+            Some(device) => device,
+            None => bail!("unable to resolve partition with UUID {partuuid:?}"),
+        }
     } else {
         let partition = match config.partition {
             Some(partition) => partition,
@@ -96,11 +106,7 @@ impl ConfigPartition {
             None
         } else {
             Some(Self::new(
-                config
-                    .path
-                    .as_deref()
-                    .unwrap_or(paths::MOUNT_POINT_CONFIG)
-                    .into(),
+                paths::prefixed(config.path.as_deref().unwrap_or(paths::MOUNT_POINT_CONFIG)),
                 config.protected.unwrap_or(true),
             ))
         }
@@ -1,5 +1,8 @@
 //! Constants for paths.
 
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
 /// Path where the system partition is mounted.
 pub const MOUNT_POINT_SYSTEM: &str = "/run/rugix/mounts/system";
 
@@ -8,3 +11,35 @@ pub const MOUNT_POINT_DATA: &str = "/run/rugix/mounts/data";
 
 /// Path where the config partition is mounted.
 pub const MOUNT_POINT_CONFIG: &str = "/run/rugix/mounts/config";
+
+/// Directory with the hooks.
+pub const HOOKS_DIR: &str = "/etc/rugix/hooks";
+
+/// Environment variable with a prefix for all system paths.
+///
+/// If set, Rugix Ctrl operates on the directory tree under the prefix instead of the
+/// actual system. This is used to test Rugix Ctrl without a (virtual) machine.
+pub const ROOT_PREFIX_VAR: &str = "RUGIX_ROOT_PREFIX";
+
+/// Prefix for all system paths.
+pub fn root_prefix() -> &'static Path {
+    static ROOT_PREFIX: OnceLock<PathBuf> = OnceLock::new();
+    ROOT_PREFIX.get_or_init(|| match std::env::var_os(ROOT_PREFIX_VAR) {
+        Some(prefix) if !prefix.is_empty() => PathBuf::from(prefix),
+        _ => PathBuf::from("/"),
+    })
+}
+
+/// Indicates whether Rugix Ctrl operates under a root prefix.
+pub fn is_prefixed() -> bool {
+    root_prefix() != Path::new("/")
+}
+
+/// Resolve a system path under the root prefix.
+pub fn prefixed(path: impl AsRef<Path>) -> PathBuf {
+    let path = path.as_ref();
+    if !is_prefixed() {
+        return path.to_path_buf();
+    }
+    root_prefix().join(path.strip_prefix("/").unwrap_or(path))
+}
@@ -1,19 +1,18 @@
 //! Functionality related to finding a system's root device.
 
-use std::path::Path;
-
 use tracing::error;
 
 use super::paths;
 use rugix_common::disk::blkdev::{find_block_device, BlockDevice};
-use rugix_common::disk::PartitionTable;
+use rugix_common::disk::{DiskId, Partition, PartitionTable};
 
 /// Find the system block device.
 pub fn find_system_device() -> Option<BlockDevice> {
-    find_block_device(if Path::new(paths::MOUNT_POINT_SYSTEM).exists() {
-        paths::MOUNT_POINT_SYSTEM
+    let mount_point = paths::prefixed(paths::MOUNT_POINT_SYSTEM);
+    find_block_device(if mount_point.exists() {
+        mount_point
     } else {
-        "/"
+        paths::prefixed("/")
     })
     .inspect_err(|error| error!("error determining system block device: {error}"))
     .ok()
@@ -60,4 +59,145 @@ impl SystemRoot {
             .ok()
             .flatten()
     }
+
+    /// Resolve a partition by its GPT partition name.
+    pub fn resolve_partlabel(&self, label: &str) -> Option<BlockDevice> {
+        let partition = find_table_partlabel(self.table.as_ref()?, label)?;
+        self.resolve_partition(partition.number.into())
+    }
+
+    /// Resolve a partition by its partition UUID.
+    pub fn resolve_partuuid(&self, uuid: &str) -> Option<BlockDevice> {
+        let partition = find_table_partuuid(self.table.as_ref()?, uuid)?;
+        self.resolve_partition(partition.number.into())
+    }
+}
+
+/// Find a partition of a partition table by its GPT partition name.
+pub fn find_table_partlabel<'t>(table: &'t PartitionTable, label: &str) -> Option<&'t Partition> {
+    table
+        .partitions
+        .iter()
+        .find(|partition| partition.name.as_deref() == Some(label))
+}
+
+/// Find a partition of a partition table by its partition UUID.
+///
+/// For MBR partition tables, the partition UUID is `<disk id>-<partition number>`.
+pub fn find_table_partuuid<'t>(table: &'t PartitionTable, uuid: &str) -> Option<&'t Partition> {
+    table.partitions.iter().find(|partition| {
+        let partuuid = match &table.disk_id {
+            DiskId::Mbr(id) => format!("{:08x}-{:02x}", id.into_raw(), partition.number),
+            _ => match &partition.gpt_id {
+                Some(gpt_id) => gpt_id.to_string(),
+                None => return false,
+            },
+        };
+        partuuid.eq_ignore_ascii_case(uuid)
+    })
+}
+
+/// Find a partition by its GPT partition name.
+///
+/// Partitions of the root device take precedence over partitions of other devices.
+pub fn find_partlabel(root: Option<&SystemRoot>, label: &str) -> Option<BlockDevice> {
+    root.and_then(|root| root.resolve_partlabel(label))
+        .or_else(|| resolve_disk_link("by-partlabel", label))
+}
+
+/// Find a partition by its partition UUID.
+///
+/// Partitions of the root device take precedence over partitions of other devices.
+pub fn find_partuuid(root: Option<&SystemRoot>, uuid: &str) -> Option<BlockDevice> {
+    root.and_then(|root| root.resolve_partuuid(uuid))
+        .or_else(|| resolve_disk_link("by-partuuid", &uuid.to_ascii_lowercase()))
+}
+
+/// Resolve a partition via the given `/dev/disk/by-*` directory.
+fn resolve_disk_link(directory: &str, name: &str) -> Option<BlockDevice> {
+    let path = paths::prefixed("/dev/disk").join(directory).join(name);
+    if !path.exists() {
+        return None;
+    }
+    BlockDevice::new(&path)
+        .inspect_err(|error| error!("error resolving {path:?}: {error}"))
+        .ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use rugix_common::disk::gpt::{gpt_types, Guid};
+    use rugix_common::disk::mbr::MbrId;
+    use rugix_common::disk::{DiskId, NumBlocks, Partition, PartitionTable, PartitionType};
+
+    use super::{find_table_partlabel, find_table_partuuid};
+
+    fn partition(
+        number: u8,
+        ty: PartitionType,
+        name: Option<&str>,
+        gpt_id: Option<Guid>,
+    ) -> Partition {
+        Partition {
+            number,
+            start: NumBlocks::from_raw(2048 * u64::from(number)),
+            size: NumBlocks::from_raw(2048),
+            ty,
+            name: name.map(str::to_owned),
+            gpt_id,
+        }
+    }
+
+    #[test]
+    fn test_gpt_resolution() {
+        let system_a: Guid = "5c0b3e1e-4b0a-4f3e-9d6c-1a2b3c4d5e6f".parse().unwrap();
+        let system_b: Guid = "8f2e6d4c-2a1b-4c3d-8e9f-0a1b2c3d4e5f".parse().unwrap();
+        let mut table = PartitionTable::new(DiskId::random_gpt(), NumBlocks::from_raw(16384));
+        table.partitions = vec![
+            partition(1, gpt_types::EFI, Some("config"), None),
+            partition(2, gpt_types::LINUX, Some("system-a"), Some(system_a)),
+            partition(3, gpt_types::LINUX, Some("system-b"), Some(system_b)),
+        ];
+        let number = |partition: Option<&Partition>| partition.map(|partition| partition.number);
+        assert_eq!(number(find_table_partlabel(&table, "config")), Some(1));
+        assert_eq!(number(find_table_partlabel(&table, "system-b")), Some(3));
+        assert_eq!(number(find_table_partlabel(&table, "System-B")), None);
+        assert_eq!(number(find_table_partlabel(&table, "data")), None);
+        assert_eq!(
+            number(find_table_partuuid(
+                &table,
+                "5c0b3e1e-4b0a-4f3e-9d6c-1a2b3c4d5e6f"
+            )),
+            Some(2)
+        );
+        // Partition UUIDs are compared case-insensitively.
+        assert_eq!(
+            number(find_table_partuuid(
+                &table,
+                "8F2E6D4C-2A1B-4C3D-8E9F-0A1B2C3D4E5F"
+            )),
+            Some(3)
+        );
+        assert_eq!(
+            number(find_table_partuuid(&table, &table.disk_id.to_string())),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mbr_resolution() {
+        let mut table = PartitionTable::new(
+            DiskId::Mbr(MbrId::new(0x1a2b3c4d)),
+            NumBlocks::from_raw(16384),
+        );
+        table.partitions = vec![
+            partition(1, PartitionType::Mbr(0x0c), None, None),
+            partition(5, PartitionType::Mbr(0x83), None, None),
+        ];
+        let number = |partition: Option<&Partition>| partition.map(|partition| partition.number);
+        assert_eq!(number(find_table_partuuid(&table, "1a2b3c4d-05")), Some(5));
+        assert_eq!(number(find_table_partuuid(&table, "1A2B3C4D-01")), Some(1));
+        assert_eq!(number(find_table_partuuid(&table, "1a2b3c4d-02")), None);
+        assert_eq!(number(find_table_partlabel(&table, "system-a")), None);
+    }
 }
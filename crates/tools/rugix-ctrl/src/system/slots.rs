@@ -7,8 +7,8 @@ use reportify::{bail, ResultExt};
 
 use crate::config::system::{BlockSlotConfig, SlotConfig};
 
-use super::root::SystemRoot;
-use super::SystemResult;
+use super::root::{find_partlabel, find_partuuid, SystemRoot};
+use super::{paths, SystemResult};
 use rugix_common::disk::blkdev::BlockDevice;
 
 /// Unique index of a slot of a system.
@@ -45,13 +45,31 @@ impl SystemSlots {
                             bail!("partition {partition} for slot {name:?} not found");
                         };
                         device
+                    } else if let Some(partlabel) = &block_slot_config.partlabel {
+                        let Some(device) = find_partlabel(root, partlabel) else {
+                            bail!("partition with label {partlabel:?} for slot {name:?} not found");
+                        };
+                        device
+                    } else if let Some(partuuid) = &block_slot_config.partuuid {
+                        let Some(device) = find_partuuid(root, partuuid) else {
+                            bail!("partition with UUID {partuuid:?} for slot {name:?} not found");
+                        };
+                        device
                     } else {
                         bail!("invalid configuration: no device and partition for {name}");
                     };
-                    SlotKind::Block(BlockSlot { device })
+                    SlotKind::Block(BlockSlot {
+                        device,
+                        skip_unchanged_blocks: block_slot_config
+                            .skip_unchanged_blocks
+                            .unwrap_or(false),
+                    })
                 }
                 SlotConfig::File(file_slot_config) => SlotKind::File {
-                    path: file_slot_config.path.clone().into(),
+                    path: paths::prefixed(&file_slot_config.path),
+                },
+                SlotConfig::Directory(directory_slot_config) => SlotKind::Directory {
+                    path: paths::prefixed(&directory_slot_config.path),
                 },
                 SlotConfig::Custom(custom_slot_config) => SlotKind::Custom {
                     handler: custom_slot_config.handler.clone(),
@@ -163,22 +181,29 @@ impl Slot {
 pub enum SlotKind {
     Block(BlockSlot),
     File { path: PathBuf },
+    Directory { path: PathBuf },
     Custom { handler: Vec<String> },
 }
 
 #[derive(Debug)]
 pub struct BlockSlot {
     device: BlockDevice,
+    skip_unchanged_blocks: bool,
 }
 
 impl BlockSlot {
     pub fn device(&self) -> &BlockDevice {
         &self.device
     }
+
+    /// Indicates whether blocks already present on the device should not be rewritten.
+    pub fn skip_unchanged_blocks(&self) -> bool {
+        self.skip_unchanged_blocks
+    }
 }
 
 /// Default slots of an MBR-partitioned root device.
-const DEFAULT_MBR_SLOTS: &[(&str, SlotConfig)] = &[
+pub const DEFAULT_MBR_SLOTS: &[(&str, SlotConfig)] = &[
     ("boot-a", default_slot_config(2)),
     ("boot-b", default_slot_config(3)),
     ("system-a", default_slot_config(5)),
@@ -186,7 +211,7 @@ const DEFAULT_MBR_SLOTS: &[(&str, SlotConfig)] = &[
 ];
 
 /// Default slots of a GPT-partitioned root device.
-const DEFAULT_GPT_SLOTS: &[(&str, SlotConfig)] = &[
+pub const DEFAULT_GPT_SLOTS: &[(&str, SlotConfig)] = &[
     ("boot-a", default_slot_config(2)),
     ("boot-b", default_slot_config(3)),
     ("system-a", default_slot_config(4)),
@@ -198,6 +223,9 @@ const fn default_slot_config(partition: u32) -> SlotConfig {
     SlotConfig::Block(BlockSlotConfig {
         device: None,
         partition: Some(partition),
+        partlabel: None,
+        partuuid: None,
         immutable: Some(true),
+        skip_unchanged_blocks: None,
     })
 }
@@ -0,0 +1,177 @@
+//! Scripts shipped with update bundles.
+//!
+//! Scripts run at well-defined stages of the update process:
+//!
+//! - `pre-install`: Before any slot is written.
+//! - `post-install`: After all payloads have been installed.
+//! - `first-boot`: On the first boot of the updated boot group.
+//!
+//! Scripts receive the same environment variables as `update-install` hooks. In
+//! addition, `RUGIX_SCRIPT_STAGE` is set to the stage the script is running at. For all
+//! stages, `RUGIX_BOOT_GROUP` is the boot group the update is installed to.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use reportify::{bail, whatever, ResultExt};
+use rugix_bundle::format::ScriptPayloadType;
+use rugix_bundle::manifest::ScriptStage;
+use tracing::{info, warn};
+
+use crate::system::{paths, System, SystemResult};
+
+/// Script shipped with an update bundle.
+#[derive(Debug, Clone)]
+pub struct UpdateScript {
+    /// Path to the script file.
+    pub path: PathBuf,
+    /// Interpreter for the script (empty if the script should be executed directly).
+    pub interpreter: Vec<String>,
+}
+
+impl UpdateScript {
+    /// Create a script from the given file and payload type.
+    pub fn new(path: PathBuf, script_type: &ScriptPayloadType) -> Self {
+        Self {
+            path,
+            interpreter: script_type.interpreter.clone(),
+        }
+    }
+
+    /// Run the script at the given stage.
+    pub fn run(&self, stage: &ScriptStage, boot_group: &str) -> SystemResult<()> {
+        info!("running {} script {:?}", stage.as_str(), self.path);
+        let mut command = match self.interpreter.split_first() {
+            Some((prog, args)) => {
+                let mut command = std::process::Command::new(prog);
+                command.args(args).arg(&self.path);
+                command
+            }
+            None => {
+                make_executable(&self.path).whatever("unable to make script executable")?;
+                std::process::Command::new(&self.path)
+            }
+        };
+        let status = command
+            .env("RUGIX_BOOT_GROUP", boot_group)
+            .env("RUGIX_SCRIPT_STAGE", stage.as_str())
+            .status()
+            .whatever("unable to spawn update script")
+            .with_info(|_| format!("stage: {}", stage.as_str()))?;
+        if !status.success() {
+            bail!(
+                "{} script failed with code {:?}",
+                stage.as_str(),
+                status.code()
+            );
+        }
+        Ok(())
+    }
+}
+
+/// Parse the stage of a script payload.
+pub fn parse_stage(script_type: &ScriptPayloadType) -> SystemResult<ScriptStage> {
+    match script_type.stage.parse() {
+        Ok(stage) => Ok(stage),
+        Err(error) => bail!("{error}"),
+    }
+}
+
+/// Prepare storing a first-boot script for the given boot group.
+///
+/// Returns the path where the script should be written to.
+pub fn first_boot_script_path(
+    boot_group: &str,
+    payload_idx: usize,
+    script_type: &ScriptPayloadType,
+) -> SystemResult<PathBuf> {
+    let dir = first_boot_dir(boot_group);
+    fs::create_dir_all(&dir).whatever("unable to create first-boot scripts directory")?;
+    let interpreter = serde_json::to_vec(&script_type.interpreter)
+        .whatever("unable to serialize script interpreter")?;
+    fs::write(
+        dir.join(format!("{payload_idx:04}.interpreter")),
+        interpreter,
+    )
+    .whatever("unable to write script interpreter")?;
+    Ok(dir.join(format!("{payload_idx:04}.script")))
+}
+
+/// Remove all first-boot scripts of the given boot group.
+pub fn clear_first_boot_scripts(boot_group: &str) -> SystemResult<()> {
+    clear_dir(&first_boot_dir(boot_group))
+}
+
+/// Run the first-boot scripts of the active boot group, if there are any.
+///
+/// Scripts are removed prior to running them, such that they run only once.
+pub fn run_first_boot_scripts(system: &System) -> SystemResult<()> {
+    let Some(active) = system.active_boot_entry() else {
+        return Ok(());
+    };
+    let boot_group = system.boot_entries()[active].name();
+    let dir = first_boot_dir(boot_group);
+    if !dir.exists() {
+        return Ok(());
+    }
+    let running_dir = dir.with_extension("running");
+    clear_dir(&running_dir)?;
+    fs::rename(&dir, &running_dir).whatever("unable to prepare first-boot scripts")?;
+    let result = run_scripts_in(&running_dir, boot_group);
+    clear_dir(&running_dir)?;
+    result
+}
+
+fn run_scripts_in(dir: &Path, boot_group: &str) -> SystemResult<()> {
+    let mut scripts = Vec::new();
+    for entry in fs::read_dir(dir).whatever("unable to list first-boot scripts")? {
+        let path = entry.whatever("unable to list first-boot scripts")?.path();
+        if path
+            .extension()
+            .is_some_and(|extension| extension == "script")
+        {
+            scripts.push(path);
+        }
+    }
+    scripts.sort();
+    for path in scripts {
+        let interpreter = match fs::read(path.with_extension("interpreter")) {
+            Ok(interpreter) => serde_json::from_slice(&interpreter)
+                .whatever("unable to parse script interpreter")?,
+            Err(error) => {
+                warn!("unable to read interpreter of {path:?}: {error}");
+                Vec::new()
+            }
+        };
+        UpdateScript { path, interpreter }.run(&ScriptStage::FirstBoot, boot_group)?;
+    }
+    Ok(())
+}
+
+fn clear_dir(dir: &Path) -> SystemResult<()> {
+    fs::remove_dir_all(dir).or_else(|error| match error.kind() {
+        std::io::ErrorKind::NotFound => Ok(()),
+        _ => Err(whatever!("unable to remove directory").with_info(format!("path: {dir:?}"))),
+    })
+}
+
+fn make_executable(path: &Path) -> std::io::Result<()> {
+    use std::os::unix::fs::PermissionsExt;
+
+    let mut permissions = fs::metadata(path)?.permissions();
+    permissions.set_mode(permissions.mode() | 0o100);
+    fs::set_permissions(path, permissions)
+}
+
+/// Directory with the first-boot scripts of the given boot group.
+fn first_boot_dir(boot_group: &str) -> PathBuf {
+    const DATA_PATH: &str = "/run/rugix/mounts/data/rugix/scripts";
+    const VAR_PATH: &str = "/var/rugix/scripts";
+    if paths::prefixed(paths::MOUNT_POINT_DATA).exists() {
+        paths::prefixed(DATA_PATH)
+    } else {
+        paths::prefixed(VAR_PATH)
+    }
+    .join(boot_group)
+    .join("first-boot")
+}
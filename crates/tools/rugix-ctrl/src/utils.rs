@@ -1,7 +1,7 @@
 use std::fs;
 use std::path::Path;
 
-use crate::system::SystemResult;
+use crate::system::{paths, SystemResult};
 use reportify::ResultExt;
 use xscript::{run, Run};
 
@@ -12,9 +12,17 @@ pub fn is_init_process() -> bool {
     std::process::id() == 1
 }
 
+/// Command to reboot the system.
+const REBOOT: &str = "/usr/sbin/reboot";
+
 /// Reboot the system.
+///
+/// When operating under a root prefix, the `reboot` command under the prefix is run
+/// instead, such that the host system is never rebooted.
 pub fn reboot() -> SystemResult<()> {
-    if is_init_process() {
+    if paths::is_prefixed() {
+        run!([paths::prefixed(REBOOT)]).whatever("unable to run `reboot` under the prefix")?;
+    } else if is_init_process() {
         // Make sure that no data is lost.
         nix::unistd::sync();
         unsafe {
@@ -34,7 +42,7 @@ pub fn reboot() -> SystemResult<()> {
 }
 
 pub fn set_flag(path: impl AsRef<Path>) -> SystemResult<()> {
-    let path = path.as_ref();
+    let path = &paths::prefixed(path);
     if let Some(parent) = path.parent() {
         fs::create_dir_all(parent).whatever("unable to create flag directory")?;
     }
@@ -43,7 +51,7 @@ pub fn set_flag(path: impl AsRef<Path>) -> SystemResult<()> {
 }
 
 pub fn clear_flag(path: impl AsRef<Path>) -> SystemResult<()> {
-    let path = path.as_ref();
+    let path = &paths::prefixed(path);
     if path.exists() {
         fs::remove_file(path).whatever("unable to clear flag")?;
     }
@@ -51,5 +59,5 @@ pub fn clear_flag(path: impl AsRef<Path>) -> SystemResult<()> {
 }
 
 pub fn is_flag_set(path: impl AsRef<Path>) -> bool {
-    path.as_ref().exists()
+    paths::prefixed(path).exists()
 }
@@ -0,0 +1,1157 @@
+//! Tests of installing, committing, and rolling back updates for each boot flow.
+//!
+//! The tests run `rugix-ctrl` with `RUGIX_ROOT_PREFIX` pointing to a temporary directory
+//! with a fake config partition and a disk image attached as loop device. The simulated
+//! bootloader mounts the system partition selected by the boot flow's state, from which
+//! `rugix-ctrl` determines the active boot group. Hence, the tests do not require a VM.
+//! They are skipped if loop devices are not available.
+
+use std::cell::RefCell;
+use std::collections::HashMap;
+use std::fs;
+use std::io::Read;
+use std::os::unix::fs::{FileExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use std::process::{Command, Output};
+
+use rugix_common::boot::grub::{
+    grub_envblk_encode, grub_write_defaults, load_grub_env, save_grub_env, RUGIX_BOOTPART,
+    RUGIX_BOOT_SPARE,
+};
+use rugix_common::boot::tryboot::AUTOBOOT_A;
+use rugix_common::boot::uboot::UBootEnv;
+use tempfile::TempDir;
+
+/// Variable of the Grub environment on the boot partition with the kernel commandline.
+const GRUB_BOOTARGS: &str = "rugpi_bootargs";
+
+/// Controller of the custom boot flow storing its state on the config partition.
+const CUSTOM_CONTROLLER: &str = r#"#!/bin/sh
+set -e
+STATE="$RUGIX_ROOT_PREFIX/run/rugix/mounts/config/custom"
+case "$1" in
+    get_default)
+        echo "{\"group\": \"$(cat "$STATE/default")\"}"
+        ;;
+    set_try_next)
+        echo "$2" > "$STATE/try-next"
+        echo "{}"
+        ;;
+    commit)
+        echo "$2" > "$STATE/default"
+        echo "{}"
+        ;;
+    *)
+        echo "{}"
+        ;;
+esac
+"#;
+
+/// Custom update handler storing the payload in a file and supporting reading blocks.
+const CUSTOM_HANDLER: &str = r#"#!/bin/sh
+set -e
+FILE="$RUGIX_ROOT_PREFIX/slots/$RUGIX_SLOT_NAME.img"
+if [ "$1" = "read" ]; then
+    echo "$RUGIX_SLOT_NAME" >> "$RUGIX_ROOT_PREFIX/slots/read-processes.log"
+    while read -r offset size; do
+        echo "$RUGIX_SLOT_NAME $offset $size" >> "$RUGIX_ROOT_PREFIX/slots/reads.log"
+        dd if="$FILE" bs=1 skip="$offset" count="$size" 2>/dev/null
+    done
+else
+    cat > "$FILE"
+    echo "$RUGIX_PAYLOAD_SIZE" > "$FILE.size"
+fi
+"#;
+
+/// Fake `reboot` command recording the requested reboot.
+const FAKE_REBOOT: &str = r#"#!/bin/sh
+touch "$RUGIX_ROOT_PREFIX/reboot-requested"
+"#;
+
+/// Size of the partitions of test disks in sectors.
+const PARTITION_SECTORS: u64 = 8192;
+
+/// Size of the filesystem images of update bundles in bytes.
+const IMAGE_SIZE: u64 = 2 * 1024 * 1024;
+
+/// MBR disk id of test disks.
+const MBR_DISK_ID: u32 = 0x1a2b3c4d;
+
+/// Fake system with a disk attached as loop device under a root prefix.
+struct TestSystem {
+    /// System partition mounted by the simulated bootloader.
+    system_mount: RefCell<Option<Mount>>,
+    disk: TestDisk,
+    root: TempDir,
+    boot_flow: &'static str,
+}
+
+impl TestSystem {
+    /// Create a fake system with the given boot flow booted into boot group `a`.
+    ///
+    /// Returns `None` if loop devices or the tools to create and read partition tables
+    /// are not available.
+    fn new(boot_flow: &'static str) -> Option<Self> {
+        // Rugix Ctrl reads the partition table of the root device with `sfdisk`.
+        if !Path::new("/usr/sbin/sfdisk").exists() {
+            eprintln!("skipping test, `sfdisk` is not available");
+            return None;
+        }
+        let root = tempfile::tempdir().unwrap();
+        // Grub uses a GPT disk while all other boot flows use an MBR disk.
+        let Some(disk) = TestDisk::create(&root.path().join("disk.img"), boot_flow == "grub-efi")
+        else {
+            eprintln!("skipping test, loop devices are not available");
+            return None;
+        };
+        let system = Self {
+            system_mount: RefCell::new(None),
+            disk,
+            root,
+            boot_flow,
+        };
+        let (system_a, system_b) = system.disk.system_partitions();
+        let boot_flow_config = if boot_flow == "custom" {
+            "type = \"custom\"\ncontroller = \"/usr/lib/rugix/boot-flow\"".to_owned()
+        } else {
+            format!("type = \"{boot_flow}\"")
+        };
+        system.write(
+            "etc/rugix/system.toml",
+            &format!(
+                r#"
+                [config-partition]
+                protected = false
+
+                [data-partition]
+                disabled = true
+
+                [slots.boot-a]
+                type = "block"
+                partition = 2
+
+                [slots.boot-b]
+                type = "block"
+                partition = 3
+
+                [slots.system-a]
+                type = "block"
+                partition = {system_a}
+
+                [slots.system-b]
+                type = "block"
+                partition = {system_b}
+
+                [boot-flow]
+                {boot_flow_config}
+                "#
+            ),
+        );
+        system.write("usr/sbin/reboot", FAKE_REBOOT);
+        fs::set_permissions(
+            system.path("usr/sbin/reboot"),
+            fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        fs::create_dir_all(system.path("run/rugix/mounts/data")).unwrap();
+        let config_dir = system.path("run/rugix/mounts/config");
+        fs::create_dir_all(&config_dir).unwrap();
+        match boot_flow {
+            "tryboot" => {
+                system.write("run/rugix/mounts/config/autoboot.txt", AUTOBOOT_A);
+                // A regular file in place of the VCIO device emulates the firmware.
+                system.write("dev/vcio", "0");
+            }
+            "u-boot" => {
+                let mut env = UBootEnv::new();
+                env.set("bootpart", "2");
+                env.save(config_dir.join("bootpart.default.env")).unwrap();
+            }
+            "grub-efi" => {
+                fs::create_dir_all(config_dir.join("rugpi")).unwrap();
+                grub_write_defaults(&config_dir).unwrap();
+            }
+            "custom" => {
+                system.write("run/rugix/mounts/config/custom/default", "a");
+                system.write("usr/lib/rugix/boot-flow", CUSTOM_CONTROLLER);
+                fs::set_permissions(
+                    system.path("usr/lib/rugix/boot-flow"),
+                    fs::Permissions::from_mode(0o755),
+                )
+                .unwrap();
+            }
+            _ => panic!("unknown boot flow {boot_flow:?}"),
+        }
+        // The kernel commandline on the boot partitions refers to the system partitions.
+        for (group, boot, system_partition) in [("a", 2, system_a), ("b", 3, system_b)] {
+            let partuuid = &system.disk.partuuids[system_partition - 1];
+            let bootargs = format!("console=tty1 root=PARTUUID={partuuid} rootwait");
+            let grubenv = grub_envblk_encode(&HashMap::from([(
+                GRUB_BOOTARGS.to_owned(),
+                bootargs.clone(),
+            )]))
+            .unwrap();
+            mkfs_ext4(
+                &system.disk.partition(boot),
+                &[("cmdline.txt", &bootargs), ("boot.grubenv", &grubenv)],
+            );
+            mkfs_ext4(
+                &system.disk.partition(system_partition),
+                &[("version", &format!("old system-{group}"))],
+            );
+        }
+        system.reboot();
+        Some(system)
+    }
+
+    /// Path of the given file in the fake system.
+    fn path(&self, path: &str) -> PathBuf {
+        self.root.path().join(path)
+    }
+
+    /// Write a file to the fake system.
+    fn write(&self, path: &str, contents: &str) {
+        let path = self.path(path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(path, contents).unwrap();
+    }
+
+    /// Read a file of the fake system.
+    fn read(&self, path: &str) -> String {
+        fs::read_to_string(self.path(path)).unwrap()
+    }
+
+    /// Version of the booted system.
+    fn version(&self) -> String {
+        self.read("run/rugix/mounts/system/version")
+    }
+
+    /// Simulate a reboot by the bootloader.
+    ///
+    /// Like the actual bootloaders, this determines the boot partition based on the boot
+    /// flow's state on the config partition, trying the spare boot partition once if
+    /// requested. The system partition is then determined by the `root` parameter of the
+    /// kernel commandline on the boot partition.
+    fn reboot(&self) {
+        let boot_partition = self.next_boot_partition();
+        let system_partition = self.root_partition(boot_partition);
+        // The contents of `/run` do not survive a reboot.
+        fs::remove_file(self.path("reboot-requested")).ok();
+        let mut system_mount = self.system_mount.borrow_mut();
+        system_mount.take();
+        let mount_point = self.path("run/rugix/mounts/system");
+        fs::create_dir_all(&mount_point).unwrap();
+        *system_mount = Some(Mount::new(
+            &self.disk.partition(system_partition),
+            &mount_point,
+        ));
+    }
+
+    /// Boot partition the bootloader boots next.
+    fn next_boot_partition(&self) -> usize {
+        let config_dir = self.path("run/rugix/mounts/config");
+        let default = match self.boot_flow {
+            "tryboot" => {
+                // The firmware's `tryboot` flag only applies to a single boot.
+                let section = if self.is_spare_flag_set() {
+                    "[tryboot]"
+                } else {
+                    "[all]"
+                };
+                self.clear_spare_flag();
+                let autoboot = self.read("run/rugix/mounts/config/autoboot.txt");
+                let mut current = "";
+                for line in autoboot.lines() {
+                    if line.starts_with('[') {
+                        current = line;
+                    } else if let Some(partition) = line.strip_prefix("boot_partition=") {
+                        if current == section {
+                            return partition.parse().unwrap();
+                        }
+                    }
+                }
+                panic!("no boot partition in section {section}");
+            }
+            "u-boot" => UBootEnv::load(config_dir.join("bootpart.default.env"))
+                .unwrap()
+                .get("bootpart")
+                .unwrap()
+                .parse()
+                .unwrap(),
+            "grub-efi" => load_grub_env(config_dir.join("rugpi/primary.grubenv")).unwrap()
+                [RUGIX_BOOTPART]
+                .parse()
+                .unwrap(),
+            "custom" => match self.read("run/rugix/mounts/config/custom/default").trim() {
+                "a" => 2,
+                _ => 3,
+            },
+            _ => unreachable!(),
+        };
+        if self.is_spare_flag_set() {
+            self.clear_spare_flag();
+            5 - default
+        } else {
+            default
+        }
+    }
+
+    /// System partition given by the kernel commandline on the given boot partition.
+    fn root_partition(&self, boot_partition: usize) -> usize {
+        if self.boot_flow == "custom" {
+            // The custom boot flow does not patch the boot partitions.
+            let (system_a, system_b) = self.disk.system_partitions();
+            return if boot_partition == 2 {
+                system_a
+            } else {
+                system_b
+            };
+        }
+        let boot_dir = tempfile::tempdir().unwrap();
+        let _mount = Mount::new(&self.disk.partition(boot_partition), boot_dir.path());
+        let cmdline = if self.boot_flow == "grub-efi" {
+            let env = load_grub_env(boot_dir.path().join("boot.grubenv")).unwrap();
+            env[GRUB_BOOTARGS].clone()
+        } else {
+            fs::read_to_string(boot_dir.path().join("cmdline.txt")).unwrap()
+        };
+        let partuuid = cmdline
+            .split_whitespace()
+            .find_map(|arg| arg.strip_prefix("root=PARTUUID="))
+            .unwrap();
+        self.disk
+            .partuuids
+            .iter()
+            .position(|other| other.eq_ignore_ascii_case(partuuid))
+            .unwrap_or_else(|| panic!("unknown root partition {partuuid:?}"))
+            + 1
+    }
+
+    /// Run `rugix-ctrl` with the given arguments.
+    fn run_ctrl(&self, args: &[&str]) -> Output {
+        Command::new(env!("CARGO_BIN_EXE_rugix-ctrl"))
+            .args(args)
+            .env("RUGIX_ROOT_PREFIX", self.root.path())
+            .output()
+            .unwrap()
+    }
+
+    /// Run `rugix-ctrl` with the given arguments and return its standard output.
+    fn ctrl(&self, args: &[&str]) -> String {
+        let output = self.run_ctrl(args);
+        assert!(
+            output.status.success(),
+            "`rugix-ctrl {}` failed:\n{}",
+            args.join(" "),
+            String::from_utf8_lossy(&output.stderr)
+        );
+        String::from_utf8(output.stdout).unwrap()
+    }
+
+    /// Run `rugix-ctrl` with the given arguments, expect it to fail, and return its
+    /// standard error.
+    fn ctrl_fails(&self, args: &[&str]) -> String {
+        let output = self.run_ctrl(args);
+        assert!(
+            !output.status.success(),
+            "`rugix-ctrl {}` succeeded",
+            args.join(" ")
+        );
+        String::from_utf8(output.stderr).unwrap()
+    }
+
+    /// Return the active and default boot group.
+    fn boot_groups(&self) -> (String, String) {
+        let info: serde_json::Value =
+            serde_json::from_str(&self.ctrl(&["system", "info", "--json"])).unwrap();
+        let boot = &info["boot"];
+        assert_eq!(boot["bootFlow"], self.boot_flow);
+        (
+            boot["activeGroup"].as_str().unwrap().to_owned(),
+            boot["defaultGroup"].as_str().unwrap().to_owned(),
+        )
+    }
+
+    /// Indicates whether the boot flow has been instructed to try the spare boot group.
+    fn is_spare_flag_set(&self) -> bool {
+        match self.boot_flow {
+            "tryboot" => self.read("dev/vcio").trim() == "1",
+            "u-boot" => UBootEnv::load(self.path("run/rugix/mounts/config/boot_spare.env"))
+                .is_ok_and(|env| env.get("boot_spare") == Some("1")),
+            "grub-efi" => {
+                load_grub_env(self.path("run/rugix/mounts/config/rugpi/boot_spare.grubenv"))
+                    .is_ok_and(|env| env.get(RUGIX_BOOT_SPARE).map(String::as_str) == Some("true"))
+            }
+            "custom" => {
+                let try_next = self.path("run/rugix/mounts/config/custom/try-next");
+                try_next.exists() && fs::read_to_string(try_next).unwrap().trim() == "b"
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    /// Clear the spare flag like the bootloader does when trying the spare boot group.
+    fn clear_spare_flag(&self) {
+        let config_dir = self.path("run/rugix/mounts/config");
+        match self.boot_flow {
+            "tryboot" => self.write("dev/vcio", "0"),
+            "u-boot" => {
+                let mut env = UBootEnv::new();
+                env.set("boot_spare", "0");
+                env.save(config_dir.join("boot_spare.env")).unwrap();
+            }
+            "grub-efi" => {
+                let env = HashMap::from([(RUGIX_BOOT_SPARE.to_owned(), "false".to_owned())]);
+                save_grub_env(config_dir.join("rugpi/boot_spare.grubenv"), &env).unwrap();
+            }
+            "custom" => fs::remove_file(config_dir.join("custom/try-next")).unwrap(),
+            _ => unreachable!(),
+        }
+    }
+
+    /// Install an update bundle to boot group `b` and check that a reboot is requested.
+    fn install_update(&self, bundle: &Path) {
+        self.ctrl(&[
+            "update",
+            "install",
+            bundle.to_str().unwrap(),
+            "--reboot",
+            "yes",
+        ]);
+        assert!(self.is_spare_flag_set());
+        assert!(self.path("reboot-requested").exists());
+    }
+
+    /// Read the first bytes of the given partition.
+    fn read_partition(&self, partition: usize, len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        fs::File::open(self.disk.partition(partition))
+            .unwrap()
+            .read_exact(&mut data)
+            .unwrap();
+        data
+    }
+}
+
+/// Disk image with a partition table attached as loop device.
+struct TestDisk {
+    device: LoopDevice,
+    /// Partition UUIDs of the partitions as used for `root=PARTUUID=...`.
+    partuuids: Vec<String>,
+}
+
+impl TestDisk {
+    /// Create a disk image with Rugix's default partition layout and attach it.
+    ///
+    /// Both layouts start with a config partition and two boot partitions. On MBR disks,
+    /// the system partitions are logical partitions `5` and `6`. On GPT disks, they are
+    /// partitions `4` and `5`.
+    fn create(image: &Path, gpt: bool) -> Option<Self> {
+        let partuuids = if gpt {
+            write_gpt(image)
+        } else {
+            write_mbr(image)
+        };
+        let device = LoopDevice::attach(image)?;
+        // Partitions are not always scanned when attaching a loop device.
+        let _ = Command::new("partx")
+            .arg("--update")
+            .arg(&device.path)
+            .output();
+        let disk = Self { device, partuuids };
+        disk.partition(disk.partuuids.len())
+            .exists()
+            .then_some(disk)
+    }
+
+    /// Path of the device of the given partition.
+    fn partition(&self, number: usize) -> PathBuf {
+        PathBuf::from(format!("{}p{number}", self.device.path.display()))
+    }
+
+    /// Numbers of the system partitions of boot groups `a` and `b`.
+    fn system_partitions(&self) -> (usize, usize) {
+        if self.partuuids.len() == 6 {
+            (5, 6)
+        } else {
+            (4, 5)
+        }
+    }
+}
+
+/// Write an MBR disk image and return the partition UUIDs.
+fn write_mbr(image: &Path) -> Vec<String> {
+    let file = fs::File::create(image).unwrap();
+    let extended_start = 2048 + 3 * PARTITION_SECTORS;
+    let extended_size = 2 * (2048 + PARTITION_SECTORS);
+    file.set_len((extended_start + extended_size) * 512)
+        .unwrap();
+    let mut entries = (0..3)
+        .map(|idx| mbr_entry(0x0c, 2048 + idx * PARTITION_SECTORS, PARTITION_SECTORS))
+        .collect::<Vec<_>>();
+    entries.push(mbr_entry(0x05, extended_start, extended_size));
+    file.write_all_at(&boot_sector(MBR_DISK_ID, &entries), 0)
+        .unwrap();
+    // Each logical partition is preceded by an extended boot record linking to the next.
+    for idx in 0..2 {
+        let offset = idx * (2048 + PARTITION_SECTORS);
+        let mut entries = vec![mbr_entry(0x83, 2048, PARTITION_SECTORS)];
+        if idx == 0 {
+            entries.push(mbr_entry(
+                0x05,
+                2048 + PARTITION_SECTORS,
+                2048 + PARTITION_SECTORS,
+            ));
+        }
+        file.write_all_at(&boot_sector(0, &entries), (extended_start + offset) * 512)
+            .unwrap();
+    }
+    (1..=6)
+        .map(|number| format!("{MBR_DISK_ID:08x}-{number:02x}"))
+        .collect()
+}
+
+/// Encode an MBR partition entry.
+fn mbr_entry(ty: u8, start: u64, size: u64) -> [u8; 16] {
+    let mut entry = [0; 16];
+    entry[4] = ty;
+    entry[8..12].copy_from_slice(&u32::try_from(start).unwrap().to_le_bytes());
+    entry[12..16].copy_from_slice(&u32::try_from(size).unwrap().to_le_bytes());
+    entry
+}
+
+/// Encode a boot sector with the given partition entries.
+fn boot_sector(disk_id: u32, entries: &[[u8; 16]]) -> [u8; 512] {
+    let mut sector = [0; 512];
+    sector[440..444].copy_from_slice(&disk_id.to_le_bytes());
+    for (idx, entry) in entries.iter().enumerate() {
+        sector[446 + 16 * idx..462 + 16 * idx].copy_from_slice(entry);
+    }
+    sector[510] = 0x55;
+    sector[511] = 0xaa;
+    sector
+}
+
+/// Write a GPT disk image and return the partition UUIDs.
+fn write_gpt(image: &Path) -> Vec<String> {
+    const EFI: &str = "c12a7328-f81f-11d2-ba4b-00a0c93ec93b";
+    const LINUX: &str = "0fc63daf-8483-4772-8e79-3d69d8477de4";
+    let file = fs::File::create(image).unwrap();
+    let disk_size = 2048 + 5 * PARTITION_SECTORS + 2048;
+    file.set_len(disk_size * 512).unwrap();
+    let protective = mbr_entry(0xee, 1, disk_size - 1);
+    file.write_all_at(&boot_sector(0, &[protective]), 0)
+        .unwrap();
+    let partuuids = (1..=5)
+        .map(|number| format!("6a1b2c3d-0000-4000-8000-00000000000{number}"))
+        .collect::<Vec<_>>();
+    let mut entries = vec![0; 128 * 128];
+    for (idx, partuuid) in partuuids.iter().enumerate() {
+        let entry = &mut entries[idx * 128..(idx + 1) * 128];
+        entry[0..16].copy_from_slice(&guid_bytes(if idx == 0 { EFI } else { LINUX }));
+        entry[16..32].copy_from_slice(&guid_bytes(partuuid));
+        let start = 2048 + idx as u64 * PARTITION_SECTORS;
+        entry[32..40].copy_from_slice(&start.to_le_bytes());
+        entry[40..48].copy_from_slice(&(start + PARTITION_SECTORS - 1).to_le_bytes());
+    }
+    // Write the primary table and the backup table at the end of the disk.
+    for (header_lba, backup_lba, entries_lba) in
+        [(1, disk_size - 1, 2), (disk_size - 1, 1, disk_size - 33)]
+    {
+        let mut header = [0; 92];
+        header[0..8].copy_from_slice(b"EFI PART");
+        header[8..12].copy_from_slice(&0x00010000u32.to_le_bytes());
+        header[12..16].copy_from_slice(&92u32.to_le_bytes());
+        header[24..32].copy_from_slice(&u64::to_le_bytes(header_lba));
+        header[32..40].copy_from_slice(&u64::to_le_bytes(backup_lba));
+        header[40..48].copy_from_slice(&34u64.to_le_bytes());
+        header[48..56].copy_from_slice(&(disk_size - 34).to_le_bytes());
+        header[56..72].copy_from_slice(&guid_bytes("6a1b2c3d-0000-4000-8000-000000000000"));
+        header[72..80].copy_from_slice(&u64::to_le_bytes(entries_lba));
+        header[80..84].copy_from_slice(&128u32.to_le_bytes());
+        header[84..88].copy_from_slice(&128u32.to_le_bytes());
+        header[88..92].copy_from_slice(&crc32(&entries).to_le_bytes());
+        let header_crc = crc32(&header);
+        header[16..20].copy_from_slice(&header_crc.to_le_bytes());
+        file.write_all_at(&header, header_lba * 512).unwrap();
+        file.write_all_at(&entries, entries_lba * 512).unwrap();
+    }
+    partuuids
+}
+
+/// Encode a GUID given in the standard string encoding as stored on disk.
+fn guid_bytes(guid: &str) -> [u8; 16] {
+    let hex = guid.replace('-', "");
+    let mut bytes = [0; 16];
+    for (idx, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(&hex[2 * idx..2 * idx + 2], 16).unwrap();
+    }
+    // The first three fields are stored in little endian.
+    bytes[0..4].reverse();
+    bytes[4..6].reverse();
+    bytes[6..8].reverse();
+    bytes
+}
+
+/// Compute the CRC32 checksum used by GPT.
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xedb88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Create an ext4 filesystem with the given files on a device or image.
+fn mkfs_ext4(target: &Path, files: &[(&str, &str)]) {
+    let contents = tempfile::tempdir().unwrap();
+    for (name, data) in files {
+        fs::write(contents.path().join(name), data).unwrap();
+    }
+    let status = Command::new("mkfs.ext4")
+        .args(["-q", "-F", "-d"])
+        .arg(contents.path())
+        .arg(target)
+        .status()
+        .unwrap();
+    assert!(
+        status.success(),
+        "unable to create filesystem on {target:?}"
+    );
+}
+
+/// Filesystem mounted by the tests, which is unmounted when dropped.
+struct Mount {
+    path: PathBuf,
+}
+
+impl Mount {
+    /// Mount the given device.
+    fn new(device: &Path, path: &Path) -> Self {
+        let status = Command::new("mount")
+            .arg(device)
+            .arg(path)
+            .status()
+            .unwrap();
+        assert!(status.success(), "unable to mount {device:?}");
+        Self {
+            path: path.to_path_buf(),
+        }
+    }
+}
+
+impl Drop for Mount {
+    fn drop(&mut self) {
+        let _ = Command::new("umount").arg(&self.path).status();
+    }
+}
+
+/// Loop device backed by an image file, which is detached when dropped.
+struct LoopDevice {
+    path: PathBuf,
+}
+
+impl LoopDevice {
+    /// Attach the given image file, if loop devices are available.
+    fn attach(image: &Path) -> Option<Self> {
+        let output = Command::new("losetup")
+            .args(["--show", "--find", "--partscan"])
+            .arg(image)
+            .output()
+            .ok()?;
+        if !output.status.success() {
+            return None;
+        }
+        let path = String::from_utf8(output.stdout).ok()?;
+        Some(Self {
+            path: PathBuf::from(path.trim()),
+        })
+    }
+}
+
+impl Drop for LoopDevice {
+    fn drop(&mut self) {
+        let _ = Command::new("losetup").arg("-d").arg(&self.path).status();
+    }
+}
+
+/// Create an update bundle with new boot and system filesystems.
+fn create_bundle(dir: &Path) -> PathBuf {
+    create_versioned_bundle(dir, 0)
+}
+
+/// Create an update bundle with the given security version.
+///
+/// Version `0` creates a bundle without a security version.
+fn create_versioned_bundle(dir: &Path, security_version: u64) -> PathBuf {
+    let bundle_dir = dir.join(format!("bundle-v{security_version}"));
+    fs::create_dir_all(bundle_dir.join("payloads")).unwrap();
+    let security_version = if security_version > 0 {
+        format!("security-version = {security_version}")
+    } else {
+        String::new()
+    };
+    fs::write(
+        bundle_dir.join("rugix-bundle.toml"),
+        format!(
+            r#"
+        update-type = "full"
+        {security_version}
+
+        [[payloads]]
+        filename = "boot.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "boot"
+
+        [[payloads]]
+        filename = "system.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "system"
+        "#
+        ),
+    )
+    .unwrap();
+    // The kernel commandline of the new boot filesystem is patched when installing it.
+    let cmdline = "console=tty1 root=PARTUUID=00000000-00 rootwait";
+    for (payload, files) in [
+        ("boot.img", [("cmdline.txt", cmdline)]),
+        ("system.img", [("version", "new system")]),
+    ] {
+        let image = bundle_dir.join("payloads").join(payload);
+        fs::File::create(&image)
+            .unwrap()
+            .set_len(IMAGE_SIZE)
+            .unwrap();
+        mkfs_ext4(&image, &files);
+    }
+    let bundle = bundle_dir.with_extension("rugixb");
+    rugix_bundle::builder::pack(&bundle_dir, &bundle, &[]).unwrap();
+    bundle
+}
+
+/// Create an incremental update bundle with a block-encoded payload for the given slot.
+fn create_block_encoded_bundle(dir: &Path, slot: &str, data: &[u8]) -> PathBuf {
+    let bundle_dir = dir.join(format!("bundle-{slot}"));
+    fs::create_dir_all(bundle_dir.join("payloads")).unwrap();
+    fs::write(
+        bundle_dir.join("rugix-bundle.toml"),
+        format!(
+            r#"
+            update-type = "incremental"
+
+            [[payloads]]
+            filename = "app.img"
+            [payloads.delivery]
+            type = "slot"
+            slot = "{slot}"
+            [payloads.block-encoding]
+            chunker = "fixed-4"
+            hash-algorithm = "sha256"
+            deduplicate = true
+            "#
+        ),
+    )
+    .unwrap();
+    fs::write(bundle_dir.join("payloads/app.img"), data).unwrap();
+    let bundle = dir.join(format!("{slot}.rugixb"));
+    rugix_bundle::builder::pack(&bundle_dir, &bundle, &[]).unwrap();
+    bundle
+}
+
+/// Install an update, boot into it, and commit it.
+fn test_install_and_commit(boot_flow: &'static str) {
+    let Some(system) = TestSystem::new(boot_flow) else {
+        return;
+    };
+    let bundle = create_bundle(system.root.path());
+    assert_eq!(system.boot_groups(), ("a".to_owned(), "a".to_owned()));
+    assert_eq!(system.version(), "old system-a");
+    system.install_update(&bundle);
+    system.reboot();
+    assert_eq!(system.boot_groups(), ("b".to_owned(), "a".to_owned()));
+    assert_eq!(system.version(), "new system");
+    system.ctrl(&["system", "commit"]);
+    assert_eq!(system.boot_groups(), ("b".to_owned(), "b".to_owned()));
+    // After the commit, the updated boot group is booted by default.
+    system.reboot();
+    assert_eq!(system.boot_groups(), ("b".to_owned(), "b".to_owned()));
+    assert_eq!(system.version(), "new system");
+}
+
+/// Install an update and roll back to the previous version.
+fn test_install_and_rollback(boot_flow: &'static str) {
+    let Some(system) = TestSystem::new(boot_flow) else {
+        return;
+    };
+    let bundle = create_bundle(system.root.path());
+    system.install_update(&bundle);
+    // The spare boot group is tried once and the spare flag is cleared.
+    system.reboot();
+    assert_eq!(system.boot_groups(), ("b".to_owned(), "a".to_owned()));
+    assert!(!system.is_spare_flag_set());
+    // Booting the spare boot group failed before the update has been committed, hence,
+    // the default boot group is booted again.
+    system.reboot();
+    assert_eq!(system.boot_groups(), ("a".to_owned(), "a".to_owned()));
+    assert!(!system.is_spare_flag_set());
+    let output = system.ctrl(&["system", "commit"]);
+    assert!(output.contains("already the default"));
+    assert_eq!(system.boot_groups(), ("a".to_owned(), "a".to_owned()));
+    assert_eq!(system.version(), "old system-a");
+}
+
+#[test]
+fn test_tryboot() {
+    test_install_and_commit("tryboot");
+    test_install_and_rollback("tryboot");
+}
+
+#[test]
+fn test_uboot() {
+    test_install_and_commit("u-boot");
+    test_install_and_rollback("u-boot");
+}
+
+#[test]
+fn test_grub_efi() {
+    test_install_and_commit("grub-efi");
+    test_install_and_rollback("grub-efi");
+}
+
+#[test]
+fn test_custom() {
+    test_install_and_commit("custom");
+    test_install_and_rollback("custom");
+}
+
+#[test]
+fn test_rollback_protection() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    let counter = "run/rugix/mounts/config/rugix/rollback/security-version";
+    system.install_update(&create_versioned_bundle(system.root.path(), 3));
+    // The counter only advances when the update is committed.
+    assert!(!system.path(counter).exists());
+    system.reboot();
+    system.ctrl(&["system", "commit"]);
+    assert_eq!(system.read(counter).trim(), "3");
+    // Bundles with a lower security version are refused.
+    let bundle = create_versioned_bundle(system.root.path(), 2);
+    let stderr = system.ctrl_fails(&["update", "install", bundle.to_str().unwrap()]);
+    assert!(stderr.contains("lower than committed version 3"));
+    assert_eq!(system.read(counter).trim(), "3");
+    // System images do not carry a security version and are refused as well.
+    system.write("images/system.img", "not a bundle");
+    let image = system.path("images/system.img");
+    let install_image = [
+        "update",
+        "install",
+        image.to_str().unwrap(),
+        "--boot-group",
+        "a",
+    ];
+    let stderr = system.ctrl_fails(&install_image);
+    assert!(stderr.contains("update without security version is refused"));
+    // If downgrades are allowed, images are installed with a warning. Here, installing
+    // fails later on as the image has no partition table.
+    system.write(
+        "etc/rugix/system.d/50-rollback.toml",
+        "[rollback-protection]\nallow-downgrade = true\n",
+    );
+    let stderr = system.ctrl_fails(&install_image);
+    assert!(stderr.contains("security version 0 lower than committed version 3"));
+    assert!(!stderr.contains("is refused"));
+    assert_eq!(system.read(counter).trim(), "3");
+}
+
+#[test]
+fn test_payload_conditions() {
+    // The custom boot flow does not mount the boot partition after installing.
+    let Some(system) = TestSystem::new("custom") else {
+        return;
+    };
+    system.write("proc/device-tree/compatible", "acme,board-b\0acme,soc\0");
+    system.write("etc/rugix/system.d/50-variant.toml", "variant = \"pro\"\n");
+    let bundle_dir = system.path("bundle-conditions");
+    fs::create_dir_all(bundle_dir.join("payloads")).unwrap();
+    fs::write(
+        bundle_dir.join("rugix-bundle.toml"),
+        r#"
+        update-type = "full"
+
+        [[payloads]]
+        filename = "boot.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "boot"
+
+        [[payloads]]
+        filename = "system-pro.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "system"
+        [payloads.condition]
+        compatible = "acme,board-b"
+        variant = "pro"
+
+        [[payloads]]
+        filename = "system-board-a.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "system"
+        [payloads.condition]
+        compatible = "acme,board-a"
+
+        [[payloads]]
+        filename = "system-lite.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "system"
+        [payloads.condition]
+        compatible = "acme,board-b"
+        variant = "lite"
+
+        [[payloads]]
+        filename = "system-dmi.img"
+        [payloads.delivery]
+        type = "slot"
+        slot = "system"
+        [payloads.condition]
+        dmi-product-name = "Gateway 3000"
+        "#,
+    )
+    .unwrap();
+    fs::write(bundle_dir.join("payloads/boot.img"), "new boot").unwrap();
+    for name in ["pro", "board-a", "lite", "dmi"] {
+        fs::write(
+            bundle_dir.join(format!("payloads/system-{name}.img")),
+            format!("new system {name}"),
+        )
+        .unwrap();
+    }
+    let bundle = system.path("conditions.rugixb");
+    rugix_bundle::builder::pack(&bundle_dir, &bundle, &[]).unwrap();
+    system.ctrl(&["update", "install", bundle.to_str().unwrap()]);
+    // Only the payloads whose conditions match have been installed. As the skipped
+    // payloads come after the matching one, they would have overwritten the slot.
+    assert_eq!(system.read_partition(3, 8), b"new boot");
+    assert_eq!(system.read_partition(6, 14), b"new system pro");
+}
+
+#[test]
+fn test_script_boot_group() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    // With more than two boot groups, incremental updates are installed to the active
+    // boot group unless a boot group is given explicitly.
+    system.write(
+        "etc/rugix/system.d/50-groups.toml",
+        r#"
+        [slots.boot-c]
+        type = "file"
+        path = "/slots/boot-c.img"
+
+        [slots.system-c]
+        type = "file"
+        path = "/slots/system-c.img"
+
+        [boot-groups.a]
+        slots = { boot = "boot-a", system = "system-a" }
+
+        [boot-groups.b]
+        slots = { boot = "boot-b", system = "system-b" }
+
+        [boot-groups.c]
+        slots = { boot = "boot-c", system = "system-c" }
+        "#,
+    );
+    let bundle_dir = system.path("bundle-scripts");
+    fs::create_dir_all(bundle_dir.join("payloads")).unwrap();
+    let mut manifest = "update-type = \"incremental\"\n".to_owned();
+    for stage in ["pre-install", "post-install", "first-boot"] {
+        manifest.push_str(&format!(
+            r#"
+            [[payloads]]
+            filename = "{stage}.sh"
+            [payloads.delivery]
+            type = "script"
+            stage = "{stage}"
+            interpreter = ["/bin/sh"]
+            "#
+        ));
+        fs::write(
+            bundle_dir.join(format!("payloads/{stage}.sh")),
+            "echo \"$RUGIX_SCRIPT_STAGE $RUGIX_BOOT_GROUP\" >> \"$RUGIX_ROOT_PREFIX/scripts.log\"\n",
+        )
+        .unwrap();
+    }
+    fs::write(bundle_dir.join("rugix-bundle.toml"), manifest).unwrap();
+    let bundle = system.path("scripts.rugixb");
+    rugix_bundle::builder::pack(&bundle_dir, &bundle, &[]).unwrap();
+    // Scripts of all stages get the boot group first-boot scripts are stored for.
+    system.ctrl(&["update", "install", bundle.to_str().unwrap()]);
+    assert_eq!(
+        system.read("scripts.log"),
+        "pre-install a\npost-install a\n"
+    );
+    let first_boot_dir = system.path("run/rugix/mounts/data/rugix/scripts/a/first-boot");
+    assert_eq!(fs::read_dir(first_boot_dir).unwrap().count(), 2);
+}
+
+#[test]
+fn test_custom_slot_block_source() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    // Handlers are commands and, hence, not resolved under the root prefix.
+    let handler = system.path("usr/lib/rugix/app-handler");
+    system.write(
+        "etc/rugix/system.d/50-app.toml",
+        &format!(
+            r#"
+            [slots.app-a]
+            type = "custom"
+            handler = [{handler:?}]
+
+            [slots.app-b]
+            type = "custom"
+            handler = [{handler:?}]
+            "#
+        ),
+    );
+    system.write("usr/lib/rugix/app-handler", CUSTOM_HANDLER);
+    fs::set_permissions(&handler, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(system.path("slots")).unwrap();
+    let data = (0..64 * 1024u32)
+        .map(|idx| (idx.wrapping_mul(2654435761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    for slot in ["app-a", "app-b"] {
+        let bundle = create_block_encoded_bundle(system.root.path(), slot, &data);
+        system.ctrl(&["update", "install", bundle.to_str().unwrap()]);
+        assert_eq!(
+            fs::read(system.path(&format!("slots/{slot}.img"))).unwrap(),
+            data
+        );
+        assert_eq!(
+            system.read(&format!("slots/{slot}.img.size")).trim(),
+            data.len().to_string()
+        );
+    }
+    // The blocks of the second update have been read from the first slot.
+    let reads = system.read("slots/reads.log");
+    assert!(reads.lines().all(|line| line.starts_with("app-a ")));
+    assert_eq!(reads.lines().count(), 16);
+    // All blocks have been read by a single process.
+    assert_eq!(system.read("slots/read-processes.log"), "app-a\n");
+}
+
+#[test]
+fn test_custom_slot_deduplicated_payload() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    let handler = system.path("usr/lib/rugix/app-handler");
+    system.write(
+        "etc/rugix/system.d/50-app.toml",
+        &format!(
+            r#"
+            [slots.app-a]
+            type = "custom"
+            handler = [{handler:?}]
+            "#
+        ),
+    );
+    system.write("usr/lib/rugix/app-handler", CUSTOM_HANDLER);
+    fs::set_permissions(&handler, fs::Permissions::from_mode(0o755)).unwrap();
+    fs::create_dir_all(system.path("slots")).unwrap();
+    // The first and the second half of the payload consist of the same blocks.
+    let half = (0..32 * 1024u32)
+        .map(|idx| (idx.wrapping_mul(2654435761) >> 24) as u8)
+        .collect::<Vec<_>>();
+    let data = [half.as_slice(), half.as_slice()].concat();
+    let bundle = create_block_encoded_bundle(system.root.path(), "app-a", &data);
+    system.ctrl(&["update", "install", bundle.to_str().unwrap()]);
+    assert_eq!(fs::read(system.path("slots/app-a.img")).unwrap(), data);
+    // Deduplicated blocks are not read back from the handler.
+    assert!(!system.path("slots/reads.log").exists());
+}
+
+#[test]
+fn test_cached_bundles() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    let data = (0..64 * 1024)
+        .map(|idx| (idx / 4096) as u8)
+        .collect::<Vec<_>>();
+    let cached = create_block_encoded_bundle(system.root.path(), "cached", &data);
+    system.write(
+        "etc/rugix/system.d/50-app.toml",
+        &format!(
+            "cached-bundles = [{cached:?}]\n\n[slots.app]\ntype = \"file\"\npath = \"/app.img\"\n"
+        ),
+    );
+    // Indices of bundles which are no longer cached are removed.
+    let bundles_dir = system.path("run/rugix/mounts/data/rugix/bundles");
+    fs::create_dir_all(bundles_dir.join("stale")).unwrap();
+    let bundle = create_block_encoded_bundle(system.root.path(), "app", &data);
+    system.ctrl(&["update", "install", bundle.to_str().unwrap()]);
+    assert_eq!(fs::read(system.path("app.img")).unwrap(), data);
+    let index_dirs = fs::read_dir(&bundles_dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .collect::<Vec<_>>();
+    assert_eq!(index_dirs.len(), 1);
+    assert_ne!(index_dirs[0].file_name().unwrap(), "stale");
+    let index_files = fs::read_dir(&index_dirs[0]).unwrap().count();
+    assert_eq!(index_files, 1);
+}
+
+#[test]
+fn test_directory_slot_deduplicated_payload() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    system.write(
+        "etc/rugix/system.d/50-app.toml",
+        "[slots.app]\ntype = \"directory\"\npath = \"/app\"\n",
+    );
+    let bundle = create_block_encoded_bundle(system.root.path(), "app", &[0; 64 * 1024]);
+    let stderr = system.ctrl_fails(&["update", "install", bundle.to_str().unwrap()]);
+    assert!(stderr.contains("does not support payloads with deduplicated blocks"));
+    assert!(!system.path("app").exists());
+}
+
+#[test]
+fn test_block_slots_by_partlabel() {
+    let Some(system) = TestSystem::new("u-boot") else {
+        return;
+    };
+    // Partitions with the given label are found via `/dev/disk/by-partlabel`.
+    for (slot, partition) in [("system-a", 5), ("system-b", 6)] {
+        let link = system.path(&format!("dev/disk/by-partlabel/{slot}"));
+        fs::create_dir_all(link.parent().unwrap()).unwrap();
+        std::os::unix::fs::symlink(system.disk.partition(partition), link).unwrap();
+    }
+    let config = system
+        .read("etc/rugix/system.toml")
+        .replace("partition = 5", "partlabel = \"system-a\"")
+        .replace("partition = 6", "partlabel = \"system-b\"");
+    system.write("etc/rugix/system.toml", &config);
+    assert_eq!(system.boot_groups(), ("a".to_owned(), "a".to_owned()));
+    system.install_update(&create_bundle(system.root.path()));
+    system.reboot();
+    assert_eq!(system.boot_groups(), ("b".to_owned(), "a".to_owned()));
+    assert_eq!(system.version(), "new system");
+}